@@ -1,13 +1,502 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{Decode, Encode, Nat, Principal};
+use ic_cdk::api::management_canister::ecdsa::{
+    ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
+    SignWithEcdsaArgument,
+};
+use ic_cdk::api::management_canister::http_request::{
+    http_request as http_outcall, CanisterHttpRequestArgument, HttpHeader, HttpMethod,
+    HttpResponse, TransformArgs, TransformContext,
+};
+use ic_ledger_types::{
+    AccountIdentifier, Block, BlockIndex, GetBlocksArgs, Memo, Operation, DEFAULT_SUBACCOUNT,
+    MAINNET_LEDGER_CANISTER_ID,
+};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    ops::Bound,
+    thread::LocalKey,
+    time::Duration,
+};
 
 // Define type aliases for memory management
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+// ((corporate account id, YYYYMM month), rental id)
+type StatementLineKey = ((u64, u64), u64);
+
+// Dates are expressed in nanoseconds since the Unix epoch, matching ic_cdk::api::time()
+const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+const NANOS_PER_HOUR: u64 = 3_600_000_000_000;
+const NANOS_PER_MINUTE: u64 = 60_000_000_000;
+// Ages and license holding periods count years of 365.25 days
+const NANOS_PER_YEAR: u64 = 31_557_600_000_000_000;
+// How long the result of a call made with an idempotency key is replayed
+const IDEMPOTENCY_KEY_TTL: u64 = 24 * NANOS_PER_HOUR;
+
+// Multipliers are expressed in basis points, 10_000 leaves a price unchanged
+const BPS_DENOMINATOR: u64 = 10_000;
+// Most loyalty points a customer can earn for every ICP spent
+const MAX_LOYALTY_POINTS_PER_ICP: u64 = 1_000_000;
+
+// First byte of the subaccounts that hold rental deposits
+const DEPOSIT_SUBACCOUNT_TAG: u8 = 1;
+// First byte of the subaccounts rentals are paid into with ckBTC
+const CKBTC_PAYMENT_SUBACCOUNT_TAG: u8 = 2;
+
+// The ckBTC ledger on the mainnet, and how long a ckBTC price is held for a rental
+const MAINNET_CKBTC_LEDGER_CANISTER_ID: &str = "mxzaz-hqaaa-aaaar-qaada-cai";
+const CKBTC_QUOTE_TTL: u64 = 30 * NANOS_PER_MINUTE;
+
+// Largest number of items returned by one page of a list query
+const MAX_PAGE_SIZE: u32 = 100;
+
+// Lower bounds, in days, of the buckets completed rentals are counted in by length and by
+// how far ahead they were booked
+const RENTAL_DAYS_BUCKETS: [u64; 7] = [0, 2, 3, 4, 8, 15, 31];
+const LEAD_DAYS_BUCKETS: [u64; 7] = [0, 1, 2, 8, 15, 31, 91];
+
+// Largest number of records written to one export chunk
+const EXPORT_CHUNK_ROWS: usize = 200;
+
+// Version of the backup format, raised whenever its layout changes
+const BACKUP_FORMAT_VERSION: u32 = 1;
+// Largest number of key and value bytes written to one backup chunk, leaving room for the
+// chunk to be sent back within the ingress message limit
+const BACKUP_CHUNK_BYTES: usize = 1_500_000;
+
+// Version of the layout cars and rental requests are stored in, raised whenever a field is
+// added. The version is the first byte of a stored record. Records stored before versions
+// existed start with the Candid magic "DIDL" instead and are version 0, so no version may
+// be b'D'. Version 1 is Candid and version 2 packed CBOR. Version 3 of cars adds their
+// marketplace listing.
+const CAR_SCHEMA_VERSION: u8 = 3;
+const RENTAL_SCHEMA_VERSION: u8 = 2;
+// Start of every Candid encoding, telling records stored as Candid from packed CBOR ones
+const CANDID_MAGIC: &[u8] = b"DIDL";
+// Largest number of records rewritten by one migration call
+const MIGRATION_BATCH_SIZE: usize = 500;
+
+// Largest number of items changed by one batch call
+const MAX_BATCH_SIZE: usize = 100;
+// Largest number of cars imported by one CSV chunk
+const MAX_CSV_IMPORT_ROWS: usize = 500;
+// Columns of a car CSV import; the first five are required
+const CAR_CSV_COLUMNS: [&str; 15] = [
+    "make",
+    "model",
+    "year",
+    "daily_rate_e8s",
+    "category",
+    "vin",
+    "license_plate",
+    "color",
+    "transmission",
+    "fuel_type",
+    "seats",
+    "mileage_km",
+    "features",
+    "branch_id",
+    "fuel_policy",
+];
+
+// VIN decoder queried by HTTPS outcall, followed by the VIN
+const VIN_DECODER_URL: &str = "https://vpic.nhtsa.dot.gov/api/vehicles/DecodeVinValues/";
+const VIN_DECODER_MAX_RESPONSE_BYTES: u64 = 16_384;
+// Cycles attached to the outcall; whatever is not charged is refunded
+const VIN_DECODER_CYCLES: u128 = 1_000_000_000;
+
+// Prices are kept in this currency; quotes and invoices can also be shown in others
+const BASE_CURRENCY: &str = "ICP";
+// The exchange rate canister on the mainnet, and the cycles every call to it costs
+const MAINNET_XRC_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+const XRC_CYCLES: u128 = 1_000_000_000;
+// Minutes a fetched exchange rate is used unless the config says otherwise
+const DEFAULT_EXCHANGE_RATE_TTL_MINUTES: u64 = 10;
+// Symbols the exchange rate canister knows as cryptocurrencies; others are fiat currencies
+const CRYPTO_CURRENCIES: [&str; 5] = ["BTC", "ETH", "ICP", "USDC", "USDT"];
+
+// Name and symbol of the ICRC-7 collection of car tokens
+const CAR_TOKEN_COLLECTION_NAME: &str = "Car Rental Fleet";
+const CAR_TOKEN_SYMBOL: &str = "CAR";
+// How far a token transfer's created_at_time may lie in the future, and how old it may be
+const CAR_TOKEN_PERMITTED_DRIFT: u64 = 2 * NANOS_PER_MINUTE;
+const CAR_TOKEN_TX_WINDOW: u64 = 24 * NANOS_PER_HOUR;
+// Threshold ECDSA key rental agreements are signed with unless the config names another
+const MAINNET_ECDSA_KEY_NAME: &str = "key_1";
+// Derivation path of the key rental agreements are signed with
+const AGREEMENT_DERIVATION_PATH: &[u8] = b"rental-agreement";
+// vetKD key customer details are encrypted under unless the config names another
+const MAINNET_VETKD_KEY_NAME: &str = "key_1";
+// Domain separator of the keys customer details are encrypted under
+const PII_KEY_CONTEXT: &[u8] = b"customer-pii";
+// Cycles attached to a vetkd_derive_key call with the mainnet key
+const VETKD_DERIVE_KEY_CYCLES: u128 = 26_153_846_153;
+// Largest rental agreement template accepted
+const MAX_AGREEMENT_TEMPLATE_BYTES: usize = 4_000;
+// Rental agreement used until admins set a template of their own
+const DEFAULT_AGREEMENT_TEMPLATE: &str = "RENTAL AGREEMENT #{rental_id}
+
+Renter: {customer} (customer #{customer_id})
+Vehicle: {car}, license plate {license_plate}
+Rental period: {start} to {end} (UTC)
+Pick-up: {pickup_branch}
+Drop-off: {dropoff_branch}
+
+Price: {price}
+Discounts: {discount}
+Security deposit: {deposit}
+Included distance: {included_km}
+Fuel policy: {fuel_policy}
+Protection: {protection}
+
+The renter accepts the terms of service version {terms_version} and agrees to return \
+the vehicle by the end of the rental period in the condition it was handed over.";
+// Largest terms of service text accepted
+const MAX_TERMS_TEXT_BYTES: usize = 16_384;
+// Largest encrypted customer details accepted
+const MAX_ENCRYPTED_PII_BYTES: usize = 1_536;
+// Most principals a car's earnings can be shared between
+const MAX_CAR_OWNERS: usize = 20;
+// Share of a marketplace rental the platform keeps unless the config says otherwise
+const DEFAULT_MARKETPLACE_COMMISSION_BPS: u64 = 1_000;
+
+// Car photos are uploaded and served in chunks of at most this many bytes
+const PHOTO_CHUNK_SIZE: u32 = 262_144;
+const MAX_PHOTO_SIZE: u64 = 5 * 1024 * 1024;
+const PHOTO_CONTENT_TYPES: [&str; 3] = ["image/jpeg", "image/png", "image/webp"];
+
+// How often the scheduler advances rental statuses
+const RENTAL_SCHEDULER_INTERVAL: Duration = Duration::from_secs(300);
+// How often the statements of the month that ended are looked for
+const STATEMENT_SCHEDULER_INTERVAL: Duration = Duration::from_secs(3_600);
+// Hours ahead of a rental's start and end its customer is reminded unless the config says
+// otherwise
+const DEFAULT_REMINDER_HOURS: u64 = 24;
+// Holds a caller may have active at once
+const MAX_ACTIVE_HOLDS: usize = 3;
+// Largest number of rentals archived by one scheduler run
+const ARCHIVE_BATCH_SIZE: usize = 100;
+// Longest summary of a record kept in an audit entry
+const AUDIT_SUMMARY_BYTES: usize = 1_024;
+// How often events are pushed to subscribers, and the most pushed in one call
+const EVENT_DELIVERY_INTERVAL: Duration = Duration::from_secs(30);
+const EVENT_DELIVERY_BATCH: usize = 50;
+// Longest wait before a failed delivery is retried
+const EVENT_RETRY_MAX_BACKOFF: u64 = NANOS_PER_HOUR;
+const MAX_EVENT_SUBSCRIPTIONS: u64 = 20;
+// Most events posted to a webhook in one request, and the limits of webhook and messaging
+// provider outcalls
+const WEBHOOK_DELIVERY_BATCH: usize = 20;
+const WEBHOOK_MAX_RESPONSE_BYTES: u64 = 2_048;
+const WEBHOOK_CYCLES: u128 = 3_000_000_000;
+const MAX_WEBHOOKS: u64 = 20;
+// Delivery attempts kept in the log of each webhook
+const WEBHOOK_DELIVERY_LOG_SIZE: usize = 100;
+// Notifications kept in each customer's inbox
+const MAX_NOTIFICATIONS_PER_CUSTOMER: usize = 200;
+// Most messages sent per run, attempts before a message is given up, and log entries kept
+const MESSAGE_SEND_BATCH: usize = 20;
+const MAX_MESSAGE_ATTEMPTS: u32 = 5;
+const MESSAGE_LOG_SIZE: usize = 1_000;
+// Sections of the certified tree, in the order of their labels, and the entries hashed
+// together in one bucket of a section
+const CERTIFIED_SECTIONS: [&[u8]; 3] = [b"availability", b"cars", b"rentals"];
+const CERTIFIED_AVAILABILITY: u8 = 0;
+const CERTIFIED_CARS: u8 = 1;
+const CERTIFIED_RENTALS: u8 = 2;
+const CERTIFIED_BUCKET_SIZE: u64 = 64;
+// Window searched by the HTTP availability route when none is given
+const HTTP_AVAILABILITY_WINDOW: u64 = 30 * NANOS_PER_DAY;
+
+// Methods ingress messages may call, sorted so they can be searched. Keep them in sync with
+// the #[ic_cdk::update] and #[ic_cdk::query] functions.
+const UPDATE_METHODS: [&str; 133] = [
+    "accept_terms",
+    "add_account_admin",
+    "add_add_on",
+    "add_authorized_driver",
+    "add_branch",
+    "add_car",
+    "add_car_from_vin",
+    "add_cars_batch",
+    "add_coupon",
+    "add_maintenance_record",
+    "add_pricing_rule",
+    "add_rental_request",
+    "anonymize_customer",
+    "apply_coupon",
+    "approve_car_listing",
+    "begin_upload",
+    "cancel_maintenance",
+    "cancel_rental",
+    "check_in_rental",
+    "check_out_car",
+    "complete_car_transfer",
+    "confirm_ckbtc_payment",
+    "confirm_payment",
+    "confirm_pickup",
+    "convert_hold",
+    "create_api_key",
+    "create_corporate_account",
+    "create_hold",
+    "decode_vin",
+    "deduct_damage_from_deposit",
+    "delete_add_on",
+    "delete_branch",
+    "delete_car",
+    "delete_coupon",
+    "delete_customer",
+    "delete_maintenance_record",
+    "delete_photo",
+    "delete_pricing_rule",
+    "delete_rental_request",
+    "derive_customer_pii_key",
+    "derive_my_pii_key",
+    "erase_my_data",
+    "file_damage_report",
+    "finish_upload",
+    "forfeit_deposit",
+    "generate_referral_code",
+    "get_agreement_public_key",
+    "get_ckbtc_payment_quote",
+    "get_pii_public_key",
+    "get_quote_in_currency",
+    "grant_role",
+    "http_request_update",
+    "icrc7_transfer",
+    "import_cars_csv",
+    "issue_gift_card",
+    "join_waitlist",
+    "leave_waitlist",
+    "list_my_car",
+    "mark_notification_read",
+    "migrate_records",
+    "mint_car_token",
+    "moderate_review",
+    "patch_car",
+    "patch_rental_request",
+    "pay_deposit",
+    "pay_for_rental",
+    "process_refund",
+    "publish_terms",
+    "redeem_gift_card",
+    "redeem_points",
+    "regenerate_agreement_document",
+    "register_customer",
+    "register_webhook",
+    "reissue_handover_code",
+    "reject_car_listing",
+    "release_deposit",
+    "release_hold",
+    "remove_account_admin",
+    "remove_authorized_driver",
+    "remove_drop_fee",
+    "remove_webhook",
+    "request_car_transfer",
+    "request_extension",
+    "request_payout",
+    "restore_state",
+    "retire_car",
+    "return_early",
+    "revoke_api_key",
+    "revoke_role",
+    "schedule_maintenance",
+    "set_add_on_stock",
+    "set_agreement_template",
+    "set_archive_after_days",
+    "set_cancellation_policy",
+    "set_car_acquisition",
+    "set_car_insurance",
+    "set_car_owners",
+    "set_date_of_birth",
+    "set_driver_license",
+    "set_drop_fee",
+    "set_eligibility_rules",
+    "set_hold_minutes",
+    "set_loyalty_rates",
+    "set_marketplace_commission",
+    "set_message_provider",
+    "set_message_template",
+    "set_opaque_ids",
+    "set_preferred_currency",
+    "set_rate_limit",
+    "set_reminder_policy",
+    "sign_rental_agreement",
+    "store_encrypted_pii",
+    "submit_review",
+    "subscribe_events",
+    "top_up_wallet",
+    "transfer_ownership",
+    "transition_rental_status",
+    "transition_rentals_batch",
+    "unsubscribe_events",
+    "update_add_on",
+    "update_branch",
+    "update_car",
+    "update_car_availability_batch",
+    "update_config",
+    "update_corporate_account",
+    "update_coupon",
+    "update_customer_profile",
+    "update_maintenance_record",
+    "update_pricing_rule",
+    "update_rental_request",
+    "upload_chunk",
+    "waive_damage_report",
+    "withdraw_earnings",
+];
+const QUERY_METHODS: [&str; 131] = [
+    "backup_state",
+    "export_cars",
+    "export_invoices",
+    "export_rentals",
+    "get_add_on",
+    "get_add_on_availability",
+    "get_agreement_document",
+    "get_agreement_template",
+    "get_archived_rental",
+    "get_branch",
+    "get_cancellation_fee",
+    "get_car",
+    "get_car_acquisition",
+    "get_car_availability",
+    "get_car_availability_certified",
+    "get_car_certified",
+    "get_car_insurance",
+    "get_car_owners",
+    "get_car_pnl",
+    "get_car_transfer",
+    "get_condition_report",
+    "get_config",
+    "get_corporate_account",
+    "get_corporate_statement",
+    "get_coupon",
+    "get_current_terms",
+    "get_customer",
+    "get_customer_stats",
+    "get_damage_report",
+    "get_demand_stats",
+    "get_deposit",
+    "get_deposit_requirement",
+    "get_encrypted_pii",
+    "get_handover_code",
+    "get_hold",
+    "get_invoice",
+    "get_maintenance_record",
+    "get_messaging_settings",
+    "get_my_earnings",
+    "get_my_referrals",
+    "get_outstanding_charges",
+    "get_owner",
+    "get_payment",
+    "get_payment_account",
+    "get_photo",
+    "get_photo_chunk",
+    "get_points_balance",
+    "get_preferred_currency",
+    "get_quote",
+    "get_rental_request",
+    "get_rental_request_certified",
+    "get_rental_timing_stats",
+    "get_review",
+    "get_signed_agreement",
+    "get_system_stats",
+    "get_terms",
+    "get_terms_acceptances",
+    "get_top_customers",
+    "get_waitlist_entry",
+    "get_wallet_balance",
+    "http_request",
+    "icrc10_supported_standards",
+    "icrc7_balance_of",
+    "icrc7_collection_metadata",
+    "icrc7_name",
+    "icrc7_owner_of",
+    "icrc7_symbol",
+    "icrc7_token_metadata",
+    "icrc7_tokens",
+    "icrc7_tokens_of",
+    "icrc7_total_supply",
+    "list_account_rentals",
+    "list_add_ons",
+    "list_api_keys",
+    "list_archived_rentals",
+    "list_audit_entries",
+    "list_authorized_drivers",
+    "list_branches",
+    "list_car_photos",
+    "list_car_token_transfers",
+    "list_car_transfers",
+    "list_cars",
+    "list_cars_at_branch",
+    "list_cars_by_category",
+    "list_cars_page",
+    "list_cars_with_expiring_insurance",
+    "list_corporate_accounts",
+    "list_corporate_statements",
+    "list_coupons",
+    "list_customers_with_expiring_licenses",
+    "list_damage_reports_for_car",
+    "list_damage_reports_for_rental",
+    "list_drop_fees",
+    "list_event_subscriptions",
+    "list_events",
+    "list_gift_cards",
+    "list_invoices_for_customer",
+    "list_maintenance_for_car",
+    "list_maintenance_windows",
+    "list_my_cars",
+    "list_my_fleet_rentals",
+    "list_my_notifications",
+    "list_overdue_rentals",
+    "list_payouts",
+    "list_pending_car_listings",
+    "list_pending_reviews",
+    "list_pricing_rules",
+    "list_refunds_for_rental",
+    "list_rental_add_ons",
+    "list_rental_extensions",
+    "list_rental_requests",
+    "list_rental_requests_for_car",
+    "list_rental_requests_for_car_page",
+    "list_rental_requests_for_customer",
+    "list_rental_requests_for_customer_page",
+    "list_rental_requests_page",
+    "list_rentals_in_period",
+    "list_reviews_for_car",
+    "list_roles",
+    "list_sent_messages",
+    "list_waitlist",
+    "list_wallet_transactions",
+    "list_webhook_deliveries",
+    "list_webhooks",
+    "my_profile",
+    "search_cars",
+    "search_cars_by_text",
+    "search_rentals",
+    "transform_vin_response",
+    "transform_webhook_response",
+    "whoami",
+];
+// Update methods the anonymous principal may call: the HTTP gateway calls anonymously
+const ANONYMOUS_UPDATE_METHODS: [&str; 1] = ["http_request_update"];
+// Largest argument accepted from an ingress message, and the methods allowed more
+const MAX_INGRESS_ARG_BYTES: usize = 65_536;
+const LARGE_ARG_METHODS: [(&str, usize); 3] = [
+    ("import_cars_csv", 262_144),
+    ("restore_state", 2_097_152),
+    ("upload_chunk", PHOTO_CHUNK_SIZE as usize + 1_024),
+];
 
 // Define the structure for a car
 #[derive(candid::CandidType, Serialize, Deserialize, Clone)]
@@ -17,304 +506,18801 @@ struct Car {
     model: String,
     year: u32,
     available: bool,
+    daily_rate_e8s: u64,
+    category: CarCategory,
+    vin: Option<String>,
+    license_plate: String,
+    color: String,
+    transmission: Option<Transmission>,
+    fuel_type: Option<FuelType>,
+    seats: u8,
+    mileage_km: u64,
+    features: Vec<String>,
+    branch_id: Option<u64>,
+    fuel_policy: Option<FuelPolicy>,
+    rating: Option<CarRating>,
+    // Advances with every change, so concurrent edits can be detected
+    version: u64,
+    // Cars stored before statuses existed have none and are active
+    status: Option<CarStatus>,
+    retired_at: Option<u64>,
+    // Set for cars listed on the marketplace by their owner rather than added by staff
+    listed_by: Option<Principal>,
+    listing_status: Option<ListingStatus>,
 }
 
-// Define the structure for a rental request
-#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
-struct RentalRequest {
-    id: u64,
-    car_id: u64,
-    customer_id: u64,
-    start_date: u64,
-    end_date: u64,
-    status: RentalStatus, // Pending, Active, Completed, Canceled
+// Define the classes of cars in the fleet
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum CarCategory {
+    Economy,
+    Compact,
+    Midsize,
+    Suv,
+    Van,
+    Luxury,
+    Electric,
 }
 
-// Define the possible statuses for a rental request
-#[derive(Debug, PartialEq, candid::CandidType, Deserialize, Serialize, Clone)]
-enum RentalStatus {
+// Define where a car listed by its owner is in review. Only approved listings can be
+// booked; editing a rejected listing submits it for review again.
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone)]
+enum ListingStatus {
     Pending,
+    Approved,
+    Rejected { reason: String },
+}
+
+// Define whether a car is still in the fleet. Cars that left it are kept for the history
+// of their rentals but cannot be booked and are left out of listings.
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum CarStatus {
     Active,
-    Completed,
-    Canceled,
+    Retired,
+    Sold,
+    WrittenOff,
 }
 
-// Implement serialization and deserialization for Car
-impl Storable for Car {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+// Define the average of the star ratings customers gave a car
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CarRating {
+    review_count: u64,
+    average_rating: f64,
+}
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Define the fields accepted when creating or updating a car
+#[derive(candid::CandidType, Deserialize)]
+struct CarPayload {
+    make: String,
+    model: String,
+    year: u32,
+    daily_rate_e8s: u64,
+    category: CarCategory,
+    vin: Option<String>,
+    license_plate: String,
+    color: String,
+    transmission: Option<Transmission>,
+    fuel_type: Option<FuelType>,
+    seats: u8,
+    mileage_km: u64,
+    features: Vec<String>,
+    branch_id: Option<u64>,
+    fuel_policy: Option<FuelPolicy>,
 }
 
-// Implement bounds for Car serialization
-impl BoundedStorable for Car {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Define a partial update of a car; only the fields given are changed
+#[derive(candid::CandidType, Deserialize)]
+struct CarPatch {
+    make: Option<String>,
+    model: Option<String>,
+    year: Option<u32>,
+    daily_rate_e8s: Option<u64>,
+    category: Option<CarCategory>,
+    vin: Option<String>,
+    license_plate: Option<String>,
+    color: Option<String>,
+    transmission: Option<Transmission>,
+    fuel_type: Option<FuelType>,
+    seats: Option<u8>,
+    mileage_km: Option<u64>,
+    features: Option<Vec<String>>,
+    branch_id: Option<u64>,
+    fuel_policy: Option<FuelPolicy>,
 }
 
-// Implement serialization and deserialization for RentalRequest
-impl Storable for RentalRequest {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+// Define the shapes cars were stored in as Candid before the latest one. The fields added
+// over time are optional, so every earlier layout decodes with the details it has.
+#[derive(Deserialize, candid::CandidType)]
+struct LegacyCar {
+    id: u64,
+    make: String,
+    model: String,
+    year: u32,
+    available: bool,
+    daily_rate_e8s: Option<u64>,
+    category: Option<CarCategory>,
+    vin: Option<String>,
+    license_plate: Option<String>,
+    color: Option<String>,
+    transmission: Option<Transmission>,
+    fuel_type: Option<FuelType>,
+    seats: Option<u8>,
+    mileage_km: Option<u64>,
+    features: Option<Vec<String>>,
+    branch_id: Option<u64>,
+    fuel_policy: Option<FuelPolicy>,
+    rating: Option<CarRating>,
+    version: Option<u64>,
+    status: Option<CarStatus>,
+    retired_at: Option<u64>,
+    listed_by: Option<Principal>,
+    listing_status: Option<ListingStatus>,
+}
+
+impl Car {
+    fn from_payload(id: u64, available: bool, payload: CarPayload) -> Self {
+        Car {
+            id,
+            make: payload.make,
+            model: payload.model,
+            year: payload.year,
+            available,
+            daily_rate_e8s: payload.daily_rate_e8s,
+            category: payload.category,
+            vin: payload.vin.map(|vin| vin.trim().to_uppercase()),
+            license_plate: payload.license_plate,
+            color: payload.color,
+            transmission: payload.transmission,
+            fuel_type: payload.fuel_type,
+            seats: payload.seats,
+            mileage_km: payload.mileage_km,
+            features: payload.features,
+            branch_id: payload.branch_id,
+            fuel_policy: payload.fuel_policy,
+            rating: None,
+            version: 0,
+            status: Some(CarStatus::Active),
+            retired_at: None,
+            listed_by: None,
+            listing_status: None,
+        }
     }
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
+    fn is_active(&self) -> bool {
+        self.status.unwrap_or(CarStatus::Active) == CarStatus::Active
+    }
+
+    // Active cars are listed unless their owner's listing awaits review or was rejected
+    fn is_listed(&self) -> bool {
+        self.is_active()
+            && self
+                .listing_status
+                .as_ref()
+                .is_none_or(|status| *status == ListingStatus::Approved)
     }
 }
 
-// Implement bounds for RentalRequest serialization
-impl BoundedStorable for RentalRequest {
-    const MAX_SIZE: u32 = 1024;
-    const IS_FIXED_SIZE: bool = false;
+// Migrate a car stored in an earlier layout; details it did not have are left empty. Cars
+// stored before rates and categories existed are free Economy cars until staff price them.
+impl From<LegacyCar> for Car {
+    fn from(car: LegacyCar) -> Self {
+        Car {
+            id: car.id,
+            make: car.make,
+            model: car.model,
+            year: car.year,
+            available: car.available,
+            daily_rate_e8s: car.daily_rate_e8s.unwrap_or(0),
+            category: car.category.unwrap_or(CarCategory::Economy),
+            vin: car.vin,
+            license_plate: car.license_plate.unwrap_or_default(),
+            color: car.color.unwrap_or_default(),
+            transmission: car.transmission,
+            fuel_type: car.fuel_type,
+            seats: car.seats.unwrap_or(0),
+            mileage_km: car.mileage_km.unwrap_or(0),
+            features: car.features.unwrap_or_default(),
+            branch_id: car.branch_id,
+            fuel_policy: car.fuel_policy,
+            rating: car.rating,
+            version: car.version.unwrap_or(0),
+            status: car.status,
+            retired_at: car.retired_at,
+            listed_by: car.listed_by,
+            listing_status: car.listing_status,
+        }
+    }
 }
 
-// Thread-local storage for memory management, ID counter, car storage, and rental request storage
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+// Define the vehicle details decoded from a VIN
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct VinDetails {
+    vin: String,
+    make: String,
+    model: String,
+    year: Option<u32>,
+}
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+// Define the outcome of a CSV import, by row number counting the header as row 1
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CsvImportReport {
+    accepted: Vec<CsvAcceptedRow>,
+    rejected: Vec<CsvRejectedRow>,
+}
 
-    static CAR_STORAGE: RefCell<StableBTreeMap<u64, Car, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+// Define a CSV row that was imported and the car it added
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CsvAcceptedRow {
+    row: u64,
+    car_id: u64,
+}
 
-    static RENTAL_REQUEST_STORAGE: RefCell<StableBTreeMap<u64, RentalRequest, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
-    ));
+// Define a CSV row that was not imported and why
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CsvRejectedRow {
+    row: u64,
+    error: Error,
 }
 
-// Define the possible errors
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
-    InvalidInput { msg: String },
+// Define a car photo; its bytes are stored as chunks and served once the upload is finished
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Photo {
+    id: u64,
+    car_id: u64,
+    content_type: String,
+    size: u64,
+    chunk_count: u32,
+    uploaded_by: Principal,
+    uploaded_at: u64,
+    finished: bool,
 }
 
-// Implement CRUD operations for cars
-#[ic_cdk::update]
-fn add_car(make: String, model: String, year: u32) -> Result<Car, Error> {
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment id counter");
+impl Photo {
+    // Length a chunk must have, given that all but the last chunk are full
+    fn chunk_len(&self, index: u32) -> u64 {
+        let offset = index as u64 * PHOTO_CHUNK_SIZE as u64;
+        self.size
+            .saturating_sub(offset)
+            .min(PHOTO_CHUNK_SIZE as u64)
+    }
+}
 
-    let car = Car {
-        id,
-        make,
-        model,
-        year,
-        available: true,
-    };
+// Define the gearbox types
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum Transmission {
+    Manual,
+    Automatic,
+}
 
-    CAR_STORAGE.with(|storage| storage.borrow_mut().insert(id, car.clone()));
-    Ok(car)
+// Define the fuel types
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum FuelType {
+    Petrol,
+    Diesel,
+    Hybrid,
+    Electric,
 }
 
-#[ic_cdk::update]
-fn delete_car(id: u64) -> Result<(), Error> {
-    match CAR_STORAGE.with(|storage| storage.borrow_mut().remove(&id)) {
-        Some(_) => Ok(()),
-        None => Err(Error::NotFound {
-            msg: format!("Car with id={} not found", id),
-        }),
-    }
+// Define how fuel is paid for; cars without a policy are rented full to full
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum FuelPolicy {
+    // The car is returned with at least the fuel it was handed over with
+    FullToFull,
+    // Fuel is included in the price and the car may be returned with any level
+    Prepaid,
 }
 
-// Implement query operations for the car rental system
-#[ic_cdk::query]
-fn get_car(id: u64) -> Result<Car, Error> {
-    match CAR_STORAGE.with(|storage| storage.borrow().get(&id)) {
-        Some(car) => Ok(car.clone()),
-        None => Err(Error::NotFound {
-            msg: format!("Car with id={} not found", id),
-        }),
-    }
+// Define a branch that cars are kept at and rentals are picked up from and dropped off at
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Branch {
+    id: u64,
+    name: String,
+    address: String,
+    latitude: f64,
+    longitude: f64,
+    opening_hours: Vec<OpeningHours>,
 }
 
-#[ic_cdk::query]
-fn get_rental_request(id: u64) -> Result<RentalRequest, Error> {
-    match RENTAL_REQUEST_STORAGE.with(|storage| storage.borrow().get(&id)) {
-        Some(rental_request) => Ok(rental_request.clone()),
-        None => Err(Error::NotFound {
-            msg: format!("Rental request with id={} not found", id),
-        }),
-    }
+// Define the fields accepted when creating or updating a branch
+#[derive(candid::CandidType, Deserialize)]
+struct BranchPayload {
+    name: String,
+    address: String,
+    latitude: f64,
+    longitude: f64,
+    opening_hours: Vec<OpeningHours>,
 }
 
-#[ic_cdk::query]
-fn list_cars() -> Vec<Car> {
-    CAR_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .map(|(_, car)| car.clone())
-            .collect()
-    })
+// Define the hours a branch is open on a weekday, in minutes after midnight
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct OpeningHours {
+    weekday: Weekday,
+    opens_at_minute: u16,
+    closes_at_minute: u16,
 }
 
-#[ic_cdk::query]
-fn list_rental_requests() -> Vec<RentalRequest> {
-    RENTAL_REQUEST_STORAGE.with(|storage| {
-        storage
-            .borrow()
-            .iter()
-            .map(|(_, request)| request.clone())
-            .collect()
-    })
+// Define the fee for a one-way rental picked up at one branch and dropped off at another
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct DropFee {
+    from_branch_id: u64,
+    to_branch_id: u64,
+    fee_e8s: u64,
 }
 
-#[ic_cdk::update]
-fn add_rental_request(
+// Define the move of a car between branches, kept as audit history once completed
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CarTransfer {
+    id: u64,
     car_id: u64,
-    customer_id: u64,
-    start_date: u64,
-    end_date: u64,
-    status: RentalStatus,
-) -> Result<RentalRequest, Error> {
-    let id = ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .expect("Cannot increment id counter");
+    from_branch_id: Option<u64>,
+    to_branch_id: u64,
+    status: TransferStatus,
+    requested_by: Principal,
+    requested_at: u64,
+    completed_by: Option<Principal>,
+    completed_at: Option<u64>,
+}
 
-    let rental_request = RentalRequest {
-        id,
-        car_id,
-        customer_id,
-        start_date,
-        end_date,
-        status,
-    };
+// Define the statuses of a car transfer
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum TransferStatus {
+    InTransit,
+    Completed,
+}
 
-    RENTAL_REQUEST_STORAGE
-        .with(|storage| storage.borrow_mut().insert(id, rental_request.clone()));
+// Define a record of maintenance performed on a car
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct MaintenanceRecord {
+    id: u64,
+    car_id: u64,
+    kind: MaintenanceKind,
+    description: String,
+    cost_e8s: u64,
+    odometer_km: u64,
+    performed_at: u64,
+    vendor: String,
+    recorded_by: Principal,
+}
 
-    Ok(rental_request)
+// Define the fields accepted when creating or updating a maintenance record
+#[derive(candid::CandidType, Deserialize)]
+struct MaintenanceRecordPayload {
+    car_id: u64,
+    kind: MaintenanceKind,
+    description: String,
+    cost_e8s: u64,
+    odometer_km: u64,
+    performed_at: u64,
+    vendor: String,
 }
 
-#[ic_cdk::update]
-fn delete_rental_request(id: u64) -> Result<(), Error> {
-    match RENTAL_REQUEST_STORAGE.with(|storage| storage.borrow_mut().remove(&id)) {
-        Some(_) => Ok(()),
-        None => Err(Error::NotFound {
-            msg: format!("Rental request with id={} not found", id),
-        }),
-    }
+// Define the kinds of maintenance
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum MaintenanceKind {
+    Service,
+    Repair,
+    Inspection,
+    Tires,
+    Cleaning,
+    Other,
+}
+
+// Define a planned period during which a car is off the road for maintenance
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct MaintenanceWindow {
+    id: u64,
+    car_id: u64,
+    start_date: u64,
+    end_date: u64,
+    description: String,
+    status: MaintenanceWindowStatus,
+    scheduled_by: Principal,
+    // Availability of the car when the window started, restored when it ends
+    car_was_available: bool,
 }
 
+// Define the fields accepted when scheduling a maintenance window
+#[derive(candid::CandidType, Deserialize)]
+struct MaintenanceWindowPayload {
+    car_id: u64,
+    start_date: u64,
+    end_date: u64,
+    description: String,
+}
 
-#[ic_cdk::query]
-fn list_rental_requests_for_car(car_id: u64) -> Vec<RentalRequest> {
-    RENTAL_REQUEST_STORAGE
-        .with(|storage| {
-            storage
-                .borrow()
-                .iter()
-                .filter_map(|(_, request)| {
-                    if request.car_id == car_id {
-                        Some(request.clone())
-                    } else {
-                        None
-                    }
-                })
-                .collect()
-        })
+// Define the statuses of a maintenance window
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum MaintenanceWindowStatus {
+    Scheduled,
+    InProgress,
+    Finished,
+    Canceled,
 }
 
-#[ic_cdk::query]
-fn list_rental_requests_for_customer(customer_id: u64) -> Vec<RentalRequest> {
-    RENTAL_REQUEST_STORAGE
-        .with(|storage| {
-            storage
-                .borrow()
-                .iter()
-                .filter_map(|(_, request)| {
-                    if request.customer_id == customer_id {
-                        Some(request.clone())
-                    } else {
-                        None
-                    }
-                })
-                .collect()
-        })
+// Define a report of damage found on a car at the end of a rental
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct DamageReport {
+    id: u64,
+    rental_id: u64,
+    car_id: u64,
+    description: String,
+    severity: DamageSeverity,
+    photo_ids: Vec<u64>,
+    estimated_cost_e8s: u64,
+    status: DamageStatus,
+    reported_by: Principal,
+    reported_at: u64,
+    // Amount kept from the rental's deposit to cover the damage
+    deducted_e8s: u64,
+    deposit_transfer_id: Option<u64>,
 }
 
-#[ic_cdk::update]
-fn update_car(id: u64, make: String, model: String, year: u32) -> Result<Car, Error> {
-    match CAR_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if let Some(car) = storage.get(&id) {
-            // Create a cloned copy of the car to update
-            let mut updated_car = car.clone();
-            // Update the car fields
-            updated_car.make = make;
-            updated_car.model = model;
-            updated_car.year = year;
-            // Replace the old car with the updated one
-            storage.insert(id, updated_car.clone());
-            Ok(updated_car)
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Car with id={} not found", id),
-            })
-        }
-    }) {
-        Ok(car) => Ok(car),
-        Err(e) => Err(e),
-    }
+// Define the fields accepted when filing a damage report
+#[derive(candid::CandidType, Deserialize)]
+struct DamageReportPayload {
+    rental_id: u64,
+    description: String,
+    severity: DamageSeverity,
+    photo_ids: Vec<u64>,
+    estimated_cost_e8s: u64,
 }
 
-#[ic_cdk::update]
-fn update_rental_request(
+// Define a customer's review of the car of a completed rental
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Review {
     id: u64,
+    rental_id: u64,
     car_id: u64,
     customer_id: u64,
-    start_date: u64,
-    end_date: u64,
-    status: RentalStatus,
-) -> Result<RentalRequest, Error> {
-    match RENTAL_REQUEST_STORAGE.with(|storage| {
-        let mut storage = storage.borrow_mut();
-        if let Some(rental_request) = storage.get(&id) {
-            // Create a cloned copy of the rental request to update
-            let mut updated_rental_request = rental_request.clone();
-            // Update the rental request fields
-            updated_rental_request.car_id = car_id;
-            updated_rental_request.customer_id = customer_id;
-            updated_rental_request.start_date = start_date;
-            updated_rental_request.end_date = end_date;
-            updated_rental_request.status = status;
-            // Replace the old rental request with the updated one
-            storage.insert(id, updated_rental_request.clone());
-            Ok(updated_rental_request)
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Rental request with id={} not found", id),
-            })
-        }
-    }) {
-        Ok(rental_request) => Ok(rental_request),
-        Err(e) => Err(e),
-    }
+    rating: u8,
+    comment: String,
+    created_at: u64,
+    status: ReviewStatus,
+    moderated_by: Option<Principal>,
+    moderated_at: Option<u64>,
 }
 
-// Error handling
-// Implement error handling for the functions above
+// Define the moderation statuses of a review; only approved reviews are public
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum ReviewStatus {
+    Pending,
+    Approved,
+    Rejected,
+    // Held for an admin to decide
+    Flagged,
+}
 
-// Export the Candid interface
-ic_cdk::export_candid!();
\ No newline at end of file
+// Define how serious a damage is
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum DamageSeverity {
+    Minor,
+    Moderate,
+    Severe,
+}
+
+// Define the statuses of a damage report
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum DamageStatus {
+    Reported,
+    Deducted,
+    Waived,
+}
+
+// Define the insurance policy covering a car
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct InsurancePolicy {
+    car_id: u64,
+    provider: String,
+    policy_number: String,
+    coverage: String,
+    expires_at: u64,
+}
+
+// Define the fields accepted when setting the insurance policy of a car
+#[derive(candid::CandidType, Deserialize)]
+struct InsurancePolicyPayload {
+    provider: String,
+    policy_number: String,
+    coverage: String,
+    expires_at: u64,
+}
+
+// Define what a car cost and how it loses value. Its value falls in a straight line from
+// the purchase price to the residual value over its useful life.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CarAcquisition {
+    car_id: u64,
+    purchase_price_e8s: u64,
+    acquired_at: u64,
+    residual_value_e8s: u64,
+    useful_life_days: u64,
+}
+
+// Define the fields accepted when recording what a car cost
+#[derive(candid::CandidType, Deserialize)]
+struct CarAcquisitionPayload {
+    purchase_price_e8s: u64,
+    acquired_at: u64,
+    residual_value_e8s: u64,
+    useful_life_days: u64,
+}
+
+impl CarAcquisition {
+    // The value the car lost within the period
+    fn depreciation_in(&self, period: &Period) -> u64 {
+        let life_end = self
+            .acquired_at
+            .saturating_add(self.useful_life_days.saturating_mul(NANOS_PER_DAY));
+        let from = period.from.max(self.acquired_at);
+        let to = period.to.min(life_end);
+        if to <= from {
+            return 0;
+        }
+        let depreciable = self
+            .purchase_price_e8s
+            .saturating_sub(self.residual_value_e8s);
+        (depreciable as u128 * (to - from) as u128 / (life_end - self.acquired_at) as u128) as u64
+    }
+}
+
+// Define the period [from, to) a report covers
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy)]
+struct Period {
+    from: u64,
+    to: u64,
+}
+
+impl Period {
+    fn contains(&self, time: u64) -> bool {
+        self.from <= time && time < self.to
+    }
+}
+
+// Define the profit and loss of a car over a period. Revenue is what was paid for its
+// rentals in the period net of refunds, plus the charges settled in the period.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CarPnl {
+    car_id: u64,
+    period: Period,
+    payments: u64,
+    rental_revenue_e8s: u64,
+    charges_e8s: u64,
+    maintenance_records: u64,
+    maintenance_cost_e8s: u64,
+    // Zero until what the car cost is recorded
+    depreciation_e8s: u64,
+    profit_e8s: i64,
+}
+
+impl MaintenanceRecord {
+    fn from_payload(id: u64, recorded_by: Principal, payload: MaintenanceRecordPayload) -> Self {
+        MaintenanceRecord {
+            id,
+            car_id: payload.car_id,
+            kind: payload.kind,
+            description: payload.description,
+            cost_e8s: payload.cost_e8s,
+            odometer_km: payload.odometer_km,
+            performed_at: payload.performed_at,
+            vendor: payload.vendor,
+            recorded_by,
+        }
+    }
+}
+
+impl Branch {
+    fn from_payload(id: u64, payload: BranchPayload) -> Self {
+        Branch {
+            id,
+            name: payload.name,
+            address: payload.address,
+            latitude: payload.latitude,
+            longitude: payload.longitude,
+            opening_hours: payload.opening_hours,
+        }
+    }
+}
+
+// Define the structure for a rental request
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct RentalRequest {
+    id: u64,
+    car_id: u64,
+    customer_id: u64,
+    start_date: u64,
+    end_date: u64,
+    status: RentalStatus, // Pending, Approved, Active, Completed, Canceled, Expired
+    owner: Principal,
+    overdue: bool,
+    quoted_price_e8s: u64,
+    payment_id: Option<u64>,
+    transfer_id: Option<u64>,
+    completed_at: Option<u64>,
+    points_redeemed: u64,
+    points_discount_e8s: u64,
+    coupon_id: Option<u64>,
+    coupon_discount_e8s: u64,
+    pickup_branch_id: Option<u64>,
+    dropoff_branch_id: Option<u64>,
+    drop_fee_e8s: u64,
+    checkout_odometer_km: Option<u64>,
+    checkin_odometer_km: Option<u64>,
+    // Kilometers included in the price; None means unlimited
+    included_km: Option<u64>,
+    fuel_policy: Option<FuelPolicy>,
+    checkout_fuel_percent: Option<u8>,
+    checkin_fuel_percent: Option<u8>,
+    // The protection plan chosen, as priced when booked
+    protection: Option<ProtectionPlan>,
+    protection_e8s: u64,
+    add_ons_e8s: u64,
+    // Set once the customer collected the car with the handover code
+    picked_up_at: Option<u64>,
+    early_return_credit_e8s: u64,
+    cancellation_fee_e8s: u64,
+    // Corporate account the rental is billed to
+    corporate_account_id: Option<u64>,
+    // Advances with every change, so concurrent edits can be detected
+    version: u64,
+    // When the rental was booked; unknown for rentals booked before this was recorded
+    requested_at: Option<u64>,
+}
+
+// Define the structure for a verified ledger payment of a rental
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Payment {
+    id: u64,
+    rental_id: u64,
+    customer_id: u64,
+    payer: Principal,
+    amount_e8s: u64,
+    block_index: u64,
+    paid_at: u64,
+    method: PaymentMethod,
+    refunded_e8s: u64,
+    wallet_e8s: u64,
+}
+
+impl Payment {
+    // Split a refund into its wallet and ledger shares; the share paid from the wallet is
+    // refunded to the wallet first
+    fn refund_split(&self, amount_e8s: u64) -> (u64, u64) {
+        let wallet_e8s = self
+            .wallet_e8s
+            .saturating_sub(self.refunded_e8s)
+            .min(amount_e8s);
+        (wallet_e8s, amount_e8s - wallet_e8s)
+    }
+}
+
+// Define the shapes payments were stored in as Candid before the latest one. The fields
+// added over time are optional, so every earlier layout decodes with what it has.
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyPayment {
+    id: u64,
+    rental_id: u64,
+    customer_id: u64,
+    payer: Principal,
+    amount_e8s: u64,
+    block_index: u64,
+    paid_at: u64,
+    method: PaymentMethod,
+    refunded_e8s: Option<u64>,
+    wallet_e8s: Option<u64>,
+}
+
+// Migrate a payment stored in an earlier layout. Payments stored before refunds or the
+// wallet existed had nothing refunded and nothing paid from a wallet.
+impl From<LegacyPayment> for Payment {
+    fn from(payment: LegacyPayment) -> Self {
+        Payment {
+            id: payment.id,
+            rental_id: payment.rental_id,
+            customer_id: payment.customer_id,
+            payer: payment.payer,
+            amount_e8s: payment.amount_e8s,
+            block_index: payment.block_index,
+            paid_at: payment.paid_at,
+            method: payment.method,
+            refunded_e8s: payment.refunded_e8s.unwrap_or(0),
+            wallet_e8s: payment.wallet_e8s.unwrap_or(0),
+        }
+    }
+}
+
+// Define how a payment was collected
+#[derive(Debug, PartialEq, candid::CandidType, Deserialize, Serialize, Clone)]
+enum PaymentMethod {
+    IcpTransfer,
+    Icrc2TransferFrom { ledger: Principal },
+    Wallet,
+    // Satoshis received in the rental's ckBTC payment subaccount
+    Ckbtc { ledger: Principal, amount_sats: u64 },
+}
+
+// Define the reasons a token payment can be refused by the ledger
+#[derive(Debug, candid::CandidType, Deserialize, Serialize)]
+enum PaymentError {
+    InsufficientFunds { balance_e8s: u64 },
+    InsufficientAllowance { allowance_e8s: u64 },
+    BadFee { expected_fee_e8s: u64 },
+    Duplicate { duplicate_of: u64 },
+    TemporarilyUnavailable,
+    Rejected { msg: String },
+}
+
+// Define the shapes rental requests were stored in as Candid before the latest one. The
+// fields added over time are optional, so every earlier layout decodes with what it has.
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyRentalRequest {
+    id: u64,
+    car_id: u64,
+    customer_id: u64,
+    start_date: u64,
+    end_date: u64,
+    status: RentalStatus,
+    owner: Option<Principal>,
+    overdue: Option<bool>,
+    quoted_price_e8s: Option<u64>,
+    payment_id: Option<u64>,
+    transfer_id: Option<u64>,
+    completed_at: Option<u64>,
+    points_redeemed: Option<u64>,
+    points_discount_e8s: Option<u64>,
+    coupon_id: Option<u64>,
+    coupon_discount_e8s: Option<u64>,
+    pickup_branch_id: Option<u64>,
+    dropoff_branch_id: Option<u64>,
+    drop_fee_e8s: Option<u64>,
+    checkout_odometer_km: Option<u64>,
+    checkin_odometer_km: Option<u64>,
+    included_km: Option<u64>,
+    fuel_policy: Option<FuelPolicy>,
+    checkout_fuel_percent: Option<u8>,
+    checkin_fuel_percent: Option<u8>,
+    protection: Option<ProtectionPlan>,
+    protection_e8s: Option<u64>,
+    add_ons_e8s: Option<u64>,
+    picked_up_at: Option<u64>,
+    early_return_credit_e8s: Option<u64>,
+    cancellation_fee_e8s: Option<u64>,
+    corporate_account_id: Option<u64>,
+    version: Option<u64>,
+    requested_at: Option<u64>,
+}
+
+// Migrate a rental request stored in an earlier layout. Rentals stored before they had an
+// owner belong to their customer's principal, or to the management canister, which never
+// calls, if that is not known either.
+impl From<LegacyRentalRequest> for RentalRequest {
+    fn from(request: LegacyRentalRequest) -> Self {
+        let owner = request.owner.unwrap_or_else(|| {
+            // The customer map may be borrowed while this rental is read
+            CUSTOMER_STORAGE
+                .with(|storage| {
+                    storage
+                        .try_borrow()
+                        .ok()
+                        .and_then(|storage| storage.get(&request.customer_id))
+                })
+                .map_or(Principal::management_canister(), |customer| customer.owner)
+        });
+        RentalRequest {
+            id: request.id,
+            car_id: request.car_id,
+            customer_id: request.customer_id,
+            start_date: request.start_date,
+            end_date: request.end_date,
+            status: request.status,
+            owner,
+            overdue: request.overdue.unwrap_or(false),
+            quoted_price_e8s: request.quoted_price_e8s.unwrap_or(0),
+            payment_id: request.payment_id,
+            transfer_id: request.transfer_id,
+            completed_at: request.completed_at,
+            points_redeemed: request.points_redeemed.unwrap_or(0),
+            points_discount_e8s: request.points_discount_e8s.unwrap_or(0),
+            coupon_id: request.coupon_id,
+            coupon_discount_e8s: request.coupon_discount_e8s.unwrap_or(0),
+            pickup_branch_id: request.pickup_branch_id,
+            dropoff_branch_id: request.dropoff_branch_id,
+            drop_fee_e8s: request.drop_fee_e8s.unwrap_or(0),
+            checkout_odometer_km: request.checkout_odometer_km,
+            checkin_odometer_km: request.checkin_odometer_km,
+            included_km: request.included_km,
+            fuel_policy: request.fuel_policy,
+            checkout_fuel_percent: request.checkout_fuel_percent,
+            checkin_fuel_percent: request.checkin_fuel_percent,
+            protection: request.protection,
+            protection_e8s: request.protection_e8s.unwrap_or(0),
+            add_ons_e8s: request.add_ons_e8s.unwrap_or(0),
+            picked_up_at: request.picked_up_at,
+            early_return_credit_e8s: request.early_return_credit_e8s.unwrap_or(0),
+            cancellation_fee_e8s: request.cancellation_fee_e8s.unwrap_or(0),
+            corporate_account_id: request.corporate_account_id,
+            version: request.version.unwrap_or(0),
+            requested_at: request.requested_at,
+        }
+    }
+}
+
+// Define the one-time code a customer presents to collect the car of an active rental
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct HandoverCode {
+    rental_id: u64,
+    code: String,
+    issued_at: u64,
+}
+
+// Define an extension of a rental's end date and the price it added
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct RentalExtension {
+    id: u64,
+    rental_id: u64,
+    previous_end_date: u64,
+    new_end_date: u64,
+    price_delta_e8s: u64,
+    requested_by: Principal,
+    requested_at: u64,
+}
+
+// Define a company whose authorized drivers rent cars billed to the account
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CorporateAccount {
+    id: u64,
+    name: String,
+    billing_email: String,
+    // Principals who manage the account and its drivers
+    admins: Vec<Principal>,
+    // Most the account's rentals starting in one calendar month may cost; None is unlimited
+    monthly_spending_limit_e8s: Option<u64>,
+    created_at: u64,
+}
+
+// Define the fields accepted when creating or updating a corporate account
+#[derive(candid::CandidType, Deserialize)]
+struct CorporateAccountPayload {
+    name: String,
+    billing_email: String,
+    monthly_spending_limit_e8s: Option<u64>,
+}
+
+// Define the statement of a corporate account for a calendar month, given as YYYYMM. Its
+// lines are stored apart from it and filled in when it is read.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CorporateStatement {
+    account_id: u64,
+    month: u32,
+    issued_at: u64,
+    lines: Vec<StatementLine>,
+    rentals_e8s: u64,
+    fees_e8s: u64,
+    tax_e8s: u64,
+    total_e8s: u64,
+    paid_e8s: u64,
+    balance_due_e8s: u64,
+}
+
+// Define a line of a corporate statement: a completed rental's invoice, or the
+// cancellation fee of a canceled rental
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct StatementLine {
+    rental_id: u64,
+    customer_id: u64,
+    invoice_number: Option<u64>,
+    rentals_e8s: u64,
+    fees_e8s: u64,
+    tax_e8s: u64,
+    total_e8s: u64,
+    paid_e8s: u64,
+}
+
+// Define a customer waiting for a car to become free over a period; entries are served in
+// the order they joined
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct WaitlistEntry {
+    id: u64,
+    car_id: u64,
+    customer_id: u64,
+    owner: Principal,
+    start_date: u64,
+    end_date: u64,
+    joined_at: u64,
+    expires_at: u64,
+    status: WaitlistStatus,
+}
+
+// Define the statuses of a waitlist entry
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum WaitlistStatus {
+    Waiting,
+    // A pending rental request was created for the entry
+    Promoted { rental_id: u64 },
+    Expired,
+    Left,
+}
+
+// Define the condition of a car when it was checked in at the end of a rental
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct ConditionReport {
+    rental_id: u64,
+    car_id: u64,
+    odometer_km: u64,
+    fuel_percent: u8,
+    notes: String,
+    checked_in_by: Principal,
+    checked_in_at: u64,
+}
+
+// Define the readings and notes taken when a car is checked in
+#[derive(candid::CandidType, Deserialize)]
+struct CheckInPayload {
+    odometer_km: u64,
+    fuel_percent: u8,
+    condition_notes: String,
+}
+
+// Define the fields accepted when creating a rental request
+#[derive(candid::CandidType, Deserialize)]
+struct RentalRequestPayload {
+    car_id: u64,
+    customer_id: u64,
+    start_date: u64,
+    end_date: u64,
+    status: RentalStatus,
+    pickup_branch_id: Option<u64>,
+    dropoff_branch_id: Option<u64>,
+    // Defaults to the Basic tier
+    protection_tier: Option<ProtectionTier>,
+    add_ons: Vec<AddOnSelection>,
+    corporate_account_id: Option<u64>,
+}
+
+// Define the fields accepted when updating a rental request last read at a version
+#[derive(candid::CandidType, Deserialize)]
+struct RentalRequestUpdatePayload {
+    car_id: u64,
+    customer_id: u64,
+    start_date: u64,
+    end_date: u64,
+    expected_version: u64,
+}
+
+// Define a partial update of a rental request; only the fields given are changed
+#[derive(candid::CandidType, Deserialize)]
+struct RentalPatch {
+    car_id: Option<u64>,
+    customer_id: Option<u64>,
+    start_date: Option<u64>,
+    end_date: Option<u64>,
+}
+
+// Define an extra rented alongside a car, such as a GPS or a child seat
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct AddOn {
+    id: u64,
+    name: String,
+    description: String,
+    daily_rate_e8s: u64,
+}
+
+// Define the fields accepted when creating or updating an add-on
+#[derive(candid::CandidType, Deserialize)]
+struct AddOnPayload {
+    name: String,
+    description: String,
+    daily_rate_e8s: u64,
+}
+
+// Define the quantity of an add-on requested for a rental
+#[derive(candid::CandidType, Deserialize, Clone)]
+struct AddOnSelection {
+    add_on_id: u64,
+    quantity: u32,
+}
+
+// Define an add-on reserved for a rental, as priced when booked
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct RentalAddOn {
+    add_on_id: u64,
+    name: String,
+    quantity: u32,
+    daily_rate_e8s: u64,
+}
+
+impl AddOn {
+    fn from_payload(id: u64, payload: AddOnPayload) -> Self {
+        AddOn {
+            id,
+            name: payload.name,
+            description: payload.description,
+            daily_rate_e8s: payload.daily_rate_e8s,
+        }
+    }
+}
+
+// Define the protection packages a customer can choose for a rental
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum ProtectionTier {
+    Basic,
+    Standard,
+    Premium,
+}
+
+// Define the price and deductible of a protection tier. The deductible is the most a
+// customer pays towards damage to the car.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct ProtectionPlan {
+    tier: ProtectionTier,
+    daily_rate_e8s: u64,
+    deductible_e8s: u64,
+}
+
+impl RentalRequest {
+    // The amount the customer pays after discounts
+    fn price_due_e8s(&self) -> u64 {
+        self.quoted_price_e8s
+            .saturating_sub(self.coupon_discount_e8s)
+            .saturating_sub(self.points_discount_e8s)
+    }
+}
+
+// Define a refund paid back against an earlier payment
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Refund {
+    id: u64,
+    rental_id: u64,
+    payment_id: u64,
+    amount_e8s: u64,
+    reason: String,
+    transfer_id: Option<u64>,
+    wallet_e8s: u64,
+    refunded_by: Principal,
+    refunded_at: u64,
+    // Satoshis sent back, for a refund of a ckBTC payment
+    amount_sats: Option<u64>,
+}
+
+// Define a movement of a customer's prepaid wallet balance; debits are negative
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct WalletTransaction {
+    id: u64,
+    customer_id: u64,
+    kind: WalletTransactionKind,
+    amount_e8s: i64,
+    balance_after_e8s: u64,
+    at: u64,
+}
+
+// Define the reasons a wallet balance changes
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+enum WalletTransactionKind {
+    TopUp { transfer_id: u64 },
+    GiftCard { gift_card_id: u64 },
+    RentalPayment { rental_id: u64 },
+    RentalPaymentReversed { rental_id: u64 },
+    Refund { rental_id: u64 },
+    EarlyReturnCredit { rental_id: u64 },
+}
+
+// Define a gift card that credits a customer's wallet once redeemed
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct GiftCard {
+    id: u64,
+    code: String,
+    amount_e8s: u64,
+    issued_by: Principal,
+    issued_at: u64,
+    redeemed_by: Option<u64>,
+    redeemed_at: Option<u64>,
+}
+
+// Define an amount billed to a customer for a rental on top of the quoted price
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Charge {
+    id: u64,
+    rental_id: u64,
+    customer_id: u64,
+    kind: ChargeKind,
+    description: String,
+    amount_e8s: u64,
+    created_at: u64,
+    settled: bool,
+}
+
+// Define the kinds of charges
+#[derive(Debug, PartialEq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum ChargeKind {
+    LateFee,
+    MileageOverage,
+    Refueling,
+    EarlyReturnFee,
+    CancellationFee,
+}
+
+// Define the invoice issued when a rental is completed
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Invoice {
+    number: u64,
+    rental_id: u64,
+    customer_id: u64,
+    issued_at: u64,
+    lines: Vec<InvoiceLine>,
+    total_e8s: u64,
+    paid_e8s: u64,
+    balance_due_e8s: u64,
+    // The amounts in the customer's preferred currency, at the rate when it was issued
+    conversion: Option<CurrencyConversion>,
+}
+
+// Define a line item of an invoice, credits are negative
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct InvoiceLine {
+    kind: InvoiceLineKind,
+    description: String,
+    amount_e8s: i64,
+}
+
+// Define the kinds of invoice line items
+#[derive(Debug, PartialEq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum InvoiceLineKind {
+    BaseRate,
+    AddOn,
+    LateFee,
+    MileageOverage,
+    Refueling,
+    EarlyReturnFee,
+    EarlyReturnCredit,
+    CancellationFee,
+    Discount,
+    Tax,
+    DepositAdjustment,
+}
+
+// Define the lifecycle of a security deposit
+#[derive(Debug, PartialEq, candid::CandidType, Deserialize, Serialize, Clone)]
+enum DepositState {
+    Held,
+    PartiallyReleased,
+    Released,
+    Forfeited,
+}
+
+// Define a movement of funds out of a deposit
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct DepositMovement {
+    amount_e8s: u64,
+    forfeited: bool,
+    reason: String,
+    transfer_id: u64,
+    at: u64,
+}
+
+// Define the security deposit held for a rental in its own subaccount
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Deposit {
+    rental_id: u64,
+    subaccount: Vec<u8>,
+    amount_e8s: u64,
+    released_e8s: u64,
+    forfeited_e8s: u64,
+    state: DepositState,
+    movements: Vec<DepositMovement>,
+}
+
+impl Deposit {
+    fn remaining_e8s(&self) -> u64 {
+        self.amount_e8s - self.released_e8s - self.forfeited_e8s
+    }
+
+    // Record a movement out of the deposit and move it to the state its totals imply
+    fn record_movement(&mut self, movement: DepositMovement) {
+        if movement.forfeited {
+            self.forfeited_e8s += movement.amount_e8s;
+        } else {
+            self.released_e8s += movement.amount_e8s;
+        }
+        self.state = if self.remaining_e8s() > 0 {
+            DepositState::PartiallyReleased
+        } else if self.released_e8s == 0 {
+            DepositState::Forfeited
+        } else {
+            DepositState::Released
+        };
+        self.movements.push(movement);
+    }
+}
+
+// Define the deposit amount required for a car category
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CategoryAmount {
+    category: CarCategory,
+    amount_e8s: u64,
+}
+
+// Define the kilometers per rental day included in the price for a car category
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CategoryMileage {
+    category: CarCategory,
+    km_per_day: u64,
+}
+
+// Define who may rent a car category; a zero minimum or an empty class list is not enforced
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct EligibilityRule {
+    category: CarCategory,
+    min_age_years: u64,
+    license_classes: Vec<String>,
+    min_license_years: u64,
+}
+
+// Define a cancellation fee: canceling at least min_hours_before_start before the start date
+// costs fee_bps of the price, and the tier with the most hours that applies is used
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CancellationTier {
+    min_hours_before_start: u64,
+    fee_bps: u64,
+}
+
+// Define the structure for a customer
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Customer {
+    id: u64,
+    name: String,
+    email: String,
+    phone: String,
+    license_number: String,
+    created_at: u64,
+    owner: Principal,
+    referral_code: Option<String>,
+    license: Option<DriverLicense>,
+    // Signed so customers born before 1970 can be recorded
+    date_of_birth: Option<i64>,
+    // Set once the customer's personal data was erased
+    erased_at: Option<u64>,
+}
+
+// Define the rental history and spend of a customer, including archived rentals. Spend is
+// what the customer paid net of refunds.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CustomerStats {
+    customer_id: u64,
+    rentals: u64,
+    completed_rentals: u64,
+    canceled_rentals: u64,
+    total_spend_e8s: u64,
+    // Average length of the completed rentals
+    average_rental_days: f64,
+    // Share of the rentals that were canceled, in basis points
+    cancellation_rate_bps: u64,
+}
+
+// Define what a customer paid within a period
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CustomerSpend {
+    customer_id: u64,
+    name: String,
+    rentals_paid: u64,
+    spend_e8s: u64,
+}
+
+// Define how often cars were asked for, and how often the car asked for was not free
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, Default)]
+struct DemandCount {
+    requests: u64,
+    unavailable: u64,
+}
+
+impl DemandCount {
+    fn add(&mut self, other: DemandCount) {
+        self.requests += other.requests;
+        self.unavailable += other.unavailable;
+    }
+}
+
+// Define the demand on one day, starting at the given time
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DayDemand {
+    day: u64,
+    demand: DemandCount,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CategoryDemand {
+    category: CarCategory,
+    demand: DemandCount,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ModelDemand {
+    make: String,
+    model: String,
+    demand: DemandCount,
+}
+
+// Define the booking requests made within a period, by day, category, and model. The
+// models most often unavailable are the ones worth buying more of.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DemandStats {
+    period: Period,
+    total: DemandCount,
+    by_day: Vec<DayDemand>,
+    by_category: Vec<CategoryDemand>,
+    // Most requested first
+    by_model: Vec<ModelDemand>,
+    // Most often unavailable first, leaving out models that always were free
+    most_unavailable: Vec<ModelDemand>,
+}
+
+// Define an overdue rental with the contact details of its customer, who may have been
+// deleted since, and the late fee billed if it were returned now
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OverdueRental {
+    rental: RentalRequest,
+    customer_name: Option<String>,
+    customer_email: Option<String>,
+    customer_phone: Option<String>,
+    hours_overdue: u64,
+    accrued_late_fee_e8s: u64,
+}
+
+// Define the lead time and length of every completed rental, counted as rentals complete so
+// reporting on them does not scan the rentals
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default)]
+struct RentalTiming {
+    completed_rentals: u64,
+    total_rental_days: u64,
+    // Completed rentals by length, one count per bucket of RENTAL_DAYS_BUCKETS
+    rental_days: Vec<u64>,
+    // Completed rentals whose booking time is known; only these have a lead time
+    timed_rentals: u64,
+    total_lead_days: u64,
+    // Timed rentals by lead time, one count per bucket of LEAD_DAYS_BUCKETS
+    lead_days: Vec<u64>,
+}
+
+impl RentalTiming {
+    fn record(&mut self, rental_request: &RentalRequest) {
+        let days = rental_days(rental_request.start_date, rental_request.end_date);
+        self.completed_rentals += 1;
+        self.total_rental_days += days;
+        count_in_bucket(&mut self.rental_days, &RENTAL_DAYS_BUCKETS, days);
+        if let Some(requested_at) = rental_request.requested_at {
+            let lead_days = rental_request.start_date.saturating_sub(requested_at) / NANOS_PER_DAY;
+            self.timed_rentals += 1;
+            self.total_lead_days += lead_days;
+            count_in_bucket(&mut self.lead_days, &LEAD_DAYS_BUCKETS, lead_days);
+        }
+    }
+}
+
+// Define how many rentals fell between two numbers of days. The last bucket has no upper
+// bound.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct DaysBucket {
+    min_days: u64,
+    max_days: Option<u64>,
+    rentals: u64,
+}
+
+// Define the distribution of completed rentals by length and by how long before the start
+// they were booked
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct RentalTimingStats {
+    completed_rentals: u64,
+    average_rental_days: f64,
+    rental_days: Vec<DaysBucket>,
+    timed_rentals: u64,
+    average_lead_days: f64,
+    lead_days: Vec<DaysBucket>,
+}
+
+// Define the shape customers were stored in before they had an owner
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyCustomer {
+    id: u64,
+    name: String,
+    email: String,
+    phone: String,
+    license_number: String,
+    created_at: u64,
+}
+
+// Migrate a customer stored before owners existed. It belongs to the management canister,
+// which never calls.
+impl From<LegacyCustomer> for Customer {
+    fn from(customer: LegacyCustomer) -> Self {
+        Customer {
+            id: customer.id,
+            name: customer.name,
+            email: customer.email,
+            phone: customer.phone,
+            license_number: customer.license_number,
+            created_at: customer.created_at,
+            owner: Principal::management_canister(),
+            referral_code: None,
+            license: None,
+            date_of_birth: None,
+            erased_at: None,
+        }
+    }
+}
+
+// Define the fields accepted when registering a customer or updating their profile
+#[derive(candid::CandidType, Deserialize)]
+struct CustomerPayload {
+    name: String,
+    email: String,
+    phone: String,
+    license_number: String,
+}
+
+// Define the details of a customer's driver's license; the number is kept on the customer
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct DriverLicense {
+    issuing_country: String,
+    class: String,
+    expires_at: u64,
+    issued_at: Option<u64>,
+}
+
+// Define a customer who registered with another customer's referral code
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Referral {
+    referrer_id: u64,
+    referred_id: u64,
+    registered_at: u64,
+    rewarded_at: Option<u64>,
+}
+
+// Define the possible statuses for a rental request
+#[derive(Debug, PartialEq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum RentalStatus {
+    Pending,
+    Approved,
+    Active,
+    Completed,
+    Canceled,
+    Expired,
+}
+
+impl RentalStatus {
+    // Pending, approved, and active rentals are still open and hold the car for their period
+    fn is_open(&self) -> bool {
+        matches!(
+            self,
+            RentalStatus::Pending | RentalStatus::Approved | RentalStatus::Active
+        )
+    }
+
+    // Legal moves: Pending -> Approved/Active/Canceled/Expired, Approved -> Active/Canceled,
+    // and Active -> Completed/Canceled
+    fn can_transition_to(&self, next: &RentalStatus) -> bool {
+        matches!(
+            (self, next),
+            (RentalStatus::Pending, RentalStatus::Approved)
+                | (RentalStatus::Pending, RentalStatus::Active)
+                | (RentalStatus::Pending, RentalStatus::Canceled)
+                | (RentalStatus::Pending, RentalStatus::Expired)
+                | (RentalStatus::Approved, RentalStatus::Active)
+                | (RentalStatus::Approved, RentalStatus::Canceled)
+                | (RentalStatus::Active, RentalStatus::Completed)
+                | (RentalStatus::Active, RentalStatus::Canceled)
+        )
+    }
+}
+
+// Define the booking an availability check leaves out. Rentals and holds count their ids
+// from different sequences, so an id alone does not say which is meant.
+#[derive(PartialEq, Clone, Copy)]
+enum ExcludedBooking {
+    Rental(u64),
+    Hold(u64),
+}
+
+// Define why a car is unavailable during an interval
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+enum BlockReason {
+    Rental {
+        rental_id: u64,
+        status: RentalStatus,
+    },
+    Transfer {
+        transfer_id: u64,
+        to_branch_id: u64,
+    },
+    Maintenance {
+        window_id: u64,
+    },
+    Hold {
+        hold_id: u64,
+    },
+}
+
+// Define an interval during which a car cannot be booked
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct AvailabilityInterval {
+    start_date: u64,
+    end_date: u64,
+    reason: BlockReason,
+}
+
+// Define a single line of a price breakdown; discounts are negative
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct QuoteLine {
+    description: String,
+    amount_e8s: i64,
+}
+
+// Define the price quoted for renting a car over a period
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Quote {
+    car_id: u64,
+    start_date: u64,
+    end_date: u64,
+    days: u64,
+    daily_rate_e8s: u64,
+    pickup_branch_id: Option<u64>,
+    dropoff_branch_id: Option<u64>,
+    drop_fee_e8s: u64,
+    protection: ProtectionPlan,
+    protection_e8s: u64,
+    add_ons: Vec<RentalAddOn>,
+    add_ons_e8s: u64,
+    lines: Vec<QuoteLine>,
+    total_e8s: u64,
+}
+
+// Define what to quote: a car over a period, with the same options as a booking
+#[derive(candid::CandidType, Deserialize)]
+struct QuoteRequest {
+    car_id: u64,
+    start_date: u64,
+    end_date: u64,
+    pickup_branch_id: Option<u64>,
+    dropoff_branch_id: Option<u64>,
+    protection_tier: Option<ProtectionTier>,
+    add_ons: Vec<AddOnSelection>,
+}
+
+// Define the price of one base currency unit in another currency, as the exchange rate
+// canister reported it. The price is rate / 10^decimals.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct ExchangeRate {
+    currency: String,
+    rate: u64,
+    decimals: u32,
+    // Start of the minute the rate is for, in seconds since the epoch
+    timestamp: u64,
+    fetched_at: u64,
+}
+
+impl ExchangeRate {
+    // Convert an amount in e8s of the base currency into 10^-8 units of this currency
+    fn convert(&self, amount_e8s: i64) -> i64 {
+        (amount_e8s as i128 * self.rate as i128 / 10i128.pow(self.decimals)) as i64
+    }
+}
+
+// Define amounts converted from the base currency, with the rate used, so they can be
+// checked later. Amounts are in 10^-8 units of the currency.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CurrencyConversion {
+    rate: ExchangeRate,
+    lines: Vec<i64>,
+    total: u64,
+}
+
+// Define a quote together with its amounts in another currency
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CurrencyQuote {
+    quote: Quote,
+    conversion: CurrencyConversion,
+}
+
+// Define the price in satoshis a rental can be paid with in ckBTC, and where to send it.
+// The price holds until it expires; confirming a payment after that prices it again.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CkbtcQuote {
+    rental_id: u64,
+    account: Account,
+    amount_sats: u64,
+    rate: ExchangeRate,
+    expires_at: u64,
+}
+
+// Define the ICRC-7 token of a car. The token id is the car id, so the car's rentals,
+// revenue, and history stay with the token whoever holds it.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CarToken {
+    car_id: u64,
+    owner: Account,
+    minted_at: u64,
+}
+
+// Define a change of a car token's holder; tokens are minted without a previous holder
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CarTokenTransfer {
+    id: u64,
+    token_id: u64,
+    from: Option<Account>,
+    to: Account,
+    memo: Option<Vec<u8>>,
+    at: u64,
+}
+
+// Define the ICRC-7 interface types
+#[derive(candid::CandidType, Deserialize, Clone)]
+enum Icrc7Value {
+    Nat(Nat),
+    Text(String),
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct Icrc7TransferArg {
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    token_id: Nat,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+enum Icrc7TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct SupportedStandard {
+    name: String,
+    url: String,
+}
+
+// Define the terms of a rental as they are signed. Their JSON encoding, with the fields in
+// this order, is what is hashed and signed.
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct AgreementTerms {
+    canister_id: String,
+    rental_id: u64,
+    customer_id: u64,
+    renter: String,
+    car_id: u64,
+    car: String,
+    license_plate: String,
+    start_date: u64,
+    end_date: u64,
+    pickup_branch_id: Option<u64>,
+    dropoff_branch_id: Option<u64>,
+    price_e8s: u64,
+    discount_e8s: u64,
+    deposit_e8s: u64,
+    included_km: Option<u64>,
+    fuel_policy: Option<FuelPolicy>,
+    protection: Option<ProtectionTier>,
+}
+
+// Define the terms of a rental signed by the canister with its threshold ECDSA key. The
+// signature is over the SHA-256 hash of the terms and is 64 bytes, r followed by s.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct SignedAgreement {
+    rental_id: u64,
+    terms: String,
+    terms_hash: Vec<u8>,
+    signature: Vec<u8>,
+    key_name: String,
+    signed_at: u64,
+}
+
+// Define a principal's share of what a car earns, in basis points
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct OwnerShare {
+    owner: Principal,
+    share_bps: u64,
+}
+
+// Define the owners of a car. Shares total at most 10_000 bps; the operator keeps the rest.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CarOwnership {
+    car_id: u64,
+    shares: Vec<OwnerShare>,
+    updated_at: u64,
+}
+
+// Define a movement of an owner's earnings; withdrawals are negative
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct EarningsEntry {
+    id: u64,
+    owner: Principal,
+    kind: EarningsEntryKind,
+    amount_e8s: i64,
+    balance_after_e8s: u64,
+    at: u64,
+}
+
+// Define the reasons an owner's earnings change
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+enum EarningsEntryKind {
+    RentalShare {
+        rental_id: u64,
+        car_id: u64,
+        share_bps: u64,
+    },
+    // An owner's share of a rental of a car listed on the marketplace, net of their share
+    // of the platform's commission
+    MarketplaceRental {
+        rental_id: u64,
+        car_id: u64,
+        share_bps: u64,
+        commission_e8s: u64,
+    },
+    // The transfer id is set once the ledger accepts the transfer
+    Withdrawal {
+        transfer_id: Option<u64>,
+    },
+    WithdrawalReversed {
+        entry_id: u64,
+    },
+}
+
+// Define a transfer of an owner's earnings to their account on the token ledger
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Payout {
+    id: u64,
+    owner: Principal,
+    amount_e8s: u64,
+    entry_id: u64,
+    transfer_id: u64,
+    paid_at: u64,
+}
+
+// Define what an owner has earned from their cars and what is left to withdraw
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct Earnings {
+    owner: Principal,
+    balance_e8s: u64,
+    earned_e8s: u64,
+    withdrawn_e8s: u64,
+    entries: Vec<EarningsEntry>,
+}
+
+// Define the currency a customer wants quotes and invoices shown in
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct CurrencyPreference {
+    currency: String,
+}
+
+// Define a customer's contact details and license number, encrypted by the customer's
+// client under the vetKD key derived for the customer's principal
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct EncryptedPii {
+    customer_id: u64,
+    ciphertext: Vec<u8>,
+    updated_at: u64,
+}
+
+// Define a version of the terms of service. Customers must have accepted the latest
+// mandatory version, and at least one version, before they can book.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct TermsOfService {
+    version: u64,
+    text: String,
+    // SHA-256 hash of the text, given back when accepting it
+    text_hash: Vec<u8>,
+    mandatory: bool,
+    published_by: Principal,
+    published_at: u64,
+}
+
+// Define a customer's acceptance of a version of the terms of service
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct TermsAcceptance {
+    customer_id: u64,
+    version: u64,
+    principal: Principal,
+    accepted_at: u64,
+}
+
+// Define the template rental agreements are rendered from, with its version. Version 0 is
+// the built-in template.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default)]
+struct AgreementTemplate {
+    version: u64,
+    text: String,
+    updated_by: Option<Principal>,
+    updated_at: u64,
+}
+
+// Define the human-readable agreement of a rental, rendered when it was approved
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct AgreementDocument {
+    rental_id: u64,
+    text: String,
+    // SHA-256 hash of the text
+    text_hash: Vec<u8>,
+    template_version: u64,
+    generated_at: u64,
+}
+
+// Define the vetKD interface of the management canister
+#[derive(candid::CandidType, Deserialize, Clone)]
+enum VetKdCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(candid::CandidType, Deserialize, Clone)]
+struct VetKdKeyId {
+    curve: VetKdCurve,
+    name: String,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKdPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKdPublicKeyResult {
+    public_key: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKdDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct VetKdDeriveKeyResult {
+    encrypted_key: Vec<u8>,
+}
+
+// Define the exchange rate canister's interface, keeping only the fields used here
+#[derive(candid::CandidType, Deserialize)]
+enum XrcAssetClass {
+    Cryptocurrency,
+    FiatCurrency,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct XrcAsset {
+    symbol: String,
+    class: XrcAssetClass,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct XrcRequest {
+    base_asset: XrcAsset,
+    quote_asset: XrcAsset,
+    timestamp: Option<u64>,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct XrcMetadata {
+    decimals: u32,
+}
+
+#[derive(candid::CandidType, Deserialize)]
+struct XrcExchangeRate {
+    timestamp: u64,
+    rate: u64,
+    metadata: XrcMetadata,
+}
+
+#[derive(Debug, candid::CandidType, Deserialize)]
+enum XrcError {
+    AnonymousPrincipalNotAllowed,
+    Pending,
+    CryptoBaseAssetNotFound,
+    CryptoQuoteAssetNotFound,
+    StablecoinRateNotFound,
+    StablecoinRateTooFewRates,
+    StablecoinRateZeroRate,
+    ForexInvalidTimestamp,
+    ForexBaseAssetNotFound,
+    ForexQuoteAssetNotFound,
+    ForexAssetsNotFound,
+    RateLimited,
+    NotEnoughCycles,
+    FailedToAcceptCycles,
+    InconsistentRatesReceived,
+    Other { code: u32, description: String },
+}
+
+// Identify a client-supplied idempotency key; keys are scoped to their caller
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct IdempotencyKey {
+    caller: Principal,
+    key: String,
+}
+
+// Define the result remembered for an idempotency key until it expires
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct IdempotencyRecord {
+    result: IdempotentResult,
+    created_at: u64,
+    expires_at: u64,
+}
+
+// Define the results of the create calls that accept an idempotency key
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+enum IdempotentResult {
+    Car(Car),
+    RentalRequest(RentalRequest),
+}
+
+// Define a car held for a period at a locked price until it is booked or the hold expires
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct QuoteHold {
+    id: u64,
+    car_id: u64,
+    start_date: u64,
+    end_date: u64,
+    owner: Principal,
+    quote: Quote,
+    created_at: u64,
+    expires_at: u64,
+    status: HoldStatus,
+}
+
+// Define the statuses of a quote hold
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum HoldStatus {
+    Active,
+    // The hold was booked as a rental request
+    Converted { rental_id: u64 },
+    Released,
+    Expired,
+}
+
+// Define the days of the week used by pricing rules
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    // The Unix epoch fell on a Thursday
+    fn of(timestamp: u64) -> Weekday {
+        const WEEK: [Weekday; 7] = [
+            Weekday::Thursday,
+            Weekday::Friday,
+            Weekday::Saturday,
+            Weekday::Sunday,
+            Weekday::Monday,
+            Weekday::Tuesday,
+            Weekday::Wednesday,
+        ];
+        WEEK[((timestamp / NANOS_PER_DAY) % 7) as usize]
+    }
+}
+
+// Define a rule that adjusts the daily rate on the days it matches.
+// Empty weekday or category lists match every day or category.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct PricingRule {
+    id: u64,
+    name: String,
+    priority: u32,
+    applies_from: Option<u64>,
+    applies_until: Option<u64>,
+    weekdays: Vec<Weekday>,
+    categories: Vec<CarCategory>,
+    min_rental_days: u64,
+    multiplier_bps: u64,
+    stackable: bool,
+}
+
+// Define the fields accepted when creating or updating a pricing rule
+#[derive(candid::CandidType, Deserialize)]
+struct PricingRulePayload {
+    name: String,
+    priority: u32,
+    applies_from: Option<u64>,
+    applies_until: Option<u64>,
+    weekdays: Vec<Weekday>,
+    categories: Vec<CarCategory>,
+    min_rental_days: u64,
+    multiplier_bps: u64,
+    stackable: bool,
+}
+
+impl PricingRule {
+    fn from_payload(id: u64, payload: PricingRulePayload) -> Self {
+        PricingRule {
+            id,
+            name: payload.name,
+            priority: payload.priority,
+            applies_from: payload.applies_from,
+            applies_until: payload.applies_until,
+            weekdays: payload.weekdays,
+            categories: payload.categories,
+            min_rental_days: payload.min_rental_days,
+            multiplier_bps: payload.multiplier_bps,
+            stackable: payload.stackable,
+        }
+    }
+
+    // Check whether the rule applies to one day of a rental of the given length
+    fn matches(&self, category: CarCategory, day_start: u64, rental_days: u64) -> bool {
+        self.applies_from.is_none_or(|from| day_start >= from)
+            && self.applies_until.is_none_or(|until| day_start < until)
+            && (self.weekdays.is_empty() || self.weekdays.contains(&Weekday::of(day_start)))
+            && (self.categories.is_empty() || self.categories.contains(&category))
+            && rental_days >= self.min_rental_days
+    }
+}
+
+// Define a promo code that discounts the price of a rental.
+// An empty category list applies to every category.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Coupon {
+    id: u64,
+    code: String,
+    discount: CouponDiscount,
+    valid_from: Option<u64>,
+    valid_until: Option<u64>,
+    max_uses: Option<u32>,
+    per_customer_limit: Option<u32>,
+    categories: Vec<CarCategory>,
+    uses: u32,
+}
+
+// Define the fields accepted when creating or updating a coupon
+#[derive(candid::CandidType, Deserialize)]
+struct CouponPayload {
+    code: String,
+    discount: CouponDiscount,
+    valid_from: Option<u64>,
+    valid_until: Option<u64>,
+    max_uses: Option<u32>,
+    per_customer_limit: Option<u32>,
+    categories: Vec<CarCategory>,
+}
+
+// Define how a coupon reduces the price
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy)]
+enum CouponDiscount {
+    Percentage { bps: u64 },
+    Fixed { amount_e8s: u64 },
+}
+
+impl Coupon {
+    fn from_payload(id: u64, uses: u32, payload: CouponPayload) -> Self {
+        Coupon {
+            id,
+            code: normalize_coupon_code(&payload.code),
+            discount: payload.discount,
+            valid_from: payload.valid_from,
+            valid_until: payload.valid_until,
+            max_uses: payload.max_uses,
+            per_customer_limit: payload.per_customer_limit,
+            categories: payload.categories,
+            uses,
+        }
+    }
+
+    // Compute the discount on a price, never more than the price itself
+    fn discount_on(&self, price_e8s: u64) -> u64 {
+        match self.discount {
+            CouponDiscount::Percentage { bps } => price_e8s * bps / BPS_DENOMINATOR,
+            CouponDiscount::Fixed { amount_e8s } => amount_e8s.min(price_e8s),
+        }
+    }
+}
+
+// Define the roles used for access control
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Deserialize, Serialize, Clone, Copy)]
+enum Role {
+    Admin,
+    Staff,
+    Customer,
+}
+
+// Define the structure for the roles granted to a principal
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct RoleAssignment {
+    principal: Principal,
+    roles: Vec<Role>,
+}
+
+// Define the operational settings of the canister
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Config {
+    min_rental_days: u64,
+    max_rental_days: u64,
+    ledger_canister_id: Option<Principal>,
+    token_ledger_canister_id: Option<Principal>,
+    deposits: Vec<CategoryAmount>,
+    ledger_fee_e8s: u64,
+    late_grace_period_minutes: u64,
+    late_fee_per_hour_e8s: u64,
+    late_fee_per_day_e8s: u64,
+    tax_rate_bps: u64,
+    loyalty_points_per_icp: u64,
+    loyalty_point_value_e8s: u64,
+    referral_reward_points: u64,
+    included_km_per_day: Vec<CategoryMileage>,
+    mileage_overage_per_km_e8s: u64,
+    refuel_fee_per_percent_e8s: u64,
+    // Share of the price of the unused full days credited on an early return
+    early_return_credit_bps: u64,
+    // Flat fee billed for returning a car early
+    early_return_fee_e8s: u64,
+    cancellation_tiers: Vec<CancellationTier>,
+    // Share of the price billed when a rental is canceled after its start date
+    no_show_fee_bps: u64,
+    protection_plans: Vec<ProtectionPlan>,
+    eligibility_rules: Vec<EligibilityRule>,
+    // How long a quote hold reserves a car
+    hold_minutes: u64,
+    // How long after it ends a closed rental is moved to the archive
+    archive_after_days: u64,
+    // Update calls a principal without a staff role may make per minute; 0 turns the limit off
+    rate_limit_per_minute: u64,
+    // Draw new car, rental, customer, and payment ids at random instead of in sequence
+    opaque_ids: bool,
+    // How many hours before a rental starts and ends its customer is reminded; 24 by default
+    pickup_reminder_hours: Option<u64>,
+    return_reminder_hours: Option<u64>,
+    // Publish an event for every reminder sent, so webhooks can pass it on; off by default
+    reminder_events: Option<bool>,
+    // Exchange rate canister to convert prices with; defaults to the mainnet one
+    xrc_canister_id: Option<Principal>,
+    // How long a fetched exchange rate is used before it is fetched again; 10 minutes by default
+    exchange_rate_ttl_minutes: Option<u64>,
+    // ckBTC ledger rentals can be paid on; defaults to the mainnet one
+    ckbtc_ledger_canister_id: Option<Principal>,
+    // Share of what is paid for a rental of a car listed by its owner that the platform keeps;
+    // 10% by default
+    marketplace_commission_bps: Option<u64>,
+    // Threshold ECDSA key rental agreements are signed with; defaults to the mainnet one
+    ecdsa_key_name: Option<String>,
+    // vetKD key customer details are encrypted under; defaults to the mainnet one
+    vetkd_key_name: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            min_rental_days: 1,
+            max_rental_days: 90,
+            ledger_canister_id: None,
+            token_ledger_canister_id: None,
+            deposits: vec![
+                CategoryAmount {
+                    category: CarCategory::Economy,
+                    amount_e8s: 500_000_000,
+                },
+                CategoryAmount {
+                    category: CarCategory::Compact,
+                    amount_e8s: 500_000_000,
+                },
+                CategoryAmount {
+                    category: CarCategory::Midsize,
+                    amount_e8s: 750_000_000,
+                },
+                CategoryAmount {
+                    category: CarCategory::Suv,
+                    amount_e8s: 1_000_000_000,
+                },
+                CategoryAmount {
+                    category: CarCategory::Van,
+                    amount_e8s: 1_000_000_000,
+                },
+                CategoryAmount {
+                    category: CarCategory::Luxury,
+                    amount_e8s: 2_000_000_000,
+                },
+                CategoryAmount {
+                    category: CarCategory::Electric,
+                    amount_e8s: 1_000_000_000,
+                },
+            ],
+            ledger_fee_e8s: 10_000,
+            late_grace_period_minutes: 60,
+            late_fee_per_hour_e8s: 10_000_000,
+            late_fee_per_day_e8s: 100_000_000,
+            tax_rate_bps: 0,
+            loyalty_points_per_icp: 10,
+            loyalty_point_value_e8s: 100_000,
+            referral_reward_points: 100,
+            included_km_per_day: Vec::new(),
+            mileage_overage_per_km_e8s: 2_000_000,
+            refuel_fee_per_percent_e8s: 5_000_000,
+            early_return_credit_bps: 5_000,
+            early_return_fee_e8s: 0,
+            cancellation_tiers: vec![
+                CancellationTier {
+                    min_hours_before_start: 48,
+                    fee_bps: 0,
+                },
+                CancellationTier {
+                    min_hours_before_start: 0,
+                    fee_bps: 5_000,
+                },
+            ],
+            no_show_fee_bps: BPS_DENOMINATOR,
+            protection_plans: vec![
+                ProtectionPlan {
+                    tier: ProtectionTier::Basic,
+                    daily_rate_e8s: 0,
+                    deductible_e8s: 1_000_000_000,
+                },
+                ProtectionPlan {
+                    tier: ProtectionTier::Standard,
+                    daily_rate_e8s: 50_000_000,
+                    deductible_e8s: 300_000_000,
+                },
+                ProtectionPlan {
+                    tier: ProtectionTier::Premium,
+                    daily_rate_e8s: 100_000_000,
+                    deductible_e8s: 0,
+                },
+            ],
+            eligibility_rules: Vec::new(),
+            hold_minutes: 15,
+            archive_after_days: 90,
+            rate_limit_per_minute: 30,
+            opaque_ids: false,
+            pickup_reminder_hours: None,
+            return_reminder_hours: None,
+            reminder_events: None,
+            xrc_canister_id: None,
+            exchange_rate_ttl_minutes: None,
+            ckbtc_ledger_canister_id: None,
+            marketplace_commission_bps: None,
+            ecdsa_key_name: None,
+            vetkd_key_name: None,
+        }
+    }
+}
+
+// Define the shapes the config was stored in before the latest one. The settings added
+// over time are optional, so every earlier layout decodes with what it has. Add each new
+// setting of Config here as well, as an option.
+#[derive(candid::CandidType, Deserialize)]
+struct LegacyConfig {
+    min_rental_days: u64,
+    max_rental_days: u64,
+    ledger_canister_id: Option<Principal>,
+    token_ledger_canister_id: Option<Principal>,
+    deposits: Option<Vec<CategoryAmount>>,
+    ledger_fee_e8s: Option<u64>,
+    late_grace_period_minutes: Option<u64>,
+    late_fee_per_hour_e8s: Option<u64>,
+    late_fee_per_day_e8s: Option<u64>,
+    tax_rate_bps: Option<u64>,
+    loyalty_points_per_icp: Option<u64>,
+    loyalty_point_value_e8s: Option<u64>,
+    referral_reward_points: Option<u64>,
+    included_km_per_day: Option<Vec<CategoryMileage>>,
+    mileage_overage_per_km_e8s: Option<u64>,
+    refuel_fee_per_percent_e8s: Option<u64>,
+    early_return_credit_bps: Option<u64>,
+    early_return_fee_e8s: Option<u64>,
+    cancellation_tiers: Option<Vec<CancellationTier>>,
+    no_show_fee_bps: Option<u64>,
+    protection_plans: Option<Vec<ProtectionPlan>>,
+    eligibility_rules: Option<Vec<EligibilityRule>>,
+    hold_minutes: Option<u64>,
+    archive_after_days: Option<u64>,
+    rate_limit_per_minute: Option<u64>,
+    opaque_ids: Option<bool>,
+    pickup_reminder_hours: Option<u64>,
+    return_reminder_hours: Option<u64>,
+    reminder_events: Option<bool>,
+    xrc_canister_id: Option<Principal>,
+    exchange_rate_ttl_minutes: Option<u64>,
+    ckbtc_ledger_canister_id: Option<Principal>,
+    marketplace_commission_bps: Option<u64>,
+    ecdsa_key_name: Option<String>,
+    vetkd_key_name: Option<String>,
+}
+
+// Migrate a config stored in an earlier layout; settings it lacks take their defaults
+impl From<LegacyConfig> for Config {
+    fn from(config: LegacyConfig) -> Self {
+        let defaults = Config::default();
+        Config {
+            min_rental_days: config.min_rental_days,
+            max_rental_days: config.max_rental_days,
+            ledger_canister_id: config.ledger_canister_id,
+            token_ledger_canister_id: config.token_ledger_canister_id,
+            deposits: config.deposits.unwrap_or(defaults.deposits),
+            ledger_fee_e8s: config.ledger_fee_e8s.unwrap_or(defaults.ledger_fee_e8s),
+            late_grace_period_minutes: config
+                .late_grace_period_minutes
+                .unwrap_or(defaults.late_grace_period_minutes),
+            late_fee_per_hour_e8s: config
+                .late_fee_per_hour_e8s
+                .unwrap_or(defaults.late_fee_per_hour_e8s),
+            late_fee_per_day_e8s: config
+                .late_fee_per_day_e8s
+                .unwrap_or(defaults.late_fee_per_day_e8s),
+            tax_rate_bps: config.tax_rate_bps.unwrap_or(defaults.tax_rate_bps),
+            loyalty_points_per_icp: config
+                .loyalty_points_per_icp
+                .unwrap_or(defaults.loyalty_points_per_icp),
+            loyalty_point_value_e8s: config
+                .loyalty_point_value_e8s
+                .unwrap_or(defaults.loyalty_point_value_e8s),
+            referral_reward_points: config
+                .referral_reward_points
+                .unwrap_or(defaults.referral_reward_points),
+            included_km_per_day: config
+                .included_km_per_day
+                .unwrap_or(defaults.included_km_per_day),
+            mileage_overage_per_km_e8s: config
+                .mileage_overage_per_km_e8s
+                .unwrap_or(defaults.mileage_overage_per_km_e8s),
+            refuel_fee_per_percent_e8s: config
+                .refuel_fee_per_percent_e8s
+                .unwrap_or(defaults.refuel_fee_per_percent_e8s),
+            early_return_credit_bps: config
+                .early_return_credit_bps
+                .unwrap_or(defaults.early_return_credit_bps),
+            early_return_fee_e8s: config
+                .early_return_fee_e8s
+                .unwrap_or(defaults.early_return_fee_e8s),
+            cancellation_tiers: config
+                .cancellation_tiers
+                .unwrap_or(defaults.cancellation_tiers),
+            no_show_fee_bps: config.no_show_fee_bps.unwrap_or(defaults.no_show_fee_bps),
+            protection_plans: config.protection_plans.unwrap_or(defaults.protection_plans),
+            eligibility_rules: config
+                .eligibility_rules
+                .unwrap_or(defaults.eligibility_rules),
+            hold_minutes: config.hold_minutes.unwrap_or(defaults.hold_minutes),
+            archive_after_days: config
+                .archive_after_days
+                .unwrap_or(defaults.archive_after_days),
+            rate_limit_per_minute: config
+                .rate_limit_per_minute
+                .unwrap_or(defaults.rate_limit_per_minute),
+            opaque_ids: config.opaque_ids.unwrap_or(defaults.opaque_ids),
+            pickup_reminder_hours: config.pickup_reminder_hours,
+            return_reminder_hours: config.return_reminder_hours,
+            reminder_events: config.reminder_events,
+            xrc_canister_id: config.xrc_canister_id,
+            exchange_rate_ttl_minutes: config.exchange_rate_ttl_minutes,
+            ckbtc_ledger_canister_id: config.ckbtc_ledger_canister_id,
+            marketplace_commission_bps: config.marketplace_commission_bps,
+            ecdsa_key_name: config.ecdsa_key_name,
+            vetkd_key_name: config.vetkd_key_name,
+        }
+    }
+}
+
+// Define one chunk of a state backup. Its entries are the raw keys and values of the stable
+// memories, and its checksum covers them along with its sequence number.
+#[derive(candid::CandidType, Deserialize)]
+struct BackupChunk {
+    format_version: u32,
+    sequence: u64,
+    entries: Vec<BackupEntry>,
+    checksum: u64,
+    next_cursor: Option<BackupCursor>,
+}
+
+// Define a stored entry of a backup; entries of cells have an empty key
+#[derive(candid::CandidType, Deserialize)]
+struct BackupEntry {
+    memory_id: u8,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+// Define where the next backup chunk starts
+#[derive(candid::CandidType, Deserialize, Clone)]
+struct BackupCursor {
+    sequence: u64,
+    memory_id: u8,
+    after_key: Option<Vec<u8>>,
+}
+
+// Define the stores whose records are rewritten by a migration, in the order they are
+// migrated
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum MigratedStore {
+    Cars,
+    RentalRequests,
+    ArchivedRentals,
+}
+
+impl MigratedStore {
+    fn next(self) -> Option<MigratedStore> {
+        match self {
+            MigratedStore::Cars => Some(MigratedStore::RentalRequests),
+            MigratedStore::RentalRequests => Some(MigratedStore::ArchivedRentals),
+            MigratedStore::ArchivedRentals => None,
+        }
+    }
+}
+
+// Define where the next batch of a migration starts
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct MigrationCursor {
+    store: MigratedStore,
+    after_id: Option<u64>,
+}
+
+// Define how far a migration has got
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MigrationProgress {
+    rewritten: u64,
+    // Where the next call continues, or None once every record is in the latest layout
+    next_cursor: Option<MigrationCursor>,
+}
+
+// Define one entry of the audit log, recording a change made by an update call. The
+// summaries are the JSON of the changed record before and after the change, cut short
+// when long.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct AuditEntry {
+    id: u64,
+    caller: Principal,
+    method: String,
+    entity_id: Option<u64>,
+    before: Option<String>,
+    after: Option<String>,
+    at: u64,
+}
+
+// Define the events published to subscribing canisters
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+enum DomainEvent {
+    CarAdded {
+        car_id: u64,
+    },
+    RentalCreated {
+        rental_id: u64,
+        car_id: u64,
+        customer_id: u64,
+    },
+    StatusChanged {
+        rental_id: u64,
+        from: RentalStatus,
+        to: RentalStatus,
+    },
+    PaymentReceived {
+        rental_id: u64,
+        payment_id: u64,
+        amount_e8s: u64,
+    },
+    ReminderSent {
+        rental_id: u64,
+        customer_id: u64,
+        kind: NotificationKind,
+        date: u64,
+    },
+}
+
+// Define the kinds of events a subscription can be limited to
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Serialize, Deserialize, Clone, Copy)]
+enum EventKind {
+    CarAdded,
+    RentalCreated,
+    StatusChanged,
+    PaymentReceived,
+    ReminderSent,
+}
+
+impl DomainEvent {
+    fn kind(&self) -> EventKind {
+        match self {
+            DomainEvent::CarAdded { .. } => EventKind::CarAdded,
+            DomainEvent::RentalCreated { .. } => EventKind::RentalCreated,
+            DomainEvent::StatusChanged { .. } => EventKind::StatusChanged,
+            DomainEvent::PaymentReceived { .. } => EventKind::PaymentReceived,
+            DomainEvent::ReminderSent { .. } => EventKind::ReminderSent,
+        }
+    }
+}
+
+// Define an event as stored in the event log, numbered in the order it happened
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct EventRecord {
+    id: u64,
+    event: DomainEvent,
+    at: u64,
+}
+
+// Define a canister subscribed to the event log. Events are pushed to its method in order,
+// as a vector of EventRecord, starting from next_event_id.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct EventSubscription {
+    id: u64,
+    canister: Principal,
+    method: String,
+    // Kinds of events pushed; an empty list means all of them
+    kinds: Vec<EventKind>,
+    next_event_id: u64,
+    created_at: u64,
+    // Deliveries that failed in a row, and when the next attempt is due
+    failures: u32,
+    retry_at: u64,
+    last_error: Option<String>,
+}
+
+// Define an HTTPS endpoint the events are posted to as signed JSON, starting from
+// next_event_id. Its secret is kept apart and only returned on registration.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Webhook {
+    id: u64,
+    url: String,
+    // Kinds of events posted; an empty list means all of them
+    kinds: Vec<EventKind>,
+    next_event_id: u64,
+    created_at: u64,
+    // Deliveries that failed in a row, and when the next attempt is due
+    failures: u32,
+    retry_at: u64,
+    last_error: Option<String>,
+}
+
+// Define a newly registered webhook with the hex secret its payloads are signed with
+#[derive(candid::CandidType)]
+struct WebhookRegistration {
+    webhook: Webhook,
+    secret: String,
+}
+
+// Define one attempt to post a batch of events to a webhook
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct WebhookDelivery {
+    id: u64,
+    webhook_id: u64,
+    first_event_id: u64,
+    last_event_id: u64,
+    event_count: u32,
+    // HTTP status returned, or the reason no response was received
+    status: Option<u16>,
+    error: Option<String>,
+    delivered: bool,
+    at: u64,
+}
+
+// Define the kinds of notifications shown in a customer's activity feed
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Serialize, Deserialize, Clone, Copy)]
+enum NotificationKind {
+    BookingReceived,
+    RentalApproved,
+    PaymentReceived,
+    PickupReminder,
+    ReturnReminder,
+}
+
+// Define a notification in a customer's inbox
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct Notification {
+    id: u64,
+    customer_id: u64,
+    kind: NotificationKind,
+    message: String,
+    // Rental the notification is about
+    rental_id: Option<u64>,
+    read: bool,
+    created_at: u64,
+}
+
+// Define the channels messages are sent to customers through
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Serialize, Deserialize, Clone, Copy)]
+enum MessageChannel {
+    Email,
+    Sms,
+}
+
+// Define an email or SMS provider. Messages are posted to its URL as JSON, with the API key
+// as a bearer token.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct MessageProvider {
+    url: String,
+    api_key: String,
+    // Address or number the messages are sent from
+    sender: String,
+}
+
+// Define the template of a message; {customer}, {car}, {start}, {end}, and {rental_id} are
+// replaced when a message is rendered
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct MessageTemplate {
+    kind: NotificationKind,
+    subject: String,
+    body: String,
+}
+
+// Define the messaging settings kept by admins. Templates override the built-in ones.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default)]
+struct MessagingSettings {
+    email: Option<MessageProvider>,
+    sms: Option<MessageProvider>,
+    templates: Vec<MessageTemplate>,
+}
+
+// Define the states of a message in the send log
+#[derive(Debug, PartialEq, Eq, candid::CandidType, Serialize, Deserialize, Clone, Copy)]
+enum MessageStatus {
+    Pending,
+    Sent,
+    Failed,
+}
+
+// Define a message to a customer as kept in the send log
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct OutboundMessage {
+    id: u64,
+    customer_id: u64,
+    rental_id: Option<u64>,
+    kind: NotificationKind,
+    channel: MessageChannel,
+    to: String,
+    subject: String,
+    body: String,
+    status: MessageStatus,
+    // Attempts made, when the next is due, and the outcome of the last one
+    attempts: u32,
+    retry_at: u64,
+    http_status: Option<u16>,
+    last_error: Option<String>,
+    created_at: u64,
+    sent_at: Option<u64>,
+}
+
+// Define a key that lets tools calling the HTTP API act as a principal
+#[derive(candid::CandidType, Serialize, Deserialize, Clone)]
+struct ApiKey {
+    id: u64,
+    principal: Principal,
+    label: String,
+    created_at: u64,
+    last_used_at: Option<u64>,
+}
+
+// Define a newly created API key with its secret, which is only returned once
+#[derive(candid::CandidType)]
+struct ApiKeyRegistration {
+    api_key: ApiKey,
+    secret: String,
+}
+
+// Define a request received through the HTTP gateway
+#[derive(candid::CandidType, Deserialize)]
+struct HttpGatewayRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// Define a response returned through the HTTP gateway; upgrade asks the gateway to send the
+// request again as an update call
+#[derive(candid::CandidType)]
+struct HttpGatewayResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    upgrade: Option<bool>,
+}
+
+// Define the criteria of an audit log search; the date range is [from, to)
+#[derive(candid::CandidType, Deserialize)]
+struct AuditFilter {
+    caller: Option<Principal>,
+    method: Option<String>,
+    entity_id: Option<u64>,
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.caller.is_none_or(|caller| entry.caller == caller)
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|method| entry.method == *method)
+            && self
+                .entity_id
+                .is_none_or(|entity_id| entry.entity_id == Some(entity_id))
+            && self.from.is_none_or(|from| entry.at >= from)
+            && self.to.is_none_or(|to| entry.at < to)
+    }
+}
+
+// Define the health and size of the canister, for dashboards and capacity planning
+#[derive(candid::CandidType)]
+struct SystemStats {
+    cars: u64,
+    customers: u64,
+    rental_requests: u64,
+    archived_rentals: u64,
+    payments: u64,
+    // Entries in every stable map, by map
+    stored_entries: Vec<(String, u64)>,
+    stable_memory_bytes: u64,
+    heap_memory_bytes: u64,
+    cycle_balance: u128,
+    // None until the canister is first upgraded
+    last_upgraded_at: Option<u64>,
+    // Work waiting for the timers
+    pending_messages: u64,
+    subscriptions_behind: u64,
+    webhooks_behind: u64,
+    deliveries_in_flight: u64,
+    restore_in_progress: bool,
+}
+
+// Define a record returned with a certificate of the subnet and a witness, both CBOR encoded.
+// The witness is a hash tree whose root is the canister's certified data and which holds the
+// record's Candid encoding at the path [section, id as 8 big-endian bytes].
+#[derive(candid::CandidType)]
+struct Certified<T> {
+    data: T,
+    certificate: Vec<u8>,
+    witness: Vec<u8>,
+}
+
+// Define a hash tree as specified by the Internet Computer interface specification
+enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned([u8; 32]),
+}
+
+impl HashTree {
+    fn digest(&self) -> [u8; 32] {
+        match self {
+            HashTree::Empty => domain_hash("ic-hashtree-empty", &[]),
+            HashTree::Fork(left, right) => {
+                domain_hash("ic-hashtree-fork", &[&left.digest(), &right.digest()])
+            }
+            HashTree::Labeled(label, tree) => {
+                domain_hash("ic-hashtree-labeled", &[label, &tree.digest()])
+            }
+            HashTree::Leaf(value) => domain_hash("ic-hashtree-leaf", &[value]),
+            HashTree::Pruned(digest) => *digest,
+        }
+    }
+
+    // Encode the tree in CBOR, as arrays tagged by the node type
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            HashTree::Empty => out.extend([0x81, 0x00]),
+            HashTree::Fork(left, right) => {
+                out.extend([0x83, 0x01]);
+                left.encode(out);
+                right.encode(out);
+            }
+            HashTree::Labeled(label, tree) => {
+                out.extend([0x83, 0x02]);
+                cbor_bytes(out, label);
+                tree.encode(out);
+            }
+            HashTree::Leaf(value) => {
+                out.extend([0x82, 0x03]);
+                cbor_bytes(out, value);
+            }
+            HashTree::Pruned(digest) => {
+                out.extend([0x82, 0x04]);
+                cbor_bytes(out, digest);
+            }
+        }
+    }
+}
+
+// Define how far a restore has got
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct RestoreProgress {
+    // Sequence number of the chunk expected next, or None once the restore is complete
+    next_sequence: Option<u64>,
+    entries_restored: u64,
+}
+
+// Define the heap state pre_upgrade writes to stable memory for post_upgrade to read back.
+// Indexes and caches are not part of it, they are rebuilt from the stable maps instead.
+#[derive(candid::CandidType, Serialize, Deserialize, Clone, Default)]
+struct UpgradeState {
+    restore_next_sequence: Option<u64>,
+    restored_entries: u64,
+    recent_calls: Vec<(Principal, Vec<u64>)>,
+}
+
+// Define the arguments accepted on install and upgrade
+#[derive(candid::CandidType, Deserialize)]
+struct InitArgs {
+    admin: Principal,
+    config: Option<Config>,
+}
+
+// Implement serialization and deserialization for Car
+impl Storable for Car {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(tag_schema_version(CAR_SCHEMA_VERSION, encode_record(self)))
+    }
+
+    // Cars may be stored in any earlier layout. Add an arm for the previous version
+    // whenever CAR_SCHEMA_VERSION is raised.
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (version, encoded) = split_schema_version(bytes.as_ref());
+        match version {
+            CAR_SCHEMA_VERSION => decode_record(encoded),
+            // Cars stored before the marketplace are not listed by an owner
+            2 => decode_record(encoded),
+            1 => Decode!(encoded, Self).unwrap(),
+            _ => Decode!(encoded, Self)
+                .or_else(|_| Decode!(encoded, LegacyCar).map(Car::from))
+                .unwrap(),
+        }
+    }
+}
+
+// Implement bounds for Car serialization
+impl BoundedStorable for Car {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for EventRecord
+impl Storable for EventRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for EventRecord serialization
+impl BoundedStorable for EventRecord {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for EventSubscription
+impl Storable for EventSubscription {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Bound EventSubscription by its method name, kinds, and last error
+impl BoundedStorable for EventSubscription {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Webhook
+impl Storable for Webhook {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Bound Webhook by its URL, kinds, and last error
+impl BoundedStorable for Webhook {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for WebhookDelivery
+impl Storable for WebhookDelivery {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Bound WebhookDelivery by its error
+impl BoundedStorable for WebhookDelivery {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Notification
+impl Storable for Notification {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Bound Notification by its message
+impl BoundedStorable for Notification {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for MessagingSettings
+impl Storable for MessagingSettings {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement serialization and deserialization for UpgradeState
+impl Storable for UpgradeState {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement serialization and deserialization for OutboundMessage
+impl Storable for OutboundMessage {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Bound OutboundMessage by its recipient, subject, body, and last error
+impl BoundedStorable for OutboundMessage {
+    const MAX_SIZE: u32 = 2_048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for ApiKey
+impl Storable for ApiKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Bound ApiKey by its label
+impl BoundedStorable for ApiKey {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for AuditEntry
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Bound AuditEntry by its two summaries and method name
+impl BoundedStorable for AuditEntry {
+    const MAX_SIZE: u32 = 2_560;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Photo
+impl Storable for Photo {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for Photo serialization
+impl BoundedStorable for Photo {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Wrap the raw bytes of one photo chunk
+struct PhotoChunk(Vec<u8>);
+
+// Implement serialization and deserialization for PhotoChunk
+impl Storable for PhotoChunk {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        PhotoChunk(bytes.into_owned())
+    }
+}
+
+// Implement bounds for PhotoChunk serialization
+impl BoundedStorable for PhotoChunk {
+    const MAX_SIZE: u32 = PHOTO_CHUNK_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for CarAcquisition
+impl Storable for CarAcquisition {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for CarAcquisition serialization
+impl BoundedStorable for CarAcquisition {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for CarToken
+impl Storable for CarToken {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for CarToken serialization
+impl BoundedStorable for CarToken {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for CarTokenTransfer
+impl Storable for CarTokenTransfer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for CarTokenTransfer serialization
+impl BoundedStorable for CarTokenTransfer {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for CarOwnership
+impl Storable for CarOwnership {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for CarOwnership serialization
+impl BoundedStorable for CarOwnership {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for EarningsEntry
+impl Storable for EarningsEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for EarningsEntry serialization
+impl BoundedStorable for EarningsEntry {
+    const MAX_SIZE: u32 = 192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for AgreementTemplate
+impl Storable for AgreementTemplate {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement serialization and deserialization for AgreementDocument
+impl Storable for AgreementDocument {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for AgreementDocument serialization
+impl BoundedStorable for AgreementDocument {
+    const MAX_SIZE: u32 = 8_192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for TermsOfService
+impl Storable for TermsOfService {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for TermsOfService serialization
+impl BoundedStorable for TermsOfService {
+    const MAX_SIZE: u32 = 17_408;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for TermsAcceptance
+impl Storable for TermsAcceptance {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for TermsAcceptance serialization
+impl BoundedStorable for TermsAcceptance {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for EncryptedPii
+impl Storable for EncryptedPii {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for EncryptedPii serialization
+impl BoundedStorable for EncryptedPii {
+    const MAX_SIZE: u32 = 2_048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for SignedAgreement
+impl Storable for SignedAgreement {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for SignedAgreement serialization
+impl BoundedStorable for SignedAgreement {
+    const MAX_SIZE: u32 = 2_048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Payout
+impl Storable for Payout {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for Payout serialization
+impl BoundedStorable for Payout {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for CkbtcQuote
+impl Storable for CkbtcQuote {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for CkbtcQuote serialization
+impl BoundedStorable for CkbtcQuote {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for CurrencyPreference
+impl Storable for CurrencyPreference {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for CurrencyPreference serialization
+impl BoundedStorable for CurrencyPreference {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for RentalTiming
+impl Storable for RentalTiming {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement serialization and deserialization for DemandCount
+impl Storable for DemandCount {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for DemandCount serialization
+impl BoundedStorable for DemandCount {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Branch
+impl Storable for Branch {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for Branch serialization
+impl BoundedStorable for Branch {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for CarTransfer
+impl Storable for CarTransfer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for CarTransfer serialization
+impl BoundedStorable for CarTransfer {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for MaintenanceRecord
+impl Storable for MaintenanceRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for MaintenanceRecord serialization
+impl BoundedStorable for MaintenanceRecord {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for MaintenanceWindow
+impl Storable for MaintenanceWindow {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for MaintenanceWindow serialization
+impl BoundedStorable for MaintenanceWindow {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for DamageReport
+impl Storable for DamageReport {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for DamageReport serialization
+impl BoundedStorable for DamageReport {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for HandoverCode
+impl Storable for HandoverCode {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for HandoverCode serialization
+impl BoundedStorable for HandoverCode {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for RentalExtension
+impl Storable for RentalExtension {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for RentalExtension serialization
+impl BoundedStorable for RentalExtension {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for CorporateAccount
+impl Storable for CorporateAccount {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for CorporateAccount serialization
+impl BoundedStorable for CorporateAccount {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for CorporateStatement
+impl Storable for CorporateStatement {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for CorporateStatement serialization
+impl BoundedStorable for CorporateStatement {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for StatementLine
+impl Storable for StatementLine {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for StatementLine serialization
+impl BoundedStorable for StatementLine {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for IdempotencyKey
+impl Storable for IdempotencyKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Implement bounds for IdempotencyKey serialization
+impl BoundedStorable for IdempotencyKey {
+    const MAX_SIZE: u32 = 160;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for IdempotencyRecord
+impl Storable for IdempotencyRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for IdempotencyRecord serialization
+impl BoundedStorable for IdempotencyRecord {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for QuoteHold
+impl Storable for QuoteHold {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for QuoteHold serialization
+impl BoundedStorable for QuoteHold {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for WaitlistEntry
+impl Storable for WaitlistEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for WaitlistEntry serialization
+impl BoundedStorable for WaitlistEntry {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for ConditionReport
+impl Storable for ConditionReport {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for ConditionReport serialization
+impl BoundedStorable for ConditionReport {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Review
+impl Storable for Review {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for Review serialization
+impl BoundedStorable for Review {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for InsurancePolicy
+impl Storable for InsurancePolicy {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for InsurancePolicy serialization
+impl BoundedStorable for InsurancePolicy {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for AddOn
+impl Storable for AddOn {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for AddOn serialization
+impl BoundedStorable for AddOn {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for RentalAddOn
+impl Storable for RentalAddOn {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for RentalAddOn serialization
+impl BoundedStorable for RentalAddOn {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for RentalRequest
+impl Storable for RentalRequest {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(tag_schema_version(
+            RENTAL_SCHEMA_VERSION,
+            encode_record(self),
+        ))
+    }
+
+    // Rental requests may be stored in any earlier layout. Add an arm for the previous
+    // version whenever RENTAL_SCHEMA_VERSION is raised.
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let (version, encoded) = split_schema_version(bytes.as_ref());
+        match version {
+            RENTAL_SCHEMA_VERSION => decode_record(encoded),
+            1 => Decode!(encoded, Self).unwrap(),
+            _ => Decode!(encoded, Self)
+                .or_else(|_| Decode!(encoded, LegacyRentalRequest).map(RentalRequest::from))
+                .unwrap(),
+        }
+    }
+}
+
+// Put the version of its layout in front of a record's Candid encoding
+fn tag_schema_version(version: u8, encoded: Vec<u8>) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(encoded.len() + 1);
+    bytes.push(version);
+    bytes.extend(encoded);
+    bytes
+}
+
+// Encode a record for stable memory as packed CBOR, which names fields and variants by
+// their index. Fields are only ever added at the end of a struct, as options, so records
+// written before a field existed decode with it empty.
+fn encode_record<T: serde::Serialize>(record: &T) -> Vec<u8> {
+    serde_cbor::ser::to_vec_packed(record).expect("Cannot encode a record")
+}
+
+// Decode a record from stable memory, stored as packed CBOR or, before that, as Candid
+fn decode_record<T: serde::de::DeserializeOwned + candid::CandidType>(bytes: &[u8]) -> T {
+    if bytes.starts_with(CANDID_MAGIC) {
+        Decode!(bytes, T).unwrap()
+    } else {
+        serde_cbor::from_slice(bytes).expect("Cannot decode a record")
+    }
+}
+
+// Split a stored record into the version of its layout and its encoding
+fn split_schema_version(bytes: &[u8]) -> (u8, &[u8]) {
+    match bytes.split_first() {
+        Some((&version, encoded)) if !bytes.starts_with(CANDID_MAGIC) => (version, encoded),
+        _ => (0, bytes),
+    }
+}
+
+// Implement bounds for RentalRequest serialization
+impl BoundedStorable for RentalRequest {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Payment
+impl Storable for Payment {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    // Payments stored as Candid may be in any earlier layout
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let bytes = bytes.as_ref();
+        if bytes.starts_with(CANDID_MAGIC) {
+            Decode!(bytes, Self)
+                .or_else(|_| Decode!(bytes, LegacyPayment).map(Payment::from))
+                .unwrap()
+        } else {
+            decode_record(bytes)
+        }
+    }
+}
+
+// Implement bounds for Payment serialization
+impl BoundedStorable for Payment {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Refund
+impl Storable for Refund {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for Refund serialization
+impl BoundedStorable for Refund {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for WalletTransaction
+impl Storable for WalletTransaction {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for WalletTransaction serialization
+impl BoundedStorable for WalletTransaction {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for GiftCard
+impl Storable for GiftCard {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for GiftCard serialization
+impl BoundedStorable for GiftCard {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Charge
+impl Storable for Charge {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for Charge serialization
+impl BoundedStorable for Charge {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Invoice
+impl Storable for Invoice {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for Invoice serialization
+impl BoundedStorable for Invoice {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Deposit
+impl Storable for Deposit {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for Deposit serialization
+impl BoundedStorable for Deposit {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Customer
+impl Storable for Customer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    // Customers stored as Candid before they had an owner are migrated
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        if !bytes.starts_with(CANDID_MAGIC) {
+            return decode_record(bytes.as_ref());
+        }
+        Decode!(bytes.as_ref(), Self)
+            .or_else(|_| Decode!(bytes.as_ref(), LegacyCustomer).map(Customer::from))
+            .unwrap()
+    }
+}
+
+// Implement bounds for Customer serialization
+impl BoundedStorable for Customer {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Referral
+impl Storable for Referral {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for Referral serialization
+impl BoundedStorable for Referral {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for RoleAssignment
+impl Storable for RoleAssignment {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for RoleAssignment serialization
+impl BoundedStorable for RoleAssignment {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for PricingRule
+impl Storable for PricingRule {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for PricingRule serialization
+impl BoundedStorable for PricingRule {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Coupon
+impl Storable for Coupon {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+// Implement bounds for Coupon serialization
+impl BoundedStorable for Coupon {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Implement serialization and deserialization for Config. A config stored before some of
+// its settings existed is decoded as a LegacyConfig.
+impl Storable for Config {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        let bytes = bytes.as_ref();
+        if bytes.starts_with(CANDID_MAGIC) {
+            Decode!(bytes, Self)
+                .or_else(|_| Decode!(bytes, LegacyConfig).map(Config::from))
+                .unwrap()
+        } else {
+            serde_cbor::from_slice(bytes)
+                .or_else(|_| serde_cbor::from_slice::<LegacyConfig>(bytes).map(Config::from))
+                .expect("Cannot decode the config")
+        }
+    }
+}
+
+// Wrap a principal so it can be used as a stable map key
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct StorablePrincipal(Principal);
+
+// Implement serialization and deserialization for StorablePrincipal
+impl Storable for StorablePrincipal {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        StorablePrincipal(Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+// Implement bounds for StorablePrincipal serialization
+impl BoundedStorable for StorablePrincipal {
+    const MAX_SIZE: u32 = 29;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Thread-local storage for memory management, ID counter, car storage, rental request storage, customer storage, the principal to customer index, role assignments, the super-admin, the config, pricing rules, payments, deposits, and refunds
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    // Cars, rentals, customers, and payments each count from their own sequence. A
+    // sequence added on upgrade starts where the shared counter is, so ids stay unique.
+    static CAR_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74))), shared_id_count())
+            .expect("Cannot create a counter")
+    );
+
+    static RENTAL_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75))), shared_id_count())
+            .expect("Cannot create a counter")
+    );
+
+    static CUSTOMER_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76))), shared_id_count())
+            .expect("Cannot create a counter")
+    );
+
+    static PAYMENT_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77))), shared_id_count())
+            .expect("Cannot create a counter")
+    );
+
+    static CAR_STORAGE: RefCell<StableBTreeMap<u64, Car, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    static RENTAL_REQUEST_STORAGE: RefCell<StableBTreeMap<u64, RentalRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+    ));
+
+    static CUSTOMER_STORAGE: RefCell<StableBTreeMap<u64, Customer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+    ));
+
+    static CUSTOMER_BY_PRINCIPAL: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    static ROLE_STORAGE: RefCell<StableBTreeMap<StorablePrincipal, RoleAssignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    static OWNER: RefCell<Cell<StorablePrincipal, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+            StorablePrincipal(Principal::anonymous()),
+        )
+        .expect("Cannot create the owner cell")
+    );
+
+    static CONFIG: RefCell<Cell<Config, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+            Config::default(),
+        )
+        .expect("Cannot create the config cell")
+    );
+
+    static PRICING_RULE_STORAGE: RefCell<StableBTreeMap<u64, PricingRule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static PAYMENT_STORAGE: RefCell<StableBTreeMap<u64, Payment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    // Ledger block index -> payment id, so a transfer can only pay for one rental
+    static PAYMENT_BY_BLOCK: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+    ));
+
+    // Rentals with a token transfer in flight, guarding against paying twice
+    static PAYMENTS_IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+
+    static DEPOSIT_STORAGE: RefCell<StableBTreeMap<u64, Deposit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    // Rentals with a deposit transfer in flight
+    static DEPOSITS_IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+
+    // Lowercase make and model token -> car ids, rebuilt from the cars after an upgrade
+    static CAR_TEXT_INDEX: RefCell<BTreeMap<String, BTreeSet<u64>>> =
+        const { RefCell::new(BTreeMap::new()) };
+
+    static REFUND_STORAGE: RefCell<StableBTreeMap<u64, Refund, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    static CHARGE_STORAGE: RefCell<StableBTreeMap<u64, Charge, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+    ));
+
+    // Invoices are numbered from their own counter so the numbers have no gaps
+    static INVOICE_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))), 0)
+            .expect("Cannot create an invoice counter")
+    );
+
+    // Rental id -> invoice
+    static INVOICE_STORAGE: RefCell<StableBTreeMap<u64, Invoice, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    // Customer id -> loyalty points balance
+    static LOYALTY_POINTS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    static COUPON_STORAGE: RefCell<StableBTreeMap<u64, Coupon, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    // (coupon id, customer id) -> number of rentals the customer used the coupon on
+    static COUPON_USES_BY_CUSTOMER: RefCell<StableBTreeMap<(u64, u64), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18)))
+    ));
+
+    // Referral code -> referring customer id
+    static REFERRAL_CODES: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    // Referred customer id -> referral
+    static REFERRAL_STORAGE: RefCell<StableBTreeMap<u64, Referral, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    // Customer id -> prepaid wallet balance
+    static WALLET_BALANCES: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    static WALLET_TRANSACTION_STORAGE: RefCell<StableBTreeMap<u64, WalletTransaction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    // Gift card code -> gift card
+    static GIFT_CARD_STORAGE: RefCell<StableBTreeMap<u64, GiftCard, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    // (car id, rental id) index over the rental requests
+    static RENTALS_BY_CAR: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24)))
+    ));
+
+    // (customer id, rental id) index over the rental requests
+    static RENTALS_BY_CUSTOMER: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    // (start date, rental id) index over the rental requests
+    static RENTALS_BY_START_DATE: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    static PHOTO_STORAGE: RefCell<StableBTreeMap<u64, Photo, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // (photo id, chunk index) -> chunk bytes
+    static PHOTO_CHUNKS: RefCell<StableBTreeMap<(u64, u32), PhotoChunk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // (car id, photo id) index over the finished photos
+    static PHOTOS_BY_CAR: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    static BRANCH_STORAGE: RefCell<StableBTreeMap<u64, Branch, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30)))
+    ));
+
+    // (pickup branch id, dropoff branch id) -> one-way drop fee
+    static DROP_FEES: RefCell<StableBTreeMap<(u64, u64), u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    static CAR_TRANSFER_STORAGE: RefCell<StableBTreeMap<u64, CarTransfer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    // (car id, transfer id) index over the car transfers
+    static TRANSFERS_BY_CAR: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    static MAINTENANCE_STORAGE: RefCell<StableBTreeMap<u64, MaintenanceRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    // (car id, maintenance record id) index over the maintenance records
+    static MAINTENANCE_BY_CAR: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    static MAINTENANCE_WINDOW_STORAGE: RefCell<StableBTreeMap<u64, MaintenanceWindow, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+
+    // (car id, maintenance window id) index over the maintenance windows
+    static MAINTENANCE_WINDOWS_BY_CAR: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+
+    static DAMAGE_REPORT_STORAGE: RefCell<StableBTreeMap<u64, DamageReport, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    // (car id, damage report id) index over the damage reports
+    static DAMAGE_REPORTS_BY_CAR: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39)))
+    ));
+
+    // Car id -> insurance policy
+    static INSURANCE_STORAGE: RefCell<StableBTreeMap<u64, InsurancePolicy, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+
+    static ADD_ON_STORAGE: RefCell<StableBTreeMap<u64, AddOn, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+
+    // (add-on id, branch id) -> units in stock at the branch
+    static ADD_ON_STOCK: RefCell<StableBTreeMap<(u64, u64), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+
+    // (rental id, add-on id) -> add-on reserved for the rental
+    static RENTAL_ADD_ONS: RefCell<StableBTreeMap<(u64, u64), RentalAddOn, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43)))
+    ));
+
+    static REVIEW_STORAGE: RefCell<StableBTreeMap<u64, Review, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44)))
+    ));
+
+    // (car id, review id) index over the reviews
+    static REVIEWS_BY_CAR: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45)))
+    ));
+
+    // Rental id -> id of its review, so each rental is reviewed once
+    static REVIEW_BY_RENTAL: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    // Rental id -> handover code of an active rental not yet picked up
+    static HANDOVER_CODES: RefCell<StableBTreeMap<u64, HandoverCode, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+
+    // Rental id -> condition report recorded at check-in
+    static CONDITION_REPORTS: RefCell<StableBTreeMap<u64, ConditionReport, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+
+    // (rental id, extension id) -> extension of the rental's end date
+    static RENTAL_EXTENSIONS: RefCell<StableBTreeMap<(u64, u64), RentalExtension, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+
+    static WAITLIST_STORAGE: RefCell<StableBTreeMap<u64, WaitlistEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+
+    // (car id, waitlist entry id) index over the waitlist, in joining order
+    static WAITLIST_BY_CAR: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+
+    static CORPORATE_ACCOUNT_STORAGE: RefCell<StableBTreeMap<u64, CorporateAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+
+    // (corporate account id, customer id) for the account's authorized drivers
+    static ACCOUNT_DRIVERS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53)))
+    ));
+
+    // (corporate account id, rental id) index over the rentals billed to accounts
+    static RENTALS_BY_ACCOUNT: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+
+    // (corporate account id, YYYYMM month) -> monthly statement
+    static STATEMENT_STORAGE: RefCell<StableBTreeMap<(u64, u64), CorporateStatement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+
+    // Lines of the monthly statements
+    static STATEMENT_LINES: RefCell<StableBTreeMap<StatementLineKey, StatementLine, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+
+    static HOLD_STORAGE: RefCell<StableBTreeMap<u64, QuoteHold, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+
+    // (car id, hold id) -> ()
+    static HOLDS_BY_CAR: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+
+    static IDEMPOTENCY_STORAGE: RefCell<StableBTreeMap<IdempotencyKey, IdempotencyRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+
+    // Closed rentals moved out of the rental request storage, and their cars
+    static ARCHIVED_RENTALS: RefCell<StableBTreeMap<u64, RentalRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60)))
+    ));
+
+    static ARCHIVED_RENTALS_BY_CAR: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61)))
+    ));
+
+    // Append-only log of the changes made by update calls, in the order they were made
+    static AUDIT_LOG: RefCell<StableBTreeMap<u64, AuditEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+
+    static EVENT_LOG: RefCell<StableBTreeMap<u64, EventRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ));
+
+    static EVENT_SUBSCRIPTIONS: RefCell<StableBTreeMap<u64, EventSubscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64)))
+    ));
+
+    static WEBHOOKS: RefCell<StableBTreeMap<u64, Webhook, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65)))
+    ));
+
+    static WEBHOOK_SECRETS: RefCell<StableBTreeMap<u64, [u8; 32], Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+
+    // Recent delivery attempts by webhook id and delivery id
+    static WEBHOOK_DELIVERIES: RefCell<StableBTreeMap<(u64, u64), WebhookDelivery, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67)))
+    ));
+
+    // Customer inboxes by customer id and notification id
+    static NOTIFICATIONS: RefCell<StableBTreeMap<(u64, u64), Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68)))
+    ));
+
+    // Reminders already sent, by rental id and the start or end date they were about
+    static REMINDERS_SENT: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69)))
+    ));
+
+    static MESSAGING: RefCell<Cell<MessagingSettings, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70))),
+            MessagingSettings::default(),
+        )
+        .expect("Cannot create the messaging settings")
+    );
+
+    // Emails and SMS to customers, pending and sent, by id
+    static MESSAGE_LOG: RefCell<StableBTreeMap<u64, OutboundMessage, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71)))
+    ));
+
+    // API keys by id, and their ids by the SHA-256 hash of their secret
+    static API_KEYS: RefCell<StableBTreeMap<u64, ApiKey, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72)))
+    ));
+
+    static API_KEY_HASHES: RefCell<StableBTreeMap<[u8; 32], u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73)))
+    ));
+
+    // Car id -> what the car cost
+    static CAR_ACQUISITIONS: RefCell<StableBTreeMap<u64, CarAcquisition, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79)))
+    ));
+
+    // (day number, car id) -> booking requests for the car made that day
+    static DEMAND_COUNTS: RefCell<StableBTreeMap<(u64, u64), DemandCount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80)))
+    ));
+
+    // Customer id -> the currency the customer wants amounts shown in
+    static CURRENCY_PREFERENCES: RefCell<StableBTreeMap<u64, CurrencyPreference, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82)))
+    ));
+
+    // Rental id -> the ckBTC price last quoted for paying it
+    static CKBTC_QUOTES: RefCell<StableBTreeMap<u64, CkbtcQuote, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83)))
+    ));
+
+    // Car id -> the ICRC-7 token of the car, for cars that were minted
+    static CAR_TOKENS: RefCell<StableBTreeMap<u64, CarToken, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84)))
+    ));
+
+    static CAR_TOKEN_TRANSFERS: RefCell<StableBTreeMap<u64, CarTokenTransfer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85)))
+    ));
+
+    // Car id -> the principals sharing what the car earns
+    static CAR_OWNERSHIP: RefCell<StableBTreeMap<u64, CarOwnership, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86)))
+    ));
+
+    // Owner principal -> earnings not yet withdrawn
+    static EARNINGS_BALANCES: RefCell<StableBTreeMap<StorablePrincipal, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87)))
+    ));
+
+    static EARNINGS_ENTRIES: RefCell<StableBTreeMap<u64, EarningsEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88)))
+    ));
+
+    static PAYOUTS: RefCell<StableBTreeMap<u64, Payout, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89)))
+    ));
+
+    // Rental id -> the terms of the rental signed when it was approved
+    static SIGNED_AGREEMENTS: RefCell<StableBTreeMap<u64, SignedAgreement, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90)))
+    ));
+
+    // Customer id -> the customer's encrypted contact details and license number
+    static ENCRYPTED_PII: RefCell<StableBTreeMap<u64, EncryptedPii, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91)))
+    ));
+
+    // Version -> terms of service
+    static TERMS_OF_SERVICE: RefCell<StableBTreeMap<u64, TermsOfService, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92)))
+    ));
+
+    // (customer id, version) -> the customer's acceptance of the version
+    static TERMS_ACCEPTANCES: RefCell<StableBTreeMap<(u64, u64), TermsAcceptance, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93)))
+    ));
+
+    static AGREEMENT_TEMPLATE: RefCell<Cell<AgreementTemplate, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94))),
+            AgreementTemplate::default(),
+        )
+        .expect("Cannot create the agreement template")
+    );
+
+    // Rental id -> the agreement rendered for the rental
+    static AGREEMENT_DOCUMENTS: RefCell<StableBTreeMap<u64, AgreementDocument, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95)))
+    ));
+
+    // Currency -> the last rate fetched for it; lost on upgrade and fetched again
+    static EXCHANGE_RATES: RefCell<BTreeMap<String, ExchangeRate>> =
+        const { RefCell::new(BTreeMap::new()) };
+
+    static RENTAL_TIMING: RefCell<Cell<RentalTiming, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81))),
+            RentalTiming::default(),
+        )
+        .expect("Cannot create the rental timing stats")
+    );
+
+    // Heap state carried across an upgrade, empty except while one is underway
+    static UPGRADE_STATE: RefCell<Cell<UpgradeState, Memory>> = RefCell::new(
+        Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78))),
+            UpgradeState::default(),
+        )
+        .expect("Cannot create the upgrade state")
+    );
+
+    // Secret from raw_rand that opaque ids are derived from, drawn again after every upgrade
+    static ID_SEED: RefCell<Option<[u8; 32]>> = const { RefCell::new(None) };
+
+    // Principal an HTTP API call acts as while it is handled
+    static HTTP_CALLER: RefCell<Option<Principal>> = const { RefCell::new(None) };
+
+    // Subscriptions and webhooks with a delivery in flight, so events are never pushed
+    // twice at once. Both take their ids from the shared counter, so they never collide.
+    static DELIVERIES_IN_FLIGHT: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+
+    // Sequence number of the backup chunk a restore expects next
+    static RESTORE_NEXT_SEQUENCE: RefCell<Option<u64>> = const { RefCell::new(None) };
+    static RESTORED_ENTRIES: RefCell<u64> = const { RefCell::new(0) };
+
+    // Digests of the certified leaves by section and id, and of the buckets by section and
+    // bucket number. The tree is rebuilt from the stable maps after an upgrade.
+    static CERTIFIED_LEAVES: RefCell<BTreeMap<(u8, u64), [u8; 32]>> = const { RefCell::new(BTreeMap::new()) };
+    static CERTIFIED_BUCKETS: RefCell<BTreeMap<(u8, u64), [u8; 32]>> = const { RefCell::new(BTreeMap::new()) };
+    // Availability changes as holds expire, so the certified encoding of each car's
+    // availability is kept to be returned as it was certified
+    static CERTIFIED_AVAILABILITY_VALUES: RefCell<BTreeMap<u64, Vec<u8>>> = const { RefCell::new(BTreeMap::new()) };
+
+    // Times of the recent update calls of each rate-limited principal, oldest first
+    static RECENT_CALLS: RefCell<BTreeMap<Principal, VecDeque<u64>>> = const { RefCell::new(BTreeMap::new()) };
+
+    // When the running code was installed by an upgrade
+    static LAST_UPGRADED_AT: RefCell<Option<u64>> = const { RefCell::new(None) };
+}
+
+// List the cells and maps included in a backup by memory id. The super-admin (memory 6)
+// is left out, so a restore never locks out the principal performing it.
+macro_rules! backup_memories {
+    (cells { $($cell_id:literal => $cell:ident),* $(,)? } maps { $($map_id:literal => $map:ident),* $(,)? }) => {
+        const BACKUP_MEMORY_IDS: &[u8] = &[$($cell_id,)* $($map_id,)*];
+
+        fn dump_backup_memory(
+            memory_id: u8,
+            after_key: Option<&[u8]>,
+            budget: &mut usize,
+            entries: &mut Vec<BackupEntry>,
+        ) -> Option<Option<Vec<u8>>> {
+            match memory_id {
+                $($cell_id => $cell.with(|cell| {
+                    if after_key.is_none() {
+                        let value = cell.borrow().get().to_bytes().into_owned();
+                        *budget = budget.saturating_sub(value.len());
+                        entries.push(BackupEntry {
+                            memory_id,
+                            key: Vec::new(),
+                            value,
+                        });
+                    }
+                    None
+                }),)*
+                $($map_id => $map.with(|map| {
+                    dump_map(&map.borrow(), memory_id, after_key, budget, entries)
+                }),)*
+                _ => None,
+            }
+        }
+
+        // Count the entries of every stable map, by map
+        fn stored_entry_counts() -> Vec<(String, u64)> {
+            vec![$((stringify!($map).to_string(), $map.with(|map| map.borrow().len())),)*]
+        }
+
+        fn clear_backup_maps() {
+            $($map.with(|map| {
+                *map.borrow_mut() = StableBTreeMap::new(
+                    MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new($map_id))),
+                )
+            });)*
+        }
+
+        fn restore_backup_entry(entry: &BackupEntry) {
+            match entry.memory_id {
+                $($cell_id => $cell.with(|cell| {
+                    let value = Storable::from_bytes(Cow::Borrowed(entry.value.as_slice()));
+                    cell.borrow_mut().set(value).expect("Cannot restore a cell");
+                }),)*
+                $($map_id => $map.with(|map| {
+                    map.borrow_mut().insert(
+                        Storable::from_bytes(Cow::Borrowed(entry.key.as_slice())),
+                        Storable::from_bytes(Cow::Borrowed(entry.value.as_slice())),
+                    );
+                }),)*
+                _ => {}
+            }
+        }
+    };
+}
+
+backup_memories! {
+    cells {
+        0 => ID_COUNTER,
+        7 => CONFIG,
+        14 => INVOICE_COUNTER,
+        70 => MESSAGING,
+        74 => CAR_ID_COUNTER,
+        75 => RENTAL_ID_COUNTER,
+        76 => CUSTOMER_ID_COUNTER,
+        77 => PAYMENT_ID_COUNTER,
+        81 => RENTAL_TIMING,
+        94 => AGREEMENT_TEMPLATE,
+    }
+    maps {
+        1 => CAR_STORAGE,
+        2 => RENTAL_REQUEST_STORAGE,
+        3 => CUSTOMER_STORAGE,
+        4 => CUSTOMER_BY_PRINCIPAL,
+        5 => ROLE_STORAGE,
+        8 => PRICING_RULE_STORAGE,
+        9 => PAYMENT_STORAGE,
+        10 => PAYMENT_BY_BLOCK,
+        11 => DEPOSIT_STORAGE,
+        12 => REFUND_STORAGE,
+        13 => CHARGE_STORAGE,
+        15 => INVOICE_STORAGE,
+        16 => LOYALTY_POINTS,
+        17 => COUPON_STORAGE,
+        18 => COUPON_USES_BY_CUSTOMER,
+        19 => REFERRAL_CODES,
+        20 => REFERRAL_STORAGE,
+        21 => WALLET_BALANCES,
+        22 => WALLET_TRANSACTION_STORAGE,
+        23 => GIFT_CARD_STORAGE,
+        24 => RENTALS_BY_CAR,
+        25 => RENTALS_BY_CUSTOMER,
+        26 => RENTALS_BY_START_DATE,
+        27 => PHOTO_STORAGE,
+        28 => PHOTO_CHUNKS,
+        29 => PHOTOS_BY_CAR,
+        30 => BRANCH_STORAGE,
+        31 => DROP_FEES,
+        32 => CAR_TRANSFER_STORAGE,
+        33 => TRANSFERS_BY_CAR,
+        34 => MAINTENANCE_STORAGE,
+        35 => MAINTENANCE_BY_CAR,
+        36 => MAINTENANCE_WINDOW_STORAGE,
+        37 => MAINTENANCE_WINDOWS_BY_CAR,
+        38 => DAMAGE_REPORT_STORAGE,
+        39 => DAMAGE_REPORTS_BY_CAR,
+        40 => INSURANCE_STORAGE,
+        41 => ADD_ON_STORAGE,
+        42 => ADD_ON_STOCK,
+        43 => RENTAL_ADD_ONS,
+        44 => REVIEW_STORAGE,
+        45 => REVIEWS_BY_CAR,
+        46 => REVIEW_BY_RENTAL,
+        47 => HANDOVER_CODES,
+        48 => CONDITION_REPORTS,
+        49 => RENTAL_EXTENSIONS,
+        50 => WAITLIST_STORAGE,
+        51 => WAITLIST_BY_CAR,
+        52 => CORPORATE_ACCOUNT_STORAGE,
+        53 => ACCOUNT_DRIVERS,
+        54 => RENTALS_BY_ACCOUNT,
+        55 => STATEMENT_STORAGE,
+        56 => STATEMENT_LINES,
+        57 => HOLD_STORAGE,
+        58 => HOLDS_BY_CAR,
+        59 => IDEMPOTENCY_STORAGE,
+        60 => ARCHIVED_RENTALS,
+        61 => ARCHIVED_RENTALS_BY_CAR,
+        62 => AUDIT_LOG,
+        63 => EVENT_LOG,
+        64 => EVENT_SUBSCRIPTIONS,
+        65 => WEBHOOKS,
+        66 => WEBHOOK_SECRETS,
+        67 => WEBHOOK_DELIVERIES,
+        68 => NOTIFICATIONS,
+        69 => REMINDERS_SENT,
+        71 => MESSAGE_LOG,
+        72 => API_KEYS,
+        73 => API_KEY_HASHES,
+        79 => CAR_ACQUISITIONS,
+        80 => DEMAND_COUNTS,
+        82 => CURRENCY_PREFERENCES,
+        83 => CKBTC_QUOTES,
+        84 => CAR_TOKENS,
+        85 => CAR_TOKEN_TRANSFERS,
+        86 => CAR_OWNERSHIP,
+        87 => EARNINGS_BALANCES,
+        88 => EARNINGS_ENTRIES,
+        89 => PAYOUTS,
+        90 => SIGNED_AGREEMENTS,
+        91 => ENCRYPTED_PII,
+        92 => TERMS_OF_SERVICE,
+        93 => TERMS_ACCEPTANCES,
+        95 => AGREEMENT_DOCUMENTS,
+    }
+}
+
+// Define one page of a list query; pass next_cursor back to fetch the following page
+#[derive(candid::CandidType, Serialize)]
+struct Page<T> {
+    items: Vec<T>,
+    total: u64,
+    next_cursor: Option<u64>,
+}
+
+// Define the formats data can be exported in
+#[derive(candid::CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum ExportFormat {
+    Csv,
+    // One JSON object per line, so chunks can be joined as they are
+    JsonLines,
+}
+
+// Define one chunk of an export; pass next_cursor back to fetch the following chunk
+#[derive(candid::CandidType)]
+struct ExportChunk {
+    data: Vec<u8>,
+    next_cursor: Option<u64>,
+}
+
+// Define the criteria of a car search; empty fields match every car
+#[derive(candid::CandidType, Deserialize)]
+struct CarFilter {
+    make: Option<String>,
+    model: Option<String>,
+    year_from: Option<u32>,
+    year_to: Option<u32>,
+    available: Option<bool>,
+    category: Option<CarCategory>,
+    transmission: Option<Transmission>,
+    fuel_type: Option<FuelType>,
+    min_seats: Option<u8>,
+    max_mileage_km: Option<u64>,
+    feature: Option<String>,
+}
+
+// Define the criteria of a rental search; the date range matches overlapping rentals
+#[derive(candid::CandidType, Deserialize)]
+struct RentalFilter {
+    status: Option<RentalStatus>,
+    from: Option<u64>,
+    to: Option<u64>,
+    car_id: Option<u64>,
+    customer_id: Option<u64>,
+}
+
+// Define the fields car searches can be sorted by
+#[derive(candid::CandidType, Deserialize, Clone, Copy)]
+enum CarSortField {
+    Id,
+    Make,
+    Model,
+    Year,
+    DailyRate,
+}
+
+// Define the fields rental searches can be sorted by
+#[derive(candid::CandidType, Deserialize, Clone, Copy)]
+enum RentalSortField {
+    Id,
+    StartDate,
+    EndDate,
+    QuotedPrice,
+}
+
+#[derive(candid::CandidType, Deserialize, Clone, Copy, PartialEq)]
+enum SortDirection {
+    Ascending,
+    Descending,
+}
+
+impl CarFilter {
+    fn matches(&self, car: &Car) -> bool {
+        self.make
+            .as_ref()
+            .is_none_or(|make| car.make.eq_ignore_ascii_case(make.trim()))
+            && self
+                .model
+                .as_ref()
+                .is_none_or(|model| car.model.eq_ignore_ascii_case(model.trim()))
+            && self.year_from.is_none_or(|from| car.year >= from)
+            && self.year_to.is_none_or(|to| car.year <= to)
+            && self
+                .available
+                .is_none_or(|available| car.available == available)
+            && self
+                .category
+                .is_none_or(|category| car.category == category)
+            && self
+                .transmission
+                .is_none_or(|transmission| car.transmission == Some(transmission))
+            && self
+                .fuel_type
+                .is_none_or(|fuel_type| car.fuel_type == Some(fuel_type))
+            && self.min_seats.is_none_or(|seats| car.seats >= seats)
+            && self
+                .max_mileage_km
+                .is_none_or(|mileage_km| car.mileage_km <= mileage_km)
+            && self.feature.as_ref().is_none_or(|feature| {
+                car.features
+                    .iter()
+                    .any(|car_feature| car_feature.eq_ignore_ascii_case(feature.trim()))
+            })
+    }
+}
+
+impl RentalFilter {
+    fn matches(&self, request: &RentalRequest) -> bool {
+        self.status.is_none_or(|status| request.status == status)
+            && self.from.is_none_or(|from| request.end_date > from)
+            && self.to.is_none_or(|to| request.start_date < to)
+            && self.car_id.is_none_or(|car_id| request.car_id == car_id)
+            && self
+                .customer_id
+                .is_none_or(|customer_id| request.customer_id == customer_id)
+    }
+}
+
+// Define the possible errors
+#[derive(Debug, candid::CandidType, Deserialize, Serialize)]
+enum Error {
+    NotFound { msg: String },
+    InvalidInput { msg: String },
+    Unauthorized { msg: String },
+    Conflict { msg: String },
+    LedgerCallFailed { msg: String },
+    CallFailed { msg: String },
+    PaymentFailed { error: PaymentError },
+    NotEligible { msg: String },
+    // Nanoseconds to wait before the caller may make another update call
+    RateLimited { retry_after: u64 },
+    // Bytes the record would take in stable memory, and the most its map can hold
+    RecordTooLarge { size: u64, max_size: u64 },
+}
+
+// Install the initial super-admin and config, defaulting the admin to the installer
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    let (admin, config) = match args {
+        Some(args) => (args.admin, args.config),
+        None => (ic_cdk::caller(), None),
+    };
+    apply_init_args(admin, config);
+    start_timers();
+    rebuild_certified_tree();
+}
+
+// Save the heap state that cannot be rebuilt from the stable maps: how far a restore has
+// got and the recent calls of rate-limited principals
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    let state = UpgradeState {
+        restore_next_sequence: RESTORE_NEXT_SEQUENCE.with(|next| *next.borrow()),
+        restored_entries: RESTORED_ENTRIES.with(|count| *count.borrow()),
+        recent_calls: RECENT_CALLS.with(|calls| {
+            calls
+                .borrow()
+                .iter()
+                .map(|(principal, times)| (*principal, times.iter().copied().collect()))
+                .collect()
+        }),
+    };
+    UPGRADE_STATE.with(|cell| {
+        cell.borrow_mut()
+            .set(state)
+            .expect("Cannot save the upgrade state")
+    });
+}
+
+// Allow controllers to reset the super-admin or config while upgrading
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    if let Some(args) = args {
+        apply_init_args(args.admin, args.config);
+    }
+    let state = UPGRADE_STATE.with(|cell| {
+        let mut cell = cell.borrow_mut();
+        let state = cell.get().clone();
+        cell.set(UpgradeState::default())
+            .expect("Cannot clear the upgrade state");
+        state
+    });
+    RESTORE_NEXT_SEQUENCE.with(|next| *next.borrow_mut() = state.restore_next_sequence);
+    RESTORED_ENTRIES.with(|count| *count.borrow_mut() = state.restored_entries);
+    RECENT_CALLS.with(|calls| {
+        *calls.borrow_mut() = state
+            .recent_calls
+            .into_iter()
+            .map(|(principal, times)| (principal, times.into()))
+            .collect()
+    });
+    // Timers and heap indexes do not survive upgrades and must be set up again
+    LAST_UPGRADED_AT.with(|at| *at.borrow_mut() = Some(ic_cdk::api::time()));
+    start_timers();
+    // Rentals stored before an index existed are indexed once
+    let rentals = RENTAL_REQUEST_STORAGE.with(|storage| storage.borrow().len());
+    if RENTALS_BY_CAR.with(|index| index.borrow().len()) != rentals
+        || RENTALS_BY_CUSTOMER.with(|index| index.borrow().len()) != rentals
+        || RENTALS_BY_START_DATE.with(|index| index.borrow().len()) != rentals
+    {
+        rebuild_rental_indexes();
+    }
+    rebuild_heap_indexes();
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            let _ = seed_opaque_ids().await;
+        })
+    });
+    ic_cdk_timers::set_timer_interval(RENTAL_SCHEDULER_INTERVAL, advance_rental_statuses);
+    ic_cdk_timers::set_timer_interval(RENTAL_SCHEDULER_INTERVAL, advance_maintenance_windows);
+    ic_cdk_timers::set_timer_interval(STATEMENT_SCHEDULER_INTERVAL, issue_corporate_statements);
+    ic_cdk_timers::set_timer_interval(EVENT_DELIVERY_INTERVAL, deliver_events);
+    ic_cdk_timers::set_timer_interval(EVENT_DELIVERY_INTERVAL, send_messages);
+}
+
+// Screen ingress messages before they are executed, so rejected ones cost the canister no
+// cycles: unknown methods, update calls by the anonymous principal, and oversized arguments
+// are dropped. Calls from other canisters are not screened, and every method still checks
+// its caller itself.
+#[ic_cdk::inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::call::method_name();
+    let is_update = UPDATE_METHODS.binary_search(&method.as_str()).is_ok();
+    if !is_update && QUERY_METHODS.binary_search(&method.as_str()).is_err() {
+        return;
+    }
+    if is_update
+        && caller() == Principal::anonymous()
+        && !ANONYMOUS_UPDATE_METHODS.contains(&method.as_str())
+    {
+        return;
+    }
+    let max_arg_bytes = LARGE_ARG_METHODS
+        .iter()
+        .find(|(name, _)| *name == method)
+        .map_or(MAX_INGRESS_ARG_BYTES, |(_, max)| *max);
+    if ic_cdk::api::call::arg_data_raw_size() > max_arg_bytes {
+        return;
+    }
+    ic_cdk::api::call::accept_message();
+}
+
+fn apply_init_args(admin: Principal, config: Option<Config>) {
+    if admin == Principal::anonymous() {
+        ic_cdk::trap("The super-admin cannot be the anonymous principal");
+    }
+    if let Some(config) = config {
+        if let Err(Error::InvalidInput { msg }) = validate_config(&config) {
+            ic_cdk::trap(&msg);
+        }
+        set_config(config);
+    }
+    set_owner(admin);
+}
+
+// Hand the super-admin role over to another principal
+#[ic_cdk::update]
+fn transfer_ownership(new_owner: Principal) -> Result<Principal, Error> {
+    check_rate_limit()?;
+    let caller = caller();
+    if caller != owner() && !ic_cdk::api::is_controller(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "Only the current owner can transfer ownership".to_string(),
+        });
+    }
+    if new_owner == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "Ownership cannot be transferred to the anonymous principal".to_string(),
+        });
+    }
+    let previous_owner = owner();
+    set_owner(new_owner);
+    record_audit(
+        "transfer_ownership",
+        None,
+        audit_summary(&previous_owner),
+        audit_summary(&new_owner),
+    );
+    Ok(new_owner)
+}
+
+// Implement backup and restore of the canister state. A backup is read one chunk at a
+// time, passing each chunk's next_cursor back until it is empty.
+#[ic_cdk::query]
+fn backup_state(cursor: Option<BackupCursor>) -> Result<BackupChunk, Error> {
+    require_any_role(&[Role::Admin])?;
+    let cursor = cursor.unwrap_or(BackupCursor {
+        sequence: 0,
+        memory_id: BACKUP_MEMORY_IDS[0],
+        after_key: None,
+    });
+    let mut entries = Vec::new();
+    let mut budget = BACKUP_CHUNK_BYTES;
+    let mut next_cursor = None;
+    let mut after_key = cursor.after_key;
+    for memory_id in BACKUP_MEMORY_IDS
+        .iter()
+        .copied()
+        .skip_while(|memory_id| *memory_id != cursor.memory_id)
+    {
+        if let Some(resume_after) =
+            dump_backup_memory(memory_id, after_key.as_deref(), &mut budget, &mut entries)
+        {
+            next_cursor = Some(BackupCursor {
+                sequence: cursor.sequence + 1,
+                memory_id,
+                after_key: resume_after,
+            });
+            break;
+        }
+        after_key = None;
+    }
+    Ok(BackupChunk {
+        format_version: BACKUP_FORMAT_VERSION,
+        sequence: cursor.sequence,
+        checksum: backup_checksum(cursor.sequence, &entries, next_cursor.is_none()),
+        entries,
+        next_cursor,
+    })
+}
+
+// Restore a backup taken with backup_state, sending its chunks in order over one or more
+// calls. The chunk with sequence 0 replaces the whole state, and every chunk is checked
+// before any is applied. Only the super-admin may restore, and keeps the role throughout.
+#[ic_cdk::update]
+fn restore_state(chunks: Vec<BackupChunk>) -> Result<RestoreProgress, Error> {
+    check_rate_limit()?;
+    let caller = caller();
+    if caller != owner() && !ic_cdk::api::is_controller(&caller) {
+        return Err(Error::Unauthorized {
+            msg: "Only the owner can restore the canister state".to_string(),
+        });
+    }
+    let mut expected = RESTORE_NEXT_SEQUENCE.with(|next| *next.borrow());
+    let mut complete = false;
+    for chunk in &chunks {
+        if complete || (chunk.sequence != 0 && Some(chunk.sequence) != expected) {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Backup chunk {} is out of order; expected {}",
+                    chunk.sequence,
+                    expected.map_or("a new restore from chunk 0".to_string(), |sequence| {
+                        sequence.to_string()
+                    })
+                ),
+            });
+        }
+        if chunk.format_version != BACKUP_FORMAT_VERSION {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Backup format version {} is not supported; expected {}",
+                    chunk.format_version, BACKUP_FORMAT_VERSION
+                ),
+            });
+        }
+        let last = chunk.next_cursor.is_none();
+        if backup_checksum(chunk.sequence, &chunk.entries, last) != chunk.checksum {
+            return Err(Error::InvalidInput {
+                msg: format!("Backup chunk {} failed its checksum", chunk.sequence),
+            });
+        }
+        if let Some(entry) = chunk
+            .entries
+            .iter()
+            .find(|entry| !BACKUP_MEMORY_IDS.contains(&entry.memory_id))
+        {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Backup chunk {} holds unknown memory {}",
+                    chunk.sequence, entry.memory_id
+                ),
+            });
+        }
+        expected = Some(chunk.sequence + 1);
+        complete = last;
+    }
+
+    for chunk in chunks {
+        if chunk.sequence == 0 {
+            clear_backup_maps();
+            RESTORED_ENTRIES.with(|count| *count.borrow_mut() = 0);
+        }
+        for entry in &chunk.entries {
+            restore_backup_entry(entry);
+        }
+        RESTORED_ENTRIES.with(|count| *count.borrow_mut() += chunk.entries.len() as u64);
+    }
+    if complete {
+        expected = None;
+        rebuild_heap_indexes();
+    }
+    RESTORE_NEXT_SEQUENCE.with(|next| *next.borrow_mut() = expected);
+    let progress = RestoreProgress {
+        next_sequence: expected,
+        entries_restored: RESTORED_ENTRIES.with(|count| *count.borrow()),
+    };
+    // The audit log is itself restored, so the restore is only recorded once it is complete
+    if complete {
+        record_audit("restore_state", None, None, audit_summary(&progress));
+    }
+    Ok(progress)
+}
+
+// Rewrite cars and rental requests stored in an earlier layout in the latest one, a batch
+// per call. Records are also rewritten whenever they change, so this only needs to run
+// before support for an old layout is dropped. Pass each call's next_cursor back until it
+// is empty.
+#[ic_cdk::update]
+fn migrate_records(cursor: Option<MigrationCursor>) -> Result<MigrationProgress, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let mut rewritten = 0;
+    let mut next_cursor = Some(cursor.unwrap_or(MigrationCursor {
+        store: MigratedStore::Cars,
+        after_id: None,
+    }));
+    while let Some(current) = next_cursor.take() {
+        let limit = MIGRATION_BATCH_SIZE - rewritten as usize;
+        if limit == 0 {
+            next_cursor = Some(current);
+            break;
+        }
+        let (count, last_id) = match current.store {
+            MigratedStore::Cars => rewrite_records(&CAR_STORAGE, current.after_id, limit),
+            MigratedStore::RentalRequests => {
+                rewrite_records(&RENTAL_REQUEST_STORAGE, current.after_id, limit)
+            }
+            MigratedStore::ArchivedRentals => {
+                rewrite_records(&ARCHIVED_RENTALS, current.after_id, limit)
+            }
+        };
+        rewritten += count;
+        next_cursor = match (last_id, current.store.next()) {
+            (Some(after_id), _) => Some(MigrationCursor {
+                store: current.store,
+                after_id: Some(after_id),
+            }),
+            (None, Some(store)) => Some(MigrationCursor {
+                store,
+                after_id: None,
+            }),
+            (None, None) => None,
+        };
+    }
+    let progress = MigrationProgress {
+        rewritten,
+        next_cursor,
+    };
+    record_audit("migrate_records", None, None, audit_summary(&progress));
+    Ok(progress)
+}
+
+// Write up to limit records after the given id back, in the latest layout. Returns how
+// many were written and, if records are left, the id of the last one.
+fn rewrite_records<V: BoundedStorable>(
+    map: &'static LocalKey<RefCell<StableBTreeMap<u64, V, Memory>>>,
+    after_id: Option<u64>,
+    limit: usize,
+) -> (u64, Option<u64>) {
+    map.with(|map| {
+        let mut map = map.borrow_mut();
+        let lower = after_id.map_or(Bound::Unbounded, Bound::Excluded);
+        let mut records: Vec<(u64, V)> = map
+            .range((lower, Bound::Unbounded))
+            .take(limit + 1)
+            .collect();
+        let more = records.len() > limit;
+        records.truncate(limit);
+        let last_id = records.last().map(|(id, _)| *id);
+        let count = records.len() as u64;
+        for (id, record) in records {
+            map.insert(id, record);
+        }
+        (count, last_id.filter(|_| more))
+    })
+}
+
+// List the audit log entries matching a filter, oldest first
+#[ic_cdk::query]
+fn list_audit_entries(
+    filter: AuditFilter,
+    cursor: Option<u64>,
+    limit: u32,
+) -> Result<Page<AuditEntry>, Error> {
+    require_any_role(&[Role::Admin])?;
+    Ok(AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let total = log
+            .iter()
+            .filter(|(_, entry)| filter.matches(entry))
+            .count() as u64;
+        page_of(
+            log.range(cursor.unwrap_or(0)..)
+                .filter(|(_, entry)| filter.matches(entry)),
+            total,
+            limit,
+        )
+    }))
+}
+
+// Append a change made by the current call to the audit log
+fn record_audit(
+    method: &str,
+    entity_id: Option<u64>,
+    before: Option<String>,
+    after: Option<String>,
+) {
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let id = log.last_key_value().map_or(0, |(id, _)| id + 1);
+        log.insert(
+            id,
+            AuditEntry {
+                id,
+                caller: caller(),
+                method: method.to_string(),
+                entity_id,
+                before,
+                after,
+                at: ic_cdk::api::time(),
+            },
+        );
+    });
+}
+
+// Summarize a value as JSON for the audit log, cut short at a character boundary
+fn audit_summary(value: &impl serde::Serialize) -> Option<String> {
+    let mut summary = serde_json::to_string(value).ok()?;
+    if summary.len() > AUDIT_SUMMARY_BYTES {
+        let mut end = AUDIT_SUMMARY_BYTES;
+        while !summary.is_char_boundary(end) {
+            end -= 1;
+        }
+        summary.truncate(end);
+    }
+    Some(summary)
+}
+
+// Implement the event log and its subscribers. Subscribers are canisters that receive
+// the events in order through a method taking a vector of EventRecord; a failed delivery
+// is retried with exponential backoff, so events are received at least once.
+#[ic_cdk::update]
+fn subscribe_events(
+    canister: Principal,
+    method: String,
+    kinds: Vec<EventKind>,
+) -> Result<EventSubscription, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    if canister == Principal::anonymous() || canister == ic_cdk::id() {
+        return Err(Error::InvalidInput {
+            msg: "Events can only be pushed to another canister".to_string(),
+        });
+    }
+    if method.trim().is_empty() || method.len() > 64 {
+        return Err(Error::InvalidInput {
+            msg: "The method name must be between 1 and 64 characters".to_string(),
+        });
+    }
+    if EVENT_SUBSCRIPTIONS.with(|storage| storage.borrow().len()) >= MAX_EVENT_SUBSCRIPTIONS {
+        return Err(Error::Conflict {
+            msg: format!(
+                "At most {} event subscriptions can be registered",
+                MAX_EVENT_SUBSCRIPTIONS
+            ),
+        });
+    }
+
+    // A new subscriber receives the events published from now on
+    let now = ic_cdk::api::time();
+    let subscription = EventSubscription {
+        id: next_id()?,
+        canister,
+        method: method.trim().to_string(),
+        kinds,
+        next_event_id: next_event_id(),
+        created_at: now,
+        failures: 0,
+        retry_at: now,
+        last_error: None,
+    };
+    EVENT_SUBSCRIPTIONS.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(subscription.id, subscription.clone())
+    });
+    record_audit(
+        "subscribe_events",
+        Some(subscription.id),
+        None,
+        audit_summary(&subscription),
+    );
+    Ok(subscription)
+}
+
+#[ic_cdk::update]
+fn unsubscribe_events(subscription_id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    match EVENT_SUBSCRIPTIONS.with(|storage| storage.borrow_mut().remove(&subscription_id)) {
+        Some(subscription) => {
+            record_audit(
+                "unsubscribe_events",
+                Some(subscription_id),
+                audit_summary(&subscription),
+                None,
+            );
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Event subscription with id={} not found", subscription_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_event_subscriptions() -> Result<Vec<EventSubscription>, Error> {
+    require_any_role(&[Role::Admin])?;
+    Ok(EVENT_SUBSCRIPTIONS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .collect()
+    }))
+}
+
+// List the event log, oldest first
+#[ic_cdk::query]
+fn list_events(cursor: Option<u64>, limit: u32) -> Result<Page<EventRecord>, Error> {
+    require_any_role(&[Role::Staff])?;
+    Ok(EVENT_LOG.with(|log| {
+        let log = log.borrow();
+        page_of(log.range(cursor.unwrap_or(0)..), log.len(), limit)
+    }))
+}
+
+fn next_event_id() -> u64 {
+    EVENT_LOG.with(|log| log.borrow().last_key_value().map_or(0, |(id, _)| id + 1))
+}
+
+// Append an event to the event log; subscribers receive it on the next delivery run
+fn publish_event(event: DomainEvent) {
+    let id = next_event_id();
+    let record = EventRecord {
+        id,
+        event,
+        at: ic_cdk::api::time(),
+    };
+    EVENT_LOG.with(|log| log.borrow_mut().insert(id, record));
+}
+
+// Start a delivery to every subscriber and webhook that has events waiting and is due
+fn deliver_events() {
+    if restore_in_progress() {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    let last_event_id = next_event_id();
+    let due: Vec<u64> = EVENT_SUBSCRIPTIONS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, subscription)| {
+                subscription.retry_at <= now && subscription.next_event_id < last_event_id
+            })
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for subscription_id in due {
+        let idle =
+            DELIVERIES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(subscription_id));
+        if idle {
+            ic_cdk::spawn(deliver_to(subscription_id));
+        }
+    }
+    let due: Vec<u64> = WEBHOOKS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, webhook)| webhook.retry_at <= now && webhook.next_event_id < last_event_id)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for webhook_id in due {
+        let idle = DELIVERIES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(webhook_id));
+        if idle {
+            ic_cdk::spawn(deliver_to_webhook(webhook_id));
+        }
+    }
+}
+
+// Push the next batch of matching events to a subscriber, advancing its cursor past them
+// once the call succeeds
+async fn deliver_to(subscription_id: u64) {
+    let subscription = EVENT_SUBSCRIPTIONS.with(|storage| storage.borrow().get(&subscription_id));
+    if let Some(subscription) = subscription {
+        let mut next_event_id = subscription.next_event_id;
+        let events = pending_events(
+            subscription.next_event_id,
+            &subscription.kinds,
+            EVENT_DELIVERY_BATCH,
+            &mut next_event_id,
+        );
+        let result = if events.is_empty() {
+            Ok(())
+        } else {
+            ic_cdk::call::<(Vec<EventRecord>,), ()>(
+                subscription.canister,
+                &subscription.method,
+                (events,),
+            )
+            .await
+            .map_err(|(code, msg)| format!("{:?} {}", code, msg))
+        };
+
+        // The subscription may have been removed while the call was in flight
+        EVENT_SUBSCRIPTIONS.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            if let Some(mut subscription) = storage.get(&subscription_id) {
+                let now = ic_cdk::api::time();
+                match result {
+                    Ok(()) => {
+                        subscription.next_event_id = next_event_id;
+                        subscription.failures = 0;
+                        subscription.retry_at = now;
+                        subscription.last_error = None;
+                    }
+                    Err(error) => {
+                        subscription.failures = subscription.failures.saturating_add(1);
+                        subscription.retry_at =
+                            now.saturating_add(delivery_backoff(subscription.failures));
+                        subscription.last_error = Some(error.chars().take(256).collect());
+                    }
+                }
+                storage.insert(subscription_id, subscription);
+            }
+        });
+    }
+    DELIVERIES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&subscription_id));
+}
+
+// Take up to batch events of the given kinds from the event log, starting at from.
+// next_event_id is moved past every event looked at, matching or not.
+fn pending_events(
+    from: u64,
+    kinds: &[EventKind],
+    batch: usize,
+    next_event_id: &mut u64,
+) -> Vec<EventRecord> {
+    EVENT_LOG.with(|log| {
+        let mut events = Vec::new();
+        for (id, record) in log.borrow().range(from..) {
+            if events.len() == batch {
+                break;
+            }
+            *next_event_id = id + 1;
+            if kinds.is_empty() || kinds.contains(&record.event.kind()) {
+                events.push(record);
+            }
+        }
+        events
+    })
+}
+
+// Wait twice as long after each failure in a row, up to the longest backoff
+fn delivery_backoff(failures: u32) -> u64 {
+    (EVENT_DELIVERY_INTERVAL.as_nanos() as u64)
+        .saturating_mul(1 << failures.min(16))
+        .min(EVENT_RETRY_MAX_BACKOFF)
+}
+
+// Implement outbound webhooks. Matching events are posted as JSON to an HTTPS URL, signed
+// with HMAC-SHA256 over "<timestamp>.<body>" using the secret returned on registration.
+// Every replica makes the request, so receivers should drop repeated X-Webhook-Delivery ids.
+#[ic_cdk::update]
+async fn register_webhook(
+    url: String,
+    kinds: Vec<EventKind>,
+) -> Result<WebhookRegistration, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let url = url.trim().to_string();
+    if !url.starts_with("https://") || url.len() <= "https://".len() || url.len() > 256 {
+        return Err(Error::InvalidInput {
+            msg: "The webhook URL must be an https:// URL of at most 256 characters".to_string(),
+        });
+    }
+    if WEBHOOKS.with(|storage| storage.borrow().len()) >= MAX_WEBHOOKS {
+        return Err(Error::Conflict {
+            msg: format!("At most {} webhooks can be registered", MAX_WEBHOOKS),
+        });
+    }
+    let mut secret = [0u8; 32];
+    secret.copy_from_slice(&random_bytes().await?[..32]);
+
+    // A new webhook receives the events published from now on
+    let now = ic_cdk::api::time();
+    let webhook = Webhook {
+        id: next_id()?,
+        url,
+        kinds,
+        next_event_id: next_event_id(),
+        created_at: now,
+        failures: 0,
+        retry_at: now,
+        last_error: None,
+    };
+    check_record_size(&webhook)?;
+    WEBHOOKS.with(|storage| storage.borrow_mut().insert(webhook.id, webhook.clone()));
+    WEBHOOK_SECRETS.with(|storage| storage.borrow_mut().insert(webhook.id, secret));
+    record_audit(
+        "register_webhook",
+        Some(webhook.id),
+        None,
+        audit_summary(&webhook),
+    );
+    Ok(WebhookRegistration {
+        webhook,
+        secret: hex_string(&secret),
+    })
+}
+
+// Remove a webhook along with its secret and delivery log
+#[ic_cdk::update]
+fn remove_webhook(webhook_id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    match WEBHOOKS.with(|storage| storage.borrow_mut().remove(&webhook_id)) {
+        Some(webhook) => {
+            WEBHOOK_SECRETS.with(|storage| storage.borrow_mut().remove(&webhook_id));
+            WEBHOOK_DELIVERIES.with(|storage| {
+                let mut storage = storage.borrow_mut();
+                let keys: Vec<(u64, u64)> = storage
+                    .range((webhook_id, 0)..=(webhook_id, u64::MAX))
+                    .map(|(key, _)| key)
+                    .collect();
+                for key in keys {
+                    storage.remove(&key);
+                }
+            });
+            record_audit(
+                "remove_webhook",
+                Some(webhook_id),
+                audit_summary(&webhook),
+                None,
+            );
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Webhook with id={} not found", webhook_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_webhooks() -> Result<Vec<Webhook>, Error> {
+    require_any_role(&[Role::Admin])?;
+    Ok(WEBHOOKS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, webhook)| webhook)
+            .collect()
+    }))
+}
+
+// List the recent delivery attempts of a webhook, oldest first
+#[ic_cdk::query]
+fn list_webhook_deliveries(
+    webhook_id: u64,
+    cursor: Option<u64>,
+    limit: u32,
+) -> Result<Page<WebhookDelivery>, Error> {
+    require_any_role(&[Role::Admin])?;
+    if !WEBHOOKS.with(|storage| storage.borrow().contains_key(&webhook_id)) {
+        return Err(Error::NotFound {
+            msg: format!("Webhook with id={} not found", webhook_id),
+        });
+    }
+    Ok(WEBHOOK_DELIVERIES.with(|storage| {
+        let storage = storage.borrow();
+        let total = storage
+            .range((webhook_id, 0)..=(webhook_id, u64::MAX))
+            .count();
+        page_of(
+            storage
+                .range((webhook_id, cursor.unwrap_or(0))..=(webhook_id, u64::MAX))
+                .map(|((_, delivery_id), delivery)| (delivery_id, delivery)),
+            total as u64,
+            limit,
+        )
+    }))
+}
+
+// Post the next batch of matching events to a webhook, advancing its cursor past them once
+// it answers with a 2xx status
+async fn deliver_to_webhook(webhook_id: u64) {
+    let webhook = WEBHOOKS.with(|storage| storage.borrow().get(&webhook_id));
+    let secret = WEBHOOK_SECRETS.with(|storage| storage.borrow().get(&webhook_id));
+    if let (Some(webhook), Some(secret)) = (webhook, secret) {
+        let mut next_event_id = webhook.next_event_id;
+        let events = pending_events(
+            webhook.next_event_id,
+            &webhook.kinds,
+            WEBHOOK_DELIVERY_BATCH,
+            &mut next_event_id,
+        );
+        let result = if events.is_empty() {
+            Ok(())
+        } else if let Ok(delivery_id) = next_id() {
+            let at = ic_cdk::api::time();
+            let response = post_webhook(&webhook, &secret, delivery_id, &events).await;
+            let delivered = matches!(response, Ok(status) if (200..300).contains(&status));
+            record_webhook_delivery(WebhookDelivery {
+                id: delivery_id,
+                webhook_id,
+                first_event_id: events[0].id,
+                last_event_id: events[events.len() - 1].id,
+                event_count: events.len() as u32,
+                status: response.as_ref().ok().copied(),
+                error: response.as_ref().err().cloned(),
+                delivered,
+                at,
+            });
+            match response {
+                Ok(_) if delivered => Ok(()),
+                Ok(status) => Err(format!("HTTP status {}", status)),
+                Err(error) => Err(error),
+            }
+        } else {
+            Err("No delivery id is left to allocate".to_string())
+        };
+
+        // The webhook may have been removed while the request was in flight
+        WEBHOOKS.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            if let Some(mut webhook) = storage.get(&webhook_id) {
+                let now = ic_cdk::api::time();
+                match result {
+                    Ok(()) => {
+                        webhook.next_event_id = next_event_id;
+                        webhook.failures = 0;
+                        webhook.retry_at = now;
+                        webhook.last_error = None;
+                    }
+                    Err(error) => {
+                        webhook.failures = webhook.failures.saturating_add(1);
+                        webhook.retry_at = now.saturating_add(delivery_backoff(webhook.failures));
+                        webhook.last_error = Some(error);
+                    }
+                }
+                storage.insert(webhook_id, webhook);
+            }
+        });
+    }
+    DELIVERIES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&webhook_id));
+}
+
+// Post events to a webhook, returning the HTTP status it answered with
+async fn post_webhook(
+    webhook: &Webhook,
+    secret: &[u8; 32],
+    delivery_id: u64,
+    events: &[EventRecord],
+) -> Result<u16, String> {
+    let body = serde_json::json!({
+        "webhook_id": webhook.id,
+        "delivery_id": delivery_id,
+        "events": events,
+    })
+    .to_string();
+    let timestamp = ic_cdk::api::time() / 1_000_000_000;
+    let signature = hmac_sha256(secret, format!("{}.{}", timestamp, body).as_bytes());
+    let header = |name: &str, value: String| HttpHeader {
+        name: name.to_string(),
+        value,
+    };
+    let request = CanisterHttpRequestArgument {
+        url: webhook.url.clone(),
+        max_response_bytes: Some(WEBHOOK_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            header("Content-Type", "application/json".to_string()),
+            header("X-Webhook-Delivery", delivery_id.to_string()),
+            header("X-Webhook-Timestamp", timestamp.to_string()),
+            header(
+                "X-Webhook-Signature",
+                format!("sha256={}", hex_string(&signature)),
+            ),
+        ],
+        body: Some(body.into_bytes()),
+        transform: Some(TransformContext::from_name(
+            "transform_webhook_response".to_string(),
+            vec![],
+        )),
+    };
+    let (response,) = http_outcall(request, WEBHOOK_CYCLES)
+        .await
+        .map_err(|(code, msg)| {
+            format!("{:?} {}", code, msg)
+                .chars()
+                .take(256)
+                .collect::<String>()
+        })?;
+    u16::try_from(&response.status.0)
+        .map_err(|_| format!("Invalid HTTP status {}", response.status))
+}
+
+// Keep only the status of a webhook or messaging provider response, so every replica
+// agrees on it
+#[ic_cdk::query]
+fn transform_webhook_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+// Log a delivery attempt, dropping the oldest ones beyond the log size of the webhook
+fn record_webhook_delivery(delivery: WebhookDelivery) {
+    let webhook_id = delivery.webhook_id;
+    WEBHOOK_DELIVERIES.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        storage.insert((webhook_id, delivery.id), delivery);
+        let keys: Vec<(u64, u64)> = storage
+            .range((webhook_id, 0)..=(webhook_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys
+            .iter()
+            .take(keys.len().saturating_sub(WEBHOOK_DELIVERY_LOG_SIZE))
+        {
+            storage.remove(key);
+        }
+    });
+}
+
+// Compute HMAC-SHA256 (RFC 2104) with a key shorter than the SHA-256 block
+fn hmac_sha256(key: &[u8; 32], message: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    block[..key.len()].copy_from_slice(key);
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// The periodic jobs wait while a restore is half applied
+fn restore_in_progress() -> bool {
+    RESTORE_NEXT_SEQUENCE.with(|next| next.borrow().is_some())
+}
+
+// Report the size of the stored data, memory and cycles, and the work the timers have left
+#[ic_cdk::query]
+fn get_system_stats() -> Result<SystemStats, Error> {
+    require_any_role(&[Role::Staff])?;
+    let last_event_id = next_event_id();
+    Ok(SystemStats {
+        cars: CAR_STORAGE.with(|storage| storage.borrow().len()),
+        customers: CUSTOMER_STORAGE.with(|storage| storage.borrow().len()),
+        rental_requests: RENTAL_REQUEST_STORAGE.with(|storage| storage.borrow().len()),
+        archived_rentals: ARCHIVED_RENTALS.with(|storage| storage.borrow().len()),
+        payments: PAYMENT_STORAGE.with(|storage| storage.borrow().len()),
+        stored_entries: stored_entry_counts(),
+        stable_memory_bytes: ic_cdk::api::stable::stable64_size() * 65_536,
+        heap_memory_bytes: heap_memory_bytes(),
+        cycle_balance: ic_cdk::api::canister_balance128(),
+        last_upgraded_at: LAST_UPGRADED_AT.with(|at| *at.borrow()),
+        pending_messages: MESSAGE_LOG.with(|log| {
+            log.borrow()
+                .iter()
+                .filter(|(_, message)| message.status == MessageStatus::Pending)
+                .count() as u64
+        }),
+        subscriptions_behind: EVENT_SUBSCRIPTIONS.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, subscription)| subscription.next_event_id < last_event_id)
+                .count() as u64
+        }),
+        webhooks_behind: WEBHOOKS.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .filter(|(_, webhook)| webhook.next_event_id < last_event_id)
+                .count() as u64
+        }),
+        deliveries_in_flight: DELIVERIES_IN_FLIGHT
+            .with(|in_flight| in_flight.borrow().len() as u64),
+        restore_in_progress: restore_in_progress(),
+    })
+}
+
+// Wasm memory is only measurable when running as a canister
+fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * 65_536
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+// Read the entries of a map after a key until the byte budget runs out. Returns where to
+// resume if the map has more entries; a chunk always takes at least one entry.
+fn dump_map<K: BoundedStorable + Ord + Clone, V: BoundedStorable>(
+    map: &StableBTreeMap<K, V, Memory>,
+    memory_id: u8,
+    after_key: Option<&[u8]>,
+    budget: &mut usize,
+    entries: &mut Vec<BackupEntry>,
+) -> Option<Option<Vec<u8>>> {
+    let start = match after_key {
+        Some(key) => Bound::Excluded(K::from_bytes(Cow::Borrowed(key))),
+        None => Bound::Unbounded,
+    };
+    let mut last_key: Option<Vec<u8>> = None;
+    for (key, value) in map.range((start, Bound::Unbounded)) {
+        let key = key.to_bytes().into_owned();
+        let value = value.to_bytes().into_owned();
+        let size = key.len() + value.len();
+        if size > *budget && !entries.is_empty() {
+            return Some(last_key.or_else(|| after_key.map(<[u8]>::to_vec)));
+        }
+        *budget = budget.saturating_sub(size);
+        last_key = Some(key.clone());
+        entries.push(BackupEntry {
+            memory_id,
+            key,
+            value,
+        });
+    }
+    None
+}
+
+// Checksum a backup chunk with 64-bit FNV-1a, length-prefixing keys and values
+fn backup_checksum(sequence: u64, entries: &[BackupEntry], last: bool) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    feed(&sequence.to_be_bytes());
+    feed(&[u8::from(last)]);
+    for entry in entries {
+        feed(&[entry.memory_id]);
+        feed(&(entry.key.len() as u64).to_be_bytes());
+        feed(&entry.key);
+        feed(&(entry.value.len() as u64).to_be_bytes());
+        feed(&entry.value);
+    }
+    hash
+}
+
+#[ic_cdk::query]
+fn get_owner() -> Principal {
+    owner()
+}
+
+fn config() -> Config {
+    CONFIG.with(|cell| cell.borrow().get().clone())
+}
+
+fn owner() -> Principal {
+    OWNER.with(|cell| cell.borrow().get().0)
+}
+
+fn set_owner(principal: Principal) {
+    OWNER
+        .with(|cell| cell.borrow_mut().set(StorablePrincipal(principal)))
+        .expect("Cannot store the owner");
+}
+
+fn set_config(config: Config) {
+    CONFIG
+        .with(|cell| cell.borrow_mut().set(config))
+        .expect("Cannot store the config");
+}
+
+// Allocate the next id from the shared counter, used by every entity without a sequence
+// of its own
+fn next_id() -> Result<u64, Error> {
+    allocate_id(&ID_COUNTER, "shared")
+}
+
+fn next_car_id() -> Result<u64, Error> {
+    let sequence_value = allocate_id(&CAR_ID_COUNTER, "car")?;
+    opaque_id("car", sequence_value, |id| {
+        CAR_STORAGE.with(|storage| storage.borrow().contains_key(&id))
+    })
+}
+
+fn next_rental_id() -> Result<u64, Error> {
+    let sequence_value = allocate_id(&RENTAL_ID_COUNTER, "rental request")?;
+    opaque_id("rental request", sequence_value, |id| {
+        RENTAL_REQUEST_STORAGE.with(|storage| storage.borrow().contains_key(&id))
+            || ARCHIVED_RENTALS.with(|storage| storage.borrow().contains_key(&id))
+    })
+}
+
+fn next_customer_id() -> Result<u64, Error> {
+    let sequence_value = allocate_id(&CUSTOMER_ID_COUNTER, "customer")?;
+    opaque_id("customer", sequence_value, |id| {
+        CUSTOMER_STORAGE.with(|storage| storage.borrow().contains_key(&id))
+    })
+}
+
+fn next_payment_id() -> Result<u64, Error> {
+    let sequence_value = allocate_id(&PAYMENT_ID_COUNTER, "payment")?;
+    opaque_id("payment", sequence_value, |id| {
+        PAYMENT_STORAGE.with(|storage| storage.borrow().contains_key(&id))
+    })
+}
+
+// With opaque ids on, turn a sequence value into an id that gives away neither the
+// sequence nor the ids handed out before it. The id is hashed from the secret seed, so it
+// cannot be guessed, and one that is taken, such as an old sequential id, is hashed again.
+fn opaque_id(name: &str, sequence_value: u64, taken: impl Fn(u64) -> bool) -> Result<u64, Error> {
+    if !config().opaque_ids {
+        return Ok(sequence_value);
+    }
+    let seed = ID_SEED
+        .with(|seed| *seed.borrow())
+        .ok_or_else(|| Error::Conflict {
+            msg: "Opaque ids are not seeded yet, try again shortly".to_string(),
+        })?;
+    for attempt in 0u32.. {
+        let digest = Sha256::new()
+            .chain_update(seed)
+            .chain_update(name.as_bytes())
+            .chain_update(sequence_value.to_be_bytes())
+            .chain_update(attempt.to_be_bytes())
+            .finalize();
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        let id = u64::from_be_bytes(bytes);
+        if id != 0 && !taken(id) {
+            return Ok(id);
+        }
+    }
+    unreachable!("a free id is found long before the attempts run out")
+}
+
+// Draw the seed of opaque ids. raw_rand cannot be called from init or post_upgrade, so
+// they set a timer that draws it right after.
+async fn seed_opaque_ids() -> Result<(), Error> {
+    let mut seed = [0; 32];
+    seed.copy_from_slice(&random_bytes().await?[..32]);
+    ID_SEED.with(|cell| *cell.borrow_mut() = Some(seed));
+    Ok(())
+}
+
+// Take the current value of a sequence and advance it, refusing to wrap around once
+// every id has been handed out
+fn allocate_id(sequence: &'static LocalKey<RefCell<IdCell>>, name: &str) -> Result<u64, Error> {
+    sequence.with(|counter| {
+        let current_value = *counter.borrow().get();
+        let next_value = current_value
+            .checked_add(1)
+            .ok_or_else(|| Error::Conflict {
+                msg: format!("The {} id sequence is exhausted", name),
+            })?;
+        counter
+            .borrow_mut()
+            .set(next_value)
+            .map_err(|error| Error::Conflict {
+                msg: format!("Cannot advance the {} id sequence: {:?}", name, error),
+            })?;
+        Ok(current_value)
+    })
+}
+
+// The value of the shared counter, where a newly added sequence starts
+fn shared_id_count() -> u64 {
+    ID_COUNTER.with(|counter| *counter.borrow().get())
+}
+
+// Implement CRUD operations for cars. A retried call with the same idempotency key
+// returns the car added by the first call instead of adding another.
+#[ic_cdk::update]
+fn add_car(payload: CarPayload, idempotency_key: Option<String>) -> Result<Car, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    match replayed_result(&idempotency_key)? {
+        Some(IdempotentResult::Car(car)) => return Ok(car),
+        Some(_) => return Err(idempotency_key_reused()),
+        None => {}
+    }
+    let car = insert_car(payload, None)?;
+    remember_result(idempotency_key, IdempotentResult::Car(car.clone()));
+    record_audit("add_car", Some(car.id), None, audit_summary(&car));
+    Ok(car)
+}
+
+// Validate and store a new car. Cars added by staff are available from the start; cars
+// listed by their owner wait for review.
+fn insert_car(payload: CarPayload, listed_by: Option<Principal>) -> Result<Car, Error> {
+    validate_car_payload(&payload)?;
+
+    let id = next_car_id()?;
+
+    let mut car = Car::from_payload(id, listed_by.is_none(), payload);
+    if listed_by.is_some() {
+        car.listed_by = listed_by;
+        car.listing_status = Some(ListingStatus::Pending);
+    }
+    check_record_size(&car)?;
+
+    store_car(&mut car);
+    index_car_text(&car);
+    publish_event(DomainEvent::CarAdded { car_id: id });
+    Ok(car)
+}
+
+// Add a car with a VIN, filling an empty make, model, or year from the VIN decoder.
+// If the decoder is unavailable the car is added from the details entered manually.
+#[ic_cdk::update]
+async fn add_car_from_vin(mut payload: CarPayload) -> Result<Car, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    let vin = payload.vin.clone().ok_or(Error::InvalidInput {
+        msg: "A VIN is required to look up a car".to_string(),
+    })?;
+    validate_vin(&vin)?;
+
+    let needs_lookup =
+        payload.make.trim().is_empty() || payload.model.trim().is_empty() || payload.year == 0;
+    if needs_lookup {
+        match fetch_vin_details(&vin).await {
+            Ok(details) => {
+                if payload.make.trim().is_empty() {
+                    payload.make = details.make;
+                }
+                if payload.model.trim().is_empty() {
+                    payload.model = details.model;
+                }
+                if payload.year == 0 {
+                    payload.year = details.year.unwrap_or(0);
+                }
+            }
+            Err(error) if payload.make.trim().is_empty() || payload.model.trim().is_empty() => {
+                return Err(error);
+            }
+            Err(_) => {}
+        }
+    }
+    let car = insert_car(payload, None)?;
+    record_audit("add_car_from_vin", Some(car.id), None, audit_summary(&car));
+    Ok(car)
+}
+
+// Decode a VIN without adding a car
+#[ic_cdk::update]
+async fn decode_vin(vin: String) -> Result<VinDetails, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    validate_vin(&vin)?;
+    fetch_vin_details(&vin).await
+}
+
+async fn fetch_vin_details(vin: &str) -> Result<VinDetails, Error> {
+    let vin = vin.trim().to_uppercase();
+    let request = CanisterHttpRequestArgument {
+        url: format!("{}{}?format=json", VIN_DECODER_URL, vin),
+        max_response_bytes: Some(VIN_DECODER_MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![HttpHeader {
+            name: "Accept".to_string(),
+            value: "application/json".to_string(),
+        }],
+        body: None,
+        transform: Some(TransformContext::from_name(
+            "transform_vin_response".to_string(),
+            vec![],
+        )),
+    };
+    let (response,) = http_outcall(request, VIN_DECODER_CYCLES)
+        .await
+        .map_err(|(code, msg)| Error::CallFailed {
+            msg: format!("VIN decoder request failed: {:?} {}", code, msg),
+        })?;
+    let details: serde_json::Value =
+        serde_json::from_slice(&response.body).map_err(|_| Error::CallFailed {
+            msg: format!("VIN decoder returned status {}", response.status),
+        })?;
+    let field = |name: &str| details[name].as_str().unwrap_or_default().to_string();
+    if field("make").is_empty() || field("model").is_empty() {
+        return Err(Error::NotFound {
+            msg: format!("VIN {} could not be decoded", vin),
+        });
+    }
+    Ok(VinDetails {
+        make: field("make"),
+        model: field("model"),
+        year: field("year").parse().ok(),
+        vin,
+    })
+}
+
+// Reduce a VIN decoder response to the fields used, so every replica agrees on it
+#[ic_cdk::query]
+fn transform_vin_response(args: TransformArgs) -> HttpResponse {
+    let decoded: serde_json::Value =
+        serde_json::from_slice(&args.response.body).unwrap_or_default();
+    let result = &decoded["Results"][0];
+    let body = serde_json::json!({
+        "make": result["Make"].as_str().unwrap_or_default(),
+        "model": result["Model"].as_str().unwrap_or_default(),
+        "year": result["ModelYear"].as_str().unwrap_or_default(),
+    });
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: body.to_string().into_bytes(),
+    }
+}
+
+// Add several cars at once. Each car is added or rejected on its own, and the results are
+// returned in the order of the payloads.
+#[ic_cdk::update]
+fn add_cars_batch(payloads: Vec<CarPayload>) -> Result<Vec<Result<Car, Error>>, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    ensure_batch_size(payloads.len())?;
+    Ok(payloads
+        .into_iter()
+        .map(|payload| {
+            insert_car(payload, None).inspect(|car| {
+                record_audit("add_cars_batch", Some(car.id), None, audit_summary(car))
+            })
+        })
+        .collect())
+}
+
+// Mark several cars available or unavailable, with one result per car
+#[ic_cdk::update]
+fn update_car_availability_batch(
+    updates: Vec<(u64, bool)>,
+) -> Result<Vec<Result<Car, Error>>, Error> {
+    check_rate_limit()?;
+    ensure_batch_size(updates.len())?;
+    Ok(updates
+        .into_iter()
+        .map(|(car_id, available)| {
+            ensure_car_controller(car_id)?;
+            let before = get_car(car_id).ok();
+            set_car_availability(car_id, available).inspect(|car| {
+                record_audit(
+                    "update_car_availability_batch",
+                    Some(car_id),
+                    before.as_ref().and_then(audit_summary),
+                    audit_summary(car),
+                )
+            })
+        })
+        .collect())
+}
+
+// A car that is checked out or in maintenance only becomes available when it comes back
+fn set_car_availability(car_id: u64, available: bool) -> Result<Car, Error> {
+    let mut car = get_car(car_id)?;
+    if available && !car.is_active() {
+        return Err(Error::Conflict {
+            msg: format!("Car with id={} is no longer in the fleet", car_id),
+        });
+    }
+    if available && !car.is_listed() {
+        return Err(Error::Conflict {
+            msg: format!("Listing of car id={} is not approved", car_id),
+        });
+    }
+    if available {
+        if let Some(window) = maintenance_windows_for_car(car_id)
+            .into_iter()
+            .find(|window| window.status == MaintenanceWindowStatus::InProgress)
+        {
+            return Err(Error::Conflict {
+                msg: format!(
+                    "Car with id={} is in maintenance window id={}",
+                    car_id, window.id
+                ),
+            });
+        }
+        if let Some(rental_request) = rentals_for_car(car_id).into_iter().find(|request| {
+            request.status == RentalStatus::Active && request.checkout_odometer_km.is_some()
+        }) {
+            return Err(Error::Conflict {
+                msg: format!(
+                    "Car with id={} is checked out by rental request id={}",
+                    car_id, rental_request.id
+                ),
+            });
+        }
+    }
+    car.available = available;
+    store_car(&mut car);
+    Ok(car)
+}
+
+fn ensure_batch_size(len: usize) -> Result<(), Error> {
+    if len > MAX_BATCH_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("A batch may hold at most {} items", MAX_BATCH_SIZE),
+        });
+    }
+    Ok(())
+}
+
+// Import cars from a CSV chunk whose first row names its columns. A large file is sent as
+// several chunks split between rows, each starting with the header. Every row is added or
+// rejected on its own, like a car added with add_car.
+#[ic_cdk::update]
+fn import_cars_csv(csv: Vec<u8>) -> Result<CsvImportReport, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let invalid = |msg: String| Error::InvalidInput { msg };
+    let text =
+        String::from_utf8(csv).map_err(|_| invalid("The CSV must be UTF-8 text".to_string()))?;
+    let mut records = parse_csv(&text).map_err(invalid)?.into_iter().enumerate();
+    let header: Vec<String> = match records.next() {
+        Some((_, header)) => header
+            .iter()
+            .map(|column| column.trim().to_lowercase())
+            .collect(),
+        None => return Err(invalid("The CSV is empty".to_string())),
+    };
+    if let Some(column) = header
+        .iter()
+        .find(|column| !CAR_CSV_COLUMNS.contains(&column.as_str()))
+    {
+        return Err(invalid(format!("Unknown column {}", column)));
+    }
+    if let Some(column) = CAR_CSV_COLUMNS[..5]
+        .iter()
+        .find(|column| !header.iter().any(|name| name == *column))
+    {
+        return Err(invalid(format!("Missing required column {}", column)));
+    }
+    if records.len() > MAX_CSV_IMPORT_ROWS {
+        return Err(invalid(format!(
+            "A CSV chunk may hold at most {} rows",
+            MAX_CSV_IMPORT_ROWS
+        )));
+    }
+
+    let mut report = CsvImportReport {
+        accepted: Vec::new(),
+        rejected: Vec::new(),
+    };
+    for (index, record) in records {
+        if record.iter().all(|field| field.trim().is_empty()) {
+            continue;
+        }
+        let row = index as u64 + 1;
+        match car_payload_from_csv(&header, &record)
+            .map_err(invalid)
+            .and_then(|payload| insert_car(payload, None))
+        {
+            Ok(car) => {
+                record_audit("import_cars_csv", Some(car.id), None, audit_summary(&car));
+                report.accepted.push(CsvAcceptedRow {
+                    row,
+                    car_id: car.id,
+                })
+            }
+            Err(error) => report.rejected.push(CsvRejectedRow { row, error }),
+        }
+    }
+    Ok(report)
+}
+
+// Split CSV text into records of fields. A field may be quoted to hold commas, line
+// breaks, or quotes, which are written twice.
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => quoted = true,
+            ',' => record.push(std::mem::take(&mut field)),
+            '\r' => {}
+            '\n' => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format!(
+            "Row {} has an unterminated quoted field",
+            records.len() + 1
+        ));
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+// Build the payload of a car from a CSV row; empty optional fields are left unset
+fn car_payload_from_csv(header: &[String], record: &[String]) -> Result<CarPayload, String> {
+    if record.len() != header.len() {
+        return Err(format!(
+            "Expected {} fields but found {}",
+            header.len(),
+            record.len()
+        ));
+    }
+    let field = |column: &str| {
+        header
+            .iter()
+            .position(|name| name == column)
+            .map_or("", |index| record[index].trim())
+    };
+    Ok(CarPayload {
+        make: field("make").to_string(),
+        model: field("model").to_string(),
+        year: parse_csv_number(field("year"), "year")?,
+        daily_rate_e8s: parse_csv_number(field("daily_rate_e8s"), "daily_rate_e8s")?,
+        category: parse_csv_variant(
+            field("category"),
+            "category",
+            &[
+                CarCategory::Economy,
+                CarCategory::Compact,
+                CarCategory::Midsize,
+                CarCategory::Suv,
+                CarCategory::Van,
+                CarCategory::Luxury,
+                CarCategory::Electric,
+            ],
+        )?,
+        vin: Some(field("vin").to_string()).filter(|vin| !vin.is_empty()),
+        license_plate: field("license_plate").to_string(),
+        color: field("color").to_string(),
+        transmission: parse_csv_optional(field("transmission"), |value| {
+            parse_csv_variant(
+                value,
+                "transmission",
+                &[Transmission::Manual, Transmission::Automatic],
+            )
+        })?,
+        fuel_type: parse_csv_optional(field("fuel_type"), |value| {
+            parse_csv_variant(
+                value,
+                "fuel_type",
+                &[
+                    FuelType::Petrol,
+                    FuelType::Diesel,
+                    FuelType::Hybrid,
+                    FuelType::Electric,
+                ],
+            )
+        })?,
+        seats: parse_csv_optional(field("seats"), |value| parse_csv_number(value, "seats"))?
+            .unwrap_or(0),
+        mileage_km: parse_csv_optional(field("mileage_km"), |value| {
+            parse_csv_number(value, "mileage_km")
+        })?
+        .unwrap_or(0),
+        // Features are separated by semicolons within their field
+        features: field("features")
+            .split(';')
+            .map(|feature| feature.trim().to_string())
+            .filter(|feature| !feature.is_empty())
+            .collect(),
+        branch_id: parse_csv_optional(field("branch_id"), |value| {
+            parse_csv_number(value, "branch_id")
+        })?,
+        fuel_policy: parse_csv_optional(field("fuel_policy"), |value| {
+            parse_csv_variant(
+                value,
+                "fuel_policy",
+                &[FuelPolicy::FullToFull, FuelPolicy::Prepaid],
+            )
+        })?,
+    })
+}
+
+fn parse_csv_number<T: std::str::FromStr>(value: &str, column: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} must be a whole number, not '{}'", column, value))
+}
+
+// Match a variant by name, ignoring case
+fn parse_csv_variant<T: std::fmt::Debug + Copy>(
+    value: &str,
+    column: &str,
+    variants: &[T],
+) -> Result<T, String> {
+    variants
+        .iter()
+        .find(|variant| format!("{:?}", variant).eq_ignore_ascii_case(value))
+        .copied()
+        .ok_or_else(|| format!("{} '{}' is not one of {:?}", column, value, variants))
+}
+
+fn parse_csv_optional<T>(
+    value: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Option<T>, String> {
+    if value.is_empty() {
+        Ok(None)
+    } else {
+        parse(value).map(Some)
+    }
+}
+
+#[ic_cdk::update]
+fn delete_car(id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    ensure_car_controller(id)?;
+    // Deleting a car would leave its rentals pointing at nothing, so such cars are retired
+    let has_archived_rentals = ARCHIVED_RENTALS_BY_CAR.with(|index| {
+        index
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .next()
+            .is_some()
+    });
+    if has_archived_rentals || !rentals_for_car(id).is_empty() {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Car with id={} has rentals on record; retire it instead",
+                id
+            ),
+        });
+    }
+    // The token's holder owns the car, so it is not deleted from under them
+    if CAR_TOKENS.with(|tokens| tokens.borrow().contains_key(&id)) {
+        return Err(Error::Conflict {
+            msg: format!("Car with id={} is minted as a token; retire it instead", id),
+        });
+    }
+
+    match CAR_STORAGE.with(|storage| storage.borrow_mut().remove(&id)) {
+        Some(car) => {
+            unindex_car_text(&car);
+            certify_car(id);
+            certify_availability(id);
+            INSURANCE_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+            CAR_OWNERSHIP.with(|storage| storage.borrow_mut().remove(&id));
+            let photo_ids: Vec<u64> = PHOTO_STORAGE.with(|storage| {
+                storage
+                    .borrow()
+                    .iter()
+                    .filter(|(_, photo)| photo.car_id == id)
+                    .map(|(photo_id, _)| photo_id)
+                    .collect()
+            });
+            for photo_id in photo_ids {
+                remove_photo(photo_id);
+            }
+            record_audit("delete_car", Some(id), audit_summary(&car), None);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Car with id={} not found", id),
+        }),
+    }
+}
+
+// Take a car out of the fleet as retired, sold, or written off. It stays readable with
+// get_car for the history of its rentals but can no longer be booked.
+#[ic_cdk::update]
+fn retire_car(id: u64, status: CarStatus) -> Result<Car, Error> {
+    check_rate_limit()?;
+    ensure_car_controller(id)?;
+    if status == CarStatus::Active {
+        return Err(Error::InvalidInput {
+            msg: "A car can only be retired as Retired, Sold, or WrittenOff".to_string(),
+        });
+    }
+    let mut car = get_car(id)?;
+    if let Some(rental_request) = rentals_for_car(id)
+        .into_iter()
+        .find(|request| request.status.is_open())
+    {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Car with id={} still has open rental request id={}",
+                id, rental_request.id
+            ),
+        });
+    }
+    let now = ic_cdk::api::time();
+    if let Some(hold) = holds_for_car(id)
+        .into_iter()
+        .find(|hold| hold.is_active(now))
+    {
+        return Err(Error::Conflict {
+            msg: format!("Car with id={} is held by hold id={}", id, hold.id),
+        });
+    }
+    if let Some(transfer) = transfer_in_transit(id) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Car with id={} is in transit with transfer id={}",
+                id, transfer.id
+            ),
+        });
+    }
+    let before = audit_summary(&car);
+    if car.is_active() {
+        car.retired_at = Some(now);
+    }
+    car.status = Some(status);
+    car.available = false;
+    store_car(&mut car);
+    record_audit("retire_car", Some(id), before, audit_summary(&car));
+    Ok(car)
+}
+
+// Implement the marketplace. Anyone may list their own car, which can be booked once an
+// admin approves the listing. Owners manage their cars as staff do, and see the rentals of
+// their own fleet only.
+#[ic_cdk::update]
+fn list_my_car(payload: CarPayload) -> Result<Car, Error> {
+    check_rate_limit()?;
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "The anonymous principal cannot list a car".to_string(),
+        });
+    }
+    let car = insert_car(payload, Some(owner))?;
+    record_audit("list_my_car", Some(car.id), None, audit_summary(&car));
+    Ok(car)
+}
+
+#[ic_cdk::query]
+fn list_pending_car_listings() -> Result<Vec<Car>, Error> {
+    require_any_role(&[Role::Admin])?;
+    Ok(CAR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, car)| car)
+            .filter(|car| car.listing_status == Some(ListingStatus::Pending))
+            .collect()
+    }))
+}
+
+// Approve a listing awaiting review; the car becomes available to book
+#[ic_cdk::update]
+fn approve_car_listing(car_id: u64) -> Result<Car, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let (before, car) = review_car_listing(car_id, ListingStatus::Approved)?;
+    record_audit(
+        "approve_car_listing",
+        Some(car_id),
+        audit_summary(&before),
+        audit_summary(&car),
+    );
+    Ok(car)
+}
+
+#[ic_cdk::update]
+fn reject_car_listing(car_id: u64, reason: String) -> Result<Car, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let reason = reason.trim().to_string();
+    if reason.is_empty() || reason.len() > 256 {
+        return Err(Error::InvalidInput {
+            msg: "Rejection reason must be between 1 and 256 characters".to_string(),
+        });
+    }
+    let (before, car) = review_car_listing(car_id, ListingStatus::Rejected { reason })?;
+    record_audit(
+        "reject_car_listing",
+        Some(car_id),
+        audit_summary(&before),
+        audit_summary(&car),
+    );
+    Ok(car)
+}
+
+// List the cars the caller owns, whether listed by them or held as tokens
+#[ic_cdk::query]
+fn list_my_cars() -> Vec<Car> {
+    let owner = caller();
+    CAR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, car)| car)
+            .filter(|car| car_controller(car) == Some(owner))
+            .collect()
+    })
+}
+
+// List the rentals of the cars the caller owns
+#[ic_cdk::query]
+fn list_my_fleet_rentals(cursor: Option<u64>, limit: u32) -> Page<RentalRequest> {
+    let mut rentals: Vec<(u64, RentalRequest)> = list_my_cars()
+        .into_iter()
+        .flat_map(|car| rentals_for_car(car.id))
+        .map(|rental| (rental.id, rental))
+        .collect();
+    rentals.sort_by_key(|(id, _)| *id);
+    let total = rentals.len() as u64;
+    let cursor = cursor.unwrap_or(0);
+    page_of(
+        rentals.into_iter().filter(|(id, _)| *id >= cursor),
+        total,
+        limit,
+    )
+}
+
+// Move a listing awaiting review to its outcome, returning the car before and after
+fn review_car_listing(car_id: u64, status: ListingStatus) -> Result<(Car, Car), Error> {
+    let before = get_car(car_id)?;
+    if before.listing_status != Some(ListingStatus::Pending) {
+        return Err(Error::Conflict {
+            msg: format!("Listing of car id={} is not awaiting review", car_id),
+        });
+    }
+    let mut car = before.clone();
+    car.available = status == ListingStatus::Approved;
+    car.listing_status = Some(status);
+    store_car(&mut car);
+    Ok((before, car))
+}
+
+// Implement ICRC-7 tokens for cars. Admins mint a car's token to its owner, who may then
+// transfer it on to anyone. The holder of a car's token controls its listing like staff:
+// they may update the car and make it available or not.
+#[ic_cdk::update]
+fn mint_car_token(car_id: u64, to: Account) -> Result<CarToken, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    get_car(car_id)?;
+    if to.owner == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "A car token cannot be minted to the anonymous principal".to_string(),
+        });
+    }
+    if CAR_TOKENS.with(|tokens| tokens.borrow().contains_key(&car_id)) {
+        return Err(Error::Conflict {
+            msg: format!("Car with id={} is already minted", car_id),
+        });
+    }
+    let now = ic_cdk::api::time();
+    let token = CarToken {
+        car_id,
+        owner: to,
+        minted_at: now,
+    };
+    let transfer = CarTokenTransfer {
+        id: next_id()?,
+        token_id: car_id,
+        from: None,
+        to,
+        memo: None,
+        at: now,
+    };
+    CAR_TOKENS.with(|tokens| tokens.borrow_mut().insert(car_id, token.clone()));
+    CAR_TOKEN_TRANSFERS.with(|transfers| transfers.borrow_mut().insert(transfer.id, transfer));
+    record_audit("mint_car_token", Some(car_id), None, audit_summary(&token));
+    Ok(token)
+}
+
+// List the holders of a car's token, the minting first
+#[ic_cdk::query]
+fn list_car_token_transfers(car_id: u64) -> Vec<CarTokenTransfer> {
+    CAR_TOKEN_TRANSFERS.with(|transfers| {
+        transfers
+            .borrow()
+            .iter()
+            .filter(|(_, transfer)| transfer.token_id == car_id)
+            .map(|(_, transfer)| transfer)
+            .collect()
+    })
+}
+
+// Staff control every car, and each car is controlled by its owner
+fn ensure_car_controller(car_id: u64) -> Result<(), Error> {
+    if require_any_role(&[Role::Staff]).is_ok() {
+        return Ok(());
+    }
+    let car = get_car(car_id)?;
+    if car_controller(&car) == Some(caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("Only staff or the owner of car id={} may change it", car_id),
+        })
+    }
+}
+
+// The holder of a car's token owns the car; a car that is not minted is owned by whoever
+// listed it, if anyone did
+fn car_controller(car: &Car) -> Option<Principal> {
+    match CAR_TOKENS.with(|tokens| tokens.borrow().get(&car.id)) {
+        Some(token) => Some(token.owner.owner),
+        None => car.listed_by,
+    }
+}
+
+#[ic_cdk::query]
+fn icrc7_name() -> String {
+    CAR_TOKEN_COLLECTION_NAME.to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_symbol() -> String {
+    CAR_TOKEN_SYMBOL.to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(CAR_TOKENS.with(|tokens| tokens.borrow().len()))
+}
+
+#[ic_cdk::query]
+fn icrc7_collection_metadata() -> Vec<(String, Icrc7Value)> {
+    vec![
+        ("icrc7:name".to_string(), Icrc7Value::Text(icrc7_name())),
+        ("icrc7:symbol".to_string(), Icrc7Value::Text(icrc7_symbol())),
+        (
+            "icrc7:total_supply".to_string(),
+            Icrc7Value::Nat(icrc7_total_supply()),
+        ),
+        (
+            "icrc7:max_update_batch_size".to_string(),
+            Icrc7Value::Nat(Nat::from(MAX_BATCH_SIZE)),
+        ),
+        (
+            "icrc7:max_query_batch_size".to_string(),
+            Icrc7Value::Nat(Nat::from(MAX_PAGE_SIZE)),
+        ),
+    ]
+}
+
+#[ic_cdk::query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-10".to_string(),
+        },
+    ]
+}
+
+// Describe each car whose token exists, in the order of the ids asked for
+#[ic_cdk::query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Icrc7Value)>>> {
+    ensure_query_batch_size(token_ids.len());
+    token_ids
+        .iter()
+        .map(|token_id| {
+            let token = car_token(token_id)?;
+            let car = CAR_STORAGE.with(|storage| storage.borrow().get(&token.car_id))?;
+            let text = |value: String| Icrc7Value::Text(value);
+            Some(vec![
+                (
+                    "icrc7:name".to_string(),
+                    text(format!("{} {} {}", car.year, car.make, car.model)),
+                ),
+                ("car:make".to_string(), text(car.make)),
+                ("car:model".to_string(), text(car.model)),
+                ("car:year".to_string(), Icrc7Value::Nat(Nat::from(car.year))),
+                (
+                    "car:category".to_string(),
+                    text(format!("{:?}", car.category)),
+                ),
+                ("car:vin".to_string(), text(car.vin.unwrap_or_default())),
+            ])
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    ensure_query_batch_size(token_ids.len());
+    token_ids
+        .iter()
+        .map(|token_id| car_token(token_id).map(|token| token.owner))
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    ensure_query_batch_size(accounts.len());
+    CAR_TOKENS.with(|tokens| {
+        let tokens = tokens.borrow();
+        accounts
+            .iter()
+            .map(|account| {
+                Nat::from(
+                    tokens
+                        .iter()
+                        .filter(|(_, token)| same_account(&token.owner, account))
+                        .count(),
+                )
+            })
+            .collect()
+    })
+}
+
+// List token ids in ascending order, after prev if given
+#[ic_cdk::query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    car_token_ids(prev, take, |_| true)
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    car_token_ids(prev, take, |token| same_account(&token.owner, &account))
+}
+
+// Transfer car tokens, each on its own. Only the holder may transfer a token, from the
+// subaccount it is held in.
+#[ic_cdk::update]
+fn icrc7_transfer(args: Vec<Icrc7TransferArg>) -> Vec<Option<Result<Nat, Icrc7TransferError>>> {
+    if check_rate_limit().is_err() {
+        ic_cdk::trap("Too many calls; try again later");
+    }
+    if args.len() > MAX_BATCH_SIZE {
+        ic_cdk::trap(&format!(
+            "A batch may hold at most {} transfers",
+            MAX_BATCH_SIZE
+        ));
+    }
+    let now = ic_cdk::api::time();
+    args.into_iter()
+        .map(|arg| Some(transfer_car_token(arg, now)))
+        .collect()
+}
+
+fn transfer_car_token(arg: Icrc7TransferArg, now: u64) -> Result<Nat, Icrc7TransferError> {
+    if let Some(created_at_time) = arg.created_at_time {
+        if created_at_time.saturating_add(CAR_TOKEN_TX_WINDOW) < now {
+            return Err(Icrc7TransferError::TooOld);
+        }
+        if created_at_time > now.saturating_add(CAR_TOKEN_PERMITTED_DRIFT) {
+            return Err(Icrc7TransferError::CreatedInFuture { ledger_time: now });
+        }
+    }
+    let mut token = car_token(&arg.token_id).ok_or(Icrc7TransferError::NonExistingTokenId)?;
+    let from = Account {
+        owner: caller(),
+        subaccount: arg.from_subaccount,
+    };
+    if !same_account(&token.owner, &from) {
+        return Err(Icrc7TransferError::Unauthorized);
+    }
+    if arg.to.owner == Principal::anonymous() || same_account(&arg.to, &from) {
+        return Err(Icrc7TransferError::InvalidRecipient);
+    }
+    let transfer = CarTokenTransfer {
+        id: next_id().map_err(|_| Icrc7TransferError::GenericError {
+            error_code: Nat::from(0u64),
+            message: "No transfer id is left".to_string(),
+        })?,
+        token_id: token.car_id,
+        from: Some(from),
+        to: arg.to,
+        memo: arg.memo,
+        at: now,
+    };
+    let before = audit_summary(&token);
+    token.owner = arg.to;
+    CAR_TOKENS.with(|tokens| tokens.borrow_mut().insert(token.car_id, token.clone()));
+    let id = transfer.id;
+    CAR_TOKEN_TRANSFERS.with(|transfers| transfers.borrow_mut().insert(id, transfer));
+    record_audit(
+        "icrc7_transfer",
+        Some(token.car_id),
+        before,
+        audit_summary(&token),
+    );
+    Ok(Nat::from(id))
+}
+
+fn car_token(token_id: &Nat) -> Option<CarToken> {
+    let car_id = u64::try_from(&token_id.0).ok()?;
+    CAR_TOKENS.with(|tokens| tokens.borrow().get(&car_id))
+}
+
+fn car_token_ids(
+    prev: Option<Nat>,
+    take: Option<Nat>,
+    include: impl Fn(&CarToken) -> bool,
+) -> Vec<Nat> {
+    let take = take.map_or(MAX_PAGE_SIZE as u64, |take| nat_to_u64(&take));
+    let start = match prev {
+        Some(prev) => match u64::try_from(&prev.0) {
+            Ok(u64::MAX) | Err(_) => return Vec::new(),
+            Ok(prev) => Bound::Excluded(prev),
+        },
+        None => Bound::Unbounded,
+    };
+    CAR_TOKENS.with(|tokens| {
+        tokens
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .filter(|(_, token)| include(token))
+            .take(take.clamp(1, MAX_PAGE_SIZE as u64) as usize)
+            .map(|(car_id, _)| Nat::from(car_id))
+            .collect()
+    })
+}
+
+// Accounts are the same if their owners are and their subaccounts are, counting no
+// subaccount as the default one
+fn same_account(a: &Account, b: &Account) -> bool {
+    a.owner == b.owner && a.effective_subaccount() == b.effective_subaccount()
+}
+
+fn ensure_query_batch_size(len: usize) {
+    if len > MAX_PAGE_SIZE as usize {
+        ic_cdk::trap(&format!(
+            "A query may ask for at most {} items",
+            MAX_PAGE_SIZE
+        ));
+    }
+}
+
+// Implement fractional ownership. An admin shares a car between owner principals; when a
+// rental of the car is invoiced, each owner is credited their share of what was paid for
+// it, net of tax and of any credit given back, and may withdraw it on the token ledger.
+#[ic_cdk::update]
+fn set_car_owners(car_id: u64, shares: Vec<OwnerShare>) -> Result<CarOwnership, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    get_car(car_id)?;
+    validate_owner_shares(&shares)?;
+    let before = CAR_OWNERSHIP
+        .with(|storage| storage.borrow().get(&car_id))
+        .as_ref()
+        .and_then(audit_summary);
+    let ownership = CarOwnership {
+        car_id,
+        shares,
+        updated_at: ic_cdk::api::time(),
+    };
+    // A car without owners earns only for the operator
+    CAR_OWNERSHIP.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if ownership.shares.is_empty() {
+            storage.remove(&car_id);
+        } else {
+            storage.insert(car_id, ownership.clone());
+        }
+    });
+    record_audit(
+        "set_car_owners",
+        Some(car_id),
+        before,
+        audit_summary(&ownership),
+    );
+    Ok(ownership)
+}
+
+#[ic_cdk::query]
+fn get_car_owners(car_id: u64) -> Result<CarOwnership, Error> {
+    get_car(car_id)?;
+    let ownership = CAR_OWNERSHIP
+        .with(|storage| storage.borrow().get(&car_id))
+        .unwrap_or(CarOwnership {
+            car_id,
+            shares: Vec::new(),
+            updated_at: 0,
+        });
+    if !ownership.shares.iter().any(|share| share.owner == caller()) {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(ownership)
+}
+
+// Sum up what the caller earned as an owner, with every movement of their earnings
+#[ic_cdk::query]
+fn get_my_earnings() -> Earnings {
+    let owner = caller();
+    let entries: Vec<EarningsEntry> = EARNINGS_ENTRIES.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, entry)| entry.owner == owner)
+            .map(|(_, entry)| entry)
+            .collect()
+    });
+    let mut earned_e8s = 0;
+    let mut withdrawn_e8s: u64 = 0;
+    for entry in &entries {
+        match entry.kind {
+            EarningsEntryKind::RentalShare { .. } | EarningsEntryKind::MarketplaceRental { .. } => {
+                earned_e8s += entry.amount_e8s.unsigned_abs()
+            }
+            EarningsEntryKind::Withdrawal { .. } => {
+                withdrawn_e8s += entry.amount_e8s.unsigned_abs()
+            }
+            EarningsEntryKind::WithdrawalReversed { .. } => {
+                withdrawn_e8s = withdrawn_e8s.saturating_sub(entry.amount_e8s.unsigned_abs())
+            }
+        }
+    }
+    Earnings {
+        owner,
+        balance_e8s: earnings_balance(&owner),
+        earned_e8s,
+        withdrawn_e8s,
+        entries,
+    }
+}
+
+// Send earnings to the caller's default account on the token ledger. The canister covers
+// the ledger fee.
+#[ic_cdk::update]
+async fn withdraw_earnings(amount_e8s: u64) -> Result<EarningsEntry, Error> {
+    check_rate_limit()?;
+    let (entry, payout) = pay_out_earnings(caller(), amount_e8s).await?;
+    record_audit(
+        "withdraw_earnings",
+        Some(payout.id),
+        None,
+        audit_summary(&payout),
+    );
+    Ok(entry)
+}
+
+// Pay out all of the caller's earnings; the commission was withheld as they accrued
+#[ic_cdk::update]
+async fn request_payout() -> Result<Payout, Error> {
+    check_rate_limit()?;
+    let owner = caller();
+    let (_, payout) = pay_out_earnings(owner, earnings_balance(&owner)).await?;
+    record_audit(
+        "request_payout",
+        Some(payout.id),
+        None,
+        audit_summary(&payout),
+    );
+    Ok(payout)
+}
+
+// List the payouts made to an owner, the oldest first
+#[ic_cdk::query]
+fn list_payouts(owner: Principal) -> Result<Vec<Payout>, Error> {
+    ensure_owner_or_admin(&owner)?;
+    Ok(PAYOUTS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, payout)| payout.owner == owner)
+            .map(|(_, payout)| payout)
+            .collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn set_marketplace_commission(commission_bps: u64) -> Result<Config, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let mut updated_config = config();
+    let before = audit_summary(&updated_config.marketplace_commission_bps);
+    updated_config.marketplace_commission_bps = Some(commission_bps);
+    validate_config(&updated_config)?;
+    set_config(updated_config.clone());
+    record_audit(
+        "set_marketplace_commission",
+        None,
+        before,
+        audit_summary(&commission_bps),
+    );
+    Ok(updated_config)
+}
+
+// Send earnings to an owner's default account, returning the debit and the payout
+async fn pay_out_earnings(
+    owner: Principal,
+    amount_e8s: u64,
+) -> Result<(EarningsEntry, Payout), Error> {
+    let balance = earnings_balance(&owner);
+    if amount_e8s == 0 || amount_e8s > balance {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Amount must be between 1 and the {} e8s left to withdraw",
+                balance
+            ),
+        });
+    }
+    let payout_id = next_id()?;
+    // Debited before the transfer, so the same earnings cannot be withdrawn twice
+    let mut entry = post_earnings_entry(
+        owner,
+        EarningsEntryKind::Withdrawal { transfer_id: None },
+        -(amount_e8s as i64),
+    )?;
+    let to = Account {
+        owner,
+        subaccount: None,
+    };
+    match icrc1_transfer(token_ledger_canister_id(), None, to, amount_e8s, entry.id).await {
+        Ok(transfer_id) => {
+            entry.kind = EarningsEntryKind::Withdrawal {
+                transfer_id: Some(transfer_id),
+            };
+            EARNINGS_ENTRIES.with(|storage| storage.borrow_mut().insert(entry.id, entry.clone()));
+            let payout = Payout {
+                id: payout_id,
+                owner,
+                amount_e8s,
+                entry_id: entry.id,
+                transfer_id,
+                paid_at: ic_cdk::api::time(),
+            };
+            PAYOUTS.with(|storage| storage.borrow_mut().insert(payout.id, payout.clone()));
+            Ok((entry, payout))
+        }
+        Err(error) => {
+            post_earnings_entry(
+                owner,
+                EarningsEntryKind::WithdrawalReversed { entry_id: entry.id },
+                amount_e8s as i64,
+            )?;
+            Err(error)
+        }
+    }
+}
+
+// Credit each owner of a rental's car their share of what was paid for the rental. A car
+// listed on the marketplace pays the platform's commission first, and belongs wholly to
+// its owner unless it is shared.
+fn accrue_owner_earnings(
+    rental_request: &RentalRequest,
+    invoice: &Invoice,
+    credited_e8s: u64,
+) -> Result<(), Error> {
+    let Ok(car) = get_car(rental_request.car_id) else {
+        return Ok(());
+    };
+    let marketplace = car.listed_by.is_some();
+    let shares = match CAR_OWNERSHIP.with(|storage| storage.borrow().get(&car.id)) {
+        Some(ownership) => ownership.shares,
+        None => match car_controller(&car).filter(|_| marketplace) {
+            Some(owner) => vec![OwnerShare {
+                owner,
+                share_bps: BPS_DENOMINATOR,
+            }],
+            None => return Ok(()),
+        },
+    };
+    let tax_e8s: i64 = invoice
+        .lines
+        .iter()
+        .filter(|line| line.kind == InvoiceLineKind::Tax)
+        .map(|line| line.amount_e8s)
+        .sum();
+    let shared_e8s = invoice
+        .paid_e8s
+        .saturating_sub(credited_e8s)
+        .saturating_sub(tax_e8s.max(0) as u64);
+    let commission_bps = if marketplace {
+        config()
+            .marketplace_commission_bps
+            .unwrap_or(DEFAULT_MARKETPLACE_COMMISSION_BPS)
+    } else {
+        0
+    };
+    for share in shares {
+        let gross_e8s = bps_of(shared_e8s, share.share_bps);
+        let commission_e8s = bps_of(gross_e8s, commission_bps);
+        let amount_e8s = gross_e8s - commission_e8s;
+        if amount_e8s == 0 {
+            continue;
+        }
+        let kind = if marketplace {
+            EarningsEntryKind::MarketplaceRental {
+                rental_id: rental_request.id,
+                car_id: car.id,
+                share_bps: share.share_bps,
+                commission_e8s,
+            }
+        } else {
+            EarningsEntryKind::RentalShare {
+                rental_id: rental_request.id,
+                car_id: car.id,
+                share_bps: share.share_bps,
+            }
+        };
+        post_earnings_entry(share.owner, kind, amount_e8s as i64)?;
+    }
+    Ok(())
+}
+
+// Take a share in basis points of an amount, rounding down
+fn bps_of(amount_e8s: u64, bps: u64) -> u64 {
+    (amount_e8s as u128 * bps as u128 / BPS_DENOMINATOR as u128) as u64
+}
+
+fn earnings_balance(owner: &Principal) -> u64 {
+    EARNINGS_BALANCES
+        .with(|storage| storage.borrow().get(&StorablePrincipal(*owner)))
+        .unwrap_or(0)
+}
+
+// Apply a credit or debit to an owner's earnings and record it in the history
+fn post_earnings_entry(
+    owner: Principal,
+    kind: EarningsEntryKind,
+    amount_e8s: i64,
+) -> Result<EarningsEntry, Error> {
+    let balance = earnings_balance(&owner);
+    let balance_after_e8s = balance
+        .checked_add_signed(amount_e8s)
+        .ok_or(Error::InvalidInput {
+            msg: format!("Owner {} has only {} e8s of earnings", owner, balance),
+        })?;
+    let entry = EarningsEntry {
+        id: next_id()?,
+        owner,
+        kind,
+        amount_e8s,
+        balance_after_e8s,
+        at: ic_cdk::api::time(),
+    };
+    EARNINGS_BALANCES.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(StorablePrincipal(owner), balance_after_e8s)
+    });
+    EARNINGS_ENTRIES.with(|storage| storage.borrow_mut().insert(entry.id, entry.clone()));
+    Ok(entry)
+}
+
+// Implement chunked photo uploads; a photo is only served once finish_upload succeeds
+#[ic_cdk::update]
+fn begin_upload(car_id: u64, content_type: String, size: u64) -> Result<Photo, Error> {
+    check_rate_limit()?;
+    ensure_car_controller(car_id)?;
+    get_car(car_id)?;
+    if !PHOTO_CONTENT_TYPES.contains(&content_type.as_str()) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Photo content type must be one of {}",
+                PHOTO_CONTENT_TYPES.join(", ")
+            ),
+        });
+    }
+    if size == 0 || size > MAX_PHOTO_SIZE {
+        return Err(Error::InvalidInput {
+            msg: format!("Photo size must be between 1 and {} bytes", MAX_PHOTO_SIZE),
+        });
+    }
+
+    let photo = Photo {
+        id: next_id()?,
+        car_id,
+        content_type,
+        size,
+        chunk_count: size.div_ceil(PHOTO_CHUNK_SIZE as u64) as u32,
+        uploaded_by: caller(),
+        uploaded_at: ic_cdk::api::time(),
+        finished: false,
+    };
+    PHOTO_STORAGE.with(|storage| storage.borrow_mut().insert(photo.id, photo.clone()));
+    record_audit("begin_upload", Some(photo.id), None, audit_summary(&photo));
+    Ok(photo)
+}
+
+#[ic_cdk::update]
+fn upload_chunk(photo_id: u64, index: u32, data: Vec<u8>) -> Result<(), Error> {
+    check_rate_limit()?;
+    let photo = get_photo(photo_id)?;
+    ensure_car_controller(photo.car_id)?;
+    if photo.finished {
+        return Err(Error::Conflict {
+            msg: format!("Upload of photo id={} is already finished", photo_id),
+        });
+    }
+    if index >= photo.chunk_count {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Chunk index must be below {} for photo id={}",
+                photo.chunk_count, photo_id
+            ),
+        });
+    }
+    let expected = photo.chunk_len(index);
+    if data.len() as u64 != expected {
+        return Err(Error::InvalidInput {
+            msg: format!("Chunk {} must be {} bytes long", index, expected),
+        });
+    }
+
+    PHOTO_CHUNKS.with(|chunks| {
+        chunks
+            .borrow_mut()
+            .insert((photo_id, index), PhotoChunk(data))
+    });
+    record_audit("upload_chunk", Some(photo_id), None, audit_summary(&index));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn finish_upload(photo_id: u64) -> Result<Photo, Error> {
+    check_rate_limit()?;
+    let mut photo = get_photo(photo_id)?;
+    if require_any_role(&[Role::Staff]).is_err() {
+        ensure_car_controller(photo.car_id)?;
+    }
+    if photo.finished {
+        return Ok(photo);
+    }
+    let missing: Vec<u32> = PHOTO_CHUNKS.with(|chunks| {
+        let chunks = chunks.borrow();
+        (0..photo.chunk_count)
+            .filter(|index| !chunks.contains_key(&(photo_id, *index)))
+            .collect()
+    });
+    if !missing.is_empty() {
+        return Err(Error::InvalidInput {
+            msg: format!("Photo id={} is missing chunks {:?}", photo_id, missing),
+        });
+    }
+    // The car may have been deleted while the photo was uploading
+    if let Err(err) = get_car(photo.car_id) {
+        remove_photo(photo_id);
+        return Err(err);
+    }
+
+    let before = audit_summary(&photo);
+    photo.finished = true;
+    PHOTO_STORAGE.with(|storage| storage.borrow_mut().insert(photo_id, photo.clone()));
+    PHOTOS_BY_CAR.with(|index| index.borrow_mut().insert((photo.car_id, photo_id), ()));
+    record_audit(
+        "finish_upload",
+        Some(photo_id),
+        before,
+        audit_summary(&photo),
+    );
+    Ok(photo)
+}
+
+#[ic_cdk::update]
+fn delete_photo(photo_id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    let photo = get_photo(photo_id)?;
+    ensure_car_controller(photo.car_id)?;
+    remove_photo(photo_id);
+    record_audit("delete_photo", Some(photo_id), audit_summary(&photo), None);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_photo(photo_id: u64) -> Result<Photo, Error> {
+    match PHOTO_STORAGE.with(|storage| storage.borrow().get(&photo_id)) {
+        Some(photo) => Ok(photo),
+        None => Err(Error::NotFound {
+            msg: format!("Photo with id={} not found", photo_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_photo_chunk(photo_id: u64, index: u32) -> Result<Vec<u8>, Error> {
+    let photo = get_photo(photo_id)?;
+    if !photo.finished {
+        return Err(Error::NotFound {
+            msg: format!("Photo with id={} is still uploading", photo_id),
+        });
+    }
+    match PHOTO_CHUNKS.with(|chunks| chunks.borrow().get(&(photo_id, index))) {
+        Some(chunk) => Ok(chunk.0),
+        None => Err(Error::NotFound {
+            msg: format!("Chunk {} of photo id={} not found", index, photo_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_car_photos(car_id: u64) -> Vec<Photo> {
+    let photo_ids: Vec<u64> = PHOTOS_BY_CAR.with(|index| {
+        index
+            .borrow()
+            .range((car_id, 0)..=(car_id, u64::MAX))
+            .map(|((_, photo_id), _)| photo_id)
+            .collect()
+    });
+    PHOTO_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        photo_ids
+            .into_iter()
+            .filter_map(|photo_id| storage.get(&photo_id))
+            .collect()
+    })
+}
+
+fn remove_photo(photo_id: u64) {
+    if let Some(photo) = PHOTO_STORAGE.with(|storage| storage.borrow_mut().remove(&photo_id)) {
+        PHOTOS_BY_CAR.with(|index| index.borrow_mut().remove(&(photo.car_id, photo_id)));
+        PHOTO_CHUNKS.with(|chunks| {
+            let mut chunks = chunks.borrow_mut();
+            for index in 0..photo.chunk_count {
+                chunks.remove(&(photo_id, index));
+            }
+        });
+    }
+}
+
+// Implement query operations for the car rental system
+#[ic_cdk::query]
+fn get_car(id: u64) -> Result<Car, Error> {
+    match CAR_STORAGE.with(|storage| storage.borrow().get(&id)) {
+        Some(car) => Ok(car.clone()),
+        None => Err(Error::NotFound {
+            msg: format!("Car with id={} not found", id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_rental_request(id: u64) -> Result<RentalRequest, Error> {
+    match RENTAL_REQUEST_STORAGE.with(|storage| storage.borrow().get(&id)) {
+        Some(rental_request) => Ok(rental_request.clone()),
+        None => Err(Error::NotFound {
+            msg: format!("Rental request with id={} not found", id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_cars() -> Vec<Car> {
+    collect_pages(|cursor| list_cars_page(cursor, MAX_PAGE_SIZE))
+}
+
+#[ic_cdk::query]
+fn list_cars_page(cursor: Option<u64>, limit: u32) -> Page<Car> {
+    CAR_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        let total = storage.iter().filter(|(_, car)| car.is_listed()).count() as u64;
+        page_of(
+            storage
+                .range(cursor.unwrap_or(0)..)
+                .filter(|(_, car)| car.is_listed()),
+            total,
+            limit,
+        )
+    })
+}
+
+#[ic_cdk::query]
+fn list_cars_by_category(category: CarCategory) -> Vec<Car> {
+    CAR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, car)| car)
+            .filter(|car| car.is_listed() && car.category == category)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn list_cars_at_branch(branch_id: u64) -> Result<Vec<Car>, Error> {
+    get_branch(branch_id)?;
+    Ok(CAR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, car)| car)
+            .filter(|car| car.is_listed() && car.branch_id == Some(branch_id))
+            .collect()
+    }))
+}
+
+// Implement branch management
+#[ic_cdk::update]
+fn add_branch(payload: BranchPayload) -> Result<Branch, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    validate_branch_payload(&payload)?;
+
+    let id = next_id()?;
+    let branch = Branch::from_payload(id, payload);
+    check_record_size(&branch)?;
+    BRANCH_STORAGE.with(|storage| storage.borrow_mut().insert(id, branch.clone()));
+    record_audit("add_branch", Some(id), None, audit_summary(&branch));
+    Ok(branch)
+}
+
+#[ic_cdk::update]
+fn update_branch(id: u64, payload: BranchPayload) -> Result<Branch, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let branch = get_branch(id)?;
+    validate_branch_payload(&payload)?;
+
+    let updated_branch = Branch::from_payload(id, payload);
+    check_record_size(&updated_branch)?;
+    BRANCH_STORAGE.with(|storage| storage.borrow_mut().insert(id, updated_branch.clone()));
+    record_audit(
+        "update_branch",
+        Some(id),
+        audit_summary(&branch),
+        audit_summary(&updated_branch),
+    );
+    Ok(updated_branch)
+}
+
+// Branches still holding cars or expecting open rentals cannot be deleted
+#[ic_cdk::update]
+fn delete_branch(id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let branch = get_branch(id)?;
+    let has_cars = CAR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .any(|(_, car)| car.branch_id == Some(id))
+    });
+    if has_cars {
+        return Err(Error::Conflict {
+            msg: format!("Branch with id={} still has cars assigned", id),
+        });
+    }
+    let has_open_rentals = RENTAL_REQUEST_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, request)| {
+            request.status.is_open()
+                && (request.pickup_branch_id == Some(id) || request.dropoff_branch_id == Some(id))
+        })
+    });
+    if has_open_rentals {
+        return Err(Error::Conflict {
+            msg: format!("Branch with id={} has open rentals", id),
+        });
+    }
+    let has_incoming_transfers = CAR_TRANSFER_STORAGE.with(|storage| {
+        storage.borrow().iter().any(|(_, transfer)| {
+            transfer.status == TransferStatus::InTransit && transfer.to_branch_id == id
+        })
+    });
+    if has_incoming_transfers {
+        return Err(Error::Conflict {
+            msg: format!("Branch with id={} has cars in transit to it", id),
+        });
+    }
+
+    BRANCH_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    DROP_FEES.with(|fees| {
+        let mut fees = fees.borrow_mut();
+        let routes: Vec<(u64, u64)> = fees
+            .iter()
+            .map(|(route, _)| route)
+            .filter(|(from, to)| *from == id || *to == id)
+            .collect();
+        for route in routes {
+            fees.remove(&route);
+        }
+    });
+    record_audit("delete_branch", Some(id), audit_summary(&branch), None);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_branch(id: u64) -> Result<Branch, Error> {
+    match BRANCH_STORAGE.with(|storage| storage.borrow().get(&id)) {
+        Some(branch) => Ok(branch),
+        None => Err(Error::NotFound {
+            msg: format!("Branch with id={} not found", id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_branches() -> Vec<Branch> {
+    BRANCH_STORAGE.with(|storage| storage.borrow().iter().map(|(_, branch)| branch).collect())
+}
+
+// Implement fleet transfers between branches; a car in transit cannot be booked
+#[ic_cdk::update]
+fn request_car_transfer(car_id: u64, to_branch_id: u64) -> Result<CarTransfer, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    let car = get_car(car_id)?;
+    get_branch(to_branch_id)?;
+    if car.branch_id == Some(to_branch_id) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Car with id={} is already at branch id={}",
+                car_id, to_branch_id
+            ),
+        });
+    }
+    if let Some(transfer) = transfer_in_transit(car_id) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Car with id={} is already in transit with transfer id={}",
+                car_id, transfer.id
+            ),
+        });
+    }
+    // Open bookings expect the car at its current branch
+    if let Some(request) = rentals_for_car(car_id)
+        .into_iter()
+        .find(|request| request.status.is_open())
+    {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Car with id={} is booked by rental request id={}",
+                car_id, request.id
+            ),
+        });
+    }
+
+    let transfer = CarTransfer {
+        id: next_id()?,
+        car_id,
+        from_branch_id: car.branch_id,
+        to_branch_id,
+        status: TransferStatus::InTransit,
+        requested_by: caller(),
+        requested_at: ic_cdk::api::time(),
+        completed_by: None,
+        completed_at: None,
+    };
+    CAR_TRANSFER_STORAGE.with(|storage| storage.borrow_mut().insert(transfer.id, transfer.clone()));
+    TRANSFERS_BY_CAR.with(|index| index.borrow_mut().insert((car_id, transfer.id), ()));
+    certify_availability(car_id);
+    record_audit(
+        "request_car_transfer",
+        Some(transfer.id),
+        None,
+        audit_summary(&transfer),
+    );
+    Ok(transfer)
+}
+
+// Record the arrival of a car and assign it to its new branch
+#[ic_cdk::update]
+fn complete_car_transfer(transfer_id: u64) -> Result<CarTransfer, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    let mut transfer = get_car_transfer(transfer_id)?;
+    if transfer.status != TransferStatus::InTransit {
+        return Err(Error::InvalidInput {
+            msg: format!("Transfer with id={} is already completed", transfer_id),
+        });
+    }
+    let before = audit_summary(&transfer);
+    // A car deleted while in transit leaves its history behind
+    if let Ok(mut car) = get_car(transfer.car_id) {
+        car.branch_id = Some(transfer.to_branch_id);
+        store_car(&mut car);
+    }
+
+    transfer.status = TransferStatus::Completed;
+    transfer.completed_by = Some(caller());
+    transfer.completed_at = Some(ic_cdk::api::time());
+    CAR_TRANSFER_STORAGE.with(|storage| storage.borrow_mut().insert(transfer_id, transfer.clone()));
+    certify_availability(transfer.car_id);
+    record_audit(
+        "complete_car_transfer",
+        Some(transfer_id),
+        before,
+        audit_summary(&transfer),
+    );
+    Ok(transfer)
+}
+
+#[ic_cdk::query]
+fn get_car_transfer(transfer_id: u64) -> Result<CarTransfer, Error> {
+    match CAR_TRANSFER_STORAGE.with(|storage| storage.borrow().get(&transfer_id)) {
+        Some(transfer) => Ok(transfer),
+        None => Err(Error::NotFound {
+            msg: format!("Transfer with id={} not found", transfer_id),
+        }),
+    }
+}
+
+// List the transfers of a car, oldest first
+#[ic_cdk::query]
+fn list_car_transfers(car_id: u64) -> Result<Vec<CarTransfer>, Error> {
+    require_any_role(&[Role::Staff])?;
+    Ok(transfers_for_car(car_id))
+}
+
+fn transfers_for_car(car_id: u64) -> Vec<CarTransfer> {
+    let transfer_ids: Vec<u64> = TRANSFERS_BY_CAR.with(|index| {
+        index
+            .borrow()
+            .range((car_id, 0)..=(car_id, u64::MAX))
+            .map(|((_, transfer_id), _)| transfer_id)
+            .collect()
+    });
+    CAR_TRANSFER_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        transfer_ids
+            .into_iter()
+            .filter_map(|transfer_id| storage.get(&transfer_id))
+            .collect()
+    })
+}
+
+fn transfer_in_transit(car_id: u64) -> Option<CarTransfer> {
+    transfers_for_car(car_id)
+        .into_iter()
+        .find(|transfer| transfer.status == TransferStatus::InTransit)
+}
+
+// Implement the maintenance history of cars
+#[ic_cdk::update]
+fn add_maintenance_record(payload: MaintenanceRecordPayload) -> Result<MaintenanceRecord, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    validate_maintenance_payload(&payload)?;
+
+    let record = MaintenanceRecord::from_payload(next_id()?, caller(), payload);
+    store_maintenance_record(&record);
+    record_audit(
+        "add_maintenance_record",
+        Some(record.id),
+        None,
+        audit_summary(&record),
+    );
+    Ok(record)
+}
+
+#[ic_cdk::update]
+fn update_maintenance_record(
+    id: u64,
+    payload: MaintenanceRecordPayload,
+) -> Result<MaintenanceRecord, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    let record = get_maintenance_record(id)?;
+    validate_maintenance_payload(&payload)?;
+
+    let updated_record = MaintenanceRecord::from_payload(id, record.recorded_by, payload);
+    MAINTENANCE_BY_CAR.with(|index| index.borrow_mut().remove(&(record.car_id, id)));
+    store_maintenance_record(&updated_record);
+    record_audit(
+        "update_maintenance_record",
+        Some(id),
+        audit_summary(&record),
+        audit_summary(&updated_record),
+    );
+    Ok(updated_record)
+}
+
+#[ic_cdk::update]
+fn delete_maintenance_record(id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+
+    match MAINTENANCE_STORAGE.with(|storage| storage.borrow_mut().remove(&id)) {
+        Some(record) => {
+            MAINTENANCE_BY_CAR.with(|index| index.borrow_mut().remove(&(record.car_id, id)));
+            record_audit(
+                "delete_maintenance_record",
+                Some(id),
+                audit_summary(&record),
+                None,
+            );
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Maintenance record with id={} not found", id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_maintenance_record(id: u64) -> Result<MaintenanceRecord, Error> {
+    require_any_role(&[Role::Staff])?;
+    match MAINTENANCE_STORAGE.with(|storage| storage.borrow().get(&id)) {
+        Some(record) => Ok(record),
+        None => Err(Error::NotFound {
+            msg: format!("Maintenance record with id={} not found", id),
+        }),
+    }
+}
+
+// List the maintenance history of a car, most recent first
+#[ic_cdk::query]
+fn list_maintenance_for_car(car_id: u64) -> Result<Vec<MaintenanceRecord>, Error> {
+    require_any_role(&[Role::Staff])?;
+    let record_ids: Vec<u64> = MAINTENANCE_BY_CAR.with(|index| {
+        index
+            .borrow()
+            .range((car_id, 0)..=(car_id, u64::MAX))
+            .map(|((_, record_id), _)| record_id)
+            .collect()
+    });
+    let mut records: Vec<MaintenanceRecord> = MAINTENANCE_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        record_ids
+            .into_iter()
+            .filter_map(|record_id| storage.get(&record_id))
+            .collect()
+    });
+    records.sort_by(|a, b| b.performed_at.cmp(&a.performed_at).then(b.id.cmp(&a.id)));
+    Ok(records)
+}
+
+// Record what a car cost, replacing what was recorded before
+#[ic_cdk::update]
+fn set_car_acquisition(
+    car_id: u64,
+    payload: CarAcquisitionPayload,
+) -> Result<CarAcquisition, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    get_car(car_id)?;
+    if payload.useful_life_days == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Useful life must be at least one day".to_string(),
+        });
+    }
+    if payload.residual_value_e8s > payload.purchase_price_e8s {
+        return Err(Error::InvalidInput {
+            msg: "Residual value cannot be more than the purchase price".to_string(),
+        });
+    }
+
+    let acquisition = CarAcquisition {
+        car_id,
+        purchase_price_e8s: payload.purchase_price_e8s,
+        acquired_at: payload.acquired_at,
+        residual_value_e8s: payload.residual_value_e8s,
+        useful_life_days: payload.useful_life_days,
+    };
+    let previous =
+        CAR_ACQUISITIONS.with(|storage| storage.borrow_mut().insert(car_id, acquisition.clone()));
+    record_audit(
+        "set_car_acquisition",
+        Some(car_id),
+        previous.as_ref().and_then(audit_summary),
+        audit_summary(&acquisition),
+    );
+    Ok(acquisition)
+}
+
+#[ic_cdk::query]
+fn get_car_acquisition(car_id: u64) -> Result<CarAcquisition, Error> {
+    require_any_role(&[Role::Staff])?;
+    match CAR_ACQUISITIONS.with(|storage| storage.borrow().get(&car_id)) {
+        Some(acquisition) => Ok(acquisition),
+        None => Err(Error::NotFound {
+            msg: format!("No acquisition recorded for car id={}", car_id),
+        }),
+    }
+}
+
+// Report what a car earned and cost over a period, including its archived rentals
+#[ic_cdk::query]
+fn get_car_pnl(car_id: u64, period: Period) -> Result<CarPnl, Error> {
+    require_any_role(&[Role::Staff])?;
+    validate_period(&period)?;
+    get_car(car_id)?;
+    let mut rental_ids = BTreeSet::new();
+    for index in [&RENTALS_BY_CAR, &ARCHIVED_RENTALS_BY_CAR] {
+        index.with(|index| {
+            rental_ids.extend(
+                index
+                    .borrow()
+                    .range((car_id, 0)..=(car_id, u64::MAX))
+                    .map(|((_, rental_id), _)| rental_id),
+            )
+        });
+    }
+
+    let (payments, rental_revenue_e8s) = PAYMENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, payment)| {
+                rental_ids.contains(&payment.rental_id) && period.contains(payment.paid_at)
+            })
+            .fold((0, 0u64), |(count, total), (_, payment)| {
+                let net_e8s = payment.amount_e8s.saturating_sub(payment.refunded_e8s);
+                (count + 1, total.saturating_add(net_e8s))
+            })
+    });
+    let charges_e8s = CHARGE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, charge)| {
+                charge.settled
+                    && rental_ids.contains(&charge.rental_id)
+                    && period.contains(charge.created_at)
+            })
+            .fold(0u64, |total, (_, charge)| {
+                total.saturating_add(charge.amount_e8s)
+            })
+    });
+    let record_ids: Vec<u64> = MAINTENANCE_BY_CAR.with(|index| {
+        index
+            .borrow()
+            .range((car_id, 0)..=(car_id, u64::MAX))
+            .map(|((_, record_id), _)| record_id)
+            .collect()
+    });
+    let (maintenance_records, maintenance_cost_e8s) = MAINTENANCE_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        record_ids
+            .into_iter()
+            .filter_map(|record_id| storage.get(&record_id))
+            .filter(|record| period.contains(record.performed_at))
+            .fold((0, 0u64), |(count, total), record| {
+                (count + 1, total.saturating_add(record.cost_e8s))
+            })
+    });
+    let depreciation_e8s = CAR_ACQUISITIONS
+        .with(|storage| storage.borrow().get(&car_id))
+        .map_or(0, |acquisition| acquisition.depreciation_in(&period));
+
+    let profit_e8s = rental_revenue_e8s as i128 + charges_e8s as i128
+        - maintenance_cost_e8s as i128
+        - depreciation_e8s as i128;
+    Ok(CarPnl {
+        car_id,
+        period,
+        payments,
+        rental_revenue_e8s,
+        charges_e8s,
+        maintenance_records,
+        maintenance_cost_e8s,
+        depreciation_e8s,
+        profit_e8s: profit_e8s.clamp(i64::MIN as i128, i64::MAX as i128) as i64,
+    })
+}
+
+// Report the booking requests made on the days starting within a period, by day, car
+// category, and model
+#[ic_cdk::query]
+fn get_demand_stats(period: Period) -> Result<DemandStats, Error> {
+    require_any_role(&[Role::Staff])?;
+    validate_period(&period)?;
+    let first_day = period.from.div_ceil(NANOS_PER_DAY);
+    let end_day = period.to.div_ceil(NANOS_PER_DAY);
+    let mut total = DemandCount::default();
+    let mut by_day: BTreeMap<u64, DemandCount> = BTreeMap::new();
+    let mut by_car: BTreeMap<u64, DemandCount> = BTreeMap::new();
+    DEMAND_COUNTS.with(|counts| {
+        for ((day, car_id), count) in counts.borrow().range((first_day, 0)..(end_day, 0)) {
+            total.add(count);
+            by_day.entry(day).or_default().add(count);
+            by_car.entry(car_id).or_default().add(count);
+        }
+    });
+
+    let mut by_category: Vec<CategoryDemand> = Vec::new();
+    let mut by_model: BTreeMap<(String, String), DemandCount> = BTreeMap::new();
+    for (car_id, count) in by_car {
+        let Some(car) = CAR_STORAGE.with(|storage| storage.borrow().get(&car_id)) else {
+            continue;
+        };
+        match by_category
+            .iter_mut()
+            .find(|entry| entry.category == car.category)
+        {
+            Some(entry) => entry.demand.add(count),
+            None => by_category.push(CategoryDemand {
+                category: car.category,
+                demand: count,
+            }),
+        }
+        by_model
+            .entry((car.make, car.model))
+            .or_default()
+            .add(count);
+    }
+    by_category.sort_by_key(|entry| std::cmp::Reverse(entry.demand.requests));
+    let model_demand = |((make, model), demand): (&(String, String), &DemandCount)| ModelDemand {
+        make: make.clone(),
+        model: model.clone(),
+        demand: *demand,
+    };
+    let mut most_unavailable: Vec<ModelDemand> = by_model
+        .iter()
+        .filter(|(_, demand)| demand.unavailable > 0)
+        .map(model_demand)
+        .collect();
+    most_unavailable.sort_by_key(|entry| std::cmp::Reverse(entry.demand.unavailable));
+    let mut by_model: Vec<ModelDemand> = by_model.iter().map(model_demand).collect();
+    by_model.sort_by_key(|entry| std::cmp::Reverse(entry.demand.requests));
+
+    Ok(DemandStats {
+        period,
+        total,
+        by_day: by_day
+            .into_iter()
+            .map(|(day, demand)| DayDemand {
+                day: day * NANOS_PER_DAY,
+                demand,
+            })
+            .collect(),
+        by_category,
+        by_model,
+        most_unavailable,
+    })
+}
+
+// Plan a maintenance window; it blocks bookings like a rental does
+#[ic_cdk::update]
+fn schedule_maintenance(payload: MaintenanceWindowPayload) -> Result<MaintenanceWindow, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    validate_maintenance_window_payload(&payload)?;
+    let MaintenanceWindowPayload {
+        car_id,
+        start_date,
+        end_date,
+        description,
+    } = payload;
+    get_car(car_id)?;
+    ensure_car_available(car_id, start_date, end_date, None)?;
+
+    let window = MaintenanceWindow {
+        id: next_id()?,
+        car_id,
+        start_date,
+        end_date,
+        description,
+        status: MaintenanceWindowStatus::Scheduled,
+        scheduled_by: caller(),
+        car_was_available: true,
+    };
+    check_record_size(&window)?;
+    MAINTENANCE_WINDOW_STORAGE
+        .with(|storage| storage.borrow_mut().insert(window.id, window.clone()));
+    MAINTENANCE_WINDOWS_BY_CAR.with(|index| index.borrow_mut().insert((car_id, window.id), ()));
+    certify_availability(car_id);
+    record_audit(
+        "schedule_maintenance",
+        Some(window.id),
+        None,
+        audit_summary(&window),
+    );
+    Ok(window)
+}
+
+// Cancel a scheduled window, or end one in progress early
+#[ic_cdk::update]
+fn cancel_maintenance(window_id: u64) -> Result<MaintenanceWindow, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    let mut window =
+        match MAINTENANCE_WINDOW_STORAGE.with(|storage| storage.borrow().get(&window_id)) {
+            Some(window) => window,
+            None => {
+                return Err(Error::NotFound {
+                    msg: format!("Maintenance window with id={} not found", window_id),
+                })
+            }
+        };
+    let before = audit_summary(&window);
+    match window.status {
+        MaintenanceWindowStatus::Scheduled => window.status = MaintenanceWindowStatus::Canceled,
+        MaintenanceWindowStatus::InProgress => finish_maintenance(&mut window),
+        status => {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Maintenance window with id={} is already {:?}",
+                    window_id, status
+                ),
+            })
+        }
+    }
+    MAINTENANCE_WINDOW_STORAGE
+        .with(|storage| storage.borrow_mut().insert(window_id, window.clone()));
+    certify_availability(window.car_id);
+    record_audit(
+        "cancel_maintenance",
+        Some(window_id),
+        before,
+        audit_summary(&window),
+    );
+    Ok(window)
+}
+
+#[ic_cdk::query]
+fn list_maintenance_windows(car_id: u64) -> Vec<MaintenanceWindow> {
+    maintenance_windows_for_car(car_id)
+}
+
+fn maintenance_windows_for_car(car_id: u64) -> Vec<MaintenanceWindow> {
+    let window_ids: Vec<u64> = MAINTENANCE_WINDOWS_BY_CAR.with(|index| {
+        index
+            .borrow()
+            .range((car_id, 0)..=(car_id, u64::MAX))
+            .map(|((_, window_id), _)| window_id)
+            .collect()
+    });
+    MAINTENANCE_WINDOW_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        window_ids
+            .into_iter()
+            .filter_map(|window_id| storage.get(&window_id))
+            .collect()
+    })
+}
+
+// Take cars off the road when their maintenance starts and return them once it ends
+fn advance_maintenance_windows() {
+    if restore_in_progress() {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    let due: Vec<MaintenanceWindow> = MAINTENANCE_WINDOW_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, window)| window)
+            .filter(|window| match window.status {
+                MaintenanceWindowStatus::Scheduled => window.start_date <= now,
+                MaintenanceWindowStatus::InProgress => window.end_date <= now,
+                _ => false,
+            })
+            .collect()
+    });
+    for mut window in due {
+        if window.status == MaintenanceWindowStatus::Scheduled {
+            window.status = MaintenanceWindowStatus::InProgress;
+            if let Ok(mut car) = get_car(window.car_id) {
+                window.car_was_available = car.available;
+                car.available = false;
+                store_car(&mut car);
+            }
+        }
+        if window.end_date <= now {
+            finish_maintenance(&mut window);
+        }
+        let car_id = window.car_id;
+        MAINTENANCE_WINDOW_STORAGE.with(|storage| storage.borrow_mut().insert(window.id, window));
+        certify_availability(car_id);
+    }
+}
+
+fn finish_maintenance(window: &mut MaintenanceWindow) {
+    window.status = MaintenanceWindowStatus::Finished;
+    if let Ok(mut car) = get_car(window.car_id) {
+        car.available = window.car_was_available;
+        store_car(&mut car);
+    }
+}
+
+// Store a maintenance record, raising the car's mileage to the odometer reading if higher
+fn store_maintenance_record(record: &MaintenanceRecord) {
+    MAINTENANCE_STORAGE.with(|storage| storage.borrow_mut().insert(record.id, record.clone()));
+    MAINTENANCE_BY_CAR.with(|index| index.borrow_mut().insert((record.car_id, record.id), ()));
+    if let Ok(mut car) = get_car(record.car_id) {
+        if record.odometer_km > car.mileage_km {
+            car.mileage_km = record.odometer_km;
+            store_car(&mut car);
+        }
+    }
+}
+
+// Implement the add-ons catalog and its stock at each branch
+#[ic_cdk::update]
+fn add_add_on(payload: AddOnPayload) -> Result<AddOn, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    validate_add_on_payload(&payload)?;
+
+    let id = next_id()?;
+    let add_on = AddOn::from_payload(id, payload);
+    check_record_size(&add_on)?;
+    ADD_ON_STORAGE.with(|storage| storage.borrow_mut().insert(id, add_on.clone()));
+    record_audit("add_add_on", Some(id), None, audit_summary(&add_on));
+    Ok(add_on)
+}
+
+#[ic_cdk::update]
+fn update_add_on(id: u64, payload: AddOnPayload) -> Result<AddOn, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let add_on = get_add_on(id)?;
+    validate_add_on_payload(&payload)?;
+
+    let updated_add_on = AddOn::from_payload(id, payload);
+    check_record_size(&updated_add_on)?;
+    ADD_ON_STORAGE.with(|storage| storage.borrow_mut().insert(id, updated_add_on.clone()));
+    record_audit(
+        "update_add_on",
+        Some(id),
+        audit_summary(&add_on),
+        audit_summary(&updated_add_on),
+    );
+    Ok(updated_add_on)
+}
+
+// Add-ons reserved by open rentals cannot be deleted
+#[ic_cdk::update]
+fn delete_add_on(id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let add_on = get_add_on(id)?;
+    let reserved_by = RENTAL_ADD_ONS.with(|reserved| {
+        reserved
+            .borrow()
+            .iter()
+            .filter(|((_, add_on_id), _)| *add_on_id == id)
+            .map(|((rental_id, _), _)| rental_id)
+            .find(|rental_id| {
+                get_rental_request(*rental_id).is_ok_and(|request| request.status.is_open())
+            })
+    });
+    if let Some(rental_id) = reserved_by {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Add-on with id={} is reserved by rental request id={}",
+                id, rental_id
+            ),
+        });
+    }
+
+    ADD_ON_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    ADD_ON_STOCK.with(|stock| {
+        let mut stock = stock.borrow_mut();
+        let keys: Vec<(u64, u64)> = stock
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            stock.remove(&key);
+        }
+    });
+    record_audit("delete_add_on", Some(id), audit_summary(&add_on), None);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_add_on(id: u64) -> Result<AddOn, Error> {
+    match ADD_ON_STORAGE.with(|storage| storage.borrow().get(&id)) {
+        Some(add_on) => Ok(add_on),
+        None => Err(Error::NotFound {
+            msg: format!("Add-on with id={} not found", id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_add_ons() -> Vec<AddOn> {
+    ADD_ON_STORAGE.with(|storage| storage.borrow().iter().map(|(_, add_on)| add_on).collect())
+}
+
+#[ic_cdk::update]
+fn set_add_on_stock(add_on_id: u64, branch_id: u64, quantity: u32) -> Result<(), Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    get_add_on(add_on_id)?;
+    get_branch(branch_id)?;
+
+    let previous =
+        ADD_ON_STOCK.with(|stock| stock.borrow_mut().insert((add_on_id, branch_id), quantity));
+    record_audit(
+        "set_add_on_stock",
+        Some(add_on_id),
+        previous.and_then(|quantity| {
+            audit_summary(&serde_json::json!({ "branch_id": branch_id, "quantity": quantity }))
+        }),
+        audit_summary(&serde_json::json!({ "branch_id": branch_id, "quantity": quantity })),
+    );
+    Ok(())
+}
+
+// Count the units of an add-on at a branch that are not reserved during a period
+#[ic_cdk::query]
+fn get_add_on_availability(
+    add_on_id: u64,
+    branch_id: u64,
+    start_date: u64,
+    end_date: u64,
+) -> Result<u32, Error> {
+    get_add_on(add_on_id)?;
+    get_branch(branch_id)?;
+    Ok(add_on_available(
+        add_on_id, branch_id, start_date, end_date, None,
+    ))
+}
+
+#[ic_cdk::query]
+fn list_rental_add_ons(rental_id: u64) -> Result<Vec<RentalAddOn>, Error> {
+    let rental_request = get_rental_request(rental_id)?;
+    if ensure_owner_or_admin(&rental_request.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(rental_add_ons(rental_id))
+}
+
+fn rental_add_ons(rental_id: u64) -> Vec<RentalAddOn> {
+    RENTAL_ADD_ONS.with(|reserved| {
+        reserved
+            .borrow()
+            .range((rental_id, 0)..=(rental_id, u64::MAX))
+            .map(|(_, add_on)| add_on)
+            .collect()
+    })
+}
+
+// Replace the add-ons reserved for a rental
+fn store_rental_add_ons(rental_id: u64, add_ons: &[RentalAddOn]) {
+    RENTAL_ADD_ONS.with(|reserved| {
+        let mut reserved = reserved.borrow_mut();
+        let keys: Vec<(u64, u64)> = reserved
+            .range((rental_id, 0)..=(rental_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            reserved.remove(&key);
+        }
+        for add_on in add_ons {
+            reserved.insert((rental_id, add_on.add_on_id), add_on.clone());
+        }
+    });
+}
+
+// Stock at the branch minus the units reserved by open rentals picked up there that
+// overlap the period
+fn add_on_available(
+    add_on_id: u64,
+    branch_id: u64,
+    start_date: u64,
+    end_date: u64,
+    exclude_rental_id: Option<u64>,
+) -> u32 {
+    let stock = ADD_ON_STOCK
+        .with(|stock| stock.borrow().get(&(add_on_id, branch_id)))
+        .unwrap_or_default();
+    let reserved: u32 = RENTAL_ADD_ONS.with(|reserved| {
+        reserved
+            .borrow()
+            .iter()
+            .filter(|((rental_id, id), _)| {
+                *id == add_on_id && Some(*rental_id) != exclude_rental_id
+            })
+            .filter(|((rental_id, _), _)| {
+                get_rental_request(*rental_id).is_ok_and(|request| {
+                    request.status.is_open()
+                        && request.pickup_branch_id == Some(branch_id)
+                        && request.start_date < end_date
+                        && start_date < request.end_date
+                })
+            })
+            .map(|(_, add_on)| add_on.quantity)
+            .sum()
+    });
+    stock.saturating_sub(reserved)
+}
+
+// Price the requested add-ons and check they are in stock at the pickup branch
+fn resolve_add_ons(
+    selections: &[AddOnSelection],
+    pickup_branch_id: Option<u64>,
+    start_date: u64,
+    end_date: u64,
+    exclude_rental_id: Option<u64>,
+) -> Result<Vec<RentalAddOn>, Error> {
+    if selections.is_empty() {
+        return Ok(Vec::new());
+    }
+    if selections.len() > 10 {
+        return Err(Error::InvalidInput {
+            msg: "A rental may include at most 10 add-ons".to_string(),
+        });
+    }
+    let branch_id = pickup_branch_id.ok_or_else(|| Error::InvalidInput {
+        msg: "Add-ons are collected at a branch, so the pickup branch is required".to_string(),
+    })?;
+    let mut add_ons: Vec<RentalAddOn> = Vec::new();
+    for selection in selections {
+        if selection.quantity == 0
+            || add_ons
+                .iter()
+                .any(|add_on| add_on.add_on_id == selection.add_on_id)
+        {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Add-on id={} must be listed once with a quantity of at least 1",
+                    selection.add_on_id
+                ),
+            });
+        }
+        let add_on = get_add_on(selection.add_on_id)?;
+        let available = add_on_available(
+            add_on.id,
+            branch_id,
+            start_date,
+            end_date,
+            exclude_rental_id,
+        );
+        if selection.quantity > available {
+            return Err(Error::Conflict {
+                msg: format!(
+                    "Only {} of add-on id={} are available at branch id={} for the period",
+                    available, add_on.id, branch_id
+                ),
+            });
+        }
+        add_ons.push(RentalAddOn {
+            add_on_id: add_on.id,
+            name: add_on.name,
+            quantity: selection.quantity,
+            daily_rate_e8s: add_on.daily_rate_e8s,
+        });
+    }
+    Ok(add_ons)
+}
+
+// Implement the one-way drop fee matrix
+#[ic_cdk::update]
+fn set_drop_fee(from_branch_id: u64, to_branch_id: u64, fee_e8s: u64) -> Result<DropFee, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    if from_branch_id == to_branch_id {
+        return Err(Error::InvalidInput {
+            msg: "Drop fees apply between two different branches".to_string(),
+        });
+    }
+    get_branch(from_branch_id)?;
+    get_branch(to_branch_id)?;
+
+    let previous = DROP_FEES.with(|fees| {
+        fees.borrow_mut()
+            .insert((from_branch_id, to_branch_id), fee_e8s)
+    });
+    let drop_fee = |fee_e8s| DropFee {
+        from_branch_id,
+        to_branch_id,
+        fee_e8s,
+    };
+    record_audit(
+        "set_drop_fee",
+        None,
+        previous.and_then(|fee_e8s| audit_summary(&drop_fee(fee_e8s))),
+        audit_summary(&drop_fee(fee_e8s)),
+    );
+    Ok(drop_fee(fee_e8s))
+}
+
+#[ic_cdk::update]
+fn remove_drop_fee(from_branch_id: u64, to_branch_id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+
+    match DROP_FEES.with(|fees| fees.borrow_mut().remove(&(from_branch_id, to_branch_id))) {
+        Some(fee_e8s) => {
+            let drop_fee = DropFee {
+                from_branch_id,
+                to_branch_id,
+                fee_e8s,
+            };
+            record_audit("remove_drop_fee", None, audit_summary(&drop_fee), None);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "No drop fee from branch id={} to branch id={}",
+                from_branch_id, to_branch_id
+            ),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_drop_fees() -> Vec<DropFee> {
+    DROP_FEES.with(|fees| {
+        fees.borrow()
+            .iter()
+            .map(|((from_branch_id, to_branch_id), fee_e8s)| DropFee {
+                from_branch_id,
+                to_branch_id,
+                fee_e8s,
+            })
+            .collect()
+    })
+}
+
+// The fee for dropping a car off away from its pickup branch, if that route is offered
+fn drop_fee(pickup_branch_id: Option<u64>, dropoff_branch_id: Option<u64>) -> Option<u64> {
+    match (pickup_branch_id, dropoff_branch_id) {
+        (Some(from), Some(to)) if from != to => {
+            DROP_FEES.with(|fees| fees.borrow().get(&(from, to)))
+        }
+        _ => Some(0),
+    }
+}
+
+// Resolve the branches of a rental. The pickup defaults to, and must match, the car's branch,
+// and the dropoff defaults to the pickup. One-way rentals need a drop fee for their route.
+fn rental_branches(
+    car: &Car,
+    pickup_branch_id: Option<u64>,
+    dropoff_branch_id: Option<u64>,
+) -> Result<(Option<u64>, Option<u64>), Error> {
+    let pickup_branch_id = pickup_branch_id.or(car.branch_id);
+    if let (Some(pickup), Some(kept_at)) = (pickup_branch_id, car.branch_id) {
+        if pickup != kept_at {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Car with id={} must be picked up at branch id={}",
+                    car.id, kept_at
+                ),
+            });
+        }
+    }
+    let dropoff_branch_id = dropoff_branch_id.or(pickup_branch_id);
+    for branch_id in [pickup_branch_id, dropoff_branch_id].into_iter().flatten() {
+        get_branch(branch_id)?;
+    }
+    if dropoff_branch_id != pickup_branch_id {
+        if pickup_branch_id.is_none() {
+            return Err(Error::InvalidInput {
+                msg: "One-way rentals must name their pickup branch".to_string(),
+            });
+        }
+        if drop_fee(pickup_branch_id, dropoff_branch_id).is_none() {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "One-way rentals from branch id={} to branch id={} are not offered",
+                    pickup_branch_id.unwrap_or_default(),
+                    dropoff_branch_id.unwrap_or_default()
+                ),
+            });
+        }
+    }
+    Ok((pickup_branch_id, dropoff_branch_id))
+}
+
+#[ic_cdk::query]
+fn list_rental_requests() -> Vec<RentalRequest> {
+    collect_pages(|cursor| list_rental_requests_page(cursor, MAX_PAGE_SIZE))
+}
+
+#[ic_cdk::query]
+fn list_rental_requests_page(cursor: Option<u64>, limit: u32) -> Page<RentalRequest> {
+    RENTAL_REQUEST_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        page_of(storage.range(cursor.unwrap_or(0)..), storage.len(), limit)
+    })
+}
+
+#[ic_cdk::query]
+fn get_car_availability(
+    car_id: u64,
+    from: u64,
+    to: u64,
+) -> Result<Vec<AvailabilityInterval>, Error> {
+    if to <= from {
+        return Err(Error::InvalidInput {
+            msg: "The end of the window must be after its start".to_string(),
+        });
+    }
+    get_car(car_id)?;
+    Ok(blocked_intervals(car_id, from, to))
+}
+
+// Implement certified queries. Cars, rental requests, and the availability of each car are
+// kept in a hash tree whose root is set as the canister's certified data on every change,
+// so responses served by a single replica can be checked against the subnet's signature.
+#[ic_cdk::query]
+fn get_car_certified(id: u64) -> Result<Certified<Car>, Error> {
+    let car = get_car(id)?;
+    let encoded = Encode!(&car).unwrap();
+    certified(car, CERTIFIED_CARS, id, encoded)
+}
+
+#[ic_cdk::query]
+fn get_rental_request_certified(id: u64) -> Result<Certified<RentalRequest>, Error> {
+    let rental_request = get_rental_request(id)?;
+    let encoded = Encode!(&rental_request).unwrap();
+    certified(rental_request, CERTIFIED_RENTALS, id, encoded)
+}
+
+// Get every interval a car is blocked, as of the last change to its bookings, holds,
+// maintenance, or transfers
+#[ic_cdk::query]
+fn get_car_availability_certified(
+    car_id: u64,
+) -> Result<Certified<Vec<AvailabilityInterval>>, Error> {
+    get_car(car_id)?;
+    let encoded = CERTIFIED_AVAILABILITY_VALUES
+        .with(|values| values.borrow().get(&car_id).cloned())
+        .ok_or(Error::NotFound {
+            msg: format!("Availability of car with id={} is not certified", car_id),
+        })?;
+    let intervals = Decode!(&encoded, Vec<AvailabilityInterval>).unwrap();
+    certified(intervals, CERTIFIED_AVAILABILITY, car_id, encoded)
+}
+
+fn certified<T>(data: T, section: u8, id: u64, encoded: Vec<u8>) -> Result<Certified<T>, Error> {
+    let certificate = ic_cdk::api::data_certificate().ok_or(Error::InvalidInput {
+        msg: "Certificates are only available in query calls".to_string(),
+    })?;
+    let mut witness = vec![0xd9, 0xd9, 0xf7];
+    certified_tree(Some((section, id, encoded))).encode(&mut witness);
+    Ok(Certified {
+        data,
+        certificate,
+        witness,
+    })
+}
+
+fn certify_car(car_id: u64) {
+    certify(CERTIFIED_CARS, car_id, encoded_car(car_id));
+}
+
+fn certify_rental(rental_id: u64) {
+    certify(CERTIFIED_RENTALS, rental_id, encoded_rental(rental_id));
+}
+
+fn certify_availability(car_id: u64) {
+    certify(CERTIFIED_AVAILABILITY, car_id, encoded_availability(car_id));
+}
+
+fn encoded_car(car_id: u64) -> Option<Vec<u8>> {
+    CAR_STORAGE
+        .with(|storage| storage.borrow().get(&car_id))
+        .map(|car| Encode!(&car).unwrap())
+}
+
+fn encoded_rental(rental_id: u64) -> Option<Vec<u8>> {
+    RENTAL_REQUEST_STORAGE
+        .with(|storage| storage.borrow().get(&rental_id))
+        .map(|rental_request| Encode!(&rental_request).unwrap())
+}
+
+// Encode the availability of a car, keeping the encoding to be returned by queries
+fn encoded_availability(car_id: u64) -> Option<Vec<u8>> {
+    let exists = CAR_STORAGE.with(|storage| storage.borrow().contains_key(&car_id));
+    let encoded = exists.then(|| Encode!(&blocked_intervals(car_id, 0, u64::MAX)).unwrap());
+    CERTIFIED_AVAILABILITY_VALUES.with(|values| match &encoded {
+        Some(encoded) => values.borrow_mut().insert(car_id, encoded.clone()),
+        None => values.borrow_mut().remove(&car_id),
+    });
+    encoded
+}
+
+// Replace or remove a leaf, then rehash its bucket and set the new root as certified data
+fn certify(section: u8, id: u64, encoded: Option<Vec<u8>>) {
+    set_certified_leaf(section, id, encoded);
+    rehash_bucket(section, id / CERTIFIED_BUCKET_SIZE);
+    set_certified_root();
+}
+
+fn set_certified_leaf(section: u8, id: u64, encoded: Option<Vec<u8>>) {
+    CERTIFIED_LEAVES.with(|leaves| match encoded {
+        Some(encoded) => leaves
+            .borrow_mut()
+            .insert((section, id), HashTree::Leaf(encoded).digest()),
+        None => leaves.borrow_mut().remove(&(section, id)),
+    });
+}
+
+fn rehash_bucket(section: u8, bucket: u64) {
+    let tree = bucket_tree(section, bucket, None);
+    CERTIFIED_BUCKETS.with(|buckets| match tree {
+        HashTree::Empty => buckets.borrow_mut().remove(&(section, bucket)),
+        tree => buckets
+            .borrow_mut()
+            .insert((section, bucket), tree.digest()),
+    });
+}
+
+fn set_certified_root() {
+    ic_cdk::api::set_certified_data(&certified_tree(None).digest());
+}
+
+// Certify every record again, hashing each bucket once
+fn rebuild_certified_tree() {
+    CERTIFIED_LEAVES.with(|leaves| leaves.borrow_mut().clear());
+    CERTIFIED_BUCKETS.with(|buckets| buckets.borrow_mut().clear());
+    CERTIFIED_AVAILABILITY_VALUES.with(|values| values.borrow_mut().clear());
+    let car_ids: Vec<u64> =
+        CAR_STORAGE.with(|storage| storage.borrow().iter().map(|(id, _)| id).collect());
+    for car_id in car_ids {
+        set_certified_leaf(CERTIFIED_CARS, car_id, encoded_car(car_id));
+        set_certified_leaf(CERTIFIED_AVAILABILITY, car_id, encoded_availability(car_id));
+    }
+    let rental_ids: Vec<u64> =
+        RENTAL_REQUEST_STORAGE.with(|storage| storage.borrow().iter().map(|(id, _)| id).collect());
+    for rental_id in rental_ids {
+        set_certified_leaf(CERTIFIED_RENTALS, rental_id, encoded_rental(rental_id));
+    }
+    let buckets: BTreeSet<(u8, u64)> = CERTIFIED_LEAVES.with(|leaves| {
+        leaves
+            .borrow()
+            .keys()
+            .map(|&(section, id)| (section, id / CERTIFIED_BUCKET_SIZE))
+            .collect()
+    });
+    for (section, bucket) in buckets {
+        rehash_bucket(section, bucket);
+    }
+    set_certified_root();
+}
+
+// Build the whole tree with every subtree pruned, except the path to the given record
+fn certified_tree(target: Option<(u8, u64, Vec<u8>)>) -> HashTree {
+    let sections = CERTIFIED_SECTIONS
+        .iter()
+        .enumerate()
+        .map(|(section, label)| {
+            let section = section as u8;
+            match &target {
+                Some((target_section, id, encoded)) if *target_section == section => {
+                    let tree = section_tree(section, Some((*id, encoded.clone())));
+                    HashTree::Labeled(label.to_vec(), Box::new(tree))
+                }
+                _ => {
+                    let digest = section_tree(section, None).digest();
+                    HashTree::Pruned(domain_hash("ic-hashtree-labeled", &[label, &digest]))
+                }
+            }
+        })
+        .collect();
+    fork_all(sections)
+}
+
+// Join the buckets of a section, pruned except for the bucket of the given record
+fn section_tree(section: u8, target: Option<(u64, Vec<u8>)>) -> HashTree {
+    let target_bucket = target.as_ref().map(|(id, _)| id / CERTIFIED_BUCKET_SIZE);
+    let buckets = CERTIFIED_BUCKETS.with(|buckets| {
+        buckets
+            .borrow()
+            .range((section, 0)..=(section, u64::MAX))
+            .map(|(&(_, bucket), digest)| {
+                if target_bucket == Some(bucket) {
+                    bucket_tree(section, bucket, target.clone())
+                } else {
+                    HashTree::Pruned(*digest)
+                }
+            })
+            .collect()
+    });
+    fork_all(buckets)
+}
+
+fn bucket_tree(section: u8, bucket: u64, target: Option<(u64, Vec<u8>)>) -> HashTree {
+    let first = bucket * CERTIFIED_BUCKET_SIZE;
+    let last = first + (CERTIFIED_BUCKET_SIZE - 1);
+    let entries: Vec<HashTree> = CERTIFIED_LEAVES.with(|leaves| {
+        leaves
+            .borrow()
+            .range((section, first)..=(section, last))
+            .map(|(&(_, id), digest)| {
+                let label = id.to_be_bytes().to_vec();
+                match &target {
+                    Some((target_id, encoded)) if *target_id == id => {
+                        HashTree::Labeled(label, Box::new(HashTree::Leaf(encoded.clone())))
+                    }
+                    _ => HashTree::Pruned(domain_hash("ic-hashtree-labeled", &[&label, digest])),
+                }
+            })
+            .collect()
+    });
+    fork_all(entries)
+}
+
+// Join trees into a balanced tree of forks, keeping their order
+fn fork_all(mut trees: Vec<HashTree>) -> HashTree {
+    match trees.len() {
+        0 => HashTree::Empty,
+        1 => trees.remove(0),
+        count => {
+            let right = trees.split_off(count / 2);
+            HashTree::Fork(Box::new(fork_all(trees)), Box::new(fork_all(right)))
+        }
+    }
+}
+
+// Hash the parts after a domain separator: the tag's length as one byte and the tag
+fn domain_hash(tag: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([tag.len() as u8]);
+    hasher.update(tag.as_bytes());
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn cbor_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    let length = bytes.len();
+    match length {
+        0..=23 => out.push(0x40 | length as u8),
+        24..=0xff => out.extend([0x58, length as u8]),
+        0x100..=0xffff => {
+            out.push(0x59);
+            out.extend((length as u16).to_be_bytes());
+        }
+        _ => {
+            out.push(0x5a);
+            out.extend((length as u32).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(bytes);
+}
+
+#[ic_cdk::query]
+fn get_quote(
+    car_id: u64,
+    start_date: u64,
+    end_date: u64,
+    pickup_branch_id: Option<u64>,
+    dropoff_branch_id: Option<u64>,
+    protection_tier: Option<ProtectionTier>,
+    add_ons: Vec<AddOnSelection>,
+) -> Result<Quote, Error> {
+    validate_rental_period(start_date, end_date)?;
+    let car = get_car(car_id)?;
+    let (pickup_branch_id, dropoff_branch_id) =
+        rental_branches(&car, pickup_branch_id, dropoff_branch_id)?;
+    let protection = protection_plan(protection_tier.unwrap_or(ProtectionTier::Basic))?;
+    let add_ons = resolve_add_ons(&add_ons, pickup_branch_id, start_date, end_date, None)?;
+    Ok(compute_quote(
+        &car,
+        start_date,
+        end_date,
+        pickup_branch_id,
+        dropoff_branch_id,
+        protection,
+        &add_ons,
+    ))
+}
+
+// Quote a rental with its amounts also in another currency. Fetching a rate that is not
+// cached costs the canister cycles, so only customers and staff may call this.
+#[ic_cdk::update]
+async fn get_quote_in_currency(
+    request: QuoteRequest,
+    currency: String,
+) -> Result<CurrencyQuote, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff, Role::Customer])?;
+    let quote = get_quote(
+        request.car_id,
+        request.start_date,
+        request.end_date,
+        request.pickup_branch_id,
+        request.dropoff_branch_id,
+        request.protection_tier,
+        request.add_ons,
+    )?;
+    let rate = exchange_rate(&currency).await?;
+    Ok(CurrencyQuote {
+        conversion: CurrencyConversion {
+            lines: quote
+                .lines
+                .iter()
+                .map(|line| rate.convert(line.amount_e8s))
+                .collect(),
+            total: rate.convert(quote.total_e8s as i64) as u64,
+            rate,
+        },
+        quote,
+    })
+}
+
+// Choose the currency a customer's invoices are also shown in, or go back to the base
+// currency only
+#[ic_cdk::update]
+fn set_preferred_currency(customer_id: u64, currency: Option<String>) -> Result<(), Error> {
+    check_rate_limit()?;
+    let customer = get_customer(customer_id)?;
+    ensure_owner_or_admin(&customer.owner)?;
+    let before = CURRENCY_PREFERENCES.with(|storage| storage.borrow().get(&customer_id));
+    match &currency {
+        Some(currency) => {
+            let currency = currency_code(currency)?;
+            CURRENCY_PREFERENCES.with(|storage| {
+                storage
+                    .borrow_mut()
+                    .insert(customer_id, CurrencyPreference { currency })
+            });
+        }
+        None => {
+            CURRENCY_PREFERENCES.with(|storage| storage.borrow_mut().remove(&customer_id));
+        }
+    }
+    record_audit(
+        "set_preferred_currency",
+        Some(customer_id),
+        before.as_ref().and_then(audit_summary),
+        audit_summary(&currency),
+    );
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_preferred_currency(customer_id: u64) -> Result<Option<String>, Error> {
+    let customer = get_customer(customer_id)?;
+    if ensure_owner_or_admin(&customer.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(CURRENCY_PREFERENCES
+        .with(|storage| storage.borrow().get(&customer_id))
+        .map(|preference| preference.currency))
+}
+
+// Add the amounts of a rental's invoice in its customer's preferred currency, if any
+async fn convert_invoice(rental_id: u64, customer_id: u64) -> Result<(), Error> {
+    let Some(preference) = CURRENCY_PREFERENCES.with(|storage| storage.borrow().get(&customer_id))
+    else {
+        return Ok(());
+    };
+    let rate = exchange_rate(&preference.currency).await?;
+    INVOICE_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(mut invoice) = storage.get(&rental_id) {
+            invoice.conversion = Some(CurrencyConversion {
+                lines: invoice
+                    .lines
+                    .iter()
+                    .map(|line| rate.convert(line.amount_e8s))
+                    .collect(),
+                total: rate.convert(invoice.total_e8s as i64) as u64,
+                rate,
+            });
+            storage.insert(rental_id, invoice);
+        }
+    });
+    Ok(())
+}
+
+// Check a currency symbol and bring it to upper case
+fn currency_code(currency: &str) -> Result<String, Error> {
+    let currency = currency.trim().to_ascii_uppercase();
+    if !(3..=5).contains(&currency.len()) || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(Error::InvalidInput {
+            msg: "A currency is a symbol of 3 to 5 letters, such as USD".to_string(),
+        });
+    }
+    Ok(currency)
+}
+
+// Find the price of the base currency in another currency, from the cache while it is
+// fresh and from the exchange rate canister otherwise
+async fn exchange_rate(currency: &str) -> Result<ExchangeRate, Error> {
+    let currency = currency_code(currency)?;
+    let now = ic_cdk::api::time();
+    if currency == BASE_CURRENCY {
+        return Ok(ExchangeRate {
+            currency,
+            rate: 1,
+            decimals: 0,
+            timestamp: now / 1_000_000_000,
+            fetched_at: now,
+        });
+    }
+    let ttl_minutes = config()
+        .exchange_rate_ttl_minutes
+        .unwrap_or(DEFAULT_EXCHANGE_RATE_TTL_MINUTES);
+    let ttl = ttl_minutes * NANOS_PER_MINUTE;
+    let cached = EXCHANGE_RATES.with(|rates| rates.borrow().get(&currency).cloned());
+    if let Some(rate) = cached.filter(|rate| now < rate.fetched_at.saturating_add(ttl)) {
+        return Ok(rate);
+    }
+
+    let asset = |symbol: &str| XrcAsset {
+        symbol: symbol.to_string(),
+        class: if CRYPTO_CURRENCIES.contains(&symbol) {
+            XrcAssetClass::Cryptocurrency
+        } else {
+            XrcAssetClass::FiatCurrency
+        },
+    };
+    let request = XrcRequest {
+        base_asset: asset(BASE_CURRENCY),
+        quote_asset: asset(&currency),
+        timestamp: None,
+    };
+    let result: Result<(Result<XrcExchangeRate, XrcError>,), _> =
+        ic_cdk::api::call::call_with_payment128(
+            xrc_canister_id(),
+            "get_exchange_rate",
+            (request,),
+            XRC_CYCLES,
+        )
+        .await;
+    let fetched = match result {
+        Ok((Ok(fetched),)) => fetched,
+        Ok((Err(error),)) => {
+            return Err(Error::CallFailed {
+                msg: format!("No exchange rate for {}: {:?}", currency, error),
+            })
+        }
+        Err((code, msg)) => {
+            return Err(Error::CallFailed {
+                msg: format!("get_exchange_rate failed: {:?} {}", code, msg),
+            })
+        }
+    };
+    let rate = ExchangeRate {
+        currency: currency.clone(),
+        rate: fetched.rate,
+        decimals: fetched.metadata.decimals,
+        timestamp: fetched.timestamp,
+        fetched_at: ic_cdk::api::time(),
+    };
+    EXCHANGE_RATES.with(|rates| rates.borrow_mut().insert(currency, rate.clone()));
+    Ok(rate)
+}
+
+fn xrc_canister_id() -> Principal {
+    config().xrc_canister_id.unwrap_or_else(|| {
+        Principal::from_text(MAINNET_XRC_CANISTER_ID).expect("Invalid exchange rate canister id")
+    })
+}
+
+// Hold a car for a period at the price quoted now, so it cannot be booked by anyone else
+// until the hold is converted into a rental request, released, or expires
+#[ic_cdk::update]
+fn create_hold(car_id: u64, start_date: u64, end_date: u64) -> Result<QuoteHold, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff, Role::Customer])?;
+    validate_rental_period(start_date, end_date)?;
+    let car = get_car(car_id)?;
+    let owner = caller();
+    let now = ic_cdk::api::time();
+    let active_holds = HOLD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, hold)| hold.owner == owner && hold.is_active(now))
+            .count()
+    });
+    if active_holds >= MAX_ACTIVE_HOLDS {
+        return Err(Error::Conflict {
+            msg: format!("At most {} holds can be active at once", MAX_ACTIVE_HOLDS),
+        });
+    }
+    ensure_car_available(car_id, start_date, end_date, None)?;
+    ensure_car_insured(car_id, end_date)?;
+    let (pickup_branch_id, dropoff_branch_id) = rental_branches(&car, None, None)?;
+    let quote = compute_quote(
+        &car,
+        start_date,
+        end_date,
+        pickup_branch_id,
+        dropoff_branch_id,
+        protection_plan(ProtectionTier::Basic)?,
+        &[],
+    );
+
+    let hold = QuoteHold {
+        id: next_id()?,
+        car_id,
+        start_date,
+        end_date,
+        owner,
+        quote,
+        created_at: now,
+        expires_at: now.saturating_add(config().hold_minutes.saturating_mul(NANOS_PER_MINUTE)),
+        status: HoldStatus::Active,
+    };
+    HOLD_STORAGE.with(|storage| storage.borrow_mut().insert(hold.id, hold.clone()));
+    HOLDS_BY_CAR.with(|index| index.borrow_mut().insert((car_id, hold.id), ()));
+    certify_availability(car_id);
+    record_audit("create_hold", Some(hold.id), None, audit_summary(&hold));
+    Ok(hold)
+}
+
+#[ic_cdk::query]
+fn get_hold(hold_id: u64) -> Result<QuoteHold, Error> {
+    let hold = get_hold_record(hold_id)?;
+    if ensure_owner_or_admin(&hold.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(hold)
+}
+
+// Book a held car as a pending rental request at the price locked by the hold
+#[ic_cdk::update]
+fn convert_hold(
+    hold_id: u64,
+    customer_id: u64,
+    corporate_account_id: Option<u64>,
+) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    let mut hold = get_hold_record(hold_id)?;
+    ensure_owner_or_admin(&hold.owner)?;
+    if !hold.is_active(ic_cdk::api::time()) {
+        return Err(Error::Conflict {
+            msg: format!("Hold id={} is no longer active", hold_id),
+        });
+    }
+    let payload = RentalRequestPayload {
+        car_id: hold.car_id,
+        customer_id,
+        start_date: hold.start_date,
+        end_date: hold.end_date,
+        status: RentalStatus::Pending,
+        pickup_branch_id: hold.quote.pickup_branch_id,
+        dropoff_branch_id: hold.quote.dropoff_branch_id,
+        protection_tier: None,
+        add_ons: Vec::new(),
+        corporate_account_id,
+    };
+    ensure_can_book(&payload)?;
+    let rental_request = create_rental_request(payload, hold.owner, Some(&hold))?;
+    let before = audit_summary(&hold);
+    hold.status = HoldStatus::Converted {
+        rental_id: rental_request.id,
+    };
+    HOLD_STORAGE.with(|storage| storage.borrow_mut().insert(hold_id, hold.clone()));
+    certify_availability(hold.car_id);
+    record_audit("convert_hold", Some(hold_id), before, audit_summary(&hold));
+    record_audit(
+        "convert_hold",
+        Some(rental_request.id),
+        None,
+        audit_summary(&rental_request),
+    );
+    Ok(rental_request)
+}
+
+// Give up a hold before it expires, freeing the car
+#[ic_cdk::update]
+fn release_hold(hold_id: u64) -> Result<QuoteHold, Error> {
+    check_rate_limit()?;
+    let mut hold = get_hold_record(hold_id)?;
+    ensure_owner_or_admin(&hold.owner)?;
+    if hold.status != HoldStatus::Active {
+        return Err(Error::InvalidInput {
+            msg: format!("Hold id={} is no longer active: {:?}", hold_id, hold.status),
+        });
+    }
+    let before = audit_summary(&hold);
+    hold.status = HoldStatus::Released;
+    HOLD_STORAGE.with(|storage| storage.borrow_mut().insert(hold_id, hold.clone()));
+    certify_availability(hold.car_id);
+    promote_waitlist(hold.car_id);
+    record_audit("release_hold", Some(hold_id), before, audit_summary(&hold));
+    Ok(hold)
+}
+
+fn get_hold_record(hold_id: u64) -> Result<QuoteHold, Error> {
+    HOLD_STORAGE
+        .with(|storage| storage.borrow().get(&hold_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Hold with id={} not found", hold_id),
+        })
+}
+
+fn holds_for_car(car_id: u64) -> Vec<QuoteHold> {
+    let hold_ids: Vec<u64> = HOLDS_BY_CAR.with(|index| {
+        index
+            .borrow()
+            .range((car_id, 0)..=(car_id, u64::MAX))
+            .map(|((_, hold_id), _)| hold_id)
+            .collect()
+    });
+    HOLD_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        hold_ids
+            .into_iter()
+            .filter_map(|hold_id| storage.get(&hold_id))
+            .collect()
+    })
+}
+
+impl QuoteHold {
+    // A hold stops blocking its car the moment it expires, before the timer marks it
+    fn is_active(&self, now: u64) -> bool {
+        self.status == HoldStatus::Active && now < self.expires_at
+    }
+}
+
+// Periodic job: mark holds past their expiry as expired, offering their cars to the waitlist
+fn expire_holds(now: u64) {
+    let expired: Vec<QuoteHold> = HOLD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, hold)| hold)
+            .filter(|hold| hold.status == HoldStatus::Active && hold.expires_at <= now)
+            .collect()
+    });
+    for mut hold in expired {
+        hold.status = HoldStatus::Expired;
+        let car_id = hold.car_id;
+        HOLD_STORAGE.with(|storage| storage.borrow_mut().insert(hold.id, hold));
+        certify_availability(car_id);
+        promote_waitlist(car_id);
+    }
+}
+
+// A retried call with the same idempotency key returns the rental request created by the
+// first call instead of booking again
+#[ic_cdk::update]
+fn add_rental_request(
+    payload: RentalRequestPayload,
+    idempotency_key: Option<String>,
+) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    ensure_can_book(&payload)?;
+    match replayed_result(&idempotency_key)? {
+        Some(IdempotentResult::RentalRequest(rental_request)) => return Ok(rental_request),
+        Some(_) => return Err(idempotency_key_reused()),
+        None => {}
+    }
+    // Only staff may create rentals that skip the approval step
+    if payload.status != RentalStatus::Pending {
+        require_any_role(&[Role::Staff])?;
+    }
+    let (car_id, start_date, end_date) = (payload.car_id, payload.start_date, payload.end_date);
+    let result = create_rental_request(payload, caller(), None);
+    if result.is_ok() {
+        record_demand(car_id, false);
+    } else if ensure_car_available(car_id, start_date, end_date, None).is_err() {
+        record_demand(car_id, true);
+    }
+    let rental_request = result?;
+    remember_result(
+        idempotency_key,
+        IdempotentResult::RentalRequest(rental_request.clone()),
+    );
+    record_audit(
+        "add_rental_request",
+        Some(rental_request.id),
+        None,
+        audit_summary(&rental_request),
+    );
+    Ok(rental_request)
+}
+
+// Count a booking request for a car on the day it was made. A request refused for other
+// reasons than the car being taken is not counted.
+fn record_demand(car_id: u64, unavailable: bool) {
+    let key = (ic_cdk::api::time() / NANOS_PER_DAY, car_id);
+    DEMAND_COUNTS.with(|counts| {
+        let mut counts = counts.borrow_mut();
+        let mut count = counts.get(&key).unwrap_or_default();
+        count.add(DemandCount {
+            requests: 1,
+            unavailable: u64::from(unavailable),
+        });
+        counts.insert(key, count);
+    });
+}
+
+// Find the unexpired result remembered for the caller's idempotency key
+fn replayed_result(idempotency_key: &Option<String>) -> Result<Option<IdempotentResult>, Error> {
+    let key = match idempotency_key {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    if key.is_empty() || key.len() > 64 {
+        return Err(Error::InvalidInput {
+            msg: "An idempotency key must be 1 to 64 characters".to_string(),
+        });
+    }
+    let key = IdempotencyKey {
+        caller: caller(),
+        key: key.clone(),
+    };
+    let now = ic_cdk::api::time();
+    Ok(IDEMPOTENCY_STORAGE
+        .with(|storage| storage.borrow().get(&key))
+        .filter(|record| now < record.expires_at)
+        .map(|record| record.result))
+}
+
+// Remember the result of a successful call; failed calls may be retried with the same key
+fn remember_result(idempotency_key: Option<String>, result: IdempotentResult) {
+    if let Some(key) = idempotency_key {
+        let now = ic_cdk::api::time();
+        let key = IdempotencyKey {
+            caller: caller(),
+            key,
+        };
+        let record = IdempotencyRecord {
+            result,
+            created_at: now,
+            expires_at: now.saturating_add(IDEMPOTENCY_KEY_TTL),
+        };
+        IDEMPOTENCY_STORAGE.with(|storage| storage.borrow_mut().insert(key, record));
+    }
+}
+
+fn idempotency_key_reused() -> Error {
+    Error::Conflict {
+        msg: "The idempotency key was already used for a different call".to_string(),
+    }
+}
+
+// Periodic job: forget the results of idempotency keys past their time to live
+fn expire_idempotency_keys(now: u64) {
+    let expired: Vec<IdempotencyKey> = IDEMPOTENCY_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, record)| record.expires_at <= now)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    IDEMPOTENCY_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for key in expired {
+            storage.remove(&key);
+        }
+    });
+}
+
+// Check that the caller may book for the customer and corporate account of a payload
+fn ensure_can_book(payload: &RentalRequestPayload) -> Result<(), Error> {
+    require_any_role(&[Role::Staff, Role::Customer])?;
+    let customer = get_customer(payload.customer_id)?;
+    match payload.corporate_account_id {
+        Some(account_id) => {
+            let account = get_corporate_account_record(account_id)?;
+            ensure_authorized_driver(account_id, customer.id)?;
+            // Account admins may book for the account's drivers
+            if ensure_owner_or_admin(&customer.owner).is_err() {
+                ensure_account_admin(&account)?;
+            }
+        }
+        None => ensure_owner_or_admin(&customer.owner)?,
+    }
+    Ok(())
+}
+
+// Book a rental for an authorized caller or a promoted waitlist entry. A rental booked
+// from a hold takes the hold's place on the car and keeps its locked price.
+fn create_rental_request(
+    payload: RentalRequestPayload,
+    owner: Principal,
+    hold: Option<&QuoteHold>,
+) -> Result<RentalRequest, Error> {
+    validate_rental_request_payload(&payload)?;
+    let RentalRequestPayload {
+        car_id,
+        customer_id,
+        start_date,
+        end_date,
+        status,
+        pickup_branch_id,
+        dropoff_branch_id,
+        protection_tier,
+        add_ons,
+        corporate_account_id,
+    } = payload;
+    let customer = get_customer(customer_id)?;
+    ensure_terms_accepted(customer_id)?;
+    ensure_license_valid(&customer, end_date)?;
+    let car = get_car(car_id)?;
+    ensure_eligible(&customer, car.category, start_date)?;
+    ensure_car_available(
+        car_id,
+        start_date,
+        end_date,
+        hold.map(|hold| ExcludedBooking::Hold(hold.id)),
+    )?;
+    ensure_car_insured(car_id, end_date)?;
+    let (pickup_branch_id, dropoff_branch_id) =
+        rental_branches(&car, pickup_branch_id, dropoff_branch_id)?;
+    let protection = protection_plan(protection_tier.unwrap_or(ProtectionTier::Basic))?;
+    let add_ons = resolve_add_ons(&add_ons, pickup_branch_id, start_date, end_date, None)?;
+    let quote = match hold {
+        Some(hold) => hold.quote.clone(),
+        None => compute_quote(
+            &car,
+            start_date,
+            end_date,
+            pickup_branch_id,
+            dropoff_branch_id,
+            protection,
+            &add_ons,
+        ),
+    };
+    if let Some(account_id) = corporate_account_id {
+        ensure_within_spending_limit(account_id, start_date, quote.total_e8s, None)?;
+    }
+
+    let id = next_rental_id()?;
+
+    let mut rental_request = RentalRequest {
+        id,
+        car_id,
+        customer_id,
+        start_date,
+        end_date,
+        status,
+        owner,
+        overdue: false,
+        quoted_price_e8s: quote.total_e8s,
+        payment_id: None,
+        transfer_id: None,
+        completed_at: None,
+        points_redeemed: 0,
+        points_discount_e8s: 0,
+        coupon_id: None,
+        coupon_discount_e8s: 0,
+        pickup_branch_id,
+        dropoff_branch_id,
+        drop_fee_e8s: quote.drop_fee_e8s,
+        checkout_odometer_km: None,
+        checkin_odometer_km: None,
+        included_km: included_km(car.category, start_date, end_date),
+        fuel_policy: Some(car.fuel_policy.unwrap_or(FuelPolicy::FullToFull)),
+        checkout_fuel_percent: None,
+        checkin_fuel_percent: None,
+        protection_e8s: quote.protection_e8s,
+        protection: Some(quote.protection),
+        add_ons_e8s: quote.add_ons_e8s,
+        picked_up_at: None,
+        early_return_credit_e8s: 0,
+        cancellation_fee_e8s: 0,
+        corporate_account_id,
+        version: 0,
+        requested_at: Some(ic_cdk::api::time()),
+    };
+
+    store_rental_request(&mut rental_request);
+    store_rental_add_ons(id, &quote.add_ons);
+
+    Ok(rental_request)
+}
+
+#[ic_cdk::update]
+fn delete_rental_request(id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    let rental_request = get_rental_request(id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    ensure_deletable(&rental_request)?;
+
+    match remove_rental_request(id) {
+        Some(removed) => {
+            record_audit(
+                "delete_rental_request",
+                Some(id),
+                audit_summary(&removed),
+                None,
+            );
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Rental request with id={} not found", id),
+        }),
+    }
+}
+
+// Only rentals that never took money may be deleted; the rest keep their payment and
+// deposit reachable and must be canceled instead
+fn ensure_deletable(rental_request: &RentalRequest) -> Result<(), Error> {
+    let id = rental_request.id;
+    if rental_request.payment_id.is_some()
+        || DEPOSIT_STORAGE.with(|storage| storage.borrow().contains_key(&id))
+    {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Rental request id={} holds a payment or deposit; cancel it instead",
+                id
+            ),
+        });
+    }
+    if !matches!(
+        rental_request.status,
+        RentalStatus::Pending | RentalStatus::Canceled | RentalStatus::Expired
+    ) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Rental request id={} cannot be deleted in status {:?}; cancel it instead",
+                id, rental_request.status
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_rental_requests_for_car(car_id: u64) -> Vec<RentalRequest> {
+    collect_pages(|cursor| list_rental_requests_for_car_page(car_id, cursor, MAX_PAGE_SIZE))
+}
+
+#[ic_cdk::query]
+fn list_rental_requests_for_car_page(
+    car_id: u64,
+    cursor: Option<u64>,
+    limit: u32,
+) -> Page<RentalRequest> {
+    RENTALS_BY_CAR.with(|index| page_of_indexed_rentals(&index.borrow(), car_id, cursor, limit))
+}
+
+#[ic_cdk::query]
+fn list_rental_requests_for_customer(customer_id: u64) -> Vec<RentalRequest> {
+    collect_pages(|cursor| {
+        list_rental_requests_for_customer_page(customer_id, cursor, MAX_PAGE_SIZE)
+    })
+}
+
+#[ic_cdk::query]
+fn list_rental_requests_for_customer_page(
+    customer_id: u64,
+    cursor: Option<u64>,
+    limit: u32,
+) -> Page<RentalRequest> {
+    RENTALS_BY_CUSTOMER
+        .with(|index| page_of_indexed_rentals(&index.borrow(), customer_id, cursor, limit))
+}
+
+// Search cars, sorted by the given field. The cursor of a search is an offset.
+#[ic_cdk::query]
+fn search_cars(
+    filter: CarFilter,
+    sort_by: CarSortField,
+    direction: SortDirection,
+    cursor: Option<u64>,
+    limit: u32,
+) -> Page<Car> {
+    let mut cars: Vec<Car> = CAR_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, car)| car)
+            .filter(|car| car.is_listed() && filter.matches(car))
+            .collect()
+    });
+    cars.sort_by(|a, b| {
+        let ordering = match sort_by {
+            CarSortField::Id => a.id.cmp(&b.id),
+            CarSortField::Make => a.make.to_lowercase().cmp(&b.make.to_lowercase()),
+            CarSortField::Model => a.model.to_lowercase().cmp(&b.model.to_lowercase()),
+            CarSortField::Year => a.year.cmp(&b.year),
+            CarSortField::DailyRate => a.daily_rate_e8s.cmp(&b.daily_rate_e8s),
+        };
+        directed(ordering.then(a.id.cmp(&b.id)), direction)
+    });
+    page_of_sorted(cars, cursor, limit)
+}
+
+// Search rental requests, sorted by the given field. The cursor of a search is an offset.
+#[ic_cdk::query]
+fn search_rentals(
+    filter: RentalFilter,
+    sort_by: RentalSortField,
+    direction: SortDirection,
+    cursor: Option<u64>,
+    limit: u32,
+) -> Page<RentalRequest> {
+    let mut requests: Vec<RentalRequest> = RENTAL_REQUEST_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| filter.matches(request))
+            .collect()
+    });
+    requests.sort_by(|a, b| {
+        let ordering = match sort_by {
+            RentalSortField::Id => a.id.cmp(&b.id),
+            RentalSortField::StartDate => a.start_date.cmp(&b.start_date),
+            RentalSortField::EndDate => a.end_date.cmp(&b.end_date),
+            RentalSortField::QuotedPrice => a.quoted_price_e8s.cmp(&b.quoted_price_e8s),
+        };
+        directed(ordering.then(a.id.cmp(&b.id)), direction)
+    });
+    page_of_sorted(requests, cursor, limit)
+}
+
+fn directed(ordering: std::cmp::Ordering, direction: SortDirection) -> std::cmp::Ordering {
+    match direction {
+        SortDirection::Ascending => ordering,
+        SortDirection::Descending => ordering.reverse(),
+    }
+}
+
+// Export every car, in id order
+#[ic_cdk::query]
+fn export_cars(format: ExportFormat, cursor: Option<u64>) -> Result<ExportChunk, Error> {
+    require_any_role(&[Role::Staff])?;
+    Ok(CAR_STORAGE.with(|storage| {
+        export_chunk(
+            storage.borrow().range(cursor.unwrap_or(0)..),
+            format,
+            cursor.is_none(),
+            "id,make,model,year,available,daily_rate_e8s,category,vin,license_plate,color,\
+             transmission,fuel_type,seats,mileage_km,features,branch_id,fuel_policy,version,\
+             status,retired_at",
+            |car: &Car| {
+                vec![
+                    car.id.to_string(),
+                    car.make.clone(),
+                    car.model.clone(),
+                    car.year.to_string(),
+                    car.available.to_string(),
+                    car.daily_rate_e8s.to_string(),
+                    format!("{:?}", car.category),
+                    car.vin.clone().unwrap_or_default(),
+                    car.license_plate.clone(),
+                    car.color.clone(),
+                    csv_optional(car.transmission),
+                    csv_optional(car.fuel_type),
+                    car.seats.to_string(),
+                    car.mileage_km.to_string(),
+                    car.features.join(";"),
+                    csv_optional(car.branch_id),
+                    csv_optional(car.fuel_policy),
+                    car.version.to_string(),
+                    format!("{:?}", car.status.unwrap_or(CarStatus::Active)),
+                    csv_optional(car.retired_at),
+                ]
+            },
+        )
+    }))
+}
+
+// Export the rental requests matching a filter, in id order
+#[ic_cdk::query]
+fn export_rentals(
+    filter: RentalFilter,
+    format: ExportFormat,
+    cursor: Option<u64>,
+) -> Result<ExportChunk, Error> {
+    require_any_role(&[Role::Staff])?;
+    Ok(RENTAL_REQUEST_STORAGE.with(|storage| {
+        export_chunk(
+            storage
+                .borrow()
+                .range(cursor.unwrap_or(0)..)
+                .filter(|(_, request)| filter.matches(request)),
+            format,
+            cursor.is_none(),
+            "id,car_id,customer_id,owner,start_date,end_date,status,quoted_price_e8s,\
+             price_due_e8s,payment_id,pickup_branch_id,dropoff_branch_id,corporate_account_id,\
+             completed_at,version",
+            |request: &RentalRequest| {
+                vec![
+                    request.id.to_string(),
+                    request.car_id.to_string(),
+                    request.customer_id.to_string(),
+                    request.owner.to_text(),
+                    request.start_date.to_string(),
+                    request.end_date.to_string(),
+                    format!("{:?}", request.status),
+                    request.quoted_price_e8s.to_string(),
+                    request.price_due_e8s().to_string(),
+                    csv_optional(request.payment_id),
+                    csv_optional(request.pickup_branch_id),
+                    csv_optional(request.dropoff_branch_id),
+                    csv_optional(request.corporate_account_id),
+                    csv_optional(request.completed_at),
+                    request.version.to_string(),
+                ]
+            },
+        )
+    }))
+}
+
+// Export the invoices issued within [from, to), in rental id order. CSV exports list the
+// totals of each invoice; JSON exports include its lines.
+#[ic_cdk::query]
+fn export_invoices(
+    from: u64,
+    to: u64,
+    format: ExportFormat,
+    cursor: Option<u64>,
+) -> Result<ExportChunk, Error> {
+    require_any_role(&[Role::Staff])?;
+    if to <= from {
+        return Err(Error::InvalidInput {
+            msg: "The end of the period must be after its start".to_string(),
+        });
+    }
+    Ok(INVOICE_STORAGE.with(|storage| {
+        export_chunk(
+            storage
+                .borrow()
+                .range(cursor.unwrap_or(0)..)
+                .filter(|(_, invoice)| from <= invoice.issued_at && invoice.issued_at < to),
+            format,
+            cursor.is_none(),
+            "number,rental_id,customer_id,issued_at,total_e8s,paid_e8s,balance_due_e8s",
+            |invoice: &Invoice| {
+                vec![
+                    invoice.number.to_string(),
+                    invoice.rental_id.to_string(),
+                    invoice.customer_id.to_string(),
+                    invoice.issued_at.to_string(),
+                    invoice.total_e8s.to_string(),
+                    invoice.paid_e8s.to_string(),
+                    invoice.balance_due_e8s.to_string(),
+                ]
+            },
+        )
+    }))
+}
+
+// Write up to EXPORT_CHUNK_ROWS records, heading the first chunk of a CSV export with the
+// column names so that the chunks join into one file
+fn export_chunk<T: serde::Serialize>(
+    records: impl Iterator<Item = (u64, T)>,
+    format: ExportFormat,
+    first_chunk: bool,
+    csv_header: &str,
+    csv_row: impl Fn(&T) -> Vec<String>,
+) -> ExportChunk {
+    let mut data = String::new();
+    if format == ExportFormat::Csv && first_chunk {
+        data.push_str(csv_header);
+        data.push('\n');
+    }
+    let mut next_cursor = None;
+    for (written, (key, record)) in records.enumerate() {
+        if written == EXPORT_CHUNK_ROWS {
+            next_cursor = Some(key);
+            break;
+        }
+        match format {
+            ExportFormat::Csv => {
+                let fields: Vec<String> = csv_row(&record)
+                    .iter()
+                    .map(|field| csv_field(field))
+                    .collect();
+                data.push_str(&fields.join(","));
+            }
+            ExportFormat::JsonLines => {
+                data.push_str(&serde_json::to_string(&record).unwrap_or_default());
+            }
+        }
+        data.push('\n');
+    }
+    ExportChunk {
+        data: data.into_bytes(),
+        next_cursor,
+    }
+}
+
+// Quote a CSV field that holds a comma, quote, or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn csv_optional<T: std::fmt::Debug>(value: Option<T>) -> String {
+    value
+        .map(|value| format!("{:?}", value))
+        .unwrap_or_default()
+}
+
+// Take one page of sorted items, using the offset of each item as its cursor
+fn page_of_sorted<T>(items: Vec<T>, cursor: Option<u64>, limit: u32) -> Page<T> {
+    let total = items.len() as u64;
+    let offset = cursor.unwrap_or(0);
+    page_of((0u64..).zip(items).skip(offset as usize), total, limit)
+}
+
+// Find cars whose make or model contains every word of the query, case-insensitively.
+// Cars where the words match at the start of a make or model word come first.
+#[ic_cdk::query]
+fn search_cars_by_text(query: String) -> Vec<Car> {
+    let terms = text_tokens(&query);
+    if terms.is_empty() {
+        return Vec::new();
+    }
+    let mut scores: BTreeMap<u64, usize> = BTreeMap::new();
+    CAR_TEXT_INDEX.with(|index| {
+        let index = index.borrow();
+        for (position, term) in terms.iter().enumerate() {
+            let mut matched: BTreeMap<u64, usize> = BTreeMap::new();
+            for (token, car_ids) in index
+                .iter()
+                .filter(|(token, _)| token.contains(term.as_str()))
+            {
+                let prefix = usize::from(token.starts_with(term.as_str()));
+                for car_id in car_ids {
+                    let score = matched.entry(*car_id).or_default();
+                    *score = (*score).max(prefix);
+                }
+            }
+            // Every term must match, so only cars matched by all terms so far survive
+            scores = if position == 0 {
+                matched
+            } else {
+                matched
+                    .into_iter()
+                    .filter_map(|(car_id, score)| {
+                        scores.get(&car_id).map(|total| (car_id, total + score))
+                    })
+                    .collect()
+            };
+        }
+    });
+
+    let mut ranked: Vec<(u64, usize)> = scores.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    ranked
+        .into_iter()
+        .filter_map(|(car_id, _)| CAR_STORAGE.with(|storage| storage.borrow().get(&car_id)))
+        .filter(|car| car.is_listed())
+        .collect()
+}
+
+// Split text into lowercase alphanumeric words
+fn text_tokens(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect()
+}
+
+fn car_text_tokens(car: &Car) -> BTreeSet<String> {
+    text_tokens(&car.make)
+        .into_iter()
+        .chain(text_tokens(&car.model))
+        .collect()
+}
+
+fn index_car_text(car: &Car) {
+    CAR_TEXT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for token in car_text_tokens(car) {
+            index.entry(token).or_default().insert(car.id);
+        }
+    });
+}
+
+fn unindex_car_text(car: &Car) {
+    CAR_TEXT_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for token in car_text_tokens(car) {
+            if let Some(car_ids) = index.get_mut(&token) {
+                car_ids.remove(&car.id);
+                if car_ids.is_empty() {
+                    index.remove(&token);
+                }
+            }
+        }
+    });
+}
+
+// Rebuild every index and cache kept on the heap from the stable maps, so queries never
+// see them stale or empty after an upgrade or a restore
+fn rebuild_heap_indexes() {
+    rebuild_car_text_index();
+    rebuild_certified_tree();
+}
+
+fn rebuild_car_text_index() {
+    CAR_TEXT_INDEX.with(|index| index.borrow_mut().clear());
+    CAR_STORAGE.with(|storage| {
+        for (_, car) in storage.borrow().iter() {
+            index_car_text(&car);
+        }
+    });
+}
+
+// List the rental requests starting within [from, to), ordered by start date
+#[ic_cdk::query]
+fn list_rentals_in_period(from: u64, to: u64) -> Result<Vec<RentalRequest>, Error> {
+    require_any_role(&[Role::Staff])?;
+    if to <= from {
+        return Err(Error::InvalidInput {
+            msg: "Period must end after it starts".to_string(),
+        });
+    }
+    Ok(rentals_starting_in(from, to))
+}
+
+fn rentals_starting_in(from: u64, to: u64) -> Vec<RentalRequest> {
+    RENTALS_BY_START_DATE.with(|index| {
+        RENTAL_REQUEST_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            index
+                .borrow()
+                .range((from, 0)..(to, 0))
+                .filter_map(|((_, rental_id), _)| storage.get(&rental_id))
+                .collect()
+        })
+    })
+}
+
+// Page through the rental requests under one prefix of a (prefix, rental id) index
+fn page_of_indexed_rentals(
+    index: &StableBTreeMap<(u64, u64), (), Memory>,
+    prefix: u64,
+    cursor: Option<u64>,
+    limit: u32,
+) -> Page<RentalRequest> {
+    let total = index.range((prefix, 0)..=(prefix, u64::MAX)).count();
+    RENTAL_REQUEST_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        page_of(
+            index
+                .range((prefix, cursor.unwrap_or(0))..=(prefix, u64::MAX))
+                .filter_map(|((_, rental_id), _)| {
+                    storage.get(&rental_id).map(|request| (rental_id, request))
+                }),
+            total as u64,
+            limit,
+        )
+    })
+}
+
+// Take one page of at most limit items from entries ordered by key
+fn page_of<T>(entries: impl Iterator<Item = (u64, T)>, total: u64, limit: u32) -> Page<T> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut items = Vec::new();
+    let mut next_cursor = None;
+    for (key, item) in entries {
+        if items.len() == limit {
+            next_cursor = Some(key);
+            break;
+        }
+        items.push(item);
+    }
+    Page {
+        items,
+        total,
+        next_cursor,
+    }
+}
+
+// Follow the cursors of a paged query to the end
+fn collect_pages<T>(mut fetch: impl FnMut(Option<u64>) -> Page<T>) -> Vec<T> {
+    let mut items = Vec::new();
+    let mut cursor = None;
+    loop {
+        let page = fetch(cursor);
+        items.extend(page.items);
+        match page.next_cursor {
+            Some(next) => cursor = Some(next),
+            None => return items,
+        }
+    }
+}
+
+// Replace the details of a car last read at the expected version
+#[ic_cdk::update]
+fn update_car(id: u64, payload: CarPayload, expected_version: u64) -> Result<Car, Error> {
+    check_rate_limit()?;
+    ensure_car_controller(id)?;
+    let (car, updated_car) = replace_car(id, payload, expected_version)?;
+    record_audit(
+        "update_car",
+        Some(id),
+        audit_summary(&car),
+        audit_summary(&updated_car),
+    );
+    Ok(updated_car)
+}
+
+// Replace the details of a car last read at the expected version, returning the car
+// before and after
+fn replace_car(id: u64, payload: CarPayload, expected_version: u64) -> Result<(Car, Car), Error> {
+    validate_car_payload(&payload)?;
+    if let Some(transfer) = transfer_in_transit(id) {
+        if payload.branch_id != transfer.from_branch_id {
+            return Err(Error::Conflict {
+                msg: format!(
+                    "Car with id={} is in transit with transfer id={}",
+                    id, transfer.id
+                ),
+            });
+        }
+    }
+
+    let car = get_car(id)?;
+    ensure_version("Car", id, car.version, expected_version)?;
+    // Build the updated car, keeping its availability, rating, and status
+    let mut updated_car = Car::from_payload(id, car.available, payload);
+    updated_car.rating = car.rating.clone();
+    updated_car.status = car.status;
+    updated_car.retired_at = car.retired_at;
+    updated_car.listed_by = car.listed_by;
+    updated_car.listing_status = match &car.listing_status {
+        Some(ListingStatus::Rejected { .. }) => Some(ListingStatus::Pending),
+        status => status.clone(),
+    };
+    check_record_size(&updated_car)?;
+    // Replace the old car with the updated one
+    store_car(&mut updated_car);
+    unindex_car_text(&car);
+    index_car_text(&updated_car);
+    Ok((car, updated_car))
+}
+
+// Change only the given details of a car last read at the expected version. The id,
+// availability, rating, and version of a car cannot be patched.
+#[ic_cdk::update]
+fn patch_car(id: u64, patch: CarPatch, expected_version: u64) -> Result<Car, Error> {
+    check_rate_limit()?;
+    ensure_car_controller(id)?;
+    let car = get_car(id)?;
+    let payload = CarPayload {
+        make: patch.make.unwrap_or(car.make),
+        model: patch.model.unwrap_or(car.model),
+        year: patch.year.unwrap_or(car.year),
+        daily_rate_e8s: patch.daily_rate_e8s.unwrap_or(car.daily_rate_e8s),
+        category: patch.category.unwrap_or(car.category),
+        vin: patch.vin.or(car.vin),
+        license_plate: patch.license_plate.unwrap_or(car.license_plate),
+        color: patch.color.unwrap_or(car.color),
+        transmission: patch.transmission.or(car.transmission),
+        fuel_type: patch.fuel_type.or(car.fuel_type),
+        seats: patch.seats.unwrap_or(car.seats),
+        mileage_km: patch.mileage_km.unwrap_or(car.mileage_km),
+        features: patch.features.unwrap_or(car.features),
+        branch_id: patch.branch_id.or(car.branch_id),
+        fuel_policy: patch.fuel_policy.or(car.fuel_policy),
+    };
+    let (car, updated_car) = replace_car(id, payload, expected_version)?;
+    record_audit(
+        "patch_car",
+        Some(id),
+        audit_summary(&car),
+        audit_summary(&updated_car),
+    );
+    Ok(updated_car)
+}
+
+// Store a car, advancing its version
+fn store_car(car: &mut Car) {
+    CAR_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        car.version = storage
+            .get(&car.id)
+            .map_or(1, |previous| previous.version + 1);
+        storage.insert(car.id, car.clone());
+    });
+    certify_car(car.id);
+}
+
+// Reject a record whose encoding would not fit in its stable map, so the insert does not
+// trap
+fn check_record_size<V: BoundedStorable>(record: &V) -> Result<(), Error> {
+    let size = record.to_bytes().len() as u64;
+    if size > u64::from(V::MAX_SIZE) {
+        return Err(Error::RecordTooLarge {
+            size,
+            max_size: u64::from(V::MAX_SIZE),
+        });
+    }
+    Ok(())
+}
+
+// Reject an edit based on a version of a record other than the one stored
+fn ensure_version(record: &str, id: u64, version: u64, expected_version: u64) -> Result<(), Error> {
+    if version != expected_version {
+        return Err(Error::Conflict {
+            msg: format!(
+                "{} with id={} is at version {}, not {}; reload it and try again",
+                record, id, version, expected_version
+            ),
+        });
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn update_rental_request(
+    id: u64,
+    payload: RentalRequestUpdatePayload,
+) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    let (rental_request, updated_rental_request) = replace_rental_request(id, payload)?;
+    record_audit(
+        "update_rental_request",
+        Some(id),
+        audit_summary(&rental_request),
+        audit_summary(&updated_rental_request),
+    );
+    Ok(updated_rental_request)
+}
+
+// Change the car, customer, or dates of an open rental request, returning it before and after
+fn replace_rental_request(
+    id: u64,
+    payload: RentalRequestUpdatePayload,
+) -> Result<(RentalRequest, RentalRequest), Error> {
+    validate_rental_update_payload(&payload)?;
+    let RentalRequestUpdatePayload {
+        car_id,
+        customer_id,
+        start_date,
+        end_date,
+        expected_version,
+    } = payload;
+    let customer = get_customer(customer_id)?;
+    ensure_owner_or_admin(&customer.owner)?;
+    ensure_license_valid(&customer, end_date)?;
+
+    let rental_request = get_rental_request(id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    ensure_version(
+        "Rental request",
+        id,
+        rental_request.version,
+        expected_version,
+    )?;
+    // Closed rentals are kept as history and cannot be edited
+    if !rental_request.status.is_open() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request with id={} cannot be updated in status {:?}",
+                id, rental_request.status
+            ),
+        });
+    }
+    if rental_request.picked_up_at.is_some() {
+        return Err(Error::Conflict {
+            msg: format!("The car of rental request id={} was already picked up", id),
+        });
+    }
+    if let Some(account_id) = rental_request.corporate_account_id {
+        ensure_authorized_driver(account_id, customer_id)?;
+    }
+    let car = get_car(car_id)?;
+    ensure_eligible(&customer, car.category, start_date)?;
+    ensure_car_available(
+        car_id,
+        start_date,
+        end_date,
+        Some(ExcludedBooking::Rental(id)),
+    )?;
+    ensure_car_insured(car_id, end_date)?;
+
+    // Create a cloned copy of the rental request to update
+    let mut updated_rental_request = rental_request.clone();
+    // Update the rental request fields
+    updated_rental_request.car_id = car_id;
+    updated_rental_request.customer_id = customer_id;
+    updated_rental_request.start_date = start_date;
+    updated_rental_request.end_date = end_date;
+    // Another car is picked up from the branch it is kept at; round trips stay round trips
+    if car_id != rental_request.car_id {
+        let one_way = rental_request.dropoff_branch_id != rental_request.pickup_branch_id;
+        let dropoff_branch_id = rental_request.dropoff_branch_id.filter(|_| one_way);
+        let (pickup_branch_id, dropoff_branch_id) = rental_branches(&car, None, dropoff_branch_id)?;
+        updated_rental_request.pickup_branch_id = pickup_branch_id;
+        updated_rental_request.dropoff_branch_id = dropoff_branch_id;
+    }
+    // The agreed price only changes when the booking itself changes
+    if car_id != rental_request.car_id
+        || start_date != rental_request.start_date
+        || end_date != rental_request.end_date
+    {
+        // The protection tier is kept, priced at the current rates
+        let tier = updated_rental_request
+            .protection
+            .as_ref()
+            .map_or(ProtectionTier::Basic, |plan| plan.tier);
+        // Add-ons are reserved again for the new booking at the current rates
+        let selections: Vec<AddOnSelection> = rental_add_ons(id)
+            .into_iter()
+            .map(|add_on| AddOnSelection {
+                add_on_id: add_on.add_on_id,
+                quantity: add_on.quantity,
+            })
+            .collect();
+        let add_ons = resolve_add_ons(
+            &selections,
+            updated_rental_request.pickup_branch_id,
+            start_date,
+            end_date,
+            Some(id),
+        )?;
+        let quote = compute_quote(
+            &car,
+            start_date,
+            end_date,
+            updated_rental_request.pickup_branch_id,
+            updated_rental_request.dropoff_branch_id,
+            protection_plan(tier)?,
+            &add_ons,
+        );
+        store_rental_add_ons(id, &quote.add_ons);
+        updated_rental_request.quoted_price_e8s = quote.total_e8s;
+        updated_rental_request.drop_fee_e8s = quote.drop_fee_e8s;
+        updated_rental_request.protection_e8s = quote.protection_e8s;
+        updated_rental_request.protection = Some(quote.protection);
+        updated_rental_request.add_ons_e8s = quote.add_ons_e8s;
+        updated_rental_request.included_km = included_km(car.category, start_date, end_date);
+        updated_rental_request.fuel_policy =
+            Some(car.fuel_policy.unwrap_or(FuelPolicy::FullToFull));
+        if let Some(coupon) = updated_rental_request
+            .coupon_id
+            .and_then(|coupon_id| COUPON_STORAGE.with(|storage| storage.borrow().get(&coupon_id)))
+        {
+            updated_rental_request.coupon_discount_e8s =
+                coupon.discount_on(updated_rental_request.quoted_price_e8s);
+        }
+    }
+    if let Some(account_id) = updated_rental_request.corporate_account_id {
+        ensure_within_spending_limit(
+            account_id,
+            start_date,
+            updated_rental_request.price_due_e8s(),
+            Some(id),
+        )?;
+    }
+    // Replace the old rental request with the updated one
+    store_rental_request(&mut updated_rental_request);
+    Ok((rental_request, updated_rental_request))
+}
+
+// Change only the given car, customer, or dates of a rental request last read at the
+// expected version. Its id, owner, status, and prices cannot be patched.
+#[ic_cdk::update]
+fn patch_rental_request(
+    id: u64,
+    patch: RentalPatch,
+    expected_version: u64,
+) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    let rental_request = get_rental_request(id)?;
+    let payload = RentalRequestUpdatePayload {
+        car_id: patch.car_id.unwrap_or(rental_request.car_id),
+        customer_id: patch.customer_id.unwrap_or(rental_request.customer_id),
+        start_date: patch.start_date.unwrap_or(rental_request.start_date),
+        end_date: patch.end_date.unwrap_or(rental_request.end_date),
+        expected_version,
+    };
+    let (rental_request, updated_rental_request) = replace_rental_request(id, payload)?;
+    record_audit(
+        "patch_rental_request",
+        Some(id),
+        audit_summary(&rental_request),
+        audit_summary(&updated_rental_request),
+    );
+    Ok(updated_rental_request)
+}
+
+#[ic_cdk::update]
+fn transition_rental_status(id: u64, new_status: RentalStatus) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    let (rental_request, updated_rental_request) = apply_transition(id, new_status)?;
+    record_audit(
+        "transition_rental_status",
+        Some(id),
+        audit_summary(&rental_request),
+        audit_summary(&updated_rental_request),
+    );
+    Ok(updated_rental_request)
+}
+
+// Move a rental request to a new status, returning it before and after
+fn apply_transition(
+    id: u64,
+    new_status: RentalStatus,
+) -> Result<(RentalRequest, RentalRequest), Error> {
+    let rental_request = get_rental_request(id)?;
+    // Owners may cancel their own rentals, every other transition is a staff decision
+    if new_status != RentalStatus::Canceled || ensure_owner_or_admin(&rental_request.owner).is_err()
+    {
+        require_any_role(&[Role::Staff])?;
+    }
+    // Expiry is decided by the scheduler and completion by check_in_rental, not by callers,
+    // and a collected car must be returned
+    if matches!(new_status, RentalStatus::Expired | RentalStatus::Completed)
+        || !rental_request.status.can_transition_to(&new_status)
+        || (new_status == RentalStatus::Canceled && rental_request.picked_up_at.is_some())
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request with id={} cannot move from {:?} to {:?}",
+                id, rental_request.status, new_status
+            ),
+        });
+    }
+
+    let mut updated_rental_request = rental_request.clone();
+    updated_rental_request.status = new_status;
+    updated_rental_request.overdue = false;
+    if new_status == RentalStatus::Canceled {
+        release_discounts(&mut updated_rental_request);
+    }
+    store_rental_request(&mut updated_rental_request);
+    Ok((rental_request, updated_rental_request))
+}
+
+// Move several rental requests to new statuses, with one result per rental request
+#[ic_cdk::update]
+fn transition_rentals_batch(
+    transitions: Vec<(u64, RentalStatus)>,
+) -> Result<Vec<Result<RentalRequest, Error>>, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    ensure_batch_size(transitions.len())?;
+    Ok(transitions
+        .into_iter()
+        .map(|(id, new_status)| {
+            apply_transition(id, new_status).map(|(rental_request, updated_rental_request)| {
+                record_audit(
+                    "transition_rentals_batch",
+                    Some(id),
+                    audit_summary(&rental_request),
+                    audit_summary(&updated_rental_request),
+                );
+                updated_rental_request
+            })
+        })
+        .collect())
+}
+
+// Cancel a rental, billing the cancellation fee of the policy. Staff who cancel with
+// transition_rental_status instead bill no fee.
+#[ic_cdk::update]
+fn cancel_rental(id: u64) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    let fee_e8s = cancellation_fee(&get_rental_request(id)?, ic_cdk::api::time());
+    let charge_id = if fee_e8s > 0 { Some(next_id()?) } else { None };
+    let (rental_request, mut canceled) = apply_transition(id, RentalStatus::Canceled)?;
+    if let Some(charge_id) = charge_id {
+        let charge = Charge {
+            id: charge_id,
+            rental_id: id,
+            customer_id: canceled.customer_id,
+            kind: ChargeKind::CancellationFee,
+            description: "Cancellation fee".to_string(),
+            amount_e8s: fee_e8s,
+            created_at: ic_cdk::api::time(),
+            settled: false,
+        };
+        CHARGE_STORAGE.with(|storage| storage.borrow_mut().insert(charge.id, charge));
+        canceled.cancellation_fee_e8s = fee_e8s;
+        store_rental_request(&mut canceled);
+    }
+    record_audit(
+        "cancel_rental",
+        Some(id),
+        audit_summary(&rental_request),
+        audit_summary(&canceled),
+    );
+    Ok(canceled)
+}
+
+// Preview the fee cancel_rental would bill now
+#[ic_cdk::query]
+fn get_cancellation_fee(id: u64) -> Result<u64, Error> {
+    let rental_request = get_rental_request(id)?;
+    if ensure_owner_or_admin(&rental_request.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(cancellation_fee(&rental_request, ic_cdk::api::time()))
+}
+
+// Requests still waiting for approval are canceled for free; confirmed bookings pay the
+// tier for the hours left before the start date, or the no-show fee once it has passed
+fn cancellation_fee(rental_request: &RentalRequest, now: u64) -> u64 {
+    if !matches!(
+        rental_request.status,
+        RentalStatus::Approved | RentalStatus::Active
+    ) {
+        return 0;
+    }
+    let config = config();
+    let fee_bps = if now >= rental_request.start_date {
+        config.no_show_fee_bps
+    } else {
+        let hours_before_start = (rental_request.start_date - now) / NANOS_PER_HOUR;
+        config
+            .cancellation_tiers
+            .iter()
+            .filter(|tier| tier.min_hours_before_start <= hours_before_start)
+            .max_by_key(|tier| tier.min_hours_before_start)
+            .map_or(0, |tier| tier.fee_bps)
+    };
+    (rental_request.price_due_e8s() as u128 * fee_bps as u128 / BPS_DENOMINATOR as u128) as u64
+}
+
+// Replace the cancellation fee tiers and the no-show fee
+#[ic_cdk::update]
+fn set_cancellation_policy(
+    tiers: Vec<CancellationTier>,
+    no_show_fee_bps: u64,
+) -> Result<Config, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    validate_cancellation_policy(&tiers, no_show_fee_bps)?;
+    let mut updated_config = config();
+    let before = audit_summary(&(
+        &updated_config.cancellation_tiers,
+        updated_config.no_show_fee_bps,
+    ));
+    updated_config.cancellation_tiers = tiers;
+    updated_config.no_show_fee_bps = no_show_fee_bps;
+    set_config(updated_config.clone());
+    record_audit(
+        "set_cancellation_policy",
+        None,
+        before,
+        audit_summary(&(
+            &updated_config.cancellation_tiers,
+            updated_config.no_show_fee_bps,
+        )),
+    );
+    Ok(updated_config)
+}
+
+// Move the end of an open rental later. The added days are priced at the current rates
+// with the protection and add-ons booked, and the extension is kept as history.
+#[ic_cdk::update]
+fn request_extension(rental_id: u64, new_end_date: u64) -> Result<RentalExtension, Error> {
+    check_rate_limit()?;
+    let mut rental_request = get_rental_request(rental_id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    if !rental_request.status.is_open() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request with id={} cannot be extended in status {:?}",
+                rental_id, rental_request.status
+            ),
+        });
+    }
+    let previous_end_date = rental_request.end_date;
+    if new_end_date <= previous_end_date || new_end_date <= ic_cdk::api::time() {
+        return Err(Error::InvalidInput {
+            msg: "The new end date must be after the current end date and in the future"
+                .to_string(),
+        });
+    }
+    let start_date = rental_request.start_date;
+    validate_rental_period(start_date, new_end_date)?;
+    let customer = get_customer(rental_request.customer_id)?;
+    ensure_license_valid(&customer, new_end_date)?;
+    let car = get_car(rental_request.car_id)?;
+    ensure_car_available(
+        car.id,
+        previous_end_date,
+        new_end_date,
+        Some(ExcludedBooking::Rental(rental_id)),
+    )?;
+    ensure_car_insured(car.id, new_end_date)?;
+    let add_ons = rental_add_ons(rental_id);
+    let selections: Vec<AddOnSelection> = add_ons
+        .iter()
+        .map(|add_on| AddOnSelection {
+            add_on_id: add_on.add_on_id,
+            quantity: add_on.quantity,
+        })
+        .collect();
+    resolve_add_ons(
+        &selections,
+        rental_request.pickup_branch_id,
+        start_date,
+        new_end_date,
+        Some(rental_id),
+    )?;
+
+    // Price the rental with and without the added days so only those days are charged
+    let protection = match rental_request.protection.clone() {
+        Some(protection) => protection,
+        None => protection_plan(ProtectionTier::Basic)?,
+    };
+    let quote_for = |end_date: u64| {
+        compute_quote(
+            &car,
+            start_date,
+            end_date,
+            rental_request.pickup_branch_id,
+            rental_request.dropoff_branch_id,
+            protection.clone(),
+            &add_ons,
+        )
+    };
+    let before = quote_for(previous_end_date);
+    let after = quote_for(new_end_date);
+    let price_delta_e8s = after.total_e8s.saturating_sub(before.total_e8s);
+
+    rental_request.end_date = new_end_date;
+    rental_request.overdue = false;
+    rental_request.quoted_price_e8s += price_delta_e8s;
+    rental_request.protection_e8s += after.protection_e8s.saturating_sub(before.protection_e8s);
+    rental_request.add_ons_e8s += after.add_ons_e8s.saturating_sub(before.add_ons_e8s);
+    rental_request.included_km = included_km(car.category, start_date, new_end_date);
+    if let Some(coupon) = rental_request
+        .coupon_id
+        .and_then(|coupon_id| COUPON_STORAGE.with(|storage| storage.borrow().get(&coupon_id)))
+    {
+        rental_request.coupon_discount_e8s = coupon.discount_on(rental_request.quoted_price_e8s);
+    }
+    if let Some(account_id) = rental_request.corporate_account_id {
+        ensure_within_spending_limit(
+            account_id,
+            start_date,
+            rental_request.price_due_e8s(),
+            Some(rental_id),
+        )?;
+    }
+    store_rental_request(&mut rental_request);
+
+    let extension = RentalExtension {
+        id: next_id()?,
+        rental_id,
+        previous_end_date,
+        new_end_date,
+        price_delta_e8s,
+        requested_by: caller(),
+        requested_at: ic_cdk::api::time(),
+    };
+    RENTAL_EXTENSIONS.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert((rental_id, extension.id), extension.clone())
+    });
+    record_audit(
+        "request_extension",
+        Some(rental_id),
+        None,
+        audit_summary(&extension),
+    );
+    Ok(extension)
+}
+
+// List the extensions of a rental, oldest first
+#[ic_cdk::query]
+fn list_rental_extensions(rental_id: u64) -> Result<Vec<RentalExtension>, Error> {
+    let rental_request = get_rental_request(rental_id)?;
+    if ensure_owner_or_admin(&rental_request.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(RENTAL_EXTENSIONS.with(|storage| {
+        storage
+            .borrow()
+            .range((rental_id, 0)..=(rental_id, u64::MAX))
+            .map(|(_, extension)| extension)
+            .collect()
+    }))
+}
+
+// Implement the pickup handover: the customer shows the code of an active rental at the
+// branch, and staff confirm it to release the car
+#[ic_cdk::query]
+fn get_handover_code(rental_id: u64) -> Result<HandoverCode, Error> {
+    let rental_request = get_rental_request(rental_id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    HANDOVER_CODES
+        .with(|codes| codes.borrow().get(&rental_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Rental request id={} has no handover code", rental_id),
+        })
+}
+
+// Replace the handover code of a rental, for instance after it was shared by mistake
+#[ic_cdk::update]
+async fn reissue_handover_code(rental_id: u64) -> Result<HandoverCode, Error> {
+    check_rate_limit()?;
+    let rental_request = get_rental_request(rental_id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    let handover_code = issue_handover_code(rental_id).await?;
+    // The code itself is a secret and is left out of the log
+    record_audit("reissue_handover_code", Some(rental_id), None, None);
+    Ok(handover_code)
+}
+
+async fn issue_handover_code(rental_id: u64) -> Result<HandoverCode, Error> {
+    let code = format!("{:08}", random_code_value(4).await? % 100_000_000);
+    // The rental may have changed while waiting for randomness
+    let rental_request = get_rental_request(rental_id)?;
+    if rental_request.status != RentalStatus::Active || rental_request.picked_up_at.is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request id={} is not waiting to be picked up",
+                rental_id
+            ),
+        });
+    }
+    let handover_code = HandoverCode {
+        rental_id,
+        code,
+        issued_at: ic_cdk::api::time(),
+    };
+    HANDOVER_CODES.with(|codes| codes.borrow_mut().insert(rental_id, handover_code.clone()));
+    Ok(handover_code)
+}
+
+// Implement signed rental agreements. When a rental is approved, its terms are hashed and
+// signed with the canister's threshold ECDSA key, so the customer and the operator can
+// each prove off-chain what was agreed.
+#[ic_cdk::query]
+fn get_signed_agreement(rental_id: u64) -> Result<SignedAgreement, Error> {
+    let rental_request = get_rental_request(rental_id)?;
+    if ensure_owner_or_admin(&rental_request.owner).is_err() {
+        ensure_car_controller(rental_request.car_id)?;
+    }
+    SIGNED_AGREEMENTS
+        .with(|storage| storage.borrow().get(&rental_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Rental request id={} has no signed agreement", rental_id),
+        })
+}
+
+// Sign the current terms of an approved rental, for instance after it was extended or
+// when signing on approval failed
+#[ic_cdk::update]
+async fn sign_rental_agreement(rental_id: u64) -> Result<SignedAgreement, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    let before = SIGNED_AGREEMENTS.with(|storage| storage.borrow().get(&rental_id));
+    let agreement = sign_agreement(rental_id).await?;
+    record_audit(
+        "sign_rental_agreement",
+        Some(rental_id),
+        before.as_ref().and_then(audit_summary),
+        audit_summary(&agreement),
+    );
+    Ok(agreement)
+}
+
+// Get the SEC1-encoded public key agreement signatures verify against
+#[ic_cdk::update]
+async fn get_agreement_public_key() -> Result<Vec<u8>, Error> {
+    check_rate_limit()?;
+    let (response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: vec![AGREEMENT_DERIVATION_PATH.to_vec()],
+        key_id: agreement_key_id(),
+    })
+    .await
+    .map_err(|(code, msg)| Error::CallFailed {
+        msg: format!("ecdsa_public_key failed: {:?} {}", code, msg),
+    })?;
+    Ok(response.public_key)
+}
+
+async fn sign_agreement(rental_id: u64) -> Result<SignedAgreement, Error> {
+    let rental_request = get_rental_request(rental_id)?;
+    if !matches!(
+        rental_request.status,
+        RentalStatus::Approved | RentalStatus::Active | RentalStatus::Completed
+    ) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request id={} cannot be signed in status {:?}",
+                rental_id, rental_request.status
+            ),
+        });
+    }
+    let terms = agreement_terms(ic_cdk::id(), &rental_request)?;
+    let terms_hash = Sha256::digest(terms.as_bytes()).to_vec();
+    let key_id = agreement_key_id();
+    let (response,) = sign_with_ecdsa(SignWithEcdsaArgument {
+        message_hash: terms_hash.clone(),
+        derivation_path: vec![AGREEMENT_DERIVATION_PATH.to_vec()],
+        key_id: key_id.clone(),
+    })
+    .await
+    .map_err(|(code, msg)| Error::CallFailed {
+        msg: format!("sign_with_ecdsa failed: {:?} {}", code, msg),
+    })?;
+    let agreement = SignedAgreement {
+        rental_id,
+        terms,
+        terms_hash,
+        signature: response.signature,
+        key_name: key_id.name,
+        signed_at: ic_cdk::api::time(),
+    };
+    check_record_size(&agreement)?;
+    SIGNED_AGREEMENTS.with(|storage| storage.borrow_mut().insert(rental_id, agreement.clone()));
+    Ok(agreement)
+}
+
+// Implement human-readable rental agreements. When a rental is approved, the agreement
+// template is rendered with the rental's details and stored with the hash of the text.
+// Admins can replace the template; documents already generated keep their text.
+#[ic_cdk::query]
+fn get_agreement_document(rental_id: u64) -> Result<AgreementDocument, Error> {
+    let rental_request = get_rental_request(rental_id)?;
+    if ensure_owner_or_admin(&rental_request.owner).is_err() {
+        ensure_car_controller(rental_request.car_id)?;
+    }
+    AGREEMENT_DOCUMENTS
+        .with(|storage| storage.borrow().get(&rental_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Rental request id={} has no agreement document", rental_id),
+        })
+}
+
+// Render the agreement of an approved rental again, for instance after it was extended or
+// the template changed
+#[ic_cdk::update]
+fn regenerate_agreement_document(rental_id: u64) -> Result<AgreementDocument, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    let rental_request = get_rental_request(rental_id)?;
+    if !matches!(
+        rental_request.status,
+        RentalStatus::Approved | RentalStatus::Active | RentalStatus::Completed
+    ) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request id={} has no agreement in status {:?}",
+                rental_id, rental_request.status
+            ),
+        });
+    }
+    let before = AGREEMENT_DOCUMENTS.with(|storage| storage.borrow().get(&rental_id));
+    let document = generate_agreement_document(&rental_request)?;
+    record_audit(
+        "regenerate_agreement_document",
+        Some(rental_id),
+        before.as_ref().and_then(audit_summary),
+        audit_summary(&document),
+    );
+    Ok(document)
+}
+
+// Replace the agreement template; an empty text restores the built-in one
+#[ic_cdk::update]
+fn set_agreement_template(text: String) -> Result<AgreementTemplate, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    if text.len() > MAX_AGREEMENT_TEMPLATE_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "The template must be at most {} bytes",
+                MAX_AGREEMENT_TEMPLATE_BYTES
+            ),
+        });
+    }
+    let before = agreement_template();
+    let template = AgreementTemplate {
+        version: before.version + 1,
+        text: text.trim().to_string(),
+        updated_by: Some(caller()),
+        updated_at: ic_cdk::api::time(),
+    };
+    AGREEMENT_TEMPLATE
+        .with(|cell| cell.borrow_mut().set(template.clone()))
+        .expect("Cannot store the agreement template");
+    record_audit(
+        "set_agreement_template",
+        Some(template.version),
+        audit_summary(&before),
+        audit_summary(&template),
+    );
+    Ok(template)
+}
+
+// Get the agreement template, with the built-in text when admins did not set one
+#[ic_cdk::query]
+fn get_agreement_template() -> Result<AgreementTemplate, Error> {
+    require_any_role(&[Role::Staff])?;
+    let mut template = agreement_template();
+    if template.text.is_empty() {
+        template.text = DEFAULT_AGREEMENT_TEMPLATE.to_string();
+    }
+    Ok(template)
+}
+
+fn agreement_template() -> AgreementTemplate {
+    AGREEMENT_TEMPLATE.with(|cell| cell.borrow().get().clone())
+}
+
+fn generate_agreement_document(rental_request: &RentalRequest) -> Result<AgreementDocument, Error> {
+    let customer = get_customer(rental_request.customer_id)?;
+    let template = agreement_template();
+    let text = if template.text.is_empty() {
+        DEFAULT_AGREEMENT_TEMPLATE
+    } else {
+        &template.text
+    };
+    let text = render_agreement(text, rental_request, &customer);
+    let document = AgreementDocument {
+        rental_id: rental_request.id,
+        text_hash: Sha256::digest(text.as_bytes()).to_vec(),
+        text,
+        template_version: template.version,
+        generated_at: ic_cdk::api::time(),
+    };
+    check_record_size(&document)?;
+    AGREEMENT_DOCUMENTS.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(rental_request.id, document.clone())
+    });
+    Ok(document)
+}
+
+// Fill in an agreement template. On top of the placeholders of messages, {customer_id},
+// {license_plate}, {pickup_branch}, {dropoff_branch}, {price}, {discount}, {deposit},
+// {included_km}, {fuel_policy}, {protection}, and {terms_version} are replaced.
+fn render_agreement(text: &str, rental_request: &RentalRequest, customer: &Customer) -> String {
+    let car = CAR_STORAGE.with(|storage| storage.borrow().get(&rental_request.car_id));
+    let branch = |branch_id: Option<u64>| {
+        branch_id
+            .and_then(|id| BRANCH_STORAGE.with(|storage| storage.borrow().get(&id)))
+            .map_or_else(|| "the car's location".to_string(), |branch| branch.name)
+    };
+    let deposit_e8s = car
+        .as_ref()
+        .map_or(0, |car| deposit_for_category(car.category));
+    let terms_version = TERMS_ACCEPTANCES.with(|storage| {
+        storage
+            .borrow()
+            .range((customer.id, 0)..=(customer.id, u64::MAX))
+            .last()
+            .map_or_else(
+                || "none".to_string(),
+                |((_, version), _)| version.to_string(),
+            )
+    });
+    fill_placeholders(text, |name| {
+        Some(match name {
+            "customer_id" => customer.id.to_string(),
+            "license_plate" => car
+                .as_ref()
+                .map_or_else(String::new, |car| car.license_plate.clone()),
+            "pickup_branch" => branch(rental_request.pickup_branch_id),
+            "dropoff_branch" => branch(rental_request.dropoff_branch_id),
+            "price" => icp_string(rental_request.quoted_price_e8s),
+            "discount" => {
+                icp_string(rental_request.points_discount_e8s + rental_request.coupon_discount_e8s)
+            }
+            "deposit" => icp_string(deposit_e8s),
+            "included_km" => rental_request
+                .included_km
+                .map_or_else(|| "unlimited".to_string(), |km| format!("{} km", km)),
+            "fuel_policy" => format!(
+                "{:?}",
+                rental_request.fuel_policy.unwrap_or(FuelPolicy::FullToFull)
+            ),
+            "protection" => rental_request.protection.as_ref().map_or_else(
+                || "none".to_string(),
+                |protection| format!("{:?}", protection.tier),
+            ),
+            "terms_version" => terms_version.clone(),
+            _ => return message_placeholder(name, rental_request, customer),
+        })
+    })
+}
+
+// Encode the terms of a rental in the canonical form that is signed
+fn agreement_terms(
+    canister_id: Principal,
+    rental_request: &RentalRequest,
+) -> Result<String, Error> {
+    let car = get_car(rental_request.car_id)?;
+    let terms = AgreementTerms {
+        canister_id: canister_id.to_text(),
+        rental_id: rental_request.id,
+        customer_id: rental_request.customer_id,
+        renter: rental_request.owner.to_text(),
+        car_id: car.id,
+        car: format!("{} {} {}", car.year, car.make, car.model),
+        license_plate: car.license_plate,
+        start_date: rental_request.start_date,
+        end_date: rental_request.end_date,
+        pickup_branch_id: rental_request.pickup_branch_id,
+        dropoff_branch_id: rental_request.dropoff_branch_id,
+        price_e8s: rental_request.quoted_price_e8s,
+        discount_e8s: rental_request.points_discount_e8s + rental_request.coupon_discount_e8s,
+        deposit_e8s: deposit_for_category(car.category),
+        included_km: rental_request.included_km,
+        fuel_policy: rental_request.fuel_policy,
+        protection: rental_request
+            .protection
+            .as_ref()
+            .map(|protection| protection.tier),
+    };
+    serde_json::to_string(&terms).map_err(|error| Error::InvalidInput {
+        msg: format!(
+            "Terms of rental request id={} cannot be encoded: {}",
+            rental_request.id, error
+        ),
+    })
+}
+
+fn agreement_key_id() -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        name: config()
+            .ecdsa_key_name
+            .unwrap_or_else(|| MAINNET_ECDSA_KEY_NAME.to_string()),
+    }
+}
+
+// Check the code presented by the customer and record the pickup; the code is used up and
+// the rental can then only be completed
+#[ic_cdk::update]
+fn confirm_pickup(rental_id: u64, code: String) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    let mut rental_request = get_rental_request(rental_id)?;
+    if rental_request.status != RentalStatus::Active || rental_request.picked_up_at.is_some() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request id={} is not waiting to be picked up",
+                rental_id
+            ),
+        });
+    }
+    match HANDOVER_CODES.with(|codes| codes.borrow().get(&rental_id)) {
+        Some(handover_code) if handover_code.code == code.trim() => {}
+        _ => {
+            return Err(Error::Unauthorized {
+                msg: format!("Invalid handover code for rental request id={}", rental_id),
+            })
+        }
+    }
+
+    let before = audit_summary(&rental_request);
+    rental_request.picked_up_at = Some(ic_cdk::api::time());
+    HANDOVER_CODES.with(|codes| codes.borrow_mut().remove(&rental_id));
+    store_rental_request(&mut rental_request);
+    record_audit(
+        "confirm_pickup",
+        Some(rental_id),
+        before,
+        audit_summary(&rental_request),
+    );
+    Ok(rental_request)
+}
+
+// Hand the car over to the customer, recording the odometer and fuel level;
+// approved rentals become active
+#[ic_cdk::update]
+fn check_out_car(
+    rental_id: u64,
+    odometer_km: u64,
+    fuel_percent: u8,
+) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    validate_fuel_percent(fuel_percent)?;
+    let mut rental_request = get_rental_request(rental_id)?;
+    if !matches!(
+        rental_request.status,
+        RentalStatus::Approved | RentalStatus::Active
+    ) || rental_request.checkout_odometer_km.is_some()
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request with id={} cannot be checked out in status {:?}",
+                rental_id, rental_request.status
+            ),
+        });
+    }
+    record_odometer(rental_request.car_id, odometer_km)?;
+    let before = audit_summary(&rental_request);
+    // The car is out until it is checked in
+    if let Ok(mut car) = get_car(rental_request.car_id) {
+        car.available = false;
+        store_car(&mut car);
+    }
+
+    rental_request.status = RentalStatus::Active;
+    rental_request.checkout_odometer_km = Some(odometer_km);
+    rental_request.checkout_fuel_percent = Some(fuel_percent);
+    store_rental_request(&mut rental_request);
+    record_audit(
+        "check_out_car",
+        Some(rental_id),
+        before,
+        audit_summary(&rental_request),
+    );
+    Ok(rental_request)
+}
+
+// Take the car back and close the rental: record its condition, bill late return, mileage,
+// and fuel, free the car at the dropoff branch, and settle the deposit
+#[ic_cdk::update]
+async fn check_in_rental(rental_id: u64, payload: CheckInPayload) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    let before = get_rental_request(rental_id).ok();
+    let rental_request = check_in(rental_id, payload, false).await?;
+    record_audit(
+        "check_in_rental",
+        Some(rental_id),
+        before.as_ref().and_then(audit_summary),
+        audit_summary(&rental_request),
+    );
+    Ok(rental_request)
+}
+
+// Check a car in at least one full day before the rental ends. The unused days are credited
+// at early_return_credit_bps of their share of the price, and early_return_fee_e8s is billed.
+#[ic_cdk::update]
+async fn return_early(rental_id: u64, payload: CheckInPayload) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    let before = get_rental_request(rental_id).ok();
+    let rental_request = check_in(rental_id, payload, true).await?;
+    record_audit(
+        "return_early",
+        Some(rental_id),
+        before.as_ref().and_then(audit_summary),
+        audit_summary(&rental_request),
+    );
+    Ok(rental_request)
+}
+
+async fn check_in(
+    rental_id: u64,
+    payload: CheckInPayload,
+    early_return: bool,
+) -> Result<RentalRequest, Error> {
+    require_any_role(&[Role::Staff])?;
+    validate_check_in_payload(&payload)?;
+    let CheckInPayload {
+        odometer_km,
+        fuel_percent,
+        condition_notes,
+    } = payload;
+    let mut rental_request = get_rental_request(rental_id)?;
+    if rental_request.status != RentalStatus::Active
+        || rental_request.checkout_odometer_km.is_none()
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request with id={} must be checked out before it is checked in",
+                rental_id
+            ),
+        });
+    }
+    let now = ic_cdk::api::time();
+    let unused_days = rental_request.end_date.saturating_sub(now) / NANOS_PER_DAY;
+    if early_return && unused_days == 0 {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request with id={} ends within a day and is checked in normally",
+                rental_id
+            ),
+        });
+    }
+    record_odometer(rental_request.car_id, odometer_km)?;
+
+    // Once the rental is stored as completed an error would leave it half closed, so a
+    // failure traps and rolls the whole check-in back instead
+    let report = ConditionReport {
+        rental_id,
+        car_id: rental_request.car_id,
+        odometer_km,
+        fuel_percent,
+        notes: condition_notes.trim().to_string(),
+        checked_in_by: caller(),
+        checked_in_at: now,
+    };
+    let closed = close_rental(&mut rental_request, report, early_return, unused_days, now);
+    if let Err(error) = closed {
+        ic_cdk::trap(&format!(
+            "Cannot check in rental request with id={}: {:?}",
+            rental_id, error
+        ));
+    }
+
+    // The rental is closed either way; a deposit left held is settled by an admin
+    let _ = settle_deposit(&rental_request).await;
+    // The invoice stands in the base currency if no rate can be had
+    let _ = convert_invoice(rental_id, rental_request.customer_id).await;
+    Ok(rental_request)
+}
+
+// Complete a returned rental: store its condition report, bill what is owed, issue the
+// invoice, pay out credits, loyalty points, and earnings, and free the car
+fn close_rental(
+    rental_request: &mut RentalRequest,
+    report: ConditionReport,
+    early_return: bool,
+    unused_days: u64,
+    now: u64,
+) -> Result<(), Error> {
+    let rental_id = rental_request.id;
+    rental_request.checkin_odometer_km = Some(report.odometer_km);
+    rental_request.checkin_fuel_percent = Some(report.fuel_percent);
+    rental_request.status = RentalStatus::Completed;
+    rental_request.overdue = false;
+    rental_request.completed_at = Some(now);
+    store_rental_request(rental_request);
+    RENTAL_TIMING.with(|cell| {
+        let mut timing = cell.borrow().get().clone();
+        timing.record(rental_request);
+        cell.borrow_mut()
+            .set(timing)
+            .expect("Cannot update the rental timing stats");
+    });
+    CONDITION_REPORTS.with(|reports| reports.borrow_mut().insert(rental_id, report));
+
+    bill_late_return(rental_request, now)?;
+    bill_mileage_overage(rental_request, now)?;
+    bill_refueling(rental_request, now)?;
+    if early_return {
+        settle_early_return(rental_request, unused_days, now)?;
+    }
+    let invoice = issue_invoice(rental_request, now);
+    // A credit on a paid rental is returned to the customer's wallet
+    let refundable_e8s = rental_request.early_return_credit_e8s.min(invoice.paid_e8s);
+    if refundable_e8s > 0 {
+        let _ = post_wallet_transaction(
+            rental_request.customer_id,
+            WalletTransactionKind::EarlyReturnCredit { rental_id },
+            refundable_e8s as i64,
+            now,
+        );
+    }
+    award_loyalty_points(rental_request.customer_id, invoice.paid_e8s);
+    accrue_owner_earnings(rental_request, &invoice, refundable_e8s)?;
+    reward_referral(rental_request.customer_id, now);
+    return_car(rental_request);
+    Ok(())
+}
+
+// Credit the unused days of an early return, prorated over the price without the drop fee,
+// and bill the early return fee
+fn settle_early_return(
+    rental_request: &mut RentalRequest,
+    unused_days: u64,
+    returned_at: u64,
+) -> Result<(), Error> {
+    let config = config();
+    let days = rental_days(rental_request.start_date, rental_request.end_date).max(1);
+    let unused_e8s = (rental_request
+        .quoted_price_e8s
+        .saturating_sub(rental_request.drop_fee_e8s) as u128
+        * unused_days.min(days) as u128
+        / days as u128) as u64;
+    rental_request.early_return_credit_e8s = (unused_e8s as u128
+        * config.early_return_credit_bps as u128
+        / BPS_DENOMINATOR as u128) as u64;
+    store_rental_request(rental_request);
+    if config.early_return_fee_e8s > 0 {
+        let charge = Charge {
+            id: next_id()?,
+            rental_id: rental_request.id,
+            customer_id: rental_request.customer_id,
+            kind: ChargeKind::EarlyReturnFee,
+            description: format!("Returned {} day(s) early", unused_days),
+            amount_e8s: config.early_return_fee_e8s,
+            created_at: returned_at,
+            settled: false,
+        };
+        CHARGE_STORAGE.with(|storage| storage.borrow_mut().insert(charge.id, charge));
+    }
+    Ok(())
+}
+
+// Free a returned car and keep it where it was dropped off. A car returned into a
+// maintenance window becomes available once the window ends.
+fn return_car(rental_request: &RentalRequest) {
+    let in_maintenance = maintenance_windows_for_car(rental_request.car_id)
+        .into_iter()
+        .find(|window| window.status == MaintenanceWindowStatus::InProgress);
+    if let Some(mut window) = in_maintenance.clone() {
+        window.car_was_available = true;
+        MAINTENANCE_WINDOW_STORAGE.with(|storage| storage.borrow_mut().insert(window.id, window));
+    }
+    if let Ok(mut car) = get_car(rental_request.car_id) {
+        car.available = in_maintenance.is_none();
+        if let Some(dropoff_branch_id) = rental_request.dropoff_branch_id {
+            car.branch_id = Some(dropoff_branch_id);
+        }
+        store_car(&mut car);
+    }
+}
+
+// Cover the rental's unsettled charges from its deposit and return the rest, unless damage
+// reported for the rental is still waiting for a decision
+async fn settle_deposit(rental_request: &RentalRequest) -> Result<(), Error> {
+    let rental_id = rental_request.id;
+    let deposit = match DEPOSIT_STORAGE.with(|storage| storage.borrow().get(&rental_id)) {
+        Some(deposit) if deposit.remaining_e8s() > 0 => deposit,
+        _ => return Ok(()),
+    };
+    let fee_e8s = config().ledger_fee_e8s;
+
+    let mut covered_e8s: u64 = 0;
+    let mut covered: Vec<Charge> = Vec::new();
+    let charges: Vec<Charge> = CHARGE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, charge)| charge)
+            .filter(|charge| charge.rental_id == rental_id && !charge.settled)
+            .collect()
+    });
+    for charge in charges {
+        if covered_e8s + charge.amount_e8s <= deposit.remaining_e8s() {
+            covered_e8s += charge.amount_e8s;
+            covered.push(charge);
+        }
+    }
+    if covered_e8s > fee_e8s {
+        let to = Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        };
+        let reason = "Charges settled at check-in".to_string();
+        move_deposit(rental_id, covered_e8s, reason, to, true).await?;
+        CHARGE_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            for mut charge in covered {
+                charge.settled = true;
+                storage.insert(charge.id, charge);
+            }
+        });
+    }
+
+    let damage_pending = damage_reports_for_car(rental_request.car_id)
+        .iter()
+        .any(|report| report.rental_id == rental_id && report.status == DamageStatus::Reported);
+    let remaining_e8s = get_deposit(rental_id)?.remaining_e8s();
+    if !damage_pending && remaining_e8s > fee_e8s {
+        let to = Account {
+            owner: rental_request.owner,
+            subaccount: None,
+        };
+        let reason = "Returned at check-in".to_string();
+        move_deposit(rental_id, remaining_e8s, reason, to, false).await?;
+    }
+    Ok(())
+}
+
+// Owners see the condition their car was returned in
+#[ic_cdk::query]
+fn get_condition_report(rental_id: u64) -> Result<ConditionReport, Error> {
+    let rental_request = get_rental_request(rental_id)?;
+    if ensure_owner_or_admin(&rental_request.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    CONDITION_REPORTS
+        .with(|reports| reports.borrow().get(&rental_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Rental request id={} has not been checked in", rental_id),
+        })
+}
+
+// Bill the kilometers driven beyond the rental's included mileage
+fn bill_mileage_overage(rental_request: &RentalRequest, returned_at: u64) -> Result<(), Error> {
+    let (included_km, checkout, checkin) = match (
+        rental_request.included_km,
+        rental_request.checkout_odometer_km,
+        rental_request.checkin_odometer_km,
+    ) {
+        (Some(included_km), Some(checkout), Some(checkin)) => (included_km, checkout, checkin),
+        _ => return Ok(()),
+    };
+    let driven_km = checkin.saturating_sub(checkout);
+    let excess_km = driven_km.saturating_sub(included_km);
+    if excess_km == 0 {
+        return Ok(());
+    }
+    let charge = Charge {
+        id: next_id()?,
+        rental_id: rental_request.id,
+        customer_id: rental_request.customer_id,
+        kind: ChargeKind::MileageOverage,
+        description: format!(
+            "Drove {} km, {} km over the {} km included",
+            driven_km, excess_km, included_km
+        ),
+        amount_e8s: excess_km.saturating_mul(config().mileage_overage_per_km_e8s),
+        created_at: returned_at,
+        settled: false,
+    };
+    CHARGE_STORAGE.with(|storage| storage.borrow_mut().insert(charge.id, charge));
+    Ok(())
+}
+
+// Bill the fuel missing from a full-to-full rental at the configured rate per percent of tank
+fn bill_refueling(rental_request: &RentalRequest, returned_at: u64) -> Result<(), Error> {
+    if rental_request.fuel_policy == Some(FuelPolicy::Prepaid) {
+        return Ok(());
+    }
+    let missing_percent = match (
+        rental_request.checkout_fuel_percent,
+        rental_request.checkin_fuel_percent,
+    ) {
+        (Some(checkout), Some(checkin)) => checkout.saturating_sub(checkin),
+        _ => return Ok(()),
+    };
+    if missing_percent == 0 {
+        return Ok(());
+    }
+    let charge = Charge {
+        id: next_id()?,
+        rental_id: rental_request.id,
+        customer_id: rental_request.customer_id,
+        kind: ChargeKind::Refueling,
+        description: format!("Refueling {}% of the tank", missing_percent),
+        amount_e8s: (missing_percent as u64).saturating_mul(config().refuel_fee_per_percent_e8s),
+        created_at: returned_at,
+        settled: false,
+    };
+    CHARGE_STORAGE.with(|storage| storage.borrow_mut().insert(charge.id, charge));
+    Ok(())
+}
+
+fn validate_fuel_percent(fuel_percent: u8) -> Result<(), Error> {
+    if fuel_percent > 100 {
+        return Err(Error::InvalidInput {
+            msg: "Fuel level must be a percentage between 0 and 100".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// The mileage included in a rental of a car category, if that category has a cap
+fn included_km(category: CarCategory, start_date: u64, end_date: u64) -> Option<u64> {
+    config()
+        .included_km_per_day
+        .iter()
+        .find(|mileage| mileage.category == category)
+        .map(|mileage| rental_days(start_date, end_date).saturating_mul(mileage.km_per_day))
+}
+
+// Implement insurance tracking for cars
+#[ic_cdk::update]
+fn set_car_insurance(
+    car_id: u64,
+    payload: InsurancePolicyPayload,
+) -> Result<InsurancePolicy, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    get_car(car_id)?;
+    if payload.provider.trim().is_empty()
+        || payload.provider.len() > 64
+        || payload.policy_number.trim().is_empty()
+        || payload.policy_number.len() > 64
+        || payload.coverage.len() > 128
+    {
+        return Err(Error::InvalidInput {
+            msg: "Provider and policy number must be between 1 and 64 characters, and coverage at most 128"
+                .to_string(),
+        });
+    }
+
+    let policy = InsurancePolicy {
+        car_id,
+        provider: payload.provider,
+        policy_number: payload.policy_number,
+        coverage: payload.coverage,
+        expires_at: payload.expires_at,
+    };
+    check_record_size(&policy)?;
+    let previous =
+        INSURANCE_STORAGE.with(|storage| storage.borrow_mut().insert(car_id, policy.clone()));
+    record_audit(
+        "set_car_insurance",
+        Some(car_id),
+        previous.as_ref().and_then(audit_summary),
+        audit_summary(&policy),
+    );
+    Ok(policy)
+}
+
+#[ic_cdk::query]
+fn get_car_insurance(car_id: u64) -> Result<InsurancePolicy, Error> {
+    require_any_role(&[Role::Staff])?;
+    match INSURANCE_STORAGE.with(|storage| storage.borrow().get(&car_id)) {
+        Some(policy) => Ok(policy),
+        None => Err(Error::NotFound {
+            msg: format!("No insurance policy recorded for car id={}", car_id),
+        }),
+    }
+}
+
+// List the policies that expire within the given number of days, including lapsed ones,
+// soonest first
+#[ic_cdk::query]
+fn list_cars_with_expiring_insurance(within_days: u64) -> Result<Vec<InsurancePolicy>, Error> {
+    require_any_role(&[Role::Staff])?;
+    let deadline = ic_cdk::api::time().saturating_add(within_days.saturating_mul(NANOS_PER_DAY));
+    let mut policies: Vec<InsurancePolicy> = INSURANCE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, policy)| policy)
+            .filter(|policy| policy.expires_at <= deadline)
+            .collect()
+    });
+    policies.sort_by_key(|policy| policy.expires_at);
+    Ok(policies)
+}
+
+// A car with a recorded policy cannot be booked past the policy's expiry
+fn ensure_car_insured(car_id: u64, end_date: u64) -> Result<(), Error> {
+    match INSURANCE_STORAGE.with(|storage| storage.borrow().get(&car_id)) {
+        Some(policy) if policy.expires_at < end_date => Err(Error::Conflict {
+            msg: format!(
+                "The insurance of car id={} expires before the end of the rental",
+                car_id
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Implement damage reports, filed by staff when a car comes back
+#[ic_cdk::update]
+fn file_damage_report(payload: DamageReportPayload) -> Result<DamageReport, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    validate_damage_report_payload(&payload)?;
+    let DamageReportPayload {
+        rental_id,
+        description,
+        severity,
+        photo_ids,
+        estimated_cost_e8s,
+    } = payload;
+    let rental_request = get_rental_request(rental_id)?;
+    if !matches!(
+        rental_request.status,
+        RentalStatus::Active | RentalStatus::Completed
+    ) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Damage cannot be reported for rental request id={} in status {:?}",
+                rental_id, rental_request.status
+            ),
+        });
+    }
+    for photo_id in &photo_ids {
+        let photo = get_photo(*photo_id)?;
+        if photo.car_id != rental_request.car_id || !photo.finished {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "Photo id={} is not a finished photo of car id={}",
+                    photo_id, rental_request.car_id
+                ),
+            });
+        }
+    }
+
+    let report = DamageReport {
+        id: next_id()?,
+        rental_id,
+        car_id: rental_request.car_id,
+        description,
+        severity,
+        photo_ids,
+        estimated_cost_e8s,
+        status: DamageStatus::Reported,
+        reported_by: caller(),
+        reported_at: ic_cdk::api::time(),
+        deducted_e8s: 0,
+        deposit_transfer_id: None,
+    };
+    check_record_size(&report)?;
+    DAMAGE_REPORT_STORAGE.with(|storage| storage.borrow_mut().insert(report.id, report.clone()));
+    DAMAGE_REPORTS_BY_CAR.with(|index| index.borrow_mut().insert((report.car_id, report.id), ()));
+    record_audit(
+        "file_damage_report",
+        Some(report.id),
+        None,
+        audit_summary(&report),
+    );
+    Ok(report)
+}
+
+// Cover a reported damage by keeping part of the rental's deposit
+#[ic_cdk::update]
+async fn deduct_damage_from_deposit(
+    report_id: u64,
+    amount_e8s: u64,
+) -> Result<DamageReport, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let report = get_damage_report(report_id)?;
+    if report.status != DamageStatus::Reported {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Damage report id={} is already {:?}",
+                report_id, report.status
+            ),
+        });
+    }
+    // Customers never pay more towards damage than the deductible of their protection
+    let rental_request = get_rental_request(report.rental_id)?;
+    if let Some(protection) = rental_request.protection {
+        let already_deducted_e8s: u64 = damage_reports_for_car(report.car_id)
+            .iter()
+            .filter(|other| other.rental_id == report.rental_id)
+            .map(|other| other.deducted_e8s)
+            .sum();
+        let allowed_e8s = protection
+            .deductible_e8s
+            .saturating_sub(already_deducted_e8s);
+        if amount_e8s > allowed_e8s {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "The {:?} protection of rental request id={} leaves {} e8s of its deductible",
+                    protection.tier, report.rental_id, allowed_e8s
+                ),
+            });
+        }
+    }
+    let to = Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
+    };
+    let reason = format!("Damage report id={}: {}", report_id, report.description);
+    let deposit = move_deposit(report.rental_id, amount_e8s, reason, to, true).await?;
+
+    let mut report = get_damage_report(report_id)?;
+    let before = audit_summary(&report);
+    report.status = DamageStatus::Deducted;
+    report.deducted_e8s = amount_e8s;
+    report.deposit_transfer_id = deposit
+        .movements
+        .last()
+        .map(|movement| movement.transfer_id);
+    DAMAGE_REPORT_STORAGE.with(|storage| storage.borrow_mut().insert(report_id, report.clone()));
+    record_audit(
+        "deduct_damage_from_deposit",
+        Some(report_id),
+        before,
+        audit_summary(&report),
+    );
+    Ok(report)
+}
+
+// Close a damage report without charging the customer
+#[ic_cdk::update]
+fn waive_damage_report(report_id: u64) -> Result<DamageReport, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    let mut report = get_damage_report(report_id)?;
+    if report.status != DamageStatus::Reported {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Damage report id={} is already {:?}",
+                report_id, report.status
+            ),
+        });
+    }
+    let before = audit_summary(&report);
+    report.status = DamageStatus::Waived;
+    DAMAGE_REPORT_STORAGE.with(|storage| storage.borrow_mut().insert(report_id, report.clone()));
+    record_audit(
+        "waive_damage_report",
+        Some(report_id),
+        before,
+        audit_summary(&report),
+    );
+    Ok(report)
+}
+
+// Implement reviews, one per completed rental, submitted by the rental's owner
+#[ic_cdk::update]
+fn submit_review(rental_id: u64, rating: u8, comment: String) -> Result<Review, Error> {
+    check_rate_limit()?;
+    let rental_request = get_rental_request(rental_id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    if rental_request.status != RentalStatus::Completed {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Only completed rentals can be reviewed, rental request id={} is {:?}",
+                rental_id, rental_request.status
+            ),
+        });
+    }
+    if !(1..=5).contains(&rating) {
+        return Err(Error::InvalidInput {
+            msg: "Rating must be between 1 and 5 stars".to_string(),
+        });
+    }
+    if comment.len() > 1000 {
+        return Err(Error::InvalidInput {
+            msg: "Review comment must be at most 1000 characters".to_string(),
+        });
+    }
+    if let Some(review_id) = REVIEW_BY_RENTAL.with(|index| index.borrow().get(&rental_id)) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Rental request id={} was already reviewed with review id={}",
+                rental_id, review_id
+            ),
+        });
+    }
+
+    let review = Review {
+        id: next_id()?,
+        rental_id,
+        car_id: rental_request.car_id,
+        customer_id: rental_request.customer_id,
+        rating,
+        comment: comment.trim().to_string(),
+        created_at: ic_cdk::api::time(),
+        status: ReviewStatus::Pending,
+        moderated_by: None,
+        moderated_at: None,
+    };
+    check_record_size(&review)?;
+    REVIEW_STORAGE.with(|storage| storage.borrow_mut().insert(review.id, review.clone()));
+    REVIEWS_BY_CAR.with(|index| index.borrow_mut().insert((review.car_id, review.id), ()));
+    REVIEW_BY_RENTAL.with(|index| index.borrow_mut().insert(rental_id, review.id));
+    record_audit(
+        "submit_review",
+        Some(review.id),
+        None,
+        audit_summary(&review),
+    );
+    Ok(review)
+}
+
+// Approve, reject, or flag a review. Staff decide pending reviews and may flag approved
+// ones; flagged reviews are decided by admins, and rejections are final.
+#[ic_cdk::update]
+fn moderate_review(review_id: u64, status: ReviewStatus) -> Result<Review, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    let mut review = get_review(review_id)?;
+    let allowed = match (review.status, status) {
+        (ReviewStatus::Pending, ReviewStatus::Approved)
+        | (ReviewStatus::Pending, ReviewStatus::Rejected)
+        | (ReviewStatus::Pending, ReviewStatus::Flagged)
+        | (ReviewStatus::Approved, ReviewStatus::Flagged) => true,
+        (ReviewStatus::Flagged, ReviewStatus::Approved)
+        | (ReviewStatus::Flagged, ReviewStatus::Rejected) => {
+            require_any_role(&[Role::Admin])?;
+            true
+        }
+        _ => false,
+    };
+    if !allowed {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Review id={} cannot be moved from {:?} to {:?}",
+                review_id, review.status, status
+            ),
+        });
+    }
+
+    let before = audit_summary(&review);
+    review.status = status;
+    review.moderated_by = Some(caller());
+    review.moderated_at = Some(ic_cdk::api::time());
+    REVIEW_STORAGE.with(|storage| storage.borrow_mut().insert(review_id, review.clone()));
+    refresh_car_rating(review.car_id);
+    record_audit(
+        "moderate_review",
+        Some(review_id),
+        before,
+        audit_summary(&review),
+    );
+    Ok(review)
+}
+
+// List the reviews waiting for a moderation decision, oldest first
+#[ic_cdk::query]
+fn list_pending_reviews() -> Result<Vec<Review>, Error> {
+    require_any_role(&[Role::Staff])?;
+    Ok(REVIEW_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, review)| review)
+            .filter(|review| matches!(review.status, ReviewStatus::Pending | ReviewStatus::Flagged))
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn get_review(review_id: u64) -> Result<Review, Error> {
+    match REVIEW_STORAGE.with(|storage| storage.borrow().get(&review_id)) {
+        Some(review) if review.status == ReviewStatus::Approved => Ok(review),
+        Some(review) => {
+            // Reviews that are not public are only shown to their author and staff
+            let rental_request = get_rental_request(review.rental_id)?;
+            if ensure_owner_or_admin(&rental_request.owner).is_err() {
+                require_any_role(&[Role::Staff])?;
+            }
+            Ok(review)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Review with id={} not found", review_id),
+        }),
+    }
+}
+
+// List the approved reviews of a car, newest first
+#[ic_cdk::query]
+fn list_reviews_for_car(car_id: u64) -> Vec<Review> {
+    let mut reviews: Vec<Review> = reviews_for_car(car_id)
+        .into_iter()
+        .filter(|review| review.status == ReviewStatus::Approved)
+        .collect();
+    reviews.reverse();
+    reviews
+}
+
+fn reviews_for_car(car_id: u64) -> Vec<Review> {
+    let review_ids: Vec<u64> = REVIEWS_BY_CAR.with(|index| {
+        index
+            .borrow()
+            .range((car_id, 0)..=(car_id, u64::MAX))
+            .map(|((_, review_id), _)| review_id)
+            .collect()
+    });
+    REVIEW_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        review_ids
+            .into_iter()
+            .filter_map(|review_id| storage.get(&review_id))
+            .collect()
+    })
+}
+
+// Recompute the average rating kept on a car from its approved reviews
+fn refresh_car_rating(car_id: u64) {
+    let ratings: Vec<u64> = reviews_for_car(car_id)
+        .iter()
+        .filter(|review| review.status == ReviewStatus::Approved)
+        .map(|review| u64::from(review.rating))
+        .collect();
+    if let Ok(mut car) = get_car(car_id) {
+        car.rating = if ratings.is_empty() {
+            None
+        } else {
+            Some(CarRating {
+                review_count: ratings.len() as u64,
+                average_rating: ratings.iter().sum::<u64>() as f64 / ratings.len() as f64,
+            })
+        };
+        store_car(&mut car);
+    }
+}
+
+#[ic_cdk::query]
+fn get_damage_report(report_id: u64) -> Result<DamageReport, Error> {
+    match DAMAGE_REPORT_STORAGE.with(|storage| storage.borrow().get(&report_id)) {
+        Some(report) => Ok(report),
+        None => Err(Error::NotFound {
+            msg: format!("Damage report with id={} not found", report_id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_damage_reports_for_car(car_id: u64) -> Result<Vec<DamageReport>, Error> {
+    require_any_role(&[Role::Staff])?;
+    Ok(damage_reports_for_car(car_id))
+}
+
+// Owners see the damage reported on their own rentals
+#[ic_cdk::query]
+fn list_damage_reports_for_rental(rental_id: u64) -> Result<Vec<DamageReport>, Error> {
+    let rental_request = get_rental_request(rental_id)?;
+    if ensure_owner_or_admin(&rental_request.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(damage_reports_for_car(rental_request.car_id)
+        .into_iter()
+        .filter(|report| report.rental_id == rental_id)
+        .collect())
+}
+
+fn damage_reports_for_car(car_id: u64) -> Vec<DamageReport> {
+    let report_ids: Vec<u64> = DAMAGE_REPORTS_BY_CAR.with(|index| {
+        index
+            .borrow()
+            .range((car_id, 0)..=(car_id, u64::MAX))
+            .map(|((_, report_id), _)| report_id)
+            .collect()
+    });
+    DAMAGE_REPORT_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        report_ids
+            .into_iter()
+            .filter_map(|report_id| storage.get(&report_id))
+            .collect()
+    })
+}
+
+// Update the car's mileage to an odometer reading, which may never go backwards
+fn record_odometer(car_id: u64, odometer_km: u64) -> Result<(), Error> {
+    let mut car = get_car(car_id)?;
+    if odometer_km < car.mileage_km {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Odometer reading {} km is below the car's mileage of {} km",
+                odometer_km, car.mileage_km
+            ),
+        });
+    }
+    car.mileage_km = odometer_km;
+    store_car(&mut car);
+    Ok(())
+}
+
+// List the active rentals past their end date, with what staff need to chase the return.
+// The cursor is a rental id.
+#[ic_cdk::query]
+fn list_overdue_rentals(cursor: Option<u64>, limit: u32) -> Result<Page<OverdueRental>, Error> {
+    require_any_role(&[Role::Staff])?;
+    let now = ic_cdk::api::time();
+    let overdue: Vec<(u64, RentalRequest)> = RENTAL_REQUEST_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, request)| request.status == RentalStatus::Active && request.end_date < now)
+            .collect()
+    });
+    let total = overdue.len() as u64;
+    let cursor = cursor.unwrap_or(0);
+    Ok(page_of(
+        overdue
+            .into_iter()
+            .filter(|(id, _)| *id >= cursor)
+            .map(|(id, rental)| {
+                let customer =
+                    CUSTOMER_STORAGE.with(|storage| storage.borrow().get(&rental.customer_id));
+                let overdue = OverdueRental {
+                    customer_name: customer.as_ref().map(|customer| customer.name.clone()),
+                    customer_email: customer.as_ref().map(|customer| customer.email.clone()),
+                    customer_phone: customer.map(|customer| customer.phone),
+                    hours_overdue: (now - rental.end_date).div_ceil(NANOS_PER_HOUR),
+                    accrued_late_fee_e8s: late_fee(rental.end_date, now),
+                    rental,
+                };
+                (id, overdue)
+            }),
+        total,
+        limit,
+    ))
+}
+
+// Bill a late fee if a rental was returned after its end date and grace period
+fn bill_late_return(rental_request: &RentalRequest, returned_at: u64) -> Result<(), Error> {
+    let fee_e8s = late_fee(rental_request.end_date, returned_at);
+    if fee_e8s == 0 {
+        return Ok(());
+    }
+    let charge = Charge {
+        id: next_id()?,
+        rental_id: rental_request.id,
+        customer_id: rental_request.customer_id,
+        kind: ChargeKind::LateFee,
+        description: format!(
+            "Returned {} hour(s) late",
+            (returned_at - rental_request.end_date).div_ceil(NANOS_PER_HOUR)
+        ),
+        amount_e8s: fee_e8s,
+        created_at: returned_at,
+        settled: false,
+    };
+    CHARGE_STORAGE.with(|storage| storage.borrow_mut().insert(charge.id, charge));
+    Ok(())
+}
+
+// Charge each full day late at the daily penalty and the remaining hours at the hourly
+// penalty, capped at one day. Nothing is charged within the grace period.
+fn late_fee(end_date: u64, returned_at: u64) -> u64 {
+    let config = config();
+    let grace = config
+        .late_grace_period_minutes
+        .saturating_mul(NANOS_PER_MINUTE);
+    let late = returned_at.saturating_sub(end_date);
+    if late <= grace {
+        return 0;
+    }
+    let full_days = late / NANOS_PER_DAY;
+    let hours = (late % NANOS_PER_DAY).div_ceil(NANOS_PER_HOUR);
+    full_days
+        .saturating_mul(config.late_fee_per_day_e8s)
+        .saturating_add(
+            hours
+                .saturating_mul(config.late_fee_per_hour_e8s)
+                .min(config.late_fee_per_day_e8s),
+        )
+}
+
+#[ic_cdk::query]
+fn get_outstanding_charges(customer_id: u64) -> Result<Vec<Charge>, Error> {
+    let customer = get_customer(customer_id)?;
+    if ensure_owner_or_admin(&customer.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(CHARGE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, charge)| charge.customer_id == customer_id && !charge.settled)
+            .map(|(_, charge)| charge)
+            .collect()
+    }))
+}
+
+// Issue the invoice of a completed rental from its quote, charges, tax, and deposit
+fn issue_invoice(rental_request: &RentalRequest, issued_at: u64) -> Invoice {
+    let mut lines = vec![InvoiceLine {
+        kind: InvoiceLineKind::BaseRate,
+        description: format!(
+            "Rental of car id={} for {} day(s)",
+            rental_request.car_id,
+            rental_days(rental_request.start_date, rental_request.end_date)
+        ),
+        amount_e8s: rental_request
+            .quoted_price_e8s
+            .saturating_sub(rental_request.drop_fee_e8s)
+            .saturating_sub(rental_request.protection_e8s)
+            .saturating_sub(rental_request.add_ons_e8s) as i64,
+    }];
+    if let Some(protection) = rental_request
+        .protection
+        .as_ref()
+        .filter(|_| rental_request.protection_e8s > 0)
+    {
+        lines.push(InvoiceLine {
+            kind: InvoiceLineKind::AddOn,
+            description: format!("{:?} protection", protection.tier),
+            amount_e8s: rental_request.protection_e8s as i64,
+        });
+    }
+    let days = rental_days(rental_request.start_date, rental_request.end_date);
+    for add_on in rental_add_ons(rental_request.id) {
+        lines.push(InvoiceLine {
+            kind: InvoiceLineKind::AddOn,
+            description: format!("{} x{}", add_on.name, add_on.quantity),
+            amount_e8s: days
+                .saturating_mul(add_on.daily_rate_e8s)
+                .saturating_mul(add_on.quantity as u64) as i64,
+        });
+    }
+    if rental_request.drop_fee_e8s > 0 {
+        lines.push(InvoiceLine {
+            kind: InvoiceLineKind::AddOn,
+            description: "One-way drop fee".to_string(),
+            amount_e8s: rental_request.drop_fee_e8s as i64,
+        });
+    }
+    if rental_request.early_return_credit_e8s > 0 {
+        lines.push(InvoiceLine {
+            kind: InvoiceLineKind::EarlyReturnCredit,
+            description: "Unused days credited on early return".to_string(),
+            amount_e8s: -(rental_request.early_return_credit_e8s as i64),
+        });
+    }
+    if rental_request.coupon_discount_e8s > 0 {
+        lines.push(InvoiceLine {
+            kind: InvoiceLineKind::Discount,
+            description: "Coupon discount".to_string(),
+            amount_e8s: -(rental_request.coupon_discount_e8s as i64),
+        });
+    }
+    if rental_request.points_discount_e8s > 0 {
+        lines.push(InvoiceLine {
+            kind: InvoiceLineKind::Discount,
+            description: format!("{} loyalty points redeemed", rental_request.points_redeemed),
+            amount_e8s: -(rental_request.points_discount_e8s as i64),
+        });
+    }
+    CHARGE_STORAGE.with(|storage| {
+        for (_, charge) in storage.borrow().iter() {
+            if charge.rental_id == rental_request.id {
+                lines.push(InvoiceLine {
+                    kind: match charge.kind {
+                        ChargeKind::LateFee => InvoiceLineKind::LateFee,
+                        ChargeKind::MileageOverage => InvoiceLineKind::MileageOverage,
+                        ChargeKind::Refueling => InvoiceLineKind::Refueling,
+                        ChargeKind::EarlyReturnFee => InvoiceLineKind::EarlyReturnFee,
+                        ChargeKind::CancellationFee => InvoiceLineKind::CancellationFee,
+                    },
+                    description: charge.description,
+                    amount_e8s: charge.amount_e8s as i64,
+                });
+            }
+        }
+    });
+    let tax_rate_bps = config().tax_rate_bps;
+    let taxable_e8s = lines
+        .iter()
+        .fold(0i64, |sum, line| sum.saturating_add(line.amount_e8s));
+    let tax_e8s = (taxable_e8s as i128 * tax_rate_bps as i128 / BPS_DENOMINATOR as i128)
+        .clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+    if tax_e8s > 0 {
+        lines.push(InvoiceLine {
+            kind: InvoiceLineKind::Tax,
+            description: format!("Tax at {} bps", tax_rate_bps),
+            amount_e8s: tax_e8s,
+        });
+    }
+    // Forfeited deposit is kept towards the bill
+    let forfeited_e8s = DEPOSIT_STORAGE
+        .with(|storage| storage.borrow().get(&rental_request.id))
+        .map_or(0, |deposit| deposit.forfeited_e8s);
+    if forfeited_e8s > 0 {
+        lines.push(InvoiceLine {
+            kind: InvoiceLineKind::DepositAdjustment,
+            description: "Forfeited deposit applied".to_string(),
+            amount_e8s: -(forfeited_e8s as i64),
+        });
+    }
+
+    let total_e8s = lines
+        .iter()
+        .fold(0i64, |sum, line| sum.saturating_add(line.amount_e8s))
+        .max(0) as u64;
+    let paid_e8s = rental_request
+        .payment_id
+        .and_then(|payment_id| PAYMENT_STORAGE.with(|storage| storage.borrow().get(&payment_id)))
+        .map_or(0, |payment| {
+            payment.amount_e8s.saturating_sub(payment.refunded_e8s)
+        });
+    let invoice = Invoice {
+        number: next_invoice_number(),
+        rental_id: rental_request.id,
+        customer_id: rental_request.customer_id,
+        issued_at,
+        lines,
+        total_e8s,
+        paid_e8s,
+        balance_due_e8s: total_e8s.saturating_sub(paid_e8s),
+        conversion: None,
+    };
+    INVOICE_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(rental_request.id, invoice.clone())
+    });
+    invoice
+}
+
+fn next_invoice_number() -> u64 {
+    INVOICE_COUNTER.with(|counter| {
+        let number = *counter.borrow().get() + 1;
+        counter
+            .borrow_mut()
+            .set(number)
+            .expect("Cannot increment the invoice counter");
+        number
+    })
+}
+
+#[ic_cdk::query]
+fn get_invoice(rental_id: u64) -> Result<Invoice, Error> {
+    let rental_request = get_rental_or_archived(rental_id)?;
+    if ensure_owner_or_admin(&rental_request.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    INVOICE_STORAGE
+        .with(|storage| storage.borrow().get(&rental_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Invoice for rental request id={} not found", rental_id),
+        })
+}
+
+#[ic_cdk::query]
+fn list_invoices_for_customer(customer_id: u64) -> Result<Vec<Invoice>, Error> {
+    let customer = get_customer(customer_id)?;
+    if ensure_owner_or_admin(&customer.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    let mut invoices: Vec<Invoice> = INVOICE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, invoice)| invoice.customer_id == customer_id)
+            .map(|(_, invoice)| invoice)
+            .collect()
+    });
+    invoices.sort_by_key(|invoice| invoice.number);
+    Ok(invoices)
+}
+
+// Periodic job: expire unapproved requests, start approved rentals, and flag overdue ones
+fn advance_rental_statuses() {
+    if restore_in_progress() {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    let due: Vec<RentalRequest> = RENTAL_REQUEST_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter_map(|(_, request)| {
+                let mut updated = request.clone();
+                match request.status {
+                    RentalStatus::Pending if request.start_date <= now => {
+                        updated.status = RentalStatus::Expired;
+                    }
+                    RentalStatus::Approved if request.start_date <= now => {
+                        updated.status = RentalStatus::Active;
+                    }
+                    RentalStatus::Active if request.end_date < now && !request.overdue => {
+                        updated.overdue = true;
+                    }
+                    _ => return None,
+                }
+                Some(updated)
+            })
+            .collect()
+    });
+    for mut request in due {
+        if request.status == RentalStatus::Expired {
+            release_discounts(&mut request);
+        }
+        store_rental_request(&mut request);
+    }
+    expire_waitlist_entries(now);
+    expire_holds(now);
+    expire_idempotency_keys(now);
+    prune_recent_calls(now);
+    send_reminders(now);
+    archive_rentals(now);
+}
+
+// Implement the archive of closed rentals. Completed, canceled, and expired rentals are
+// moved out of the rental request storage once they are older than archive_after_days,
+// so the queries over current rentals stay fast. Their invoices and other records stay.
+#[ic_cdk::query]
+fn get_archived_rental(id: u64) -> Result<RentalRequest, Error> {
+    let rental_request = ARCHIVED_RENTALS
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("Archived rental request with id={} not found", id),
+        })?;
+    if ensure_owner_or_admin(&rental_request.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(rental_request)
+}
+
+#[ic_cdk::query]
+fn list_archived_rentals(cursor: Option<u64>, limit: u32) -> Result<Page<RentalRequest>, Error> {
+    require_any_role(&[Role::Staff])?;
+    Ok(ARCHIVED_RENTALS.with(|storage| {
+        let storage = storage.borrow();
+        page_of(storage.range(cursor.unwrap_or(0)..), storage.len(), limit)
+    }))
+}
+
+#[ic_cdk::update]
+fn set_archive_after_days(days: u64) -> Result<Config, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let mut updated_config = config();
+    let before = audit_summary(&updated_config.archive_after_days);
+    updated_config.archive_after_days = days;
+    validate_config(&updated_config)?;
+    set_config(updated_config.clone());
+    record_audit("set_archive_after_days", None, before, audit_summary(&days));
+    Ok(updated_config)
+}
+
+// Find a rental request whether it is current or archived
+fn get_rental_or_archived(id: u64) -> Result<RentalRequest, Error> {
+    get_rental_request(id).or_else(|error| {
+        ARCHIVED_RENTALS
+            .with(|storage| storage.borrow().get(&id))
+            .ok_or(error)
+    })
+}
+
+// Move up to a batch of closed rentals that ended before the archive age into the archive,
+// oldest first. A rental never ends before it starts, so only the start date index up to
+// the cutoff needs to be searched.
+fn archive_rentals(now: u64) {
+    let cutoff = now.saturating_sub(config().archive_after_days.saturating_mul(NANOS_PER_DAY));
+    for rental_request in rentals_due_for_archive(cutoff) {
+        RENTAL_REQUEST_STORAGE.with(|storage| storage.borrow_mut().remove(&rental_request.id));
+        unindex_rental_request(&rental_request);
+        forget_reminders(rental_request.id);
+        certify_rental(rental_request.id);
+        ARCHIVED_RENTALS_BY_CAR.with(|index| {
+            index
+                .borrow_mut()
+                .insert((rental_request.car_id, rental_request.id), ())
+        });
+        ARCHIVED_RENTALS.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(rental_request.id, rental_request)
+        });
+    }
+}
+
+fn rentals_due_for_archive(cutoff: u64) -> Vec<RentalRequest> {
+    RENTALS_BY_START_DATE.with(|index| {
+        RENTAL_REQUEST_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            index
+                .borrow()
+                .range((0, 0)..=(cutoff, u64::MAX))
+                .filter_map(|((_, rental_id), _)| storage.get(&rental_id))
+                .filter(|request| {
+                    let ended_at = request.completed_at.unwrap_or(request.end_date);
+                    !request.status.is_open() && ended_at <= cutoff
+                })
+                .take(ARCHIVE_BATCH_SIZE)
+                .collect()
+        })
+    })
+}
+
+// Implement corporate accounts, managed by their admins, whose drivers book rentals billed
+// to the account
+#[ic_cdk::update]
+fn create_corporate_account(
+    payload: CorporateAccountPayload,
+    admin: Principal,
+) -> Result<CorporateAccount, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    validate_corporate_account_payload(&payload)?;
+    if admin == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "The account admin cannot be the anonymous principal".to_string(),
+        });
+    }
+    let account = CorporateAccount {
+        id: next_id()?,
+        name: payload.name,
+        billing_email: payload.billing_email,
+        admins: vec![admin],
+        monthly_spending_limit_e8s: payload.monthly_spending_limit_e8s,
+        created_at: ic_cdk::api::time(),
+    };
+    check_record_size(&account)?;
+    CORPORATE_ACCOUNT_STORAGE
+        .with(|storage| storage.borrow_mut().insert(account.id, account.clone()));
+    record_audit(
+        "create_corporate_account",
+        Some(account.id),
+        None,
+        audit_summary(&account),
+    );
+    Ok(account)
+}
+
+#[ic_cdk::update]
+fn update_corporate_account(
+    id: u64,
+    payload: CorporateAccountPayload,
+) -> Result<CorporateAccount, Error> {
+    check_rate_limit()?;
+    let mut account = get_corporate_account_record(id)?;
+    ensure_account_admin(&account)?;
+    validate_corporate_account_payload(&payload)?;
+    account.name = payload.name;
+    account.billing_email = payload.billing_email;
+    account.monthly_spending_limit_e8s = payload.monthly_spending_limit_e8s;
+    let previous =
+        CORPORATE_ACCOUNT_STORAGE.with(|storage| storage.borrow_mut().insert(id, account.clone()));
+    record_audit(
+        "update_corporate_account",
+        Some(id),
+        previous.as_ref().and_then(audit_summary),
+        audit_summary(&account),
+    );
+    Ok(account)
+}
+
+#[ic_cdk::update]
+fn add_account_admin(id: u64, admin: Principal) -> Result<CorporateAccount, Error> {
+    check_rate_limit()?;
+    let mut account = get_corporate_account_record(id)?;
+    ensure_account_admin(&account)?;
+    if admin == Principal::anonymous() || account.admins.contains(&admin) {
+        return Err(Error::InvalidInput {
+            msg: format!("{} cannot be added as an admin of account id={}", admin, id),
+        });
+    }
+    if account.admins.len() >= 10 {
+        return Err(Error::InvalidInput {
+            msg: "A corporate account may have at most 10 admins".to_string(),
+        });
+    }
+    account.admins.push(admin);
+    let previous =
+        CORPORATE_ACCOUNT_STORAGE.with(|storage| storage.borrow_mut().insert(id, account.clone()));
+    record_audit(
+        "add_account_admin",
+        Some(id),
+        previous.as_ref().and_then(audit_summary),
+        audit_summary(&account),
+    );
+    Ok(account)
+}
+
+// Remove an admin from an account, which always keeps at least one
+#[ic_cdk::update]
+fn remove_account_admin(id: u64, admin: Principal) -> Result<CorporateAccount, Error> {
+    check_rate_limit()?;
+    let mut account = get_corporate_account_record(id)?;
+    ensure_account_admin(&account)?;
+    if !account.admins.contains(&admin) || account.admins.len() == 1 {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "{} is not an admin of account id={} that can be removed",
+                admin, id
+            ),
+        });
+    }
+    account.admins.retain(|existing| *existing != admin);
+    let previous =
+        CORPORATE_ACCOUNT_STORAGE.with(|storage| storage.borrow_mut().insert(id, account.clone()));
+    record_audit(
+        "remove_account_admin",
+        Some(id),
+        previous.as_ref().and_then(audit_summary),
+        audit_summary(&account),
+    );
+    Ok(account)
+}
+
+#[ic_cdk::update]
+fn add_authorized_driver(id: u64, customer_id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    let account = get_corporate_account_record(id)?;
+    ensure_account_admin(&account)?;
+    get_customer(customer_id)?;
+    ACCOUNT_DRIVERS.with(|drivers| drivers.borrow_mut().insert((id, customer_id), ()));
+    record_audit(
+        "add_authorized_driver",
+        Some(id),
+        None,
+        audit_summary(&customer_id),
+    );
+    Ok(())
+}
+
+// Stop a driver from booking for the account; rentals already booked are not changed
+#[ic_cdk::update]
+fn remove_authorized_driver(id: u64, customer_id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    let account = get_corporate_account_record(id)?;
+    ensure_account_admin(&account)?;
+    match ACCOUNT_DRIVERS.with(|drivers| drivers.borrow_mut().remove(&(id, customer_id))) {
+        Some(_) => {
+            record_audit(
+                "remove_authorized_driver",
+                Some(id),
+                audit_summary(&customer_id),
+                None,
+            );
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "Customer id={} is not a driver of account id={}",
+                customer_id, id
+            ),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_corporate_account(id: u64) -> Result<CorporateAccount, Error> {
+    let account = get_corporate_account_record(id)?;
+    ensure_account_admin(&account)?;
+    Ok(account)
+}
+
+#[ic_cdk::query]
+fn list_corporate_accounts() -> Result<Vec<CorporateAccount>, Error> {
+    require_any_role(&[Role::Staff])?;
+    Ok(CORPORATE_ACCOUNT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, account)| account)
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn list_authorized_drivers(id: u64) -> Result<Vec<Customer>, Error> {
+    let account = get_corporate_account_record(id)?;
+    ensure_account_admin(&account)?;
+    let customer_ids: Vec<u64> = ACCOUNT_DRIVERS.with(|drivers| {
+        drivers
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|((_, customer_id), _)| customer_id)
+            .collect()
+    });
+    Ok(customer_ids
+        .into_iter()
+        .filter_map(|customer_id| get_customer(customer_id).ok())
+        .collect())
+}
+
+#[ic_cdk::query]
+fn list_account_rentals(id: u64) -> Result<Vec<RentalRequest>, Error> {
+    let account = get_corporate_account_record(id)?;
+    ensure_account_admin(&account)?;
+    Ok(rentals_for_account(id))
+}
+
+// Retrieve the statement of an account for a month given as YYYYMM
+#[ic_cdk::query]
+fn get_corporate_statement(account_id: u64, month: u32) -> Result<CorporateStatement, Error> {
+    let account = get_corporate_account_record(account_id)?;
+    ensure_account_admin(&account)?;
+    let key = (account_id, u64::from(month));
+    match STATEMENT_STORAGE.with(|storage| storage.borrow().get(&key)) {
+        Some(mut statement) => {
+            statement.lines = STATEMENT_LINES.with(|lines| {
+                lines
+                    .borrow()
+                    .range((key, 0)..=(key, u64::MAX))
+                    .map(|(_, line)| line)
+                    .collect()
+            });
+            Ok(statement)
+        }
+        None => Err(Error::NotFound {
+            msg: format!(
+                "No statement was issued for account id={} for month {}",
+                account_id, month
+            ),
+        }),
+    }
+}
+
+// List the statements issued for an account, oldest first and without their lines
+#[ic_cdk::query]
+fn list_corporate_statements(account_id: u64) -> Result<Vec<CorporateStatement>, Error> {
+    let account = get_corporate_account_record(account_id)?;
+    ensure_account_admin(&account)?;
+    Ok(STATEMENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .range((account_id, 0)..=(account_id, u64::MAX))
+            .map(|(_, statement)| statement)
+            .collect()
+    }))
+}
+
+// Periodic job: once a month is over, issue its statement to every corporate account
+fn issue_corporate_statements() {
+    if restore_in_progress() {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    let (year, month) = year_month(now);
+    let (year, month) = if month == 1 {
+        (year - 1, 12)
+    } else {
+        (year, month - 1)
+    };
+    let account_ids: Vec<u64> = CORPORATE_ACCOUNT_STORAGE
+        .with(|storage| storage.borrow().iter().map(|(id, _)| id).collect());
+    for account_id in account_ids {
+        let key = (account_id, year * 100 + month);
+        if !STATEMENT_STORAGE.with(|storage| storage.borrow().contains_key(&key)) {
+            issue_corporate_statement(account_id, (year, month), now);
+        }
+    }
+}
+
+// Aggregate the invoices of the account's rentals completed in the month and the
+// cancellation fees billed in it
+fn issue_corporate_statement(account_id: u64, (year, month): (u64, u64), issued_at: u64) {
+    let key = (account_id, year * 100 + month);
+    let mut statement = CorporateStatement {
+        account_id,
+        month: (year * 100 + month) as u32,
+        issued_at,
+        lines: Vec::new(),
+        rentals_e8s: 0,
+        fees_e8s: 0,
+        tax_e8s: 0,
+        total_e8s: 0,
+        paid_e8s: 0,
+        balance_due_e8s: 0,
+    };
+    for rental_request in rentals_for_account(account_id) {
+        let line = match rental_request.status {
+            RentalStatus::Completed
+                if rental_request
+                    .completed_at
+                    .is_some_and(|completed_at| year_month(completed_at) == (year, month)) =>
+            {
+                match INVOICE_STORAGE.with(|storage| storage.borrow().get(&rental_request.id)) {
+                    Some(invoice) => statement_line_for_invoice(&rental_request, &invoice),
+                    None => continue,
+                }
+            }
+            RentalStatus::Canceled => {
+                let fees_e8s: u64 = CHARGE_STORAGE.with(|storage| {
+                    storage
+                        .borrow()
+                        .iter()
+                        .map(|(_, charge)| charge)
+                        .filter(|charge| {
+                            charge.rental_id == rental_request.id
+                                && charge.kind == ChargeKind::CancellationFee
+                                && year_month(charge.created_at) == (year, month)
+                        })
+                        .map(|charge| charge.amount_e8s)
+                        .sum()
+                });
+                if fees_e8s == 0 {
+                    continue;
+                }
+                StatementLine {
+                    rental_id: rental_request.id,
+                    customer_id: rental_request.customer_id,
+                    invoice_number: None,
+                    rentals_e8s: 0,
+                    fees_e8s,
+                    tax_e8s: 0,
+                    total_e8s: fees_e8s,
+                    paid_e8s: 0,
+                }
+            }
+            _ => continue,
+        };
+        statement.rentals_e8s += line.rentals_e8s;
+        statement.fees_e8s += line.fees_e8s;
+        statement.tax_e8s += line.tax_e8s;
+        statement.total_e8s += line.total_e8s;
+        statement.paid_e8s += line.paid_e8s;
+        STATEMENT_LINES.with(|lines| lines.borrow_mut().insert((key, line.rental_id), line));
+    }
+    statement.balance_due_e8s = statement.total_e8s.saturating_sub(statement.paid_e8s);
+    STATEMENT_STORAGE.with(|storage| storage.borrow_mut().insert(key, statement));
+}
+
+// Split an invoice into the rental itself, the fees billed on top, and tax
+fn statement_line_for_invoice(rental_request: &RentalRequest, invoice: &Invoice) -> StatementLine {
+    let sum_of = |kinds: &[InvoiceLineKind]| -> u64 {
+        invoice
+            .lines
+            .iter()
+            .filter(|line| kinds.contains(&line.kind))
+            .map(|line| line.amount_e8s)
+            .sum::<i64>()
+            .max(0) as u64
+    };
+    let fees_e8s = sum_of(&[
+        InvoiceLineKind::LateFee,
+        InvoiceLineKind::MileageOverage,
+        InvoiceLineKind::Refueling,
+        InvoiceLineKind::EarlyReturnFee,
+        InvoiceLineKind::CancellationFee,
+    ]);
+    let tax_e8s = sum_of(&[InvoiceLineKind::Tax]);
+    StatementLine {
+        rental_id: rental_request.id,
+        customer_id: rental_request.customer_id,
+        invoice_number: Some(invoice.number),
+        rentals_e8s: invoice.total_e8s.saturating_sub(fees_e8s + tax_e8s),
+        fees_e8s,
+        tax_e8s,
+        total_e8s: invoice.total_e8s,
+        paid_e8s: invoice.paid_e8s,
+    }
+}
+
+fn rentals_for_account(account_id: u64) -> Vec<RentalRequest> {
+    let rental_ids: Vec<u64> = RENTALS_BY_ACCOUNT.with(|index| {
+        index
+            .borrow()
+            .range((account_id, 0)..=(account_id, u64::MAX))
+            .map(|((_, rental_id), _)| rental_id)
+            .collect()
+    });
+    RENTAL_REQUEST_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        rental_ids
+            .into_iter()
+            .filter_map(|rental_id| storage.get(&rental_id))
+            .collect()
+    })
+}
+
+fn get_corporate_account_record(id: u64) -> Result<CorporateAccount, Error> {
+    CORPORATE_ACCOUNT_STORAGE
+        .with(|storage| storage.borrow().get(&id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Corporate account with id={} not found", id),
+        })
+}
+
+// Account admins manage their account; staff can manage every account
+fn ensure_account_admin(account: &CorporateAccount) -> Result<(), Error> {
+    if account.admins.contains(&caller()) {
+        return Ok(());
+    }
+    require_any_role(&[Role::Staff])
+}
+
+fn ensure_authorized_driver(account_id: u64, customer_id: u64) -> Result<(), Error> {
+    if ACCOUNT_DRIVERS.with(|drivers| drivers.borrow().contains_key(&(account_id, customer_id))) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!(
+                "Customer id={} is not an authorized driver of account id={}",
+                customer_id, account_id
+            ),
+        })
+    }
+}
+
+// The rentals of an account starting in one calendar month, other than the one excluded,
+// may not cost more than its monthly limit together with the amount being booked
+fn ensure_within_spending_limit(
+    account_id: u64,
+    start_date: u64,
+    amount_e8s: u64,
+    exclude_rental_id: Option<u64>,
+) -> Result<(), Error> {
+    let limit_e8s = match get_corporate_account_record(account_id)?.monthly_spending_limit_e8s {
+        Some(limit_e8s) => limit_e8s,
+        None => return Ok(()),
+    };
+    let month = year_month(start_date);
+    let spent_e8s: u64 = rentals_for_account(account_id)
+        .iter()
+        .filter(|rental| Some(rental.id) != exclude_rental_id)
+        .filter(|rental| {
+            !matches!(
+                rental.status,
+                RentalStatus::Canceled | RentalStatus::Expired
+            ) && year_month(rental.start_date) == month
+        })
+        .map(|rental| rental.price_due_e8s())
+        .sum();
+    if spent_e8s.saturating_add(amount_e8s) > limit_e8s {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Account id={} has {} of its {} e8s monthly limit left",
+                account_id,
+                limit_e8s.saturating_sub(spent_e8s),
+                limit_e8s
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Implement the waitlist for cars that are booked over the period a customer wants
+#[ic_cdk::update]
+fn join_waitlist(
+    car_id: u64,
+    customer_id: u64,
+    start_date: u64,
+    end_date: u64,
+    expires_at: Option<u64>,
+) -> Result<WaitlistEntry, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff, Role::Customer])?;
+    let customer = get_customer(customer_id)?;
+    ensure_owner_or_admin(&customer.owner)?;
+    validate_rental_period(start_date, end_date)?;
+    get_car(car_id)?;
+    let now = ic_cdk::api::time();
+    // By default an entry waits until the period it asks for begins
+    let expires_at = expires_at.unwrap_or(start_date);
+    if expires_at <= now || expires_at > start_date {
+        return Err(Error::InvalidInput {
+            msg: "A waitlist entry must expire in the future and no later than its start date"
+                .to_string(),
+        });
+    }
+    if ensure_car_available(car_id, start_date, end_date, None).is_ok() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Car with id={} is available for this period and can be booked directly",
+                car_id
+            ),
+        });
+    }
+    if let Some(existing) = waitlist_for_car(car_id).into_iter().find(|entry| {
+        entry.customer_id == customer_id
+            && entry.status == WaitlistStatus::Waiting
+            && entry.start_date < end_date
+            && start_date < entry.end_date
+    }) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Customer id={} is already waiting for this car with entry id={}",
+                customer_id, existing.id
+            ),
+        });
+    }
+
+    let entry = WaitlistEntry {
+        id: next_id()?,
+        car_id,
+        customer_id,
+        owner: caller(),
+        start_date,
+        end_date,
+        joined_at: now,
+        expires_at,
+        status: WaitlistStatus::Waiting,
+    };
+    WAITLIST_STORAGE.with(|storage| storage.borrow_mut().insert(entry.id, entry.clone()));
+    WAITLIST_BY_CAR.with(|index| index.borrow_mut().insert((car_id, entry.id), ()));
+    record_audit("join_waitlist", Some(entry.id), None, audit_summary(&entry));
+    Ok(entry)
+}
+
+#[ic_cdk::update]
+fn leave_waitlist(entry_id: u64) -> Result<WaitlistEntry, Error> {
+    check_rate_limit()?;
+    let mut entry = get_waitlist_entry(entry_id)?;
+    ensure_owner_or_admin(&entry.owner)?;
+    if entry.status != WaitlistStatus::Waiting {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Waitlist entry id={} is no longer waiting: {:?}",
+                entry_id, entry.status
+            ),
+        });
+    }
+    let before = audit_summary(&entry);
+    entry.status = WaitlistStatus::Left;
+    WAITLIST_STORAGE.with(|storage| storage.borrow_mut().insert(entry_id, entry.clone()));
+    record_audit(
+        "leave_waitlist",
+        Some(entry_id),
+        before,
+        audit_summary(&entry),
+    );
+    Ok(entry)
+}
+
+#[ic_cdk::query]
+fn get_waitlist_entry(entry_id: u64) -> Result<WaitlistEntry, Error> {
+    match WAITLIST_STORAGE.with(|storage| storage.borrow().get(&entry_id)) {
+        Some(entry) => {
+            if ensure_owner_or_admin(&entry.owner).is_err() {
+                require_any_role(&[Role::Staff])?;
+            }
+            Ok(entry)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Waitlist entry with id={} not found", entry_id),
+        }),
+    }
+}
+
+// List the entries still waiting for a car, first in line first
+#[ic_cdk::query]
+fn list_waitlist(car_id: u64) -> Result<Vec<WaitlistEntry>, Error> {
+    require_any_role(&[Role::Staff])?;
+    Ok(waitlist_for_car(car_id)
+        .into_iter()
+        .filter(|entry| entry.status == WaitlistStatus::Waiting)
+        .collect())
+}
+
+fn waitlist_for_car(car_id: u64) -> Vec<WaitlistEntry> {
+    let entry_ids: Vec<u64> = WAITLIST_BY_CAR.with(|index| {
+        index
+            .borrow()
+            .range((car_id, 0)..=(car_id, u64::MAX))
+            .map(|((_, entry_id), _)| entry_id)
+            .collect()
+    });
+    WAITLIST_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        entry_ids
+            .into_iter()
+            .filter_map(|entry_id| storage.get(&entry_id))
+            .collect()
+    })
+}
+
+// Book the freed car for waiting customers in joining order. Each entry whose period is
+// now free gets a pending rental request; entries that cannot be booked keep waiting.
+fn promote_waitlist(car_id: u64) {
+    let now = ic_cdk::api::time();
+    for mut entry in waitlist_for_car(car_id) {
+        if entry.status != WaitlistStatus::Waiting || entry.expires_at <= now {
+            continue;
+        }
+        if ensure_car_available(car_id, entry.start_date, entry.end_date, None).is_err() {
+            continue;
+        }
+        let payload = RentalRequestPayload {
+            car_id,
+            customer_id: entry.customer_id,
+            start_date: entry.start_date,
+            end_date: entry.end_date,
+            status: RentalStatus::Pending,
+            pickup_branch_id: None,
+            dropoff_branch_id: None,
+            protection_tier: None,
+            add_ons: Vec::new(),
+            corporate_account_id: None,
+        };
+        if let Ok(rental_request) = create_rental_request(payload, entry.owner, None) {
+            entry.status = WaitlistStatus::Promoted {
+                rental_id: rental_request.id,
+            };
+            WAITLIST_STORAGE.with(|storage| storage.borrow_mut().insert(entry.id, entry));
+        }
+    }
+}
+
+fn expire_waitlist_entries(now: u64) {
+    let expired: Vec<WaitlistEntry> = WAITLIST_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.status == WaitlistStatus::Waiting && entry.expires_at <= now)
+            .collect()
+    });
+    for mut entry in expired {
+        entry.status = WaitlistStatus::Expired;
+        WAITLIST_STORAGE.with(|storage| storage.borrow_mut().insert(entry.id, entry));
+    }
+}
+
+// Implement the loyalty points program
+#[ic_cdk::query]
+fn get_points_balance(customer_id: u64) -> Result<u64, Error> {
+    let customer = get_customer(customer_id)?;
+    if ensure_owner_or_admin(&customer.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(points_balance(customer_id))
+}
+
+// Spend loyalty points as a discount on an unpaid rental
+#[ic_cdk::update]
+fn redeem_points(rental_id: u64, points: u64) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    let rental_request = get_rental_request(rental_id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    ensure_payable(&rental_request)?;
+    let balance = points_balance(rental_request.customer_id);
+    if points == 0 || points > balance {
+        return Err(Error::InvalidInput {
+            msg: format!("Points must be between 1 and the balance of {}", balance),
+        });
+    }
+    let discount_e8s = points.saturating_mul(config().loyalty_point_value_e8s);
+    if discount_e8s > rental_request.price_due_e8s() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "A discount of {} e8s exceeds the {} e8s due",
+                discount_e8s,
+                rental_request.price_due_e8s()
+            ),
+        });
+    }
+
+    let before = audit_summary(&rental_request);
+    let mut updated_rental_request = rental_request;
+    updated_rental_request.points_redeemed += points;
+    updated_rental_request.points_discount_e8s += discount_e8s;
+    LOYALTY_POINTS.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(updated_rental_request.customer_id, balance - points)
+    });
+    store_rental_request(&mut updated_rental_request);
+    record_audit(
+        "redeem_points",
+        Some(rental_id),
+        before,
+        audit_summary(&updated_rental_request),
+    );
+    Ok(updated_rental_request)
+}
+
+#[ic_cdk::update]
+fn set_rate_limit(per_minute: u64) -> Result<Config, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let mut updated_config = config();
+    let before = audit_summary(&updated_config.rate_limit_per_minute);
+    updated_config.rate_limit_per_minute = per_minute;
+    validate_config(&updated_config)?;
+    set_config(updated_config.clone());
+    record_audit("set_rate_limit", None, before, audit_summary(&per_minute));
+    Ok(updated_config)
+}
+
+// Turn opaque ids on or off for ids handed out from now on. Ids already handed out keep
+// working either way.
+#[ic_cdk::update]
+async fn set_opaque_ids(enabled: bool) -> Result<Config, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    if enabled && ID_SEED.with(|seed| seed.borrow().is_none()) {
+        seed_opaque_ids().await?;
+    }
+    let mut updated_config = config();
+    let before = audit_summary(&updated_config.opaque_ids);
+    updated_config.opaque_ids = enabled;
+    validate_config(&updated_config)?;
+    set_config(updated_config.clone());
+    record_audit("set_opaque_ids", None, before, audit_summary(&enabled));
+    Ok(updated_config)
+}
+
+// Set how many hours ahead of a rental's start and end its customer is reminded, and
+// whether each reminder is also published as an event
+#[ic_cdk::update]
+fn set_reminder_policy(
+    pickup_hours: u64,
+    return_hours: u64,
+    publish_events: bool,
+) -> Result<Config, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let mut updated_config = config();
+    let before = audit_summary(&(
+        updated_config.pickup_reminder_hours,
+        updated_config.return_reminder_hours,
+        updated_config.reminder_events,
+    ));
+    updated_config.pickup_reminder_hours = Some(pickup_hours);
+    updated_config.return_reminder_hours = Some(return_hours);
+    updated_config.reminder_events = Some(publish_events);
+    validate_config(&updated_config)?;
+    set_config(updated_config.clone());
+    record_audit(
+        "set_reminder_policy",
+        None,
+        before,
+        audit_summary(&(pickup_hours, return_hours, publish_events)),
+    );
+    Ok(updated_config)
+}
+
+#[ic_cdk::update]
+fn set_hold_minutes(minutes: u64) -> Result<Config, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let mut updated_config = config();
+    let before = audit_summary(&updated_config.hold_minutes);
+    updated_config.hold_minutes = minutes;
+    validate_config(&updated_config)?;
+    set_config(updated_config.clone());
+    record_audit("set_hold_minutes", None, before, audit_summary(&minutes));
+    Ok(updated_config)
+}
+
+#[ic_cdk::update]
+fn set_loyalty_rates(points_per_icp: u64, point_value_e8s: u64) -> Result<Config, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let mut updated_config = config();
+    let before = audit_summary(&(
+        updated_config.loyalty_points_per_icp,
+        updated_config.loyalty_point_value_e8s,
+    ));
+    updated_config.loyalty_points_per_icp = points_per_icp;
+    updated_config.loyalty_point_value_e8s = point_value_e8s;
+    validate_config(&updated_config)?;
+    set_config(updated_config.clone());
+    record_audit(
+        "set_loyalty_rates",
+        None,
+        before,
+        audit_summary(&(points_per_icp, point_value_e8s)),
+    );
+    Ok(updated_config)
+}
+
+#[ic_cdk::query]
+fn get_config() -> Result<Config, Error> {
+    require_any_role(&[Role::Admin])?;
+    Ok(config())
+}
+
+// Replace the whole config at once. It is checked like the individual setters check
+// their part of it, and nothing is changed if any of it is invalid.
+#[ic_cdk::update]
+async fn update_config(updated_config: Config) -> Result<Config, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    validate_config(&updated_config)?;
+    if updated_config.opaque_ids && ID_SEED.with(|seed| seed.borrow().is_none()) {
+        seed_opaque_ids().await?;
+    }
+    let before = audit_summary(&config());
+    set_config(updated_config.clone());
+    record_audit(
+        "update_config",
+        None,
+        before,
+        audit_summary(&updated_config),
+    );
+    Ok(updated_config)
+}
+
+// Replace the eligibility rules, keeping at most one rule per car category
+#[ic_cdk::update]
+fn set_eligibility_rules(rules: Vec<EligibilityRule>) -> Result<Config, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    validate_eligibility_rules(&rules)?;
+    let mut updated_config = config();
+    let before = audit_summary(&updated_config.eligibility_rules);
+    updated_config.eligibility_rules = rules;
+    set_config(updated_config.clone());
+    record_audit(
+        "set_eligibility_rules",
+        None,
+        before,
+        audit_summary(&updated_config.eligibility_rules),
+    );
+    Ok(updated_config)
+}
+
+fn points_balance(customer_id: u64) -> u64 {
+    LOYALTY_POINTS
+        .with(|storage| storage.borrow().get(&customer_id))
+        .unwrap_or(0)
+}
+
+// Earn points in proportion to the amount paid
+fn award_loyalty_points(customer_id: u64, spent_e8s: u64) {
+    credit_points(customer_id, loyalty_points_for(spent_e8s));
+}
+
+fn loyalty_points_for(spent_e8s: u64) -> u64 {
+    let points =
+        (spent_e8s as u128).saturating_mul(config().loyalty_points_per_icp as u128) / 100_000_000;
+    points.min(u64::MAX as u128) as u64
+}
+
+fn credit_points(customer_id: u64, points: u64) {
+    if points > 0 {
+        let balance = points_balance(customer_id);
+        LOYALTY_POINTS.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(customer_id, balance.saturating_add(points))
+        });
+    }
+}
+
+// Give back the points and coupon use of a rental that will never be paid for
+fn release_discounts(rental_request: &mut RentalRequest) {
+    if rental_request.payment_id.is_some() {
+        return;
+    }
+    if let Some(coupon_id) = rental_request.coupon_id.take() {
+        release_coupon_use(coupon_id, rental_request.customer_id);
+        rental_request.coupon_discount_e8s = 0;
+    }
+    if rental_request.points_redeemed > 0 {
+        let balance = points_balance(rental_request.customer_id);
+        LOYALTY_POINTS.with(|storage| {
+            storage.borrow_mut().insert(
+                rental_request.customer_id,
+                balance + rental_request.points_redeemed,
+            )
+        });
+        rental_request.points_redeemed = 0;
+        rental_request.points_discount_e8s = 0;
+    }
+}
+
+// Implement CRUD operations for customers
+#[ic_cdk::update]
+fn register_customer(
+    payload: CustomerPayload,
+    referral_code: Option<String>,
+) -> Result<Customer, Error> {
+    check_rate_limit()?;
+    validate_customer_payload(&payload)?;
+    let CustomerPayload {
+        name,
+        email,
+        phone,
+        license_number,
+    } = payload;
+
+    let owner = caller();
+    if owner == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous principals cannot register as customers".to_string(),
+        });
+    }
+    if let Some(existing_id) = customer_id_for(&owner) {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Principal {} is already registered as customer id={}",
+                owner, existing_id
+            ),
+        });
+    }
+
+    let referrer = match referral_code {
+        Some(code) => Some(referrer_for_code(&code, &email, &license_number)?),
+        None => None,
+    };
+
+    let id = next_customer_id()?;
+
+    let customer = Customer {
+        id,
+        name,
+        email,
+        phone,
+        license_number,
+        created_at: ic_cdk::api::time(),
+        owner,
+        referral_code: None,
+        license: None,
+        date_of_birth: None,
+        erased_at: None,
+    };
+    check_record_size(&customer)?;
+
+    if let Some(referrer) = referrer {
+        let referral = Referral {
+            referrer_id: referrer.id,
+            referred_id: id,
+            registered_at: customer.created_at,
+            rewarded_at: None,
+        };
+        REFERRAL_STORAGE.with(|storage| storage.borrow_mut().insert(id, referral));
+    }
+    CUSTOMER_STORAGE.with(|storage| storage.borrow_mut().insert(id, customer.clone()));
+    CUSTOMER_BY_PRINCIPAL.with(|index| index.borrow_mut().insert(StorablePrincipal(owner), id));
+    add_role(owner, Role::Customer);
+    record_audit(
+        "register_customer",
+        Some(id),
+        None,
+        audit_summary(&customer),
+    );
+    Ok(customer)
+}
+
+#[ic_cdk::update]
+fn update_customer_profile(id: u64, payload: CustomerPayload) -> Result<Customer, Error> {
+    check_rate_limit()?;
+    // A customer whose details are encrypted leaves them empty to change only their name
+    let encrypted = ENCRYPTED_PII.with(|storage| storage.borrow().contains_key(&id));
+    let name_only = encrypted
+        && payload.email.is_empty()
+        && payload.phone.is_empty()
+        && payload.license_number.is_empty();
+    if name_only {
+        validate_customer_name(&payload.name)?;
+    } else {
+        validate_customer_payload(&payload)?;
+    }
+    let CustomerPayload {
+        name,
+        email,
+        phone,
+        license_number,
+    } = payload;
+
+    CUSTOMER_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(customer) = storage.get(&id) {
+            ensure_owner_or_admin(&customer.owner)?;
+            // Update the profile fields, keeping id and created_at intact
+            let mut updated_customer = customer.clone();
+            updated_customer.name = name;
+            if name_only {
+                check_record_size(&updated_customer)?;
+                storage.insert(id, updated_customer.clone());
+                record_audit(
+                    "update_customer_profile",
+                    Some(id),
+                    audit_summary(&customer),
+                    audit_summary(&updated_customer),
+                );
+                return Ok(updated_customer);
+            }
+            updated_customer.email = email;
+            updated_customer.phone = phone;
+            // The recorded details describe the old license and must be entered again
+            if license_number != customer.license_number {
+                updated_customer.license = None;
+            }
+            updated_customer.license_number = license_number;
+            check_record_size(&updated_customer)?;
+            storage.insert(id, updated_customer.clone());
+            // Details entered in plaintext replace the encrypted ones
+            ENCRYPTED_PII.with(|storage| storage.borrow_mut().remove(&id));
+            record_audit(
+                "update_customer_profile",
+                Some(id),
+                audit_summary(&customer),
+                audit_summary(&updated_customer),
+            );
+            Ok(updated_customer)
+        } else {
+            Err(Error::NotFound {
+                msg: format!("Customer with id={} not found", id),
+            })
+        }
+    })
+}
+
+// Implement encrypted customer details with vetKD. A customer's client encrypts their email,
+// phone, and license number to their own principal under the canister's vetKD public key
+// and stores the ciphertext, which clears the plaintext fields. Only the customer, and
+// staff acting for them, can have the decryption key derived, and only encrypted to a
+// transport key of their own.
+#[ic_cdk::update]
+fn store_encrypted_pii(ciphertext: Vec<u8>) -> Result<Customer, Error> {
+    check_rate_limit()?;
+    let mut customer = my_profile()?;
+    if ciphertext.is_empty() || ciphertext.len() > MAX_ENCRYPTED_PII_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Encrypted details must be between 1 and {} bytes",
+                MAX_ENCRYPTED_PII_BYTES
+            ),
+        });
+    }
+    let before = audit_summary(&customer);
+    let encrypted = EncryptedPii {
+        customer_id: customer.id,
+        ciphertext,
+        updated_at: ic_cdk::api::time(),
+    };
+    ENCRYPTED_PII.with(|storage| storage.borrow_mut().insert(customer.id, encrypted));
+    customer.email.clear();
+    customer.phone.clear();
+    customer.license_number.clear();
+    CUSTOMER_STORAGE.with(|storage| storage.borrow_mut().insert(customer.id, customer.clone()));
+    record_audit(
+        "store_encrypted_pii",
+        Some(customer.id),
+        before,
+        audit_summary(&customer),
+    );
+    Ok(customer)
+}
+
+#[ic_cdk::query]
+fn get_encrypted_pii(customer_id: u64) -> Result<EncryptedPii, Error> {
+    let customer = get_customer(customer_id)?;
+    if ensure_owner_or_admin(&customer.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    ENCRYPTED_PII
+        .with(|storage| storage.borrow().get(&customer_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Customer with id={} has no encrypted details", customer_id),
+        })
+}
+
+// Get the vetKD public key details are encrypted under; the identity is the principal of
+// the customer they belong to
+#[ic_cdk::update]
+async fn get_pii_public_key() -> Result<Vec<u8>, Error> {
+    check_rate_limit()?;
+    let args = VetKdPublicKeyArgs {
+        canister_id: None,
+        context: PII_KEY_CONTEXT.to_vec(),
+        key_id: pii_key_id(),
+    };
+    let result: Result<(VetKdPublicKeyResult,), _> = ic_cdk::call(
+        Principal::management_canister(),
+        "vetkd_public_key",
+        (args,),
+    )
+    .await;
+    match result {
+        Ok((result,)) => Ok(result.public_key),
+        Err((code, msg)) => Err(Error::CallFailed {
+            msg: format!("vetkd_public_key failed: {:?} {}", code, msg),
+        }),
+    }
+}
+
+// Derive the caller's decryption key, encrypted to the given transport key
+#[ic_cdk::update]
+async fn derive_my_pii_key(transport_public_key: Vec<u8>) -> Result<Vec<u8>, Error> {
+    check_rate_limit()?;
+    let customer = my_profile()?;
+    derive_pii_key(customer.owner, transport_public_key).await
+}
+
+// Derive the decryption key of a customer's details for a staff member, encrypted to the
+// staff member's transport key. Every derivation is logged.
+#[ic_cdk::update]
+async fn derive_customer_pii_key(
+    customer_id: u64,
+    transport_public_key: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    let customer = get_customer(customer_id)?;
+    let key = derive_pii_key(customer.owner, transport_public_key).await?;
+    record_audit("derive_customer_pii_key", Some(customer_id), None, None);
+    Ok(key)
+}
+
+async fn derive_pii_key(owner: Principal, transport_public_key: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if transport_public_key.is_empty() || transport_public_key.len() > 128 {
+        return Err(Error::InvalidInput {
+            msg: "Transport public key must be between 1 and 128 bytes".to_string(),
+        });
+    }
+    let args = VetKdDeriveKeyArgs {
+        input: owner.as_slice().to_vec(),
+        context: PII_KEY_CONTEXT.to_vec(),
+        transport_public_key,
+        key_id: pii_key_id(),
+    };
+    let result: Result<(VetKdDeriveKeyResult,), _> = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        VETKD_DERIVE_KEY_CYCLES,
+    )
+    .await;
+    match result {
+        Ok((result,)) => Ok(result.encrypted_key),
+        Err((code, msg)) => Err(Error::CallFailed {
+            msg: format!("vetkd_derive_key failed: {:?} {}", code, msg),
+        }),
+    }
+}
+
+fn pii_key_id() -> VetKdKeyId {
+    VetKdKeyId {
+        curve: VetKdCurve::Bls12381G2,
+        name: config()
+            .vetkd_key_name
+            .unwrap_or_else(|| MAINNET_VETKD_KEY_NAME.to_string()),
+    }
+}
+
+// Record the driver's license of a customer; rentals must end before it expires
+#[ic_cdk::update]
+fn set_driver_license(customer_id: u64, license: DriverLicense) -> Result<Customer, Error> {
+    check_rate_limit()?;
+    let mut customer = get_customer(customer_id)?;
+    ensure_owner_or_admin(&customer.owner)?;
+    if license.issuing_country.trim().is_empty()
+        || license.issuing_country.len() > 64
+        || license.class.trim().is_empty()
+        || license.class.len() > 16
+    {
+        return Err(Error::InvalidInput {
+            msg: "Issuing country must be between 1 and 64 characters, and class between 1 and 16"
+                .to_string(),
+        });
+    }
+    if license.expires_at <= ic_cdk::api::time() {
+        return Err(Error::InvalidInput {
+            msg: "Driver's license has already expired".to_string(),
+        });
+    }
+    if license
+        .issued_at
+        .is_some_and(|issued_at| issued_at > ic_cdk::api::time() || issued_at >= license.expires_at)
+    {
+        return Err(Error::InvalidInput {
+            msg: "Driver's license must be issued in the past and before it expires".to_string(),
+        });
+    }
+
+    let before = audit_summary(&customer);
+    customer.license = Some(license);
+    CUSTOMER_STORAGE.with(|storage| storage.borrow_mut().insert(customer_id, customer.clone()));
+    record_audit(
+        "set_driver_license",
+        Some(customer_id),
+        before,
+        audit_summary(&customer),
+    );
+    Ok(customer)
+}
+
+// Record the date of birth of a customer, used by the age rules of car categories
+#[ic_cdk::update]
+fn set_date_of_birth(customer_id: u64, date_of_birth: i64) -> Result<Customer, Error> {
+    check_rate_limit()?;
+    let mut customer = get_customer(customer_id)?;
+    ensure_owner_or_admin(&customer.owner)?;
+    if i128::from(date_of_birth) >= i128::from(ic_cdk::api::time()) {
+        return Err(Error::InvalidInput {
+            msg: "Date of birth must be in the past".to_string(),
+        });
+    }
+
+    let before = audit_summary(&customer);
+    customer.date_of_birth = Some(date_of_birth);
+    CUSTOMER_STORAGE.with(|storage| storage.borrow_mut().insert(customer_id, customer.clone()));
+    record_audit(
+        "set_date_of_birth",
+        Some(customer_id),
+        before,
+        audit_summary(&customer),
+    );
+    Ok(customer)
+}
+
+// List the customers whose license expires within the given number of days, soonest first
+#[ic_cdk::query]
+fn list_customers_with_expiring_licenses(within_days: u64) -> Result<Vec<Customer>, Error> {
+    require_any_role(&[Role::Staff])?;
+    let deadline = ic_cdk::api::time().saturating_add(within_days.saturating_mul(NANOS_PER_DAY));
+    let mut customers: Vec<Customer> = CUSTOMER_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, customer)| customer)
+            .filter(|customer| {
+                customer
+                    .license
+                    .as_ref()
+                    .is_some_and(|license| license.expires_at <= deadline)
+            })
+            .collect()
+    });
+    customers.sort_by_key(|customer| customer.license.as_ref().map(|license| license.expires_at));
+    Ok(customers)
+}
+
+// A customer with a recorded license cannot rent past its expiry
+fn ensure_license_valid(customer: &Customer, end_date: u64) -> Result<(), Error> {
+    match &customer.license {
+        Some(license) if license.expires_at < end_date => Err(Error::InvalidInput {
+            msg: format!(
+                "The driver's license of customer id={} expires before the end of the rental",
+                customer.id
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Check a customer against the eligibility rule of a car category on the first rental day
+fn ensure_eligible(
+    customer: &Customer,
+    category: CarCategory,
+    start_date: u64,
+) -> Result<(), Error> {
+    let rule = match config()
+        .eligibility_rules
+        .into_iter()
+        .find(|rule| rule.category == category)
+    {
+        Some(rule) => rule,
+        None => return Ok(()),
+    };
+    let not_eligible = |reason: String| {
+        Err(Error::NotEligible {
+            msg: format!(
+                "Customer id={} cannot rent {:?} cars: {}",
+                customer.id, category, reason
+            ),
+        })
+    };
+    let years_since =
+        |since: i128| ((i128::from(start_date) - since).max(0) / i128::from(NANOS_PER_YEAR)) as u64;
+
+    if rule.min_age_years > 0 {
+        match customer.date_of_birth {
+            Some(born) if years_since(born.into()) >= rule.min_age_years => {}
+            Some(_) => {
+                return not_eligible(format!("drivers must be at least {}", rule.min_age_years))
+            }
+            None => return not_eligible("a date of birth is required".to_string()),
+        }
+    }
+    if rule.license_classes.is_empty() && rule.min_license_years == 0 {
+        return Ok(());
+    }
+    let license = match &customer.license {
+        Some(license) => license,
+        None => return not_eligible("driver's license details are required".to_string()),
+    };
+    if !rule.license_classes.is_empty()
+        && !rule
+            .license_classes
+            .iter()
+            .any(|class| class.trim().eq_ignore_ascii_case(license.class.trim()))
+    {
+        return not_eligible(format!(
+            "a license of class {} is required",
+            rule.license_classes.join(" or ")
+        ));
+    }
+    if rule.min_license_years > 0 {
+        match license.issued_at {
+            Some(issued_at) if years_since(issued_at.into()) >= rule.min_license_years => {}
+            Some(_) => {
+                return not_eligible(format!(
+                    "the license must have been held for at least {} years",
+                    rule.min_license_years
+                ))
+            }
+            None => return not_eligible("the license issue date is required".to_string()),
+        }
+    }
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_customer(id: u64) -> Result<Customer, Error> {
+    match CUSTOMER_STORAGE.with(|storage| storage.borrow().get(&id)) {
+        Some(customer) => Ok(customer.clone()),
+        None => Err(Error::NotFound {
+            msg: format!("Customer with id={} not found", id),
+        }),
+    }
+}
+
+// Report how far ahead rentals are booked and how long they last, over all completed rentals
+#[ic_cdk::query]
+fn get_rental_timing_stats() -> Result<RentalTimingStats, Error> {
+    require_any_role(&[Role::Staff])?;
+    let timing = RENTAL_TIMING.with(|cell| cell.borrow().get().clone());
+    let average = |total: u64, count: u64| {
+        if count == 0 {
+            0.0
+        } else {
+            total as f64 / count as f64
+        }
+    };
+    Ok(RentalTimingStats {
+        completed_rentals: timing.completed_rentals,
+        average_rental_days: average(timing.total_rental_days, timing.completed_rentals),
+        rental_days: days_buckets(&RENTAL_DAYS_BUCKETS, &timing.rental_days),
+        timed_rentals: timing.timed_rentals,
+        average_lead_days: average(timing.total_lead_days, timing.timed_rentals),
+        lead_days: days_buckets(&LEAD_DAYS_BUCKETS, &timing.lead_days),
+    })
+}
+
+// Pair each bucket's lower bound with the next one's and with its count
+fn days_buckets(bounds: &[u64], counts: &[u64]) -> Vec<DaysBucket> {
+    bounds
+        .iter()
+        .enumerate()
+        .map(|(index, &min_days)| DaysBucket {
+            min_days,
+            max_days: bounds.get(index + 1).map(|next| next - 1),
+            rentals: counts.get(index).copied().unwrap_or(0),
+        })
+        .collect()
+}
+
+// Count a number of days in the last bucket whose lower bound it reaches
+fn count_in_bucket(counts: &mut Vec<u64>, bounds: &[u64], days: u64) {
+    if counts.len() < bounds.len() {
+        counts.resize(bounds.len(), 0);
+    }
+    let index = bounds
+        .iter()
+        .rposition(|&min_days| days >= min_days)
+        .unwrap_or(0);
+    counts[index] += 1;
+}
+
+// Summarize a customer's rentals and spend, for marketing and risk decisions
+#[ic_cdk::query]
+fn get_customer_stats(customer_id: u64) -> Result<CustomerStats, Error> {
+    require_any_role(&[Role::Staff])?;
+    get_customer(customer_id)?;
+    let mut rentals = rentals_for_customer(customer_id);
+    ARCHIVED_RENTALS.with(|storage| {
+        rentals.extend(
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, rental)| rental)
+                .filter(|rental| rental.customer_id == customer_id),
+        )
+    });
+    let completed: Vec<&RentalRequest> = rentals
+        .iter()
+        .filter(|rental| rental.status == RentalStatus::Completed)
+        .collect();
+    let canceled_rentals = rentals
+        .iter()
+        .filter(|rental| rental.status == RentalStatus::Canceled)
+        .count() as u64;
+    let total_spend_e8s = PAYMENT_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, payment)| payment.customer_id == customer_id)
+            .fold(0u64, |total, (_, payment)| {
+                total.saturating_add(payment.amount_e8s.saturating_sub(payment.refunded_e8s))
+            })
+    });
+    let completed_days: u64 = completed
+        .iter()
+        .map(|rental| rental_days(rental.start_date, rental.end_date))
+        .sum();
+    Ok(CustomerStats {
+        customer_id,
+        rentals: rentals.len() as u64,
+        completed_rentals: completed.len() as u64,
+        canceled_rentals,
+        total_spend_e8s,
+        average_rental_days: if completed.is_empty() {
+            0.0
+        } else {
+            completed_days as f64 / completed.len() as f64
+        },
+        cancellation_rate_bps: if rentals.is_empty() {
+            0
+        } else {
+            canceled_rentals * BPS_DENOMINATOR / rentals.len() as u64
+        },
+    })
+}
+
+// List the customers who paid the most within a period, net of refunds, highest first
+#[ic_cdk::query]
+fn get_top_customers(limit: u32, period: Period) -> Result<Vec<CustomerSpend>, Error> {
+    require_any_role(&[Role::Staff])?;
+    validate_period(&period)?;
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut spend: BTreeMap<u64, (BTreeSet<u64>, u64)> = BTreeMap::new();
+    PAYMENT_STORAGE.with(|storage| {
+        for (_, payment) in storage.borrow().iter() {
+            if period.contains(payment.paid_at) {
+                let entry = spend.entry(payment.customer_id).or_default();
+                entry.0.insert(payment.rental_id);
+                entry.1 = entry
+                    .1
+                    .saturating_add(payment.amount_e8s.saturating_sub(payment.refunded_e8s));
+            }
+        }
+    });
+    let mut ranked: Vec<(u64, (BTreeSet<u64>, u64))> = spend.into_iter().collect();
+    ranked.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then(a.0.cmp(&b.0)));
+    ranked.truncate(limit);
+    Ok(CUSTOMER_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        ranked
+            .into_iter()
+            .map(|(customer_id, (rental_ids, spend_e8s))| CustomerSpend {
+                customer_id,
+                name: storage
+                    .get(&customer_id)
+                    .map_or_else(String::new, |customer| customer.name),
+                rentals_paid: rental_ids.len() as u64,
+                spend_e8s,
+            })
+            .collect()
+    }))
+}
+
+#[ic_cdk::update]
+fn delete_customer(id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    let customer = get_customer(id)?;
+    ensure_owner_or_admin(&customer.owner)?;
+
+    // Refuse to orphan rentals that are still in progress
+    let has_open_rentals = rentals_for_customer(id)
+        .iter()
+        .any(|request| request.status.is_open());
+    if has_open_rentals {
+        return Err(Error::InvalidInput {
+            msg: format!("Customer with id={} has pending or active rentals", id),
+        });
+    }
+
+    CUSTOMER_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    if let Some(code) = customer
+        .referral_code
+        .as_deref()
+        .and_then(parse_referral_code)
+    {
+        REFERRAL_CODES.with(|storage| storage.borrow_mut().remove(&code));
+    }
+    REFERRAL_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    CURRENCY_PREFERENCES.with(|storage| storage.borrow_mut().remove(&id));
+    ENCRYPTED_PII.with(|storage| storage.borrow_mut().remove(&id));
+    TERMS_ACCEPTANCES.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let keys: Vec<(u64, u64)> = storage
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            storage.remove(&key);
+        }
+    });
+    CUSTOMER_BY_PRINCIPAL.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&StorablePrincipal(customer.owner))
+    });
+    remove_role(customer.owner, Role::Customer);
+    record_audit("delete_customer", Some(id), audit_summary(&customer), None);
+    Ok(())
+}
+
+// Implement the terms of service. Admins publish new versions; a mandatory version must be
+// accepted before a customer can book again, while other versions can be accepted at will.
+#[ic_cdk::update]
+fn publish_terms(text: String, mandatory: bool) -> Result<TermsOfService, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    if text.trim().is_empty() || text.len() > MAX_TERMS_TEXT_BYTES {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Terms of service must be between 1 and {} bytes",
+                MAX_TERMS_TEXT_BYTES
+            ),
+        });
+    }
+    let version = current_terms().map_or(1, |terms| terms.version + 1);
+    let terms = TermsOfService {
+        version,
+        text_hash: Sha256::digest(text.as_bytes()).to_vec(),
+        text,
+        // Customers cannot book before accepting some version, so the first one binds them
+        mandatory: mandatory || version == 1,
+        published_by: caller(),
+        published_at: ic_cdk::api::time(),
+    };
+    TERMS_OF_SERVICE.with(|storage| storage.borrow_mut().insert(version, terms.clone()));
+    record_audit(
+        "publish_terms",
+        Some(version),
+        None,
+        audit_summary(&(version, &terms.text_hash, mandatory)),
+    );
+    Ok(terms)
+}
+
+#[ic_cdk::query]
+fn get_current_terms() -> Result<TermsOfService, Error> {
+    current_terms().ok_or_else(|| Error::NotFound {
+        msg: "No terms of service were published".to_string(),
+    })
+}
+
+#[ic_cdk::query]
+fn get_terms(version: u64) -> Result<TermsOfService, Error> {
+    TERMS_OF_SERVICE
+        .with(|storage| storage.borrow().get(&version))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Terms of service version {} not found", version),
+        })
+}
+
+// Accept the current terms of service. The hash of the text must be given back, so the
+// acceptance is of the text the customer was shown.
+#[ic_cdk::update]
+fn accept_terms(version: u64, text_hash: Vec<u8>) -> Result<TermsAcceptance, Error> {
+    check_rate_limit()?;
+    let customer = my_profile()?;
+    let terms = get_current_terms()?;
+    if version != terms.version {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Version {} is not the current terms of service, version {} is",
+                version, terms.version
+            ),
+        });
+    }
+    if text_hash != terms.text_hash {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "The hash does not match the text of terms of service version {}",
+                version
+            ),
+        });
+    }
+    if let Some(acceptance) =
+        TERMS_ACCEPTANCES.with(|storage| storage.borrow().get(&(customer.id, version)))
+    {
+        return Ok(acceptance);
+    }
+    let acceptance = TermsAcceptance {
+        customer_id: customer.id,
+        version,
+        principal: caller(),
+        accepted_at: ic_cdk::api::time(),
+    };
+    TERMS_ACCEPTANCES.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert((customer.id, version), acceptance.clone())
+    });
+    record_audit(
+        "accept_terms",
+        Some(customer.id),
+        None,
+        audit_summary(&acceptance),
+    );
+    Ok(acceptance)
+}
+
+// List the versions of the terms of service a customer accepted, oldest first
+#[ic_cdk::query]
+fn get_terms_acceptances(customer_id: u64) -> Result<Vec<TermsAcceptance>, Error> {
+    let customer = get_customer(customer_id)?;
+    if ensure_owner_or_admin(&customer.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(TERMS_ACCEPTANCES.with(|storage| {
+        storage
+            .borrow()
+            .range((customer_id, 0)..=(customer_id, u64::MAX))
+            .map(|(_, acceptance)| acceptance)
+            .collect()
+    }))
+}
+
+fn current_terms() -> Option<TermsOfService> {
+    TERMS_OF_SERVICE.with(|storage| storage.borrow().last_key_value().map(|(_, terms)| terms))
+}
+
+// A customer must have accepted the latest mandatory version of the terms, or a later one
+fn ensure_terms_accepted(customer_id: u64) -> Result<(), Error> {
+    let required = TERMS_OF_SERVICE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, terms)| terms.mandatory)
+            .map(|(version, _)| version)
+            .last()
+    });
+    let Some(required) = required else {
+        return Ok(());
+    };
+    let accepted = TERMS_ACCEPTANCES.with(|storage| {
+        storage
+            .borrow()
+            .range((customer_id, required)..=(customer_id, u64::MAX))
+            .next()
+            .is_some()
+    });
+    if accepted {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput {
+            msg: format!(
+                "Customer id={} must accept the current terms of service before booking",
+                customer_id
+            ),
+        })
+    }
+}
+
+// Erase the caller's personal data. Unlike deleting the profile, the customer record and
+// its rentals, payments, and invoices are kept, so statistics and the books still add up.
+#[ic_cdk::update]
+fn erase_my_data() -> Result<(), Error> {
+    check_rate_limit()?;
+    let customer = my_profile()?;
+    erase_customer_data(customer, "erase_my_data")?;
+    Ok(())
+}
+
+// Erase the personal data of a customer on their behalf
+#[ic_cdk::update]
+fn anonymize_customer(id: u64) -> Result<Customer, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let customer = get_customer(id)?;
+    erase_customer_data(customer, "anonymize_customer")
+}
+
+// Clear the personal fields of a customer and hand their records to the management
+// canister, which never calls. Amounts, dates, cars, and ratings stay as they were.
+fn erase_customer_data(mut customer: Customer, method: &str) -> Result<Customer, Error> {
+    let id = customer.id;
+    if customer.erased_at.is_some() {
+        return Err(Error::Conflict {
+            msg: format!("Customer with id={} was already erased", id),
+        });
+    }
+    let rentals = rentals_for_customer(id);
+    if rentals.iter().any(|request| request.status.is_open()) {
+        return Err(Error::InvalidInput {
+            msg: format!("Customer with id={} has pending or active rentals", id),
+        });
+    }
+    // Money still owed to the customer is paid to their principal, which erasure gives up
+    let archived: Vec<RentalRequest> = ARCHIVED_RENTALS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, rental)| rental)
+            .filter(|rental| rental.customer_id == id)
+            .collect()
+    });
+    let deposit_held = rentals.iter().chain(archived.iter()).any(|rental| {
+        DEPOSIT_STORAGE
+            .with(|storage| storage.borrow().get(&rental.id))
+            .is_some_and(|deposit| deposit.remaining_e8s() > 0)
+    });
+    if deposit_held {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Customer with id={} has a deposit that must be released or forfeited first",
+                id
+            ),
+        });
+    }
+    if wallet_balance(id) > 0 {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Customer with id={} must spend their wallet balance first",
+                id
+            ),
+        });
+    }
+    if earnings_balance(&customer.owner) > 0 {
+        return Err(Error::InvalidInput {
+            msg: format!("Customer with id={} must withdraw their earnings first", id),
+        });
+    }
+
+    let erased = erased_values(&customer);
+    let principal = customer.owner;
+    let erased_owner = Principal::management_canister();
+    if let Some(code) = customer
+        .referral_code
+        .as_deref()
+        .and_then(parse_referral_code)
+    {
+        REFERRAL_CODES.with(|storage| storage.borrow_mut().remove(&code));
+    }
+    scrub_customer(&mut customer, ic_cdk::api::time());
+    CUSTOMER_STORAGE.with(|storage| storage.borrow_mut().insert(id, customer.clone()));
+    CUSTOMER_BY_PRINCIPAL.with(|index| index.borrow_mut().remove(&StorablePrincipal(principal)));
+    remove_role(principal, Role::Customer);
+    CURRENCY_PREFERENCES.with(|storage| storage.borrow_mut().remove(&id));
+    ENCRYPTED_PII.with(|storage| storage.borrow_mut().remove(&id));
+
+    // Signed agreements and agreement documents are records of the contract and are kept
+    for mut rental in rentals {
+        rental.owner = erased_owner;
+        store_rental_request(&mut rental);
+    }
+    ARCHIVED_RENTALS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        for mut rental in archived {
+            rental.owner = erased_owner;
+            storage.insert(rental.id, rental);
+        }
+    });
+    WAITLIST_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let entries: Vec<WaitlistEntry> = storage
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.customer_id == id)
+            .collect();
+        for mut entry in entries {
+            if entry.status == WaitlistStatus::Waiting {
+                entry.status = WaitlistStatus::Left;
+            }
+            entry.owner = erased_owner;
+            storage.insert(entry.id, entry);
+        }
+    });
+    REVIEW_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let reviews: Vec<Review> = storage
+            .iter()
+            .map(|(_, review)| review)
+            .filter(|review| review.customer_id == id && !review.comment.is_empty())
+            .collect();
+        for mut review in reviews {
+            review.comment.clear();
+            storage.insert(review.id, review);
+        }
+    });
+    NOTIFICATIONS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let keys: Vec<(u64, u64)> = storage
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            storage.remove(&key);
+        }
+    });
+    // Which terms were accepted and when is kept, but not by which principal
+    TERMS_ACCEPTANCES.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let acceptances: Vec<TermsAcceptance> = storage
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(_, acceptance)| acceptance)
+            .collect();
+        for mut acceptance in acceptances {
+            acceptance.principal = erased_owner;
+            storage.insert((id, acceptance.version), acceptance);
+        }
+    });
+    MESSAGE_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let ids: Vec<u64> = log
+            .iter()
+            .filter(|(_, message)| message.customer_id == id)
+            .map(|(message_id, _)| message_id)
+            .collect();
+        for message_id in ids {
+            log.remove(&message_id);
+        }
+    });
+    // Earlier audit entries may quote the profile or have been made by the customer
+    AUDIT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let entries: Vec<AuditEntry> = log
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| {
+                let mut summaries = entry.before.iter().chain(entry.after.iter());
+                entry.caller == principal
+                    || summaries.any(|summary| erased.iter().any(|value| summary.contains(value)))
+            })
+            .collect();
+        for mut entry in entries {
+            if entry.caller == principal {
+                entry.caller = erased_owner;
+            }
+            entry.before = entry.before.map(|summary| redact(&summary, &erased));
+            entry.after = entry.after.map(|summary| redact(&summary, &erased));
+            log.insert(entry.id, entry);
+        }
+    });
+
+    record_audit(method, Some(id), None, audit_summary(&customer));
+    Ok(customer)
+}
+
+fn scrub_customer(customer: &mut Customer, at: u64) {
+    customer.name.clear();
+    customer.email.clear();
+    customer.phone.clear();
+    customer.license_number.clear();
+    customer.owner = Principal::management_canister();
+    customer.referral_code = None;
+    customer.license = None;
+    customer.date_of_birth = None;
+    customer.erased_at = Some(at);
+}
+
+// Collect the personal values of a customer as they may appear in stored text
+fn erased_values(customer: &Customer) -> Vec<String> {
+    let mut values = vec![customer.owner.to_text()];
+    values.extend(
+        [
+            &customer.name,
+            &customer.email,
+            &customer.phone,
+            &customer.license_number,
+        ]
+        .into_iter()
+        .chain(customer.referral_code.as_ref())
+        .filter(|value| !value.trim().is_empty())
+        .map(|value| serde_json::to_string(value).unwrap_or_default()),
+    );
+    values
+}
+
+// Replace each of the values in a text with a marker. Values other than principals are
+// matched as JSON strings, so a short name is not replaced inside other words.
+fn redact(text: &str, values: &[String]) -> String {
+    values.iter().fold(text.to_string(), |text, value| {
+        let marker = if value.starts_with('"') {
+            "\"[erased]\""
+        } else {
+            "[erased]"
+        };
+        text.replace(value.as_str(), marker)
+    })
+}
+
+// Implement the referral program
+#[ic_cdk::update]
+async fn generate_referral_code() -> Result<String, Error> {
+    check_rate_limit()?;
+    let customer = my_profile()?;
+    if let Some(code) = customer.referral_code {
+        return Ok(code);
+    }
+    // Five random bytes make a ten character code
+    let code_value = random_code_value(5).await?;
+    if REFERRAL_CODES.with(|storage| storage.borrow().contains_key(&code_value)) {
+        return Err(Error::Conflict {
+            msg: "Generated referral code is taken, please retry".to_string(),
+        });
+    }
+
+    // The profile may have changed while waiting for randomness
+    let mut customer = my_profile()?;
+    if let Some(code) = customer.referral_code {
+        return Ok(code);
+    }
+    let code = format!("{:010X}", code_value);
+    customer.referral_code = Some(code.clone());
+    REFERRAL_CODES.with(|storage| storage.borrow_mut().insert(code_value, customer.id));
+    CUSTOMER_STORAGE.with(|storage| storage.borrow_mut().insert(customer.id, customer.clone()));
+    record_audit(
+        "generate_referral_code",
+        Some(customer.id),
+        None,
+        audit_summary(&code),
+    );
+    Ok(code)
+}
+
+// List the customers referred by the caller
+#[ic_cdk::query]
+fn get_my_referrals() -> Result<Vec<Referral>, Error> {
+    let customer = my_profile()?;
+    Ok(REFERRAL_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, referral)| referral.referrer_id == customer.id)
+            .map(|(_, referral)| referral)
+            .collect()
+    }))
+}
+
+// Find the referrer of a code, refusing referrals of oneself under another identity
+fn referrer_for_code(code: &str, email: &str, license_number: &str) -> Result<Customer, Error> {
+    let invalid = || Error::InvalidInput {
+        msg: format!("Referral code {} is not valid", code.trim()),
+    };
+    let referrer_id = parse_referral_code(code)
+        .and_then(|code| REFERRAL_CODES.with(|storage| storage.borrow().get(&code)))
+        .ok_or_else(invalid)?;
+    let referrer = get_customer(referrer_id).map_err(|_| invalid())?;
+    // A referrer whose details are encrypted has none to compare, so only a different
+    // principal keeps them from referring themselves
+    if referrer.email.eq_ignore_ascii_case(email.trim())
+        || referrer.license_number.trim() == license_number.trim()
+    {
+        return Err(Error::InvalidInput {
+            msg: "Customers cannot refer themselves".to_string(),
+        });
+    }
+    Ok(referrer)
+}
+
+fn parse_referral_code(code: &str) -> Option<u64> {
+    u64::from_str_radix(code.trim(), 16).ok()
+}
+
+// Draw a code from the management canister's randomness, using up to eight bytes
+async fn random_code_value(bytes: usize) -> Result<u64, Error> {
+    Ok(random_bytes().await?[..bytes]
+        .iter()
+        .fold(0u64, |value, byte| (value << 8) | *byte as u64))
+}
+
+// Fetch 32 random bytes from the management canister
+async fn random_bytes() -> Result<Vec<u8>, Error> {
+    let (random,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| Error::CallFailed {
+            msg: format!("raw_rand failed: {:?} {}", code, msg),
+        })?;
+    Ok(random)
+}
+
+// Credit both parties once the referred customer completes their first rental
+fn reward_referral(customer_id: u64, now: u64) {
+    let Some(mut referral) = REFERRAL_STORAGE.with(|storage| storage.borrow().get(&customer_id))
+    else {
+        return;
+    };
+    if referral.rewarded_at.is_some() {
+        return;
+    }
+    let points = config().referral_reward_points;
+    credit_points(referral.referrer_id, points);
+    credit_points(referral.referred_id, points);
+    referral.rewarded_at = Some(now);
+    REFERRAL_STORAGE.with(|storage| storage.borrow_mut().insert(customer_id, referral));
+}
+
+// Implement the prepaid wallet
+#[ic_cdk::query]
+fn get_wallet_balance(customer_id: u64) -> Result<u64, Error> {
+    let customer = get_customer(customer_id)?;
+    if ensure_owner_or_admin(&customer.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(wallet_balance(customer_id))
+}
+
+#[ic_cdk::query]
+fn list_wallet_transactions(customer_id: u64) -> Result<Vec<WalletTransaction>, Error> {
+    let customer = get_customer(customer_id)?;
+    if ensure_owner_or_admin(&customer.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(WALLET_TRANSACTION_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, transaction)| transaction.customer_id == customer_id)
+            .map(|(_, transaction)| transaction)
+            .collect()
+    }))
+}
+
+// Add funds to the caller's wallet with an ICRC-2 transfer_from
+#[ic_cdk::update]
+async fn top_up_wallet(amount_e8s: u64) -> Result<WalletTransaction, Error> {
+    check_rate_limit()?;
+    let customer = my_profile()?;
+    if amount_e8s == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Top-up amount must be positive".to_string(),
+        });
+    }
+    let transfer_id = icrc2_transfer_from(
+        token_ledger_canister_id(),
+        customer.owner,
+        None,
+        amount_e8s,
+        customer.id,
+    )
+    .await?;
+    let transaction = post_wallet_transaction(
+        customer.id,
+        WalletTransactionKind::TopUp { transfer_id },
+        amount_e8s as i64,
+        ic_cdk::api::time(),
+    )?;
+    record_audit(
+        "top_up_wallet",
+        Some(customer.id),
+        None,
+        audit_summary(&transaction),
+    );
+    Ok(transaction)
+}
+
+#[ic_cdk::update]
+async fn issue_gift_card(amount_e8s: u64) -> Result<GiftCard, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    if amount_e8s == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Gift card amount must be positive".to_string(),
+        });
+    }
+    let code_value = random_code_value(8).await?;
+    if GIFT_CARD_STORAGE.with(|storage| storage.borrow().contains_key(&code_value)) {
+        return Err(Error::Conflict {
+            msg: "Generated gift card code is taken, please retry".to_string(),
+        });
+    }
+    let gift_card = GiftCard {
+        id: next_id()?,
+        code: format!("{:016X}", code_value),
+        amount_e8s,
+        issued_by: caller(),
+        issued_at: ic_cdk::api::time(),
+        redeemed_by: None,
+        redeemed_at: None,
+    };
+    GIFT_CARD_STORAGE.with(|storage| storage.borrow_mut().insert(code_value, gift_card.clone()));
+    // The code redeems the card, so it is left out of the log
+    record_audit(
+        "issue_gift_card",
+        Some(gift_card.id),
+        None,
+        audit_summary(&serde_json::json!({ "amount_e8s": amount_e8s })),
+    );
+    Ok(gift_card)
+}
+
+#[ic_cdk::query]
+fn list_gift_cards() -> Result<Vec<GiftCard>, Error> {
+    require_any_role(&[Role::Admin])?;
+    Ok(GIFT_CARD_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, gift_card)| gift_card)
+            .collect()
+    }))
+}
+
+// Credit the value of an unredeemed gift card to the caller's wallet
+#[ic_cdk::update]
+fn redeem_gift_card(code: String) -> Result<WalletTransaction, Error> {
+    check_rate_limit()?;
+    let customer = my_profile()?;
+    let invalid = || Error::InvalidInput {
+        msg: format!("Gift card {} is not valid", code.trim()),
+    };
+    let code_value = u64::from_str_radix(code.trim(), 16).map_err(|_| invalid())?;
+    let mut gift_card = GIFT_CARD_STORAGE
+        .with(|storage| storage.borrow().get(&code_value))
+        .ok_or_else(invalid)?;
+    if gift_card.redeemed_by.is_some() {
+        return Err(Error::Conflict {
+            msg: format!("Gift card {} was already redeemed", gift_card.code),
+        });
+    }
+
+    let transaction = post_wallet_transaction(
+        customer.id,
+        WalletTransactionKind::GiftCard {
+            gift_card_id: gift_card.id,
+        },
+        gift_card.amount_e8s as i64,
+        ic_cdk::api::time(),
+    )?;
+    gift_card.redeemed_by = Some(customer.id);
+    gift_card.redeemed_at = Some(transaction.at);
+    GIFT_CARD_STORAGE.with(|storage| storage.borrow_mut().insert(code_value, gift_card.clone()));
+    record_audit(
+        "redeem_gift_card",
+        Some(gift_card.id),
+        None,
+        audit_summary(&transaction),
+    );
+    Ok(transaction)
+}
+
+fn wallet_balance(customer_id: u64) -> u64 {
+    WALLET_BALANCES
+        .with(|storage| storage.borrow().get(&customer_id))
+        .unwrap_or(0)
+}
+
+// Apply a credit or debit to a wallet and record it in the history
+fn post_wallet_transaction(
+    customer_id: u64,
+    kind: WalletTransactionKind,
+    amount_e8s: i64,
+    at: u64,
+) -> Result<WalletTransaction, Error> {
+    let balance = wallet_balance(customer_id);
+    let balance_after_e8s = balance
+        .checked_add_signed(amount_e8s)
+        .ok_or(Error::InvalidInput {
+            msg: format!(
+                "Wallet of customer id={} holds only {} e8s",
+                customer_id, balance
+            ),
+        })?;
+    let transaction = WalletTransaction {
+        id: next_id()?,
+        customer_id,
+        kind,
+        amount_e8s,
+        balance_after_e8s,
+        at,
+    };
+    WALLET_BALANCES.with(|storage| storage.borrow_mut().insert(customer_id, balance_after_e8s));
+    WALLET_TRANSACTION_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(transaction.id, transaction.clone())
+    });
+    Ok(transaction)
+}
+
+// Implement identity queries for the calling principal
+#[ic_cdk::query]
+fn whoami() -> Principal {
+    caller()
+}
+
+#[ic_cdk::query]
+fn my_profile() -> Result<Customer, Error> {
+    let caller = caller();
+    match customer_id_for(&caller) {
+        Some(customer_id) => get_customer(customer_id),
+        None => Err(Error::NotFound {
+            msg: format!("No customer registered for principal {}", caller),
+        }),
+    }
+}
+
+// Implement a JSON API over HTTP for clients without Candid tooling. Reads are served by
+// the http_request query; bookings are upgraded to http_request_update and must carry an
+// API key as a bearer token, since calls through the HTTP gateway are anonymous.
+//   GET /cars?cursor=&limit=                     one page of cars
+//   GET /cars/{id}/availability?from=&to=        blocked intervals, the next 30 days by default
+//   GET /metrics                                 Prometheus metrics, for staff API keys
+//   POST /rentals                                a RentalRequestPayload, with an optional
+//                                                Idempotency-Key header
+#[ic_cdk::query]
+fn http_request(request: HttpGatewayRequest) -> HttpGatewayResponse {
+    let (path, query) = split_url(&request.url);
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let param = |name: &str| -> Option<u64> {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .and_then(|(_, value)| value.parse().ok())
+    };
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["cars"]) => json_response(
+            200,
+            &list_cars_page(
+                param("cursor"),
+                param("limit").map_or(MAX_PAGE_SIZE, |limit| limit as u32),
+            ),
+        ),
+        ("GET", ["cars", car_id, "availability"]) => match car_id.parse::<u64>() {
+            Ok(car_id) => {
+                let from = param("from").unwrap_or_else(ic_cdk::api::time);
+                let to = param("to").unwrap_or(from.saturating_add(HTTP_AVAILABILITY_WINDOW));
+                result_response(200, get_car_availability(car_id, from, to))
+            }
+            Err(_) => error_response(404, "Not found"),
+        },
+        ("GET", ["metrics"]) => metrics_response(&request),
+        ("POST", ["rentals"]) => HttpGatewayResponse {
+            status_code: 200,
+            headers: vec![],
+            body: vec![],
+            upgrade: Some(true),
+        },
+        (_, ["cars"]) | (_, ["cars", _, "availability"]) | (_, ["rentals"]) | (_, ["metrics"]) => {
+            error_response(405, "Method not allowed")
+        }
+        _ => error_response(404, "Not found"),
+    }
+}
+
+#[ic_cdk::update]
+fn http_request_update(request: HttpGatewayRequest) -> HttpGatewayResponse {
+    let (path, _) = split_url(&request.url);
+    if request.method != "POST" || path.trim_end_matches('/') != "/rentals" {
+        return error_response(404, "Not found");
+    }
+    let principal = match request_api_key(&request) {
+        Some(mut api_key) => {
+            api_key.last_used_at = Some(ic_cdk::api::time());
+            API_KEYS.with(|storage| storage.borrow_mut().insert(api_key.id, api_key.clone()));
+            api_key.principal
+        }
+        None => return error_response(401, "A valid API key is required"),
+    };
+    let payload: RentalRequestPayload = match serde_json::from_slice(&request.body) {
+        Ok(payload) => payload,
+        Err(error) => return error_response(400, &format!("Invalid rental request: {}", error)),
+    };
+    HTTP_CALLER.with(|caller| *caller.borrow_mut() = Some(principal));
+    let result = add_rental_request(payload, request_header(&request, "Idempotency-Key"));
+    HTTP_CALLER.with(|caller| *caller.borrow_mut() = None);
+    result_response(201, result)
+}
+
+// Create an API key acting as a principal; its secret is only returned here
+#[ic_cdk::update]
+async fn create_api_key(principal: Principal, label: String) -> Result<ApiKeyRegistration, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "An API key cannot act as the anonymous principal".to_string(),
+        });
+    }
+    if label.trim().is_empty() || label.len() > 64 {
+        return Err(Error::InvalidInput {
+            msg: "The label must be between 1 and 64 characters".to_string(),
+        });
+    }
+    let secret = hex_string(&random_bytes().await?);
+    let api_key = ApiKey {
+        id: next_id()?,
+        principal,
+        label: label.trim().to_string(),
+        created_at: ic_cdk::api::time(),
+        last_used_at: None,
+    };
+    API_KEYS.with(|storage| storage.borrow_mut().insert(api_key.id, api_key.clone()));
+    API_KEY_HASHES.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(Sha256::digest(secret.as_bytes()).into(), api_key.id)
+    });
+    record_audit(
+        "create_api_key",
+        Some(api_key.id),
+        None,
+        audit_summary(&api_key),
+    );
+    Ok(ApiKeyRegistration { api_key, secret })
+}
+
+#[ic_cdk::update]
+fn revoke_api_key(id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    match API_KEYS.with(|storage| storage.borrow_mut().remove(&id)) {
+        Some(api_key) => {
+            API_KEY_HASHES.with(|storage| {
+                let mut storage = storage.borrow_mut();
+                let hash = storage
+                    .iter()
+                    .find(|(_, key_id)| *key_id == id)
+                    .map(|(hash, _)| hash);
+                if let Some(hash) = hash {
+                    storage.remove(&hash);
+                }
+            });
+            record_audit("revoke_api_key", Some(id), audit_summary(&api_key), None);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("API key with id={} not found", id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_api_keys() -> Result<Vec<ApiKey>, Error> {
+    require_any_role(&[Role::Admin])?;
+    Ok(API_KEYS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, api_key)| api_key)
+            .collect()
+    }))
+}
+
+// Find the API key a request carries as a bearer token
+fn request_api_key(request: &HttpGatewayRequest) -> Option<ApiKey> {
+    let secret = request_header(request, "Authorization")?
+        .strip_prefix("Bearer ")?
+        .trim()
+        .to_string();
+    let hash: [u8; 32] = Sha256::digest(secret.as_bytes()).into();
+    let id = API_KEY_HASHES.with(|storage| storage.borrow().get(&hash))?;
+    API_KEYS.with(|storage| storage.borrow().get(&id))
+}
+
+fn request_header(request: &HttpGatewayRequest, name: &str) -> Option<String> {
+    request
+        .headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().to_string())
+}
+
+// Serve the metrics in the Prometheus text format to callers with a staff API key
+fn metrics_response(request: &HttpGatewayRequest) -> HttpGatewayResponse {
+    let api_key = match request_api_key(request) {
+        Some(api_key) => api_key,
+        None => return error_response(401, "A valid API key is required"),
+    };
+    HTTP_CALLER.with(|caller| *caller.borrow_mut() = Some(api_key.principal));
+    let allowed = require_any_role(&[Role::Staff]);
+    HTTP_CALLER.with(|caller| *caller.borrow_mut() = None);
+    if let Err(error) = allowed {
+        return result_response::<()>(200, Err(error));
+    }
+    HttpGatewayResponse {
+        status_code: 200,
+        headers: vec![(
+            "Content-Type".to_string(),
+            "text/plain; version=0.0.4".to_string(),
+        )],
+        body: metrics_text().into_bytes(),
+        upgrade: None,
+    }
+}
+
+// Render the fleet, rental, payment, and canister gauges and counters
+fn metrics_text() -> String {
+    let mut text = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, u128)>| {
+        text.push_str(&format!(
+            "# HELP {} {}\n# TYPE {} {}\n",
+            name, help, name, kind
+        ));
+        for (labels, value) in samples {
+            text.push_str(&format!("{}{} {}\n", name, labels, value));
+        }
+    };
+
+    let (cars, active_cars) = CAR_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        let active = storage.iter().filter(|(_, car)| car.is_active()).count();
+        (storage.len(), active as u64)
+    });
+    metric(
+        "car_rental_cars",
+        "gauge",
+        "Cars in the fleet, including retired ones.",
+        vec![(String::new(), cars as u128)],
+    );
+    metric(
+        "car_rental_active_cars",
+        "gauge",
+        "Cars that can be rented.",
+        vec![(String::new(), active_cars as u128)],
+    );
+
+    let mut rentals: BTreeMap<String, u128> = [
+        RentalStatus::Pending,
+        RentalStatus::Approved,
+        RentalStatus::Active,
+        RentalStatus::Completed,
+        RentalStatus::Canceled,
+        RentalStatus::Expired,
+    ]
+    .iter()
+    .map(|status| (format!("{:?}", status), 0))
+    .collect();
+    let mut count_rental = |request: RentalRequest| {
+        *rentals.entry(format!("{:?}", request.status)).or_default() += 1;
+    };
+    RENTAL_REQUEST_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .for_each(|(_, request)| count_rental(request))
+    });
+    ARCHIVED_RENTALS.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .for_each(|(_, request)| count_rental(request))
+    });
+    metric(
+        "car_rental_rentals",
+        "gauge",
+        "Rental requests by status, including archived ones.",
+        rentals
+            .into_iter()
+            .map(|(status, count)| (format!("{{status=\"{}\"}}", status), count))
+            .collect(),
+    );
+
+    let mut payments: BTreeMap<&str, (u128, u128)> = BTreeMap::new();
+    let mut refunded_e8s = 0u128;
+    PAYMENT_STORAGE.with(|storage| {
+        for (_, payment) in storage.borrow().iter() {
+            let method = match payment.method {
+                PaymentMethod::IcpTransfer => "icp_transfer",
+                PaymentMethod::Icrc2TransferFrom { .. } => "icrc2_transfer_from",
+                PaymentMethod::Wallet => "wallet",
+                PaymentMethod::Ckbtc { .. } => "ckbtc",
+            };
+            let entry = payments.entry(method).or_default();
+            entry.0 += 1;
+            entry.1 += payment.amount_e8s as u128;
+            refunded_e8s += payment.refunded_e8s as u128;
+        }
+    });
+    let by_method = |value: fn(&(u128, u128)) -> u128| {
+        payments
+            .iter()
+            .map(|(method, totals)| (format!("{{method=\"{}\"}}", method), value(totals)))
+            .collect()
+    };
+    metric(
+        "car_rental_payments_total",
+        "counter",
+        "Payments received by method.",
+        by_method(|totals| totals.0),
+    );
+    metric(
+        "car_rental_payments_e8s_total",
+        "counter",
+        "Amount paid by method, in e8s.",
+        by_method(|totals| totals.1),
+    );
+    metric(
+        "car_rental_refunds_e8s_total",
+        "counter",
+        "Amount refunded, in e8s.",
+        vec![(String::new(), refunded_e8s)],
+    );
+
+    metric(
+        "car_rental_stable_memory_bytes",
+        "gauge",
+        "Stable memory used by the canister.",
+        vec![(
+            String::new(),
+            ic_cdk::api::stable::stable64_size() as u128 * 65_536,
+        )],
+    );
+    metric(
+        "car_rental_cycle_balance",
+        "gauge",
+        "Cycles held by the canister.",
+        vec![(String::new(), ic_cdk::api::canister_balance128())],
+    );
+    text
+}
+
+fn split_url(url: &str) -> (&str, &str) {
+    url.split_once('?').unwrap_or((url, ""))
+}
+
+fn json_response(status_code: u16, value: &impl serde::Serialize) -> HttpGatewayResponse {
+    HttpGatewayResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: serde_json::to_vec(value).unwrap_or_default(),
+        upgrade: None,
+    }
+}
+
+fn error_response(status_code: u16, message: &str) -> HttpGatewayResponse {
+    json_response(status_code, &serde_json::json!({ "error": message }))
+}
+
+// Answer with the value, or with the error and the HTTP status matching it
+fn result_response<T: serde::Serialize>(
+    status_code: u16,
+    result: Result<T, Error>,
+) -> HttpGatewayResponse {
+    match result {
+        Ok(value) => json_response(status_code, &value),
+        Err(error) => {
+            let status_code = match error {
+                Error::NotFound { .. } => 404,
+                Error::InvalidInput { .. } => 400,
+                Error::Unauthorized { .. } => 403,
+                Error::Conflict { .. } => 409,
+                Error::NotEligible { .. } | Error::PaymentFailed { .. } => 422,
+                Error::RecordTooLarge { .. } => 413,
+                Error::RateLimited { .. } => 429,
+                Error::LedgerCallFailed { .. } | Error::CallFailed { .. } => 502,
+            };
+            json_response(status_code, &serde_json::json!({ "error": error }))
+        }
+    }
+}
+
+// Implement the customer notification inbox. Notifications are added when a booking is
+// received, approved, or paid, and a day before pickup and return.
+#[ic_cdk::query]
+fn list_my_notifications(
+    unread_only: bool,
+    cursor: Option<u64>,
+    limit: u32,
+) -> Result<Page<Notification>, Error> {
+    let customer = my_profile()?;
+    // Inboxes are capped, so the whole inbox is read to list it newest first
+    let inbox: Vec<(u64, Notification)> = NOTIFICATIONS.with(|storage| {
+        storage
+            .borrow()
+            .range((customer.id, 0)..=(customer.id, u64::MAX))
+            .filter(|(_, notification)| !unread_only || !notification.read)
+            .map(|((_, id), notification)| (id, notification))
+            .collect()
+    });
+    let total = inbox.len() as u64;
+    Ok(page_of(
+        inbox
+            .into_iter()
+            .rev()
+            .filter(|(id, _)| cursor.is_none_or(|cursor| *id <= cursor)),
+        total,
+        limit,
+    ))
+}
+
+#[ic_cdk::update]
+fn mark_notification_read(id: u64) -> Result<Notification, Error> {
+    check_rate_limit()?;
+    let customer = my_profile()?;
+    let mut notification = NOTIFICATIONS
+        .with(|storage| storage.borrow().get(&(customer.id, id)))
+        .ok_or(Error::NotFound {
+            msg: format!("Notification with id={} not found", id),
+        })?;
+    if !notification.read {
+        let before = notification.clone();
+        notification.read = true;
+        NOTIFICATIONS.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert((customer.id, id), notification.clone())
+        });
+        record_audit(
+            "mark_notification_read",
+            Some(id),
+            audit_summary(&before),
+            audit_summary(&notification),
+        );
+    }
+    Ok(notification)
+}
+
+// Add a notification about a rental to its customer's inbox, dropping the oldest ones
+// beyond the inbox size
+fn notify(rental_request: &RentalRequest, kind: NotificationKind, message: String) {
+    let customer_id = rental_request.customer_id;
+    // Notifications are best effort and are skipped once no id is left
+    let id = match next_id() {
+        Ok(id) => id,
+        Err(_) => return,
+    };
+    let notification = Notification {
+        id,
+        customer_id,
+        kind,
+        message,
+        rental_id: Some(rental_request.id),
+        read: false,
+        created_at: ic_cdk::api::time(),
+    };
+    queue_messages(rental_request, kind);
+    NOTIFICATIONS.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        storage.insert((customer_id, notification.id), notification);
+        let keys: Vec<(u64, u64)> = storage
+            .range((customer_id, 0)..=(customer_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys
+            .iter()
+            .take(keys.len().saturating_sub(MAX_NOTIFICATIONS_PER_CUSTOMER))
+        {
+            storage.remove(key);
+        }
+    });
+}
+
+// Remind customers of approved rentals starting and active rentals ending within the
+// configured hours. Each reminder is sent once for a given date, so a rental moved to a new
+// date is reminded of again.
+fn send_reminders(now: u64) {
+    let config = config();
+    let pickup_hours = config
+        .pickup_reminder_hours
+        .unwrap_or(DEFAULT_REMINDER_HOURS);
+    let return_hours = config
+        .return_reminder_hours
+        .unwrap_or(DEFAULT_REMINDER_HOURS);
+    let pickup_horizon = now.saturating_add(pickup_hours * NANOS_PER_HOUR);
+    let return_horizon = now.saturating_add(return_hours * NANOS_PER_HOUR);
+    let due: Vec<(RentalRequest, NotificationKind, u64)> = RENTAL_REQUEST_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter_map(|(_, request)| match request.status {
+                RentalStatus::Approved
+                    if request.start_date > now && request.start_date <= pickup_horizon =>
+                {
+                    let date = request.start_date;
+                    Some((request, NotificationKind::PickupReminder, date))
+                }
+                RentalStatus::Active
+                    if request.end_date > now && request.end_date <= return_horizon =>
+                {
+                    let date = request.end_date;
+                    Some((request, NotificationKind::ReturnReminder, date))
+                }
+                _ => None,
+            })
+            .collect()
+    });
+    for (request, kind, date) in due {
+        let sent = REMINDERS_SENT.with(|sent| sent.borrow_mut().insert((request.id, date), ()));
+        if sent.is_some() {
+            continue;
+        }
+        let message = match kind {
+            NotificationKind::PickupReminder => format!(
+                "Reminder: your rental of the {} starts on {}",
+                car_name(request.car_id),
+                date_string(date)
+            ),
+            _ => format!(
+                "Reminder: the {} is due back on {}",
+                car_name(request.car_id),
+                date_string(date)
+            ),
+        };
+        notify(&request, kind, message);
+        if config.reminder_events.unwrap_or(false) {
+            publish_event(DomainEvent::ReminderSent {
+                rental_id: request.id,
+                customer_id: request.customer_id,
+                kind,
+                date,
+            });
+        }
+    }
+}
+
+fn forget_reminders(rental_id: u64) {
+    REMINDERS_SENT.with(|sent| {
+        let mut sent = sent.borrow_mut();
+        let keys: Vec<(u64, u64)> = sent
+            .range((rental_id, 0)..=(rental_id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            sent.remove(&key);
+        }
+    });
+}
+
+// Implement email and SMS messages to customers through providers reached by HTTPS
+// outcall. A message is queued for every configured channel when a notification with a
+// template is added, and sent by a timer, retrying failed sends with backoff. Every replica
+// makes the request, so the message id is sent as an Idempotency-Key for the provider.
+#[ic_cdk::update]
+fn set_message_provider(
+    channel: MessageChannel,
+    provider: Option<MessageProvider>,
+) -> Result<(), Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    if let Some(provider) = &provider {
+        if !provider.url.starts_with("https://") || provider.url.len() > 256 {
+            return Err(Error::InvalidInput {
+                msg: "The provider URL must be an https:// URL of at most 256 characters"
+                    .to_string(),
+            });
+        }
+        if provider.api_key.trim().is_empty() || provider.api_key.len() > 256 {
+            return Err(Error::InvalidInput {
+                msg: "The API key must be between 1 and 256 characters".to_string(),
+            });
+        }
+        if provider.sender.trim().is_empty() || provider.sender.len() > 128 {
+            return Err(Error::InvalidInput {
+                msg: "The sender must be between 1 and 128 characters".to_string(),
+            });
+        }
+    }
+    let mut settings = messaging_settings();
+    let before = redacted(&settings);
+    match channel {
+        MessageChannel::Email => settings.email = provider,
+        MessageChannel::Sms => settings.sms = provider,
+    }
+    MESSAGING
+        .with(|cell| cell.borrow_mut().set(settings.clone()))
+        .expect("Cannot store the messaging settings");
+    record_audit(
+        "set_message_provider",
+        None,
+        audit_summary(&before),
+        audit_summary(&redacted(&settings)),
+    );
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn set_message_template(template: MessageTemplate) -> Result<MessageTemplate, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    if template.subject.len() > 128 {
+        return Err(Error::InvalidInput {
+            msg: "The subject must be at most 128 characters".to_string(),
+        });
+    }
+    if template.body.trim().is_empty() || template.body.len() > 1_000 {
+        return Err(Error::InvalidInput {
+            msg: "The body must be between 1 and 1000 characters".to_string(),
+        });
+    }
+    let mut settings = messaging_settings();
+    let before = settings
+        .templates
+        .iter()
+        .find(|existing| existing.kind == template.kind)
+        .cloned();
+    settings
+        .templates
+        .retain(|existing| existing.kind != template.kind);
+    settings.templates.push(template.clone());
+    MESSAGING
+        .with(|cell| cell.borrow_mut().set(settings))
+        .expect("Cannot store the messaging settings");
+    record_audit(
+        "set_message_template",
+        None,
+        before.as_ref().and_then(audit_summary),
+        audit_summary(&template),
+    );
+    Ok(template)
+}
+
+// Get the messaging settings; API keys are never returned
+#[ic_cdk::query]
+fn get_messaging_settings() -> Result<MessagingSettings, Error> {
+    require_any_role(&[Role::Admin])?;
+    Ok(redacted(&messaging_settings()))
+}
+
+// List the send log, oldest first
+#[ic_cdk::query]
+fn list_sent_messages(cursor: Option<u64>, limit: u32) -> Result<Page<OutboundMessage>, Error> {
+    require_any_role(&[Role::Admin])?;
+    Ok(MESSAGE_LOG.with(|log| {
+        let log = log.borrow();
+        page_of(log.range(cursor.unwrap_or(0)..), log.len(), limit)
+    }))
+}
+
+fn messaging_settings() -> MessagingSettings {
+    MESSAGING.with(|cell| cell.borrow().get().clone())
+}
+
+fn redacted(settings: &MessagingSettings) -> MessagingSettings {
+    let mut settings = settings.clone();
+    for provider in [&mut settings.email, &mut settings.sms]
+        .into_iter()
+        .flatten()
+    {
+        provider.api_key.clear();
+    }
+    settings
+}
+
+// Find the template of a kind of notification. Bookings, approvals, and return reminders
+// have built-in ones; other kinds are only sent once admins set a template.
+fn message_template(
+    settings: &MessagingSettings,
+    kind: NotificationKind,
+) -> Option<MessageTemplate> {
+    if let Some(template) = settings
+        .templates
+        .iter()
+        .find(|template| template.kind == kind)
+    {
+        return Some(template.clone());
+    }
+    let (subject, body) = match kind {
+        NotificationKind::BookingReceived => (
+            "Booking received",
+            "Hi {customer}, we received your booking #{rental_id} of the {car} from {start} to {end}.",
+        ),
+        NotificationKind::RentalApproved => (
+            "Booking approved",
+            "Hi {customer}, your booking #{rental_id} of the {car} from {start} was approved.",
+        ),
+        NotificationKind::ReturnReminder => (
+            "Return reminder",
+            "Hi {customer}, the {car} of booking #{rental_id} is due back on {end}.",
+        ),
+        _ => return None,
+    };
+    Some(MessageTemplate {
+        kind,
+        subject: subject.to_string(),
+        body: body.to_string(),
+    })
+}
+
+fn render_template(text: &str, rental_request: &RentalRequest, customer: &Customer) -> String {
+    fill_placeholders(text, |name| {
+        message_placeholder(name, rental_request, customer)
+    })
+}
+
+fn message_placeholder(
+    name: &str,
+    rental_request: &RentalRequest,
+    customer: &Customer,
+) -> Option<String> {
+    Some(match name {
+        "customer" => customer.name.clone(),
+        "car" => car_name(rental_request.car_id),
+        "start" => date_string(rental_request.start_date),
+        "end" => date_string(rental_request.end_date),
+        "rental_id" => rental_request.id.to_string(),
+        _ => return None,
+    })
+}
+
+// Replace each {name} in a template with its value in a single pass, so a value that
+// looks like a placeholder is left as it is. Unknown placeholders are kept.
+fn fill_placeholders(text: &str, value_of: impl Fn(&str) -> Option<String>) -> String {
+    let mut filled = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let value = after
+            .find('}')
+            .and_then(|end| value_of(&after[..end]).map(|value| (end, value)));
+        match value {
+            Some((end, value)) => {
+                filled.push_str(&value);
+                rest = &after[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = after;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+// Queue a message about a rental on every channel with a provider and a customer contact.
+// Customers whose details are encrypted have no contact the canister can read.
+fn queue_messages(rental_request: &RentalRequest, kind: NotificationKind) {
+    let settings = messaging_settings();
+    let template = match message_template(&settings, kind) {
+        Some(template) => template,
+        None => return,
+    };
+    let customer =
+        match CUSTOMER_STORAGE.with(|storage| storage.borrow().get(&rental_request.customer_id)) {
+            Some(customer) => customer,
+            None => return,
+        };
+    let channels = [
+        (MessageChannel::Email, &settings.email, &customer.email),
+        (MessageChannel::Sms, &settings.sms, &customer.phone),
+    ];
+    for (channel, provider, to) in channels {
+        if provider.is_none() || to.trim().is_empty() {
+            continue;
+        }
+        let id = match next_id() {
+            Ok(id) => id,
+            Err(_) => return,
+        };
+        let now = ic_cdk::api::time();
+        let message = OutboundMessage {
+            id,
+            customer_id: customer.id,
+            rental_id: Some(rental_request.id),
+            kind,
+            channel,
+            to: to.chars().take(128).collect(),
+            subject: render_template(&template.subject, rental_request, &customer)
+                .chars()
+                .take(128)
+                .collect(),
+            body: render_template(&template.body, rental_request, &customer)
+                .chars()
+                .take(1_000)
+                .collect(),
+            status: MessageStatus::Pending,
+            attempts: 0,
+            retry_at: now,
+            http_status: None,
+            last_error: None,
+            created_at: now,
+            sent_at: None,
+        };
+        MESSAGE_LOG.with(|log| {
+            let mut log = log.borrow_mut();
+            log.insert(message.id, message);
+            while log.len() > MESSAGE_LOG_SIZE as u64 {
+                match log.first_key_value() {
+                    Some((id, _)) => log.remove(&id),
+                    None => break,
+                };
+            }
+        });
+    }
+}
+
+// Start sending the pending messages that are due
+fn send_messages() {
+    if restore_in_progress() {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    let due: Vec<u64> = MESSAGE_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .filter(|(_, message)| {
+                message.status == MessageStatus::Pending && message.retry_at <= now
+            })
+            .map(|(id, _)| id)
+            .take(MESSAGE_SEND_BATCH)
+            .collect()
+    });
+    for message_id in due {
+        let idle = DELIVERIES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(message_id));
+        if idle {
+            ic_cdk::spawn(send_message(message_id));
+        }
+    }
+}
+
+// Post a message to its channel's provider, giving up after the last attempt
+async fn send_message(message_id: u64) {
+    let message = MESSAGE_LOG.with(|log| log.borrow().get(&message_id));
+    let settings = messaging_settings();
+    if let Some(message) = message {
+        let provider = match message.channel {
+            MessageChannel::Email => settings.email,
+            MessageChannel::Sms => settings.sms,
+        };
+        let response = match provider {
+            Some(provider) => post_message(&provider, &message).await,
+            None => Err("No provider is configured for the channel".to_string()),
+        };
+        MESSAGE_LOG.with(|log| {
+            let mut log = log.borrow_mut();
+            if let Some(mut message) = log.get(&message_id) {
+                let now = ic_cdk::api::time();
+                message.attempts = message.attempts.saturating_add(1);
+                message.http_status = response.as_ref().ok().copied();
+                let result = match response {
+                    Ok(status) if (200..300).contains(&status) => Ok(()),
+                    Ok(status) => Err(format!("HTTP status {}", status)),
+                    Err(error) => Err(error),
+                };
+                match result {
+                    Ok(()) => {
+                        message.status = MessageStatus::Sent;
+                        message.sent_at = Some(now);
+                        message.last_error = None;
+                    }
+                    Err(error) => {
+                        if message.attempts >= MAX_MESSAGE_ATTEMPTS {
+                            message.status = MessageStatus::Failed;
+                        }
+                        message.retry_at = now.saturating_add(delivery_backoff(message.attempts));
+                        message.last_error = Some(error);
+                    }
+                }
+                log.insert(message_id, message);
+            }
+        });
+    }
+    DELIVERIES_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&message_id));
+}
+
+// Post a message to a provider, returning the HTTP status it answered with
+async fn post_message(
+    provider: &MessageProvider,
+    message: &OutboundMessage,
+) -> Result<u16, String> {
+    let body = serde_json::json!({
+        "channel": match message.channel {
+            MessageChannel::Email => "email",
+            MessageChannel::Sms => "sms",
+        },
+        "from": provider.sender,
+        "to": message.to,
+        "subject": message.subject,
+        "body": message.body,
+    })
+    .to_string();
+    let header = |name: &str, value: String| HttpHeader {
+        name: name.to_string(),
+        value,
+    };
+    let request = CanisterHttpRequestArgument {
+        url: provider.url.clone(),
+        max_response_bytes: Some(WEBHOOK_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            header("Content-Type", "application/json".to_string()),
+            header("Authorization", format!("Bearer {}", provider.api_key)),
+            header("Idempotency-Key", message.id.to_string()),
+        ],
+        body: Some(body.into_bytes()),
+        transform: Some(TransformContext::from_name(
+            "transform_webhook_response".to_string(),
+            vec![],
+        )),
+    };
+    let (response,) = http_outcall(request, WEBHOOK_CYCLES)
+        .await
+        .map_err(|(code, msg)| {
+            format!("{:?} {}", code, msg)
+                .chars()
+                .take(256)
+                .collect::<String>()
+        })?;
+    u16::try_from(&response.status.0)
+        .map_err(|_| format!("Invalid HTTP status {}", response.status))
+}
+
+// Name a car by its year, make, and model for messages to customers
+fn car_name(car_id: u64) -> String {
+    CAR_STORAGE
+        .with(|storage| storage.borrow().get(&car_id))
+        .map_or_else(
+            || format!("car #{}", car_id),
+            |car| format!("{} {} {}", car.year, car.make, car.model),
+        )
+}
+
+fn icp_string(amount_e8s: u64) -> String {
+    format!(
+        "{}.{:08} ICP",
+        amount_e8s / 100_000_000,
+        amount_e8s % 100_000_000
+    )
+}
+
+// Format a timestamp as a UTC date and time, YYYY-MM-DD HH:MM
+fn date_string(timestamp: u64) -> String {
+    let (year, month, day) = civil_date(timestamp);
+    let minutes = (timestamp % NANOS_PER_DAY) / NANOS_PER_MINUTE;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        minutes / 60,
+        minutes % 60
+    )
+}
+
+// Implement ICP ledger payments. The customer transfers the quoted price to the
+// canister's default account with the rental id as memo, then confirms the block.
+#[ic_cdk::query]
+fn get_payment_account() -> String {
+    AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT).to_hex()
+}
+
+#[ic_cdk::update]
+async fn confirm_payment(rental_id: u64, block_index: BlockIndex) -> Result<Payment, Error> {
+    check_rate_limit()?;
+    let rental_request = get_rental_request(rental_id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    ensure_payable(&rental_request)?;
+    ensure_block_unused(block_index)?;
+
+    let block = fetch_block(ledger_canister_id(), block_index).await?;
+    let (from, to, amount) = match block.transaction.operation {
+        Some(Operation::Transfer {
+            from, to, amount, ..
+        }) => (from, to, amount),
+        _ => {
+            return Err(Error::InvalidInput {
+                msg: format!("Block {} is not a transfer", block_index),
+            })
+        }
+    };
+    if block.transaction.memo != Memo(rental_id) {
+        return Err(Error::InvalidInput {
+            msg: format!("Block {} does not carry memo {}", block_index, rental_id),
+        });
+    }
+    if from != AccountIdentifier::new(&rental_request.owner, &DEFAULT_SUBACCOUNT) {
+        return Err(Error::InvalidInput {
+            msg: format!("Block {} was not sent by the rental owner", block_index),
+        });
+    }
+    if to != AccountIdentifier::new(&ic_cdk::id(), &DEFAULT_SUBACCOUNT) {
+        return Err(Error::InvalidInput {
+            msg: format!("Block {} was not sent to the canister account", block_index),
+        });
+    }
+    if amount.e8s() < rental_request.price_due_e8s() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Block {} transferred {} e8s but the rental costs {} e8s",
+                block_index,
+                amount.e8s(),
+                rental_request.price_due_e8s()
+            ),
+        });
+    }
+
+    // State may have changed while waiting for the ledger
+    let mut rental_request = get_rental_request(rental_id)?;
+    ensure_payable(&rental_request)?;
+    ensure_block_unused(block_index)?;
+
+    let payment = Payment {
+        id: next_payment_id()?,
+        rental_id,
+        customer_id: rental_request.customer_id,
+        payer: rental_request.owner,
+        amount_e8s: amount.e8s(),
+        block_index,
+        paid_at: ic_cdk::api::time(),
+        method: PaymentMethod::IcpTransfer,
+        refunded_e8s: 0,
+        wallet_e8s: 0,
+    };
+    PAYMENT_BY_BLOCK.with(|index| index.borrow_mut().insert(block_index, payment.id));
+    record_payment(&mut rental_request, payment.clone());
+    record_audit(
+        "confirm_payment",
+        Some(rental_id),
+        None,
+        audit_summary(&payment),
+    );
+    Ok(payment)
+}
+
+// Collect the quoted price from the customer's wallet first, and the rest with an
+// ICRC-2 transfer_from after the customer approved the canister
+#[ic_cdk::update]
+async fn pay_for_rental(rental_id: u64) -> Result<Payment, Error> {
+    check_rate_limit()?;
+    let rental_request = get_rental_request(rental_id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    ensure_payable(&rental_request)?;
+
+    let ledger = token_ledger_canister_id();
+    let customer_id = rental_request.customer_id;
+    let amount_e8s = rental_request.price_due_e8s();
+    let wallet_e8s = wallet_balance(customer_id).min(amount_e8s);
+    // The payment id is taken before any tokens move so the payment can always be recorded
+    let payment_id = next_payment_id()?;
+    // The wallet share is taken before calling the ledger so it cannot be spent twice
+    let wallet_debit = if wallet_e8s > 0 {
+        Some(post_wallet_transaction(
+            customer_id,
+            WalletTransactionKind::RentalPayment { rental_id },
+            -(wallet_e8s as i64),
+            ic_cdk::api::time(),
+        )?)
+    } else {
+        None
+    };
+    let (method, block_index) = match wallet_debit {
+        Some(debit) if wallet_e8s == amount_e8s => (PaymentMethod::Wallet, debit.id),
+        _ => {
+            PAYMENTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(rental_id));
+            let result = icrc2_transfer_from(
+                ledger,
+                rental_request.owner,
+                None,
+                amount_e8s - wallet_e8s,
+                rental_id,
+            )
+            .await;
+            PAYMENTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&rental_id));
+            match result {
+                Ok(transfer_id) => (PaymentMethod::Icrc2TransferFrom { ledger }, transfer_id),
+                Err(error) => {
+                    if wallet_e8s > 0 {
+                        post_wallet_transaction(
+                            customer_id,
+                            WalletTransactionKind::RentalPaymentReversed { rental_id },
+                            wallet_e8s as i64,
+                            ic_cdk::api::time(),
+                        )?;
+                    }
+                    return Err(error);
+                }
+            }
+        }
+    };
+
+    // The tokens have moved, so the payment is recorded even if the rental changed meanwhile
+    let mut rental_request = get_rental_request(rental_id)?;
+    let payment = Payment {
+        id: payment_id,
+        rental_id,
+        customer_id,
+        payer: rental_request.owner,
+        amount_e8s,
+        block_index,
+        paid_at: ic_cdk::api::time(),
+        method,
+        refunded_e8s: 0,
+        wallet_e8s,
+    };
+    record_payment(&mut rental_request, payment.clone());
+    record_audit(
+        "pay_for_rental",
+        Some(rental_id),
+        None,
+        audit_summary(&payment),
+    );
+    Ok(payment)
+}
+
+// Implement ckBTC payments. Each rental has its own subaccount of the canister on the
+// ckBTC ledger. The customer sends the quoted satoshis there and confirms; the canister
+// reads the subaccount's balance and moves it to its main account, paying the fee from it.
+#[ic_cdk::update]
+async fn get_ckbtc_payment_quote(rental_id: u64) -> Result<CkbtcQuote, Error> {
+    check_rate_limit()?;
+    let rental_request = get_rental_request(rental_id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    ensure_payable(&rental_request)?;
+    let now = ic_cdk::api::time();
+    if let Some(quote) = CKBTC_QUOTES
+        .with(|quotes| quotes.borrow().get(&rental_id))
+        .filter(|quote| now < quote.expires_at)
+    {
+        return Ok(quote);
+    }
+    quote_ckbtc_payment(&rental_request).await
+}
+
+#[ic_cdk::update]
+async fn confirm_ckbtc_payment(rental_id: u64) -> Result<Payment, Error> {
+    check_rate_limit()?;
+    let rental_request = get_rental_request(rental_id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    ensure_payable(&rental_request)?;
+    let quote = CKBTC_QUOTES
+        .with(|quotes| quotes.borrow().get(&rental_id))
+        .ok_or_else(|| Error::InvalidInput {
+            msg: format!(
+                "Rental request with id={} has no ckBTC price; get one first",
+                rental_id
+            ),
+        })?;
+    let quote = if ic_cdk::api::time() < quote.expires_at {
+        quote
+    } else {
+        quote_ckbtc_payment(&rental_request).await?
+    };
+
+    let ledger = ckbtc_ledger_canister_id();
+    let subaccount = ckbtc_payment_subaccount(rental_id);
+    // No other call may sweep the subaccount or pay the rental until this one is done
+    ensure_payable(&get_rental_request(rental_id)?)?;
+    PAYMENTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(rental_id));
+    let result = sweep_ckbtc_payment(ledger, subaccount, quote.amount_sats, rental_id).await;
+    PAYMENTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&rental_id));
+    let (amount_sats, transfer_id) = result?;
+
+    // The satoshis have moved, so the payment is recorded even if the rental changed meanwhile
+    let mut rental_request = get_rental_request(rental_id)?;
+    let payment = Payment {
+        id: next_payment_id()?,
+        rental_id,
+        customer_id: rental_request.customer_id,
+        payer: rental_request.owner,
+        amount_e8s: rental_request.price_due_e8s(),
+        block_index: transfer_id,
+        paid_at: ic_cdk::api::time(),
+        method: PaymentMethod::Ckbtc {
+            ledger,
+            amount_sats,
+        },
+        refunded_e8s: 0,
+        wallet_e8s: 0,
+    };
+    CKBTC_QUOTES.with(|quotes| quotes.borrow_mut().remove(&rental_id));
+    record_payment(&mut rental_request, payment.clone());
+    record_audit(
+        "confirm_ckbtc_payment",
+        Some(rental_id),
+        None,
+        audit_summary(&payment),
+    );
+    Ok(payment)
+}
+
+// Price a rental's due amount in satoshis at the current BTC rate and hold it
+async fn quote_ckbtc_payment(rental_request: &RentalRequest) -> Result<CkbtcQuote, Error> {
+    let rate = exchange_rate("BTC").await?;
+    let quote = CkbtcQuote {
+        rental_id: rental_request.id,
+        account: Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(ckbtc_payment_subaccount(rental_request.id)),
+        },
+        amount_sats: rate.convert(rental_request.price_due_e8s() as i64) as u64,
+        expires_at: ic_cdk::api::time() + CKBTC_QUOTE_TTL,
+        rate,
+    };
+    CKBTC_QUOTES.with(|quotes| quotes.borrow_mut().insert(rental_request.id, quote.clone()));
+    Ok(quote)
+}
+
+// Move what was paid into a rental's subaccount to the canister's main account, once it
+// covers the price. Returns the satoshis received and the id of the sweeping transfer.
+async fn sweep_ckbtc_payment(
+    ledger: Principal,
+    subaccount: Subaccount,
+    amount_sats: u64,
+    rental_id: u64,
+) -> Result<(u64, u64), Error> {
+    let account = Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(subaccount),
+    };
+    let (balance,): (Nat,) = ic_cdk::call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(code, msg)| Error::LedgerCallFailed {
+            msg: format!("icrc1_balance_of failed: {:?} {}", code, msg),
+        })?;
+    let balance_sats = nat_to_u64(&balance);
+    if balance_sats < amount_sats {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Received {} sats but the rental costs {} sats",
+                balance_sats, amount_sats
+            ),
+        });
+    }
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, msg)| Error::LedgerCallFailed {
+            msg: format!("icrc1_fee failed: {:?} {}", code, msg),
+        })?;
+    let to = Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
+    };
+    let transfer_id = icrc1_transfer(
+        ledger,
+        Some(subaccount),
+        to,
+        balance_sats.saturating_sub(nat_to_u64(&fee)),
+        rental_id,
+    )
+    .await?;
+    Ok((balance_sats, transfer_id))
+}
+
+fn ckbtc_ledger_canister_id() -> Principal {
+    config().ckbtc_ledger_canister_id.unwrap_or_else(|| {
+        Principal::from_text(MAINNET_CKBTC_LEDGER_CANISTER_ID).expect("Invalid ckBTC ledger id")
+    })
+}
+
+// Derive the subaccount a rental is paid into with ckBTC: a domain tag followed by the
+// rental id
+fn ckbtc_payment_subaccount(rental_id: u64) -> Subaccount {
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = CKBTC_PAYMENT_SUBACCOUNT_TAG;
+    subaccount[24..].copy_from_slice(&rental_id.to_be_bytes());
+    subaccount
+}
+
+// Store a payment, link it to its rental, and activate the rental
+fn record_payment(rental_request: &mut RentalRequest, payment: Payment) {
+    rental_request.payment_id = Some(payment.id);
+    // Wallet-only payments have no ledger transfer
+    rental_request.transfer_id =
+        (payment.method != PaymentMethod::Wallet).then_some(payment.block_index);
+    if rental_request
+        .status
+        .can_transition_to(&RentalStatus::Active)
+    {
+        rental_request.status = RentalStatus::Active;
+    }
+    publish_event(DomainEvent::PaymentReceived {
+        rental_id: payment.rental_id,
+        payment_id: payment.id,
+        amount_e8s: payment.amount_e8s,
+    });
+    notify(
+        rental_request,
+        NotificationKind::PaymentReceived,
+        format!(
+            "Your payment of {} for the {} was received",
+            icp_string(payment.amount_e8s),
+            car_name(rental_request.car_id)
+        ),
+    );
+    PAYMENT_STORAGE.with(|storage| storage.borrow_mut().insert(payment.id, payment));
+    store_rental_request(rental_request);
+}
+
+#[ic_cdk::query]
+fn get_payment(id: u64) -> Result<Payment, Error> {
+    match PAYMENT_STORAGE.with(|storage| storage.borrow().get(&id)) {
+        Some(payment) => {
+            let rental_request = get_rental_request(payment.rental_id)?;
+            ensure_owner_or_admin(&rental_request.owner)?;
+            Ok(payment)
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Payment with id={} not found", id),
+        }),
+    }
+}
+
+// Only unpaid rentals that are waiting to start can be paid for
+fn ensure_payable(rental_request: &RentalRequest) -> Result<(), Error> {
+    let in_flight =
+        PAYMENTS_IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&rental_request.id));
+    if rental_request.payment_id.is_some() || in_flight {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Rental request with id={} is already paid",
+                rental_request.id
+            ),
+        });
+    }
+    if !rental_request
+        .status
+        .can_transition_to(&RentalStatus::Active)
+    {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request with id={} cannot be paid in status {:?}",
+                rental_request.id, rental_request.status
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn ensure_block_unused(block_index: BlockIndex) -> Result<(), Error> {
+    match PAYMENT_BY_BLOCK.with(|index| index.borrow().get(&block_index)) {
+        Some(payment_id) => Err(Error::Conflict {
+            msg: format!(
+                "Block {} was already used by payment id={}",
+                block_index, payment_id
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn ledger_canister_id() -> Principal {
+    config()
+        .ledger_canister_id
+        .unwrap_or(MAINNET_LEDGER_CANISTER_ID)
+}
+
+fn token_ledger_canister_id() -> Principal {
+    config()
+        .token_ledger_canister_id
+        .unwrap_or_else(ledger_canister_id)
+}
+
+// Pull tokens from a customer's default account into one of the canister's subaccounts
+async fn icrc2_transfer_from(
+    ledger: Principal,
+    from: Principal,
+    to_subaccount: Option<Subaccount>,
+    amount_e8s: u64,
+    memo: u64,
+) -> Result<u64, Error> {
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: from,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: to_subaccount,
+        },
+        amount: Nat::from(amount_e8s),
+        fee: None,
+        memo: Some(memo.into()),
+        created_at_time: Some(ic_cdk::api::time()),
+    };
+    let result: Result<(Result<Nat, TransferFromError>,), _> =
+        ic_cdk::call(ledger, "icrc2_transfer_from", (args,)).await;
+    match result {
+        Ok((Ok(block_index),)) => Ok(nat_to_u64(&block_index)),
+        Ok((Err(error),)) => Err(Error::PaymentFailed {
+            error: PaymentError::from(error),
+        }),
+        Err((code, msg)) => Err(Error::LedgerCallFailed {
+            msg: format!("icrc2_transfer_from failed: {:?} {}", code, msg),
+        }),
+    }
+}
+
+// Send tokens out of one of the canister's subaccounts; the ledger fee is paid on top
+async fn icrc1_transfer(
+    ledger: Principal,
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount_e8s: u64,
+    memo: u64,
+) -> Result<u64, Error> {
+    let args = TransferArg {
+        from_subaccount,
+        to,
+        fee: None,
+        created_at_time: Some(ic_cdk::api::time()),
+        memo: Some(memo.into()),
+        amount: Nat::from(amount_e8s),
+    };
+    let result: Result<(Result<Nat, TransferError>,), _> =
+        ic_cdk::call(ledger, "icrc1_transfer", (args,)).await;
+    match result {
+        Ok((Ok(block_index),)) => Ok(nat_to_u64(&block_index)),
+        Ok((Err(error),)) => Err(Error::PaymentFailed {
+            error: PaymentError::from(error),
+        }),
+        Err((code, msg)) => Err(Error::LedgerCallFailed {
+            msg: format!("icrc1_transfer failed: {:?} {}", code, msg),
+        }),
+    }
+}
+
+fn nat_to_u64(value: &Nat) -> u64 {
+    u64::try_from(&value.0).unwrap_or(u64::MAX)
+}
+
+impl From<TransferFromError> for PaymentError {
+    fn from(error: TransferFromError) -> Self {
+        match error {
+            TransferFromError::InsufficientFunds { balance } => PaymentError::InsufficientFunds {
+                balance_e8s: nat_to_u64(&balance),
+            },
+            TransferFromError::InsufficientAllowance { allowance } => {
+                PaymentError::InsufficientAllowance {
+                    allowance_e8s: nat_to_u64(&allowance),
+                }
+            }
+            TransferFromError::BadFee { expected_fee } => PaymentError::BadFee {
+                expected_fee_e8s: nat_to_u64(&expected_fee),
+            },
+            TransferFromError::Duplicate { duplicate_of } => PaymentError::Duplicate {
+                duplicate_of: nat_to_u64(&duplicate_of),
+            },
+            TransferFromError::TemporarilyUnavailable => PaymentError::TemporarilyUnavailable,
+            other => PaymentError::Rejected {
+                msg: format!("{:?}", other),
+            },
+        }
+    }
+}
+
+impl From<TransferError> for PaymentError {
+    fn from(error: TransferError) -> Self {
+        match error {
+            TransferError::InsufficientFunds { balance } => PaymentError::InsufficientFunds {
+                balance_e8s: nat_to_u64(&balance),
+            },
+            TransferError::BadFee { expected_fee } => PaymentError::BadFee {
+                expected_fee_e8s: nat_to_u64(&expected_fee),
+            },
+            TransferError::Duplicate { duplicate_of } => PaymentError::Duplicate {
+                duplicate_of: nat_to_u64(&duplicate_of),
+            },
+            TransferError::TemporarilyUnavailable => PaymentError::TemporarilyUnavailable,
+            other => PaymentError::Rejected {
+                msg: format!("{:?}", other),
+            },
+        }
+    }
+}
+
+// Fetch a single block from the ledger, following it into the archive if needed
+async fn fetch_block(ledger: Principal, block_index: BlockIndex) -> Result<Block, Error> {
+    let args = GetBlocksArgs {
+        start: block_index,
+        length: 1,
+    };
+    let response = ic_ledger_types::query_blocks(ledger, args.clone())
+        .await
+        .map_err(|(code, msg)| Error::LedgerCallFailed {
+            msg: format!("query_blocks failed: {:?} {}", code, msg),
+        })?;
+    if let Some(block) = response.blocks.into_iter().next() {
+        return Ok(block);
+    }
+
+    let archive = response
+        .archived_blocks
+        .into_iter()
+        .find(|range| range.start <= block_index && block_index - range.start < range.length);
+    if let Some(range) = archive {
+        let result = ic_ledger_types::query_archived_blocks(&range.callback, args)
+            .await
+            .map_err(|(code, msg)| Error::LedgerCallFailed {
+                msg: format!("query_archived_blocks failed: {:?} {}", code, msg),
+            })?;
+        if let Ok(range) = result {
+            if let Some(block) = range.blocks.into_iter().next() {
+                return Ok(block);
+            }
+        }
+    }
+    Err(Error::NotFound {
+        msg: format!("Block {} not found on the ledger", block_index),
+    })
+}
+
+// Implement refunds of canceled rentals. Refunds are paid from the canister's main
+// account on the ledger the original payment used, and the canister covers the fee.
+#[ic_cdk::update]
+async fn process_refund(rental_id: u64, amount_e8s: u64, reason: String) -> Result<Refund, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Staff])?;
+    if reason.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "A reason is required to process a refund".to_string(),
+        });
+    }
+    let rental_request = get_rental_request(rental_id)?;
+    if rental_request.status != RentalStatus::Canceled {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request with id={} must be canceled before it is refunded",
+                rental_id
+            ),
+        });
+    }
+    let payment_id = rental_request
+        .payment_id
+        .ok_or_else(|| Error::InvalidInput {
+            msg: format!("Rental request with id={} was never paid", rental_id),
+        })?;
+    if PAYMENTS_IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&rental_id)) {
+        return Err(Error::Conflict {
+            msg: format!(
+                "A transfer for rental request id={} is in progress",
+                rental_id
+            ),
+        });
+    }
+    // Staff may refund any payment, so it is read without the owner check of get_payment
+    let payment = PAYMENT_STORAGE
+        .with(|storage| storage.borrow().get(&payment_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("Payment with id={} not found", payment_id),
+        })?;
+    let refundable_e8s = payment.amount_e8s - payment.refunded_e8s;
+    if amount_e8s == 0 || amount_e8s > refundable_e8s {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Amount must be between 1 and the refundable {} e8s",
+                refundable_e8s
+            ),
+        });
+    }
+    let (wallet_e8s, ledger_e8s) = payment.refund_split(amount_e8s);
+    let refund_id = next_id()?;
+    // A ckBTC payment is refunded in satoshis, in proportion to what was received
+    let amount_sats = match payment.method {
+        PaymentMethod::Ckbtc { amount_sats, .. } => Some(
+            (ledger_e8s as u128 * amount_sats as u128 / payment.amount_e8s.max(1) as u128) as u64,
+        ),
+        _ => None,
+    };
+    let transfer_id = if ledger_e8s > 0 {
+        let ledger = match payment.method {
+            PaymentMethod::IcpTransfer => ledger_canister_id(),
+            PaymentMethod::Icrc2TransferFrom { ledger } => ledger,
+            PaymentMethod::Wallet => token_ledger_canister_id(),
+            PaymentMethod::Ckbtc { ledger, .. } => ledger,
+        };
+        let to = Account {
+            owner: payment.payer,
+            subaccount: None,
+        };
+        PAYMENTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(rental_id));
+        let result = icrc1_transfer(
+            ledger,
+            None,
+            to,
+            amount_sats.unwrap_or(ledger_e8s),
+            rental_id,
+        )
+        .await;
+        PAYMENTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&rental_id));
+        Some(result?)
+    } else {
+        None
+    };
+    if wallet_e8s > 0 {
+        post_wallet_transaction(
+            payment.customer_id,
+            WalletTransactionKind::Refund { rental_id },
+            wallet_e8s as i64,
+            ic_cdk::api::time(),
+        )?;
+    }
+
+    let refund = Refund {
+        id: refund_id,
+        rental_id,
+        payment_id,
+        amount_e8s,
+        reason,
+        transfer_id,
+        wallet_e8s,
+        refunded_by: caller(),
+        refunded_at: ic_cdk::api::time(),
+        amount_sats,
+    };
+    PAYMENT_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(mut payment) = storage.get(&payment_id) {
+            payment.refunded_e8s += amount_e8s;
+            storage.insert(payment_id, payment);
+        }
+    });
+    REFUND_STORAGE.with(|storage| storage.borrow_mut().insert(refund.id, refund.clone()));
+    record_audit(
+        "process_refund",
+        Some(rental_id),
+        None,
+        audit_summary(&refund),
+    );
+    Ok(refund)
+}
+
+#[ic_cdk::query]
+fn list_refunds_for_rental(rental_id: u64) -> Result<Vec<Refund>, Error> {
+    let rental_request = get_rental_request(rental_id)?;
+    if ensure_owner_or_admin(&rental_request.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    Ok(REFUND_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .filter(|(_, refund)| refund.rental_id == rental_id)
+            .map(|(_, refund)| refund)
+            .collect()
+    }))
+}
+
+// Implement security deposits. Each rental holds its deposit in a dedicated subaccount
+// of the canister, funded with an ICRC-2 transfer_from on the token ledger.
+#[ic_cdk::query]
+fn get_deposit_requirement(rental_id: u64) -> Result<CategoryAmount, Error> {
+    let rental_request = get_rental_request(rental_id)?;
+    let car = get_car(rental_request.car_id)?;
+    Ok(CategoryAmount {
+        category: car.category,
+        amount_e8s: deposit_for_category(car.category),
+    })
+}
+
+#[ic_cdk::query]
+fn get_deposit(rental_id: u64) -> Result<Deposit, Error> {
+    let rental_request = get_rental_request(rental_id)?;
+    if ensure_owner_or_admin(&rental_request.owner).is_err() {
+        require_any_role(&[Role::Staff])?;
+    }
+    DEPOSIT_STORAGE
+        .with(|storage| storage.borrow().get(&rental_id))
+        .ok_or_else(|| Error::NotFound {
+            msg: format!("No deposit held for rental request id={}", rental_id),
+        })
+}
+
+#[ic_cdk::update]
+async fn pay_deposit(rental_id: u64) -> Result<Deposit, Error> {
+    check_rate_limit()?;
+    let rental_request = get_rental_request(rental_id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    if !rental_request.status.is_open() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental request with id={} cannot take a deposit in status {:?}",
+                rental_id, rental_request.status
+            ),
+        });
+    }
+    ensure_deposit_idle(rental_id)?;
+    if DEPOSIT_STORAGE.with(|storage| storage.borrow().contains_key(&rental_id)) {
+        return Err(Error::Conflict {
+            msg: format!("Rental request with id={} already has a deposit", rental_id),
+        });
+    }
+    let car = get_car(rental_request.car_id)?;
+    let amount_e8s = deposit_for_category(car.category);
+    let subaccount = deposit_subaccount(rental_id);
+
+    DEPOSITS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(rental_id));
+    let result = icrc2_transfer_from(
+        token_ledger_canister_id(),
+        rental_request.owner,
+        Some(subaccount),
+        amount_e8s,
+        rental_id,
+    )
+    .await;
+    DEPOSITS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&rental_id));
+    result?;
+
+    let deposit = Deposit {
+        rental_id,
+        subaccount: subaccount.to_vec(),
+        amount_e8s,
+        released_e8s: 0,
+        forfeited_e8s: 0,
+        state: DepositState::Held,
+        movements: Vec::new(),
+    };
+    DEPOSIT_STORAGE.with(|storage| storage.borrow_mut().insert(rental_id, deposit.clone()));
+    record_audit(
+        "pay_deposit",
+        Some(rental_id),
+        None,
+        audit_summary(&deposit),
+    );
+    Ok(deposit)
+}
+
+// Return part or all of a deposit to the customer
+#[ic_cdk::update]
+async fn release_deposit(
+    rental_id: u64,
+    amount_e8s: u64,
+    reason: String,
+) -> Result<Deposit, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let rental_request = get_rental_request(rental_id)?;
+    let to = Account {
+        owner: rental_request.owner,
+        subaccount: None,
+    };
+    let before = DEPOSIT_STORAGE.with(|storage| storage.borrow().get(&rental_id));
+    let deposit = move_deposit(rental_id, amount_e8s, reason, to, false).await?;
+    record_audit(
+        "release_deposit",
+        Some(rental_id),
+        before.as_ref().and_then(audit_summary),
+        audit_summary(&deposit),
+    );
+    Ok(deposit)
+}
+
+// Keep part or all of a deposit by moving it into the canister's main account
+#[ic_cdk::update]
+async fn forfeit_deposit(
+    rental_id: u64,
+    amount_e8s: u64,
+    reason: String,
+) -> Result<Deposit, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let to = Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
+    };
+    let before = DEPOSIT_STORAGE.with(|storage| storage.borrow().get(&rental_id));
+    let deposit = move_deposit(rental_id, amount_e8s, reason, to, true).await?;
+    record_audit(
+        "forfeit_deposit",
+        Some(rental_id),
+        before.as_ref().and_then(audit_summary),
+        audit_summary(&deposit),
+    );
+    Ok(deposit)
+}
+
+// Transfer funds out of a deposit subaccount and record the movement. The ledger fee is
+// taken from the moved amount so the subaccount never runs short.
+async fn move_deposit(
+    rental_id: u64,
+    amount_e8s: u64,
+    reason: String,
+    to: Account,
+    forfeited: bool,
+) -> Result<Deposit, Error> {
+    if reason.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "A reason is required to move a deposit".to_string(),
+        });
+    }
+    ensure_deposit_idle(rental_id)?;
+    let deposit = get_deposit(rental_id)?;
+    if amount_e8s == 0 || amount_e8s > deposit.remaining_e8s() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Amount must be between 1 and the remaining {} e8s of the deposit",
+                deposit.remaining_e8s()
+            ),
+        });
+    }
+    let fee_e8s = config().ledger_fee_e8s;
+    if amount_e8s <= fee_e8s {
+        return Err(Error::InvalidInput {
+            msg: format!("Amount must exceed the ledger fee of {} e8s", fee_e8s),
+        });
+    }
+
+    DEPOSITS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(rental_id));
+    let result = icrc1_transfer(
+        token_ledger_canister_id(),
+        Some(deposit_subaccount(rental_id)),
+        to,
+        amount_e8s - fee_e8s,
+        rental_id,
+    )
+    .await;
+    DEPOSITS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&rental_id));
+    let transfer_id = result?;
+
+    let mut deposit = get_deposit(rental_id)?;
+    deposit.record_movement(DepositMovement {
+        amount_e8s,
+        forfeited,
+        reason,
+        transfer_id,
+        at: ic_cdk::api::time(),
+    });
+    DEPOSIT_STORAGE.with(|storage| storage.borrow_mut().insert(rental_id, deposit.clone()));
+    Ok(deposit)
+}
+
+fn ensure_deposit_idle(rental_id: u64) -> Result<(), Error> {
+    if DEPOSITS_IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&rental_id)) {
+        Err(Error::Conflict {
+            msg: format!(
+                "A deposit transfer for rental request id={} is in progress",
+                rental_id
+            ),
+        })
+    } else {
+        Ok(())
+    }
+}
+
+fn protection_plan(tier: ProtectionTier) -> Result<ProtectionPlan, Error> {
+    config()
+        .protection_plans
+        .into_iter()
+        .find(|plan| plan.tier == tier)
+        .ok_or_else(|| Error::InvalidInput {
+            msg: format!("{:?} protection is not offered", tier),
+        })
+}
+
+fn deposit_for_category(category: CarCategory) -> u64 {
+    config()
+        .deposits
+        .iter()
+        .find(|deposit| deposit.category == category)
+        .map(|deposit| deposit.amount_e8s)
+        .unwrap_or_default()
+}
+
+// Derive the subaccount holding a rental's deposit: a domain tag followed by the rental id
+fn deposit_subaccount(rental_id: u64) -> Subaccount {
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = DEPOSIT_SUBACCOUNT_TAG;
+    subaccount[24..].copy_from_slice(&rental_id.to_be_bytes());
+    subaccount
+}
+
+// Implement management of pricing rules for administrators
+#[ic_cdk::update]
+fn add_pricing_rule(payload: PricingRulePayload) -> Result<PricingRule, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    validate_pricing_rule(&payload)?;
+
+    let id = next_id()?;
+    let rule = PricingRule::from_payload(id, payload);
+    check_record_size(&rule)?;
+    PRICING_RULE_STORAGE.with(|storage| storage.borrow_mut().insert(id, rule.clone()));
+    record_audit("add_pricing_rule", Some(id), None, audit_summary(&rule));
+    Ok(rule)
+}
+
+#[ic_cdk::update]
+fn update_pricing_rule(id: u64, payload: PricingRulePayload) -> Result<PricingRule, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    validate_pricing_rule(&payload)?;
+
+    PRICING_RULE_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(previous) = storage.get(&id) {
+            let rule = PricingRule::from_payload(id, payload);
+            check_record_size(&rule)?;
+            storage.insert(id, rule.clone());
+            record_audit(
+                "update_pricing_rule",
+                Some(id),
+                audit_summary(&previous),
+                audit_summary(&rule),
+            );
+            Ok(rule)
+        } else {
+            Err(Error::NotFound {
+                msg: format!("Pricing rule with id={} not found", id),
+            })
+        }
+    })
+}
+
+#[ic_cdk::update]
+fn delete_pricing_rule(id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+
+    match PRICING_RULE_STORAGE.with(|storage| storage.borrow_mut().remove(&id)) {
+        Some(rule) => {
+            record_audit("delete_pricing_rule", Some(id), audit_summary(&rule), None);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Pricing rule with id={} not found", id),
+        }),
+    }
+}
+
+// List the pricing rules in the order they are applied
+#[ic_cdk::query]
+fn list_pricing_rules() -> Vec<PricingRule> {
+    let mut rules: Vec<PricingRule> = PRICING_RULE_STORAGE
+        .with(|storage| storage.borrow().iter().map(|(_, rule)| rule).collect());
+    rules.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.id.cmp(&b.id)));
+    rules
+}
+
+// Implement CRUD operations for coupons
+#[ic_cdk::update]
+fn add_coupon(payload: CouponPayload) -> Result<Coupon, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    validate_coupon(&payload, None)?;
+
+    let id = next_id()?;
+    let coupon = Coupon::from_payload(id, 0, payload);
+    check_record_size(&coupon)?;
+    COUPON_STORAGE.with(|storage| storage.borrow_mut().insert(id, coupon.clone()));
+    record_audit("add_coupon", Some(id), None, audit_summary(&coupon));
+    Ok(coupon)
+}
+
+#[ic_cdk::update]
+fn update_coupon(id: u64, payload: CouponPayload) -> Result<Coupon, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    let coupon = get_coupon(id)?;
+    validate_coupon(&payload, Some(id))?;
+
+    let updated_coupon = Coupon::from_payload(id, coupon.uses, payload);
+    check_record_size(&updated_coupon)?;
+    COUPON_STORAGE.with(|storage| storage.borrow_mut().insert(id, updated_coupon.clone()));
+    record_audit(
+        "update_coupon",
+        Some(id),
+        audit_summary(&coupon),
+        audit_summary(&updated_coupon),
+    );
+    Ok(updated_coupon)
+}
+
+#[ic_cdk::update]
+fn delete_coupon(id: u64) -> Result<(), Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+
+    match COUPON_STORAGE.with(|storage| storage.borrow_mut().remove(&id)) {
+        Some(coupon) => {
+            record_audit("delete_coupon", Some(id), audit_summary(&coupon), None);
+            Ok(())
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Coupon with id={} not found", id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn get_coupon(id: u64) -> Result<Coupon, Error> {
+    require_any_role(&[Role::Admin])?;
+    match COUPON_STORAGE.with(|storage| storage.borrow().get(&id)) {
+        Some(coupon) => Ok(coupon),
+        None => Err(Error::NotFound {
+            msg: format!("Coupon with id={} not found", id),
+        }),
+    }
+}
+
+#[ic_cdk::query]
+fn list_coupons() -> Result<Vec<Coupon>, Error> {
+    require_any_role(&[Role::Admin])?;
+    Ok(COUPON_STORAGE.with(|storage| storage.borrow().iter().map(|(_, coupon)| coupon).collect()))
+}
+
+// Validate a coupon code against a rental and record the discount
+#[ic_cdk::update]
+fn apply_coupon(rental_id: u64, code: String) -> Result<RentalRequest, Error> {
+    check_rate_limit()?;
+    let rental_request = get_rental_request(rental_id)?;
+    ensure_owner_or_admin(&rental_request.owner)?;
+    ensure_payable(&rental_request)?;
+    if rental_request.coupon_id.is_some() {
+        return Err(Error::Conflict {
+            msg: format!(
+                "Rental request with id={} already has a coupon applied",
+                rental_id
+            ),
+        });
+    }
+    let code = normalize_coupon_code(&code);
+    let invalid = || Error::InvalidInput {
+        msg: format!("Coupon {} is not valid for this rental", code),
+    };
+    let mut coupon = coupon_by_code(&code).ok_or_else(invalid)?;
+    let now = ic_cdk::api::time();
+    let car = get_car(rental_request.car_id)?;
+    let customer_uses = COUPON_USES_BY_CUSTOMER
+        .with(|storage| {
+            storage
+                .borrow()
+                .get(&(coupon.id, rental_request.customer_id))
+        })
+        .unwrap_or(0);
+    if coupon.valid_from.is_some_and(|from| now < from)
+        || coupon.valid_until.is_some_and(|until| now >= until)
+        || coupon.max_uses.is_some_and(|max| coupon.uses >= max)
+        || coupon
+            .per_customer_limit
+            .is_some_and(|limit| customer_uses >= limit)
+        || !(coupon.categories.is_empty() || coupon.categories.contains(&car.category))
+    {
+        return Err(invalid());
+    }
+
+    let before = audit_summary(&rental_request);
+    let mut updated_rental_request = rental_request;
+    updated_rental_request.coupon_id = Some(coupon.id);
+    updated_rental_request.coupon_discount_e8s = coupon
+        .discount_on(updated_rental_request.quoted_price_e8s)
+        .min(updated_rental_request.price_due_e8s());
+    coupon.uses += 1;
+    COUPON_USES_BY_CUSTOMER.with(|storage| {
+        storage.borrow_mut().insert(
+            (coupon.id, updated_rental_request.customer_id),
+            customer_uses + 1,
+        )
+    });
+    COUPON_STORAGE.with(|storage| storage.borrow_mut().insert(coupon.id, coupon));
+    store_rental_request(&mut updated_rental_request);
+    record_audit(
+        "apply_coupon",
+        Some(rental_id),
+        before,
+        audit_summary(&updated_rental_request),
+    );
+    Ok(updated_rental_request)
+}
+
+fn coupon_by_code(code: &str) -> Option<Coupon> {
+    COUPON_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, coupon)| coupon)
+            .find(|coupon| coupon.code == code)
+    })
+}
+
+// Undo the use of a coupon, unless the coupon was deleted since
+fn release_coupon_use(coupon_id: u64, customer_id: u64) {
+    COUPON_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if let Some(mut coupon) = storage.get(&coupon_id) {
+            coupon.uses = coupon.uses.saturating_sub(1);
+            storage.insert(coupon_id, coupon);
+        }
+    });
+    COUPON_USES_BY_CUSTOMER.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        let key = (coupon_id, customer_id);
+        if let Some(uses) = storage.get(&key) {
+            storage.insert(key, uses.saturating_sub(1));
+        }
+    });
+}
+
+// Codes are matched case-insensitively
+fn normalize_coupon_code(code: &str) -> String {
+    code.trim().to_uppercase()
+}
+
+// Implement role management for administrators
+#[ic_cdk::update]
+fn grant_role(principal: Principal, role: Role) -> Result<RoleAssignment, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidInput {
+            msg: "Roles cannot be granted to the anonymous principal".to_string(),
+        });
+    }
+    let before = audit_summary(&roles_of(&principal));
+    let assignment = add_role(principal, role);
+    record_audit("grant_role", None, before, audit_summary(&assignment));
+    Ok(assignment)
+}
+
+#[ic_cdk::update]
+fn revoke_role(principal: Principal, role: Role) -> Result<RoleAssignment, Error> {
+    check_rate_limit()?;
+    require_any_role(&[Role::Admin])?;
+    if !roles_of(&principal).contains(&role) {
+        return Err(Error::NotFound {
+            msg: format!("Principal {} does not have role {:?}", principal, role),
+        });
+    }
+    let before = audit_summary(&roles_of(&principal));
+    let assignment = remove_role(principal, role);
+    record_audit("revoke_role", None, before, audit_summary(&assignment));
+    Ok(assignment)
+}
+
+#[ic_cdk::query]
+fn list_roles() -> Result<Vec<RoleAssignment>, Error> {
+    require_any_role(&[Role::Admin])?;
+    Ok(ROLE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .iter()
+            .map(|(_, assignment)| assignment.clone())
+            .collect()
+    }))
+}
+
+// Role helpers
+fn roles_of(principal: &Principal) -> Vec<Role> {
+    ROLE_STORAGE.with(|storage| {
+        storage
+            .borrow()
+            .get(&StorablePrincipal(*principal))
+            .map(|assignment| assignment.roles)
+            .unwrap_or_default()
+    })
+}
+
+fn add_role(principal: Principal, role: Role) -> RoleAssignment {
+    let mut roles = roles_of(&principal);
+    if !roles.contains(&role) {
+        roles.push(role);
+    }
+    let assignment = RoleAssignment { principal, roles };
+    ROLE_STORAGE.with(|storage| {
+        storage
+            .borrow_mut()
+            .insert(StorablePrincipal(principal), assignment.clone())
+    });
+    assignment
+}
+
+fn remove_role(principal: Principal, role: Role) -> RoleAssignment {
+    let mut roles = roles_of(&principal);
+    roles.retain(|r| *r != role);
+    let assignment = RoleAssignment { principal, roles };
+    ROLE_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        if assignment.roles.is_empty() {
+            storage.remove(&StorablePrincipal(principal));
+        } else {
+            storage.insert(StorablePrincipal(principal), assignment.clone());
+        }
+    });
+    assignment
+}
+
+// The caller of the current call, or the principal of the API key an HTTP API call uses
+fn caller() -> Principal {
+    HTTP_CALLER
+        .with(|caller| *caller.borrow())
+        .unwrap_or_else(ic_cdk::caller)
+}
+
+// Limit the update calls of principals without a staff role to rate_limit_per_minute in any
+// sliding minute. Rejected calls are not counted, so a caller who waits retry_after gets in.
+fn check_rate_limit() -> Result<(), Error> {
+    let limit = config().rate_limit_per_minute as usize;
+    let caller = caller();
+    if limit == 0 || is_admin(&caller) || roles_of(&caller).contains(&Role::Staff) {
+        return Ok(());
+    }
+    let now = ic_cdk::api::time();
+    RECENT_CALLS.with(|recent| {
+        let mut recent = recent.borrow_mut();
+        let calls = recent.entry(caller).or_default();
+        while calls
+            .front()
+            .is_some_and(|at| at.saturating_add(NANOS_PER_MINUTE) <= now)
+        {
+            calls.pop_front();
+        }
+        if calls.len() >= limit {
+            let oldest = calls[calls.len() - limit];
+            return Err(Error::RateLimited {
+                retry_after: oldest.saturating_add(NANOS_PER_MINUTE) - now,
+            });
+        }
+        calls.push_back(now);
+        Ok(())
+    })
+}
+
+// Forget the principals whose last call is more than a minute old
+fn prune_recent_calls(now: u64) {
+    RECENT_CALLS.with(|recent| {
+        recent.borrow_mut().retain(|_, calls| {
+            calls
+                .back()
+                .is_some_and(|at| at.saturating_add(NANOS_PER_MINUTE) > now)
+        })
+    });
+}
+
+// Guard that passes for administrators or callers holding any of the given roles
+fn require_any_role(roles: &[Role]) -> Result<(), Error> {
+    let caller = caller();
+    if is_admin(&caller) || roles_of(&caller).iter().any(|role| roles.contains(role)) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("Principal {} requires one of the roles {:?}", caller, roles),
+        })
+    }
+}
+
+// Error handling
+// Look up the customer id registered for a principal
+fn customer_id_for(principal: &Principal) -> Option<u64> {
+    CUSTOMER_BY_PRINCIPAL.with(|index| index.borrow().get(&StorablePrincipal(*principal)))
+}
+
+// Canister controllers, the super-admin, and holders of the Admin role act as administrators
+fn is_admin(principal: &Principal) -> bool {
+    ic_cdk::api::is_controller(principal)
+        || *principal == owner()
+        || roles_of(principal).contains(&Role::Admin)
+}
+
+// Allow the call only for the owning principal or an administrator
+fn ensure_owner_or_admin(owner: &Principal) -> Result<(), Error> {
+    let caller = caller();
+    if caller == *owner || is_admin(&caller) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!("Principal {} is not allowed to modify this record", caller),
+        })
+    }
+}
+
+// Collect every rental request for a car
+fn rentals_for_car(car_id: u64) -> Vec<RentalRequest> {
+    RENTALS_BY_CAR.with(|index| indexed_rentals(&index.borrow(), car_id))
+}
+
+// Collect every rental request of a customer
+fn rentals_for_customer(customer_id: u64) -> Vec<RentalRequest> {
+    RENTALS_BY_CUSTOMER.with(|index| indexed_rentals(&index.borrow(), customer_id))
+}
+
+fn indexed_rentals(
+    index: &StableBTreeMap<(u64, u64), (), Memory>,
+    prefix: u64,
+) -> Vec<RentalRequest> {
+    RENTAL_REQUEST_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        index
+            .range((prefix, 0)..=(prefix, u64::MAX))
+            .filter_map(|((_, rental_id), _)| storage.get(&rental_id))
+            .collect()
+    })
+}
+
+// Store a rental request, advancing its version, and keep its indexes and dependents in step
+fn store_rental_request(rental_request: &mut RentalRequest) {
+    let previous = RENTAL_REQUEST_STORAGE.with(|storage| {
+        let mut storage = storage.borrow_mut();
+        rental_request.version = storage
+            .get(&rental_request.id)
+            .map_or(1, |previous| previous.version + 1);
+        storage.insert(rental_request.id, rental_request.clone())
+    });
+    let previous_status = previous.as_ref().map(|previous| previous.status);
+    if let Some(previous) = previous {
+        unindex_rental_request(&previous);
+        if previous.car_id != rental_request.car_id {
+            certify_availability(previous.car_id);
+        }
+    }
+    index_rental_request(rental_request);
+    certify_rental(rental_request.id);
+    certify_availability(rental_request.car_id);
+    match previous_status {
+        None => publish_event(DomainEvent::RentalCreated {
+            rental_id: rental_request.id,
+            car_id: rental_request.car_id,
+            customer_id: rental_request.customer_id,
+        }),
+        Some(status) if status != rental_request.status => {
+            publish_event(DomainEvent::StatusChanged {
+                rental_id: rental_request.id,
+                from: status,
+                to: rental_request.status,
+            })
+        }
+        Some(_) => {}
+    }
+    match previous_status {
+        None => notify(
+            rental_request,
+            NotificationKind::BookingReceived,
+            format!(
+                "Your booking of the {} from {} to {} was received",
+                car_name(rental_request.car_id),
+                date_string(rental_request.start_date),
+                date_string(rental_request.end_date)
+            ),
+        ),
+        Some(status)
+            if status != RentalStatus::Approved
+                && rental_request.status == RentalStatus::Approved =>
+        {
+            let rental_id = rental_request.id;
+            // Staff can generate the document again if the template does not fit
+            let _ = generate_agreement_document(rental_request);
+            ic_cdk::spawn(async move {
+                // Staff can sign the agreement again if signing fails
+                let _ = sign_agreement(rental_id).await;
+            });
+            notify(
+                rental_request,
+                NotificationKind::RentalApproved,
+                format!(
+                    "Your booking of the {} from {} was approved",
+                    car_name(rental_request.car_id),
+                    date_string(rental_request.start_date)
+                ),
+            )
+        }
+        Some(_) => {}
+    }
+
+    // A canceled or expired booking frees its period for the waitlist
+    let freed = matches!(
+        rental_request.status,
+        RentalStatus::Canceled | RentalStatus::Expired
+    );
+    if freed && previous_status.is_some_and(|status| status.is_open()) {
+        promote_waitlist(rental_request.car_id);
+    }
+
+    // A rental that just became active gets a handover code; others need none
+    if rental_request.status != RentalStatus::Active {
+        HANDOVER_CODES.with(|codes| codes.borrow_mut().remove(&rental_request.id));
+    } else if previous_status != Some(RentalStatus::Active) {
+        let rental_id = rental_request.id;
+        ic_cdk::spawn(async move {
+            // The owner can ask for a code again if randomness is unavailable
+            let _ = issue_handover_code(rental_id).await;
+        });
+    }
+}
+
+fn remove_rental_request(id: u64) -> Option<RentalRequest> {
+    let removed = RENTAL_REQUEST_STORAGE.with(|storage| storage.borrow_mut().remove(&id));
+    if let Some(removed) = &removed {
+        unindex_rental_request(removed);
+        if removed.status.is_open() {
+            promote_waitlist(removed.car_id);
+        }
+        store_rental_add_ons(id, &[]);
+        HANDOVER_CODES.with(|codes| codes.borrow_mut().remove(&id));
+        RENTAL_EXTENSIONS.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            let keys: Vec<(u64, u64)> = storage
+                .range((id, 0)..=(id, u64::MAX))
+                .map(|(key, _)| key)
+                .collect();
+            for key in keys {
+                storage.remove(&key);
+            }
+        });
+    }
+    removed
+}
+
+fn index_rental_request(rental_request: &RentalRequest) {
+    RENTALS_BY_CAR.with(|index| {
+        index
+            .borrow_mut()
+            .insert((rental_request.car_id, rental_request.id), ())
+    });
+    RENTALS_BY_CUSTOMER.with(|index| {
+        index
+            .borrow_mut()
+            .insert((rental_request.customer_id, rental_request.id), ())
+    });
+    RENTALS_BY_START_DATE.with(|index| {
+        index
+            .borrow_mut()
+            .insert((rental_request.start_date, rental_request.id), ())
+    });
+    if let Some(account_id) = rental_request.corporate_account_id {
+        RENTALS_BY_ACCOUNT.with(|index| {
+            index
+                .borrow_mut()
+                .insert((account_id, rental_request.id), ())
+        });
+    }
+}
+
+fn unindex_rental_request(rental_request: &RentalRequest) {
+    RENTALS_BY_CAR.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(rental_request.car_id, rental_request.id))
+    });
+    RENTALS_BY_CUSTOMER.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(rental_request.customer_id, rental_request.id))
+    });
+    RENTALS_BY_START_DATE.with(|index| {
+        index
+            .borrow_mut()
+            .remove(&(rental_request.start_date, rental_request.id))
+    });
+    if let Some(account_id) = rental_request.corporate_account_id {
+        RENTALS_BY_ACCOUNT
+            .with(|index| index.borrow_mut().remove(&(account_id, rental_request.id)));
+    }
+}
+
+fn rebuild_rental_indexes() {
+    RENTAL_REQUEST_STORAGE.with(|storage| {
+        for (_, rental_request) in storage.borrow().iter() {
+            index_rental_request(&rental_request);
+        }
+    });
+}
+
+// Collect the intervals overlapping [from, to) during which a car is unavailable, ordered by start
+fn blocked_intervals(car_id: u64, from: u64, to: u64) -> Vec<AvailabilityInterval> {
+    let mut intervals: Vec<AvailabilityInterval> = rentals_for_car(car_id)
+        .into_iter()
+        .filter(|request| {
+            request.status.is_open() && request.start_date < to && from < request.end_date
+        })
+        .map(|request| AvailabilityInterval {
+            start_date: request.start_date,
+            end_date: request.end_date,
+            reason: BlockReason::Rental {
+                rental_id: request.id,
+                status: request.status,
+            },
+        })
+        .collect();
+    intervals.extend(
+        maintenance_windows_for_car(car_id)
+            .into_iter()
+            .filter(|window| {
+                matches!(
+                    window.status,
+                    MaintenanceWindowStatus::Scheduled | MaintenanceWindowStatus::InProgress
+                ) && window.start_date < to
+                    && from < window.end_date
+            })
+            .map(|window| AvailabilityInterval {
+                start_date: window.start_date,
+                end_date: window.end_date,
+                reason: BlockReason::Maintenance {
+                    window_id: window.id,
+                },
+            }),
+    );
+    let now = ic_cdk::api::time();
+    intervals.extend(
+        holds_for_car(car_id)
+            .into_iter()
+            .filter(|hold| hold.is_active(now) && hold.start_date < to && from < hold.end_date)
+            .map(|hold| AvailabilityInterval {
+                start_date: hold.start_date,
+                end_date: hold.end_date,
+                reason: BlockReason::Hold { hold_id: hold.id },
+            }),
+    );
+    // A car in transit is blocked until the transfer is completed
+    if let Some(transfer) = transfer_in_transit(car_id) {
+        if transfer.requested_at < to {
+            intervals.push(AvailabilityInterval {
+                start_date: transfer.requested_at,
+                end_date: u64::MAX,
+                reason: BlockReason::Transfer {
+                    transfer_id: transfer.id,
+                    to_branch_id: transfer.to_branch_id,
+                },
+            });
+        }
+    }
+    intervals.sort_by_key(|interval| interval.start_date);
+    intervals
+}
+
+// Reject the period if the car left the fleet, or if it overlaps another pending or
+// active rental of the same car, a maintenance window, a transfer in transit, or another
+// customer's hold. The excluded booking is the one being changed or converted.
+fn ensure_car_available(
+    car_id: u64,
+    start_date: u64,
+    end_date: u64,
+    exclude: Option<ExcludedBooking>,
+) -> Result<(), Error> {
+    if let Some(car) = CAR_STORAGE.with(|storage| storage.borrow().get(&car_id)) {
+        if !car.is_active() {
+            return Err(Error::Conflict {
+                msg: format!("Car with id={} is no longer in the fleet", car_id),
+            });
+        }
+        if !car.is_listed() {
+            return Err(Error::Conflict {
+                msg: format!("Listing of car id={} is not approved", car_id),
+            });
+        }
+    }
+    let conflict = blocked_intervals(car_id, start_date, end_date)
+        .into_iter()
+        .find(|interval| match interval.reason {
+            BlockReason::Rental { rental_id, .. } => {
+                exclude != Some(ExcludedBooking::Rental(rental_id))
+            }
+            BlockReason::Hold { hold_id } => exclude != Some(ExcludedBooking::Hold(hold_id)),
+            BlockReason::Transfer { .. } | BlockReason::Maintenance { .. } => true,
+        });
+    match conflict {
+        Some(AvailabilityInterval {
+            reason: BlockReason::Rental { rental_id, .. },
+            ..
+        }) => Err(Error::Conflict {
+            msg: format!(
+                "Car with id={} is already booked by rental request id={} for an overlapping period",
+                car_id, rental_id
+            ),
+        }),
+        Some(AvailabilityInterval {
+            reason:
+                BlockReason::Transfer {
+                    transfer_id,
+                    to_branch_id,
+                },
+            ..
+        }) => Err(Error::Conflict {
+            msg: format!(
+                "Car with id={} is in transit to branch id={} with transfer id={}",
+                car_id, to_branch_id, transfer_id
+            ),
+        }),
+        Some(AvailabilityInterval {
+            reason: BlockReason::Maintenance { window_id },
+            ..
+        }) => Err(Error::Conflict {
+            msg: format!(
+                "Car with id={} is in maintenance window id={} during the period",
+                car_id, window_id
+            ),
+        }),
+        Some(AvailabilityInterval {
+            reason: BlockReason::Hold { hold_id },
+            ..
+        }) => Err(Error::Conflict {
+            msg: format!(
+                "Car with id={} is held by hold id={} for an overlapping period",
+                car_id, hold_id
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+// Count the started days between two dates
+fn rental_days(start_date: u64, end_date: u64) -> u64 {
+    end_date.saturating_sub(start_date).div_ceil(NANOS_PER_DAY)
+}
+
+// Find the calendar year and month (1-12) of a timestamp in UTC
+fn year_month(timestamp: u64) -> (u64, u64) {
+    let (year, month, _) = civil_date(timestamp);
+    (year, month)
+}
+
+// Find the calendar year, month (1-12), and day of a timestamp in UTC
+fn civil_date(timestamp: u64) -> (u64, u64, u64) {
+    // Count days from 1 March of year 0 in 400-year eras, so leap days end each year
+    let days = timestamp / NANOS_PER_DAY + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    (era * 400 + year_of_era + u64::from(month <= 2), month, day)
+}
+
+// Price a rental at the car's current daily rate, adjusted day by day by the matching
+// pricing rules. Rules apply in descending priority (ties broken by id) and a matching
+// rule that is not stackable stops lower priority rules from applying to that day.
+fn compute_quote(
+    car: &Car,
+    start_date: u64,
+    end_date: u64,
+    pickup_branch_id: Option<u64>,
+    dropoff_branch_id: Option<u64>,
+    protection: ProtectionPlan,
+    add_ons: &[RentalAddOn],
+) -> Quote {
+    let days = rental_days(start_date, end_date);
+    let base_e8s = days.saturating_mul(car.daily_rate_e8s);
+
+    let rules = list_pricing_rules();
+
+    // Net adjustment and number of affected days per rule, in rule order
+    let mut adjustments: Vec<(i128, u64)> = vec![(0, 0); rules.len()];
+    let mut total: u128 = 0;
+    for day in 0..days {
+        let day_start = start_date.saturating_add(day.saturating_mul(NANOS_PER_DAY));
+        let mut day_rate = car.daily_rate_e8s as u128;
+        for (index, rule) in rules.iter().enumerate() {
+            if !rule.matches(car.category, day_start, days) {
+                continue;
+            }
+            let adjusted = day_rate * rule.multiplier_bps as u128 / BPS_DENOMINATOR as u128;
+            adjustments[index].0 += adjusted as i128 - day_rate as i128;
+            adjustments[index].1 += 1;
+            day_rate = adjusted;
+            if !rule.stackable {
+                break;
+            }
+        }
+        total += day_rate;
+    }
+
+    let mut lines = vec![QuoteLine {
+        description: format!("{} days at {} e8s per day", days, car.daily_rate_e8s),
+        amount_e8s: i64::try_from(base_e8s).unwrap_or(i64::MAX),
+    }];
+    for (rule, (amount, affected_days)) in rules.iter().zip(adjustments) {
+        if affected_days > 0 {
+            lines.push(QuoteLine {
+                description: format!("{} ({} days)", rule.name, affected_days),
+                amount_e8s: amount.clamp(i64::MIN as i128, i64::MAX as i128) as i64,
+            });
+        }
+    }
+    let drop_fee_e8s = drop_fee(pickup_branch_id, dropoff_branch_id).unwrap_or(0);
+    if drop_fee_e8s > 0 {
+        lines.push(QuoteLine {
+            description: format!(
+                "One-way drop fee from branch id={} to branch id={}",
+                pickup_branch_id.unwrap_or_default(),
+                dropoff_branch_id.unwrap_or_default()
+            ),
+            amount_e8s: i64::try_from(drop_fee_e8s).unwrap_or(i64::MAX),
+        });
+        total += drop_fee_e8s as u128;
+    }
+    let protection_e8s = days.saturating_mul(protection.daily_rate_e8s);
+    if protection_e8s > 0 {
+        lines.push(QuoteLine {
+            description: format!(
+                "{:?} protection ({} days at {} e8s per day)",
+                protection.tier, days, protection.daily_rate_e8s
+            ),
+            amount_e8s: i64::try_from(protection_e8s).unwrap_or(i64::MAX),
+        });
+        total += protection_e8s as u128;
+    }
+    let mut add_ons_e8s: u64 = 0;
+    for add_on in add_ons {
+        let amount_e8s = days
+            .saturating_mul(add_on.daily_rate_e8s)
+            .saturating_mul(add_on.quantity as u64);
+        lines.push(QuoteLine {
+            description: format!(
+                "{} x{} ({} days at {} e8s per day)",
+                add_on.name, add_on.quantity, days, add_on.daily_rate_e8s
+            ),
+            amount_e8s: i64::try_from(amount_e8s).unwrap_or(i64::MAX),
+        });
+        add_ons_e8s = add_ons_e8s.saturating_add(amount_e8s);
+    }
+    total += add_ons_e8s as u128;
+
+    Quote {
+        car_id: car.id,
+        start_date,
+        end_date,
+        days,
+        daily_rate_e8s: car.daily_rate_e8s,
+        pickup_branch_id,
+        dropoff_branch_id,
+        drop_fee_e8s,
+        protection,
+        protection_e8s,
+        add_ons: add_ons.to_vec(),
+        add_ons_e8s,
+        lines,
+        total_e8s: u64::try_from(total).unwrap_or(u64::MAX),
+    }
+}
+
+// Validate the fields of a new rental request that need no lookups
+fn validate_rental_request_payload(payload: &RentalRequestPayload) -> Result<(), Error> {
+    validate_rental_period(payload.start_date, payload.end_date)?;
+    if !payload.status.is_open() {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental requests cannot be created in status {:?}",
+                payload.status
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Validate the fields of a rental request update that need no lookups
+fn validate_rental_update_payload(payload: &RentalRequestUpdatePayload) -> Result<(), Error> {
+    validate_rental_period(payload.start_date, payload.end_date)
+}
+
+// Validate the readings and notes of a check-in
+fn validate_check_in_payload(payload: &CheckInPayload) -> Result<(), Error> {
+    validate_fuel_percent(payload.fuel_percent)?;
+    if payload.condition_notes.len() > 1000 {
+        return Err(Error::InvalidInput {
+            msg: "Condition notes must be at most 1000 characters".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Validate the period and description of a maintenance window
+fn validate_maintenance_window_payload(payload: &MaintenanceWindowPayload) -> Result<(), Error> {
+    if payload.end_date <= payload.start_date || payload.end_date <= ic_cdk::api::time() {
+        return Err(Error::InvalidInput {
+            msg: "Maintenance must end after it starts and in the future".to_string(),
+        });
+    }
+    if payload.description.len() > 256 {
+        return Err(Error::InvalidInput {
+            msg: "Description must be at most 256 characters".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Validate the description and photo count of a damage report
+fn validate_damage_report_payload(payload: &DamageReportPayload) -> Result<(), Error> {
+    if payload.description.trim().is_empty() || payload.description.len() > 512 {
+        return Err(Error::InvalidInput {
+            msg: "Damage description must be between 1 and 512 characters".to_string(),
+        });
+    }
+    if payload.photo_ids.len() > 10 {
+        return Err(Error::InvalidInput {
+            msg: "A damage report may reference at most 10 photos".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Validate a rental period against the configured rental length limits
+fn validate_rental_period(start_date: u64, end_date: u64) -> Result<(), Error> {
+    if end_date <= start_date {
+        return Err(Error::InvalidInput {
+            msg: "Rental end date must be after the start date".to_string(),
+        });
+    }
+    let config = config();
+    let days = rental_days(start_date, end_date);
+    if days < config.min_rental_days || days > config.max_rental_days {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Rental length of {} days is outside the allowed range of {}-{} days",
+                days, config.min_rental_days, config.max_rental_days
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn validate_period(period: &Period) -> Result<(), Error> {
+    if period.to <= period.from {
+        return Err(Error::InvalidInput {
+            msg: "Period must end after it starts".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Validate the fields of a pricing rule
+fn validate_pricing_rule(payload: &PricingRulePayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Pricing rule name must not be empty".to_string(),
+        });
+    }
+    if let (Some(from), Some(until)) = (payload.applies_from, payload.applies_until) {
+        if until <= from {
+            return Err(Error::InvalidInput {
+                msg: "Pricing rule must end after it starts".to_string(),
+            });
+        }
+    }
+    if payload.multiplier_bps == 0 {
+        return Err(Error::InvalidInput {
+            msg: "Pricing rule multiplier must be positive".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Validate the details of a car, keeping a stored car within its size bound
+fn validate_car_payload(payload: &CarPayload) -> Result<(), Error> {
+    let invalid = |msg: &str| {
+        Err(Error::InvalidInput {
+            msg: msg.to_string(),
+        })
+    };
+    if payload.make.trim().is_empty() || payload.model.trim().is_empty() {
+        return invalid("Car make and model must not be empty");
+    }
+    if payload.make.len() > 64 || payload.model.len() > 64 {
+        return invalid("Car make and model must be at most 64 characters");
+    }
+    if payload.license_plate.len() > 16 || payload.color.len() > 32 {
+        return invalid("License plate must be at most 16 and color at most 32 characters");
+    }
+    if payload.features.len() > 10 || payload.features.iter().any(|feature| feature.len() > 32) {
+        return invalid("A car may list at most 10 features of at most 32 characters");
+    }
+    if let Some(vin) = &payload.vin {
+        validate_vin(vin)?;
+    }
+    if let Some(branch_id) = payload.branch_id {
+        get_branch(branch_id)?;
+    }
+    Ok(())
+}
+
+fn validate_maintenance_payload(payload: &MaintenanceRecordPayload) -> Result<(), Error> {
+    get_car(payload.car_id)?;
+    if payload.description.len() > 256 || payload.vendor.len() > 64 {
+        return Err(Error::InvalidInput {
+            msg: "Description must be at most 256 and vendor at most 64 characters".to_string(),
+        });
+    }
+    // Records are kept for work already done
+    if payload.performed_at > ic_cdk::api::time() {
+        return Err(Error::InvalidInput {
+            msg: "Maintenance cannot be recorded before it is performed".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_corporate_account_payload(payload: &CorporateAccountPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() || payload.name.len() > 128 {
+        return Err(Error::InvalidInput {
+            msg: "Account name must be between 1 and 128 characters".to_string(),
+        });
+    }
+    if !payload.billing_email.contains('@') || payload.billing_email.len() > 128 {
+        return Err(Error::InvalidInput {
+            msg: format!("Invalid billing email address: {}", payload.billing_email),
+        });
+    }
+    Ok(())
+}
+
+fn validate_owner_shares(shares: &[OwnerShare]) -> Result<(), Error> {
+    if shares.len() > MAX_CAR_OWNERS {
+        return Err(Error::InvalidInput {
+            msg: format!("A car can have at most {} owners", MAX_CAR_OWNERS),
+        });
+    }
+    let mut owners = BTreeSet::new();
+    for share in shares {
+        if share.owner == Principal::anonymous() {
+            return Err(Error::InvalidInput {
+                msg: "A car cannot be owned by the anonymous principal".to_string(),
+            });
+        }
+        if share.share_bps == 0 {
+            return Err(Error::InvalidInput {
+                msg: format!("Share of owner {} must be positive", share.owner),
+            });
+        }
+        if !owners.insert(share.owner) {
+            return Err(Error::InvalidInput {
+                msg: format!("Owner {} is given more than one share", share.owner),
+            });
+        }
+    }
+    let total_bps = shares
+        .iter()
+        .fold(0u64, |total, share| total.saturating_add(share.share_bps));
+    if total_bps > BPS_DENOMINATOR {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "Owner shares total {} bps, more than {}",
+                total_bps, BPS_DENOMINATOR
+            ),
+        });
+    }
+    Ok(())
+}
+
+fn validate_add_on_payload(payload: &AddOnPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() || payload.name.len() > 32 {
+        return Err(Error::InvalidInput {
+            msg: "Add-on name must be between 1 and 32 characters".to_string(),
+        });
+    }
+    if payload.description.len() > 256 {
+        return Err(Error::InvalidInput {
+            msg: "Add-on description must be at most 256 characters".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_branch_payload(payload: &BranchPayload) -> Result<(), Error> {
+    let invalid = |msg: &str| {
+        Err(Error::InvalidInput {
+            msg: msg.to_string(),
+        })
+    };
+    if payload.name.trim().is_empty() || payload.name.len() > 64 {
+        return invalid("Branch name must be between 1 and 64 characters");
+    }
+    if payload.address.len() > 256 {
+        return invalid("Branch address must be at most 256 characters");
+    }
+    if !(-90.0..=90.0).contains(&payload.latitude) || !(-180.0..=180.0).contains(&payload.longitude)
+    {
+        return invalid("Branch latitude must be within ±90 and longitude within ±180 degrees");
+    }
+    if payload.opening_hours.len() > 14 {
+        return invalid("A branch may list at most 14 opening hours");
+    }
+    if payload.opening_hours.iter().any(|hours| {
+        hours.opens_at_minute >= hours.closes_at_minute || hours.closes_at_minute > 1440
+    }) {
+        return invalid("Opening hours must open before they close, within one day");
+    }
+    Ok(())
+}
+
+// Validate the length, alphabet, and check digit (position 9) of a VIN
+fn validate_vin(vin: &str) -> Result<(), Error> {
+    const WEIGHTS: [u32; 17] = [8, 7, 6, 5, 4, 3, 2, 10, 0, 9, 8, 7, 6, 5, 4, 3, 2];
+    let vin = vin.trim().to_uppercase();
+    let values: Option<Vec<u32>> = vin
+        .chars()
+        .map(|c| match c {
+            '0'..='9' => c.to_digit(10),
+            'A' | 'J' => Some(1),
+            'B' | 'K' | 'S' => Some(2),
+            'C' | 'L' | 'T' => Some(3),
+            'D' | 'M' | 'U' => Some(4),
+            'E' | 'N' | 'V' => Some(5),
+            'F' | 'W' => Some(6),
+            'G' | 'P' | 'X' => Some(7),
+            'H' | 'Y' => Some(8),
+            'R' | 'Z' => Some(9),
+            // I, O, and Q are never used in a VIN
+            _ => None,
+        })
+        .collect();
+    let values = match values {
+        Some(values) if values.len() == 17 => values,
+        _ => {
+            return Err(Error::InvalidInput {
+                msg: "VIN must be 17 letters and digits, excluding I, O, and Q".to_string(),
+            })
+        }
+    };
+    let remainder = values
+        .iter()
+        .zip(WEIGHTS)
+        .map(|(value, weight)| value * weight)
+        .sum::<u32>()
+        % 11;
+    let expected = if remainder == 10 {
+        'X'
+    } else {
+        char::from_digit(remainder, 10).unwrap()
+    };
+    if vin.chars().nth(8) != Some(expected) {
+        return Err(Error::InvalidInput {
+            msg: format!("VIN {} has an invalid check digit", vin),
+        });
+    }
+    Ok(())
+}
+
+// Validate a coupon, refusing codes already used by another coupon
+fn validate_coupon(payload: &CouponPayload, id: Option<u64>) -> Result<(), Error> {
+    let code = normalize_coupon_code(&payload.code);
+    if code.is_empty() || code.len() > 32 {
+        return Err(Error::InvalidInput {
+            msg: "Coupon code must be between 1 and 32 characters".to_string(),
+        });
+    }
+    if let (Some(from), Some(until)) = (payload.valid_from, payload.valid_until) {
+        if until <= from {
+            return Err(Error::InvalidInput {
+                msg: "Coupon must expire after it becomes valid".to_string(),
+            });
+        }
+    }
+    match payload.discount {
+        CouponDiscount::Percentage { bps } if bps == 0 || bps > BPS_DENOMINATOR => {
+            return Err(Error::InvalidInput {
+                msg: "Coupon percentage must be between 1 and 10000 bps".to_string(),
+            });
+        }
+        CouponDiscount::Fixed { amount_e8s: 0 } => {
+            return Err(Error::InvalidInput {
+                msg: "Coupon amount must be positive".to_string(),
+            });
+        }
+        _ => {}
+    }
+    if coupon_by_code(&code).is_some_and(|coupon| Some(coupon.id) != id) {
+        return Err(Error::Conflict {
+            msg: format!("Coupon code {} is already in use", code),
+        });
+    }
+    Ok(())
+}
+
+// Validate the operational settings
+fn validate_config(config: &Config) -> Result<(), Error> {
+    if config.min_rental_days > config.max_rental_days {
+        return Err(Error::InvalidInput {
+            msg: "min_rental_days must not exceed max_rental_days".to_string(),
+        });
+    }
+    if config.tax_rate_bps > BPS_DENOMINATOR {
+        return Err(Error::InvalidInput {
+            msg: "tax_rate_bps must not exceed 10000".to_string(),
+        });
+    }
+    if config.early_return_credit_bps > BPS_DENOMINATOR {
+        return Err(Error::InvalidInput {
+            msg: "early_return_credit_bps must not exceed 10000".to_string(),
+        });
+    }
+    if config.loyalty_points_per_icp > MAX_LOYALTY_POINTS_PER_ICP {
+        return Err(Error::InvalidInput {
+            msg: format!(
+                "loyalty_points_per_icp must be at most {}",
+                MAX_LOYALTY_POINTS_PER_ICP
+            ),
+        });
+    }
+    if config.hold_minutes == 0 || config.hold_minutes > 24 * 60 {
+        return Err(Error::InvalidInput {
+            msg: "hold_minutes must be between 1 and 1440".to_string(),
+        });
+    }
+    // Rentals stay current until the corporate statement of their month has been issued
+    if config.archive_after_days < 62 || config.archive_after_days > 3_650 {
+        return Err(Error::InvalidInput {
+            msg: "archive_after_days must be between 62 and 3650".to_string(),
+        });
+    }
+    if config
+        .exchange_rate_ttl_minutes
+        .is_some_and(|minutes| minutes == 0 || minutes > 24 * 60)
+    {
+        return Err(Error::InvalidInput {
+            msg: "exchange_rate_ttl_minutes must be between 1 and 1440".to_string(),
+        });
+    }
+    for (field, name) in [
+        ("ecdsa_key_name", &config.ecdsa_key_name),
+        ("vetkd_key_name", &config.vetkd_key_name),
+    ] {
+        if let Some(name) = name {
+            if name.trim().is_empty() || name.len() > 64 {
+                return Err(Error::InvalidInput {
+                    msg: format!("{} must be between 1 and 64 characters", field),
+                });
+            }
+        }
+    }
+    if config
+        .marketplace_commission_bps
+        .is_some_and(|bps| bps > BPS_DENOMINATOR)
+    {
+        return Err(Error::InvalidInput {
+            msg: "marketplace_commission_bps must not exceed 10000".to_string(),
+        });
+    }
+    if config.rate_limit_per_minute > 10_000 {
+        return Err(Error::InvalidInput {
+            msg: "rate_limit_per_minute must be at most 10000".to_string(),
+        });
+    }
+    for (name, hours) in [
+        ("pickup_reminder_hours", config.pickup_reminder_hours),
+        ("return_reminder_hours", config.return_reminder_hours),
+    ] {
+        if hours.is_some_and(|hours| hours == 0 || hours > 7 * 24) {
+            return Err(Error::InvalidInput {
+                msg: format!("{} must be between 1 and 168", name),
+            });
+        }
+    }
+    validate_eligibility_rules(&config.eligibility_rules)?;
+    validate_cancellation_policy(&config.cancellation_tiers, config.no_show_fee_bps)
+}
+
+// Validate the eligibility rules, which must have distinct car categories
+fn validate_eligibility_rules(rules: &[EligibilityRule]) -> Result<(), Error> {
+    for (index, rule) in rules.iter().enumerate() {
+        if rules[..index]
+            .iter()
+            .any(|other| other.category == rule.category)
+        {
+            return Err(Error::InvalidInput {
+                msg: format!("{:?} has more than one eligibility rule", rule.category),
+            });
+        }
+        if rule.license_classes.len() > 10
+            || rule
+                .license_classes
+                .iter()
+                .any(|class| class.trim().is_empty() || class.len() > 16)
+        {
+            return Err(Error::InvalidInput {
+                msg: "At most 10 license classes of 1 to 16 characters are allowed".to_string(),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Validate the cancellation fee tiers, which must have distinct hours
+fn validate_cancellation_policy(
+    tiers: &[CancellationTier],
+    no_show_fee_bps: u64,
+) -> Result<(), Error> {
+    if tiers.len() > 10 {
+        return Err(Error::InvalidInput {
+            msg: "At most 10 cancellation tiers are allowed".to_string(),
+        });
+    }
+    for (index, tier) in tiers.iter().enumerate() {
+        if tier.fee_bps > BPS_DENOMINATOR {
+            return Err(Error::InvalidInput {
+                msg: "Cancellation fees must not exceed 10000 bps".to_string(),
+            });
+        }
+        if tiers[..index]
+            .iter()
+            .any(|other| other.min_hours_before_start == tier.min_hours_before_start)
+        {
+            return Err(Error::InvalidInput {
+                msg: format!(
+                    "More than one cancellation tier starts {} hours before the rental",
+                    tier.min_hours_before_start
+                ),
+            });
+        }
+    }
+    if no_show_fee_bps > BPS_DENOMINATOR {
+        return Err(Error::InvalidInput {
+            msg: "no_show_fee_bps must not exceed 10000".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Validate the required customer profile fields
+fn validate_customer_payload(payload: &CustomerPayload) -> Result<(), Error> {
+    validate_customer_name(&payload.name)?;
+    if !payload.email.contains('@') {
+        return Err(Error::InvalidInput {
+            msg: format!("Invalid email address: {}", payload.email),
+        });
+    }
+    if payload.license_number.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "License number must not be empty".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn validate_customer_name(name: &str) -> Result<(), Error> {
+    if name.trim().is_empty() {
+        return Err(Error::InvalidInput {
+            msg: "Customer name must not be empty".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Export the Candid interface
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The car and rental request shapes the canister first stored
+    #[derive(candid::CandidType)]
+    struct BaselineCar {
+        id: u64,
+        make: String,
+        model: String,
+        year: u32,
+        available: bool,
+    }
+
+    #[derive(candid::CandidType)]
+    enum BaselineRentalStatus {
+        Active,
+    }
+
+    #[derive(candid::CandidType)]
+    struct BaselineRentalRequest {
+        id: u64,
+        car_id: u64,
+        customer_id: u64,
+        start_date: u64,
+        end_date: u64,
+        status: BaselineRentalStatus,
+    }
+
+    fn baseline_rental_request(id: u64, customer_id: u64) -> Vec<u8> {
+        Encode!(&BaselineRentalRequest {
+            id,
+            car_id: 1,
+            customer_id,
+            start_date: 10,
+            end_date: 20,
+            status: BaselineRentalStatus::Active,
+        })
+        .unwrap()
+    }
+
+    fn payment(amount_e8s: u64, wallet_e8s: u64, refunded_e8s: u64) -> Payment {
+        Payment {
+            id: 1,
+            rental_id: 1,
+            customer_id: 1,
+            payer: Principal::anonymous(),
+            amount_e8s,
+            block_index: 0,
+            paid_at: 0,
+            method: PaymentMethod::Wallet,
+            refunded_e8s,
+            wallet_e8s,
+        }
+    }
+
+    fn deposit(amount_e8s: u64) -> Deposit {
+        Deposit {
+            rental_id: 1,
+            subaccount: deposit_subaccount(1).to_vec(),
+            amount_e8s,
+            released_e8s: 0,
+            forfeited_e8s: 0,
+            state: DepositState::Held,
+            movements: Vec::new(),
+        }
+    }
+
+    fn movement(amount_e8s: u64, forfeited: bool) -> DepositMovement {
+        DepositMovement {
+            amount_e8s,
+            forfeited,
+            reason: "Inspection".to_string(),
+            transfer_id: 0,
+            at: 0,
+        }
+    }
+
+    // Run a future that is expected to finish without waiting on an inter-canister call
+    fn run_ready<F: std::future::Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        match std::future::Future::poll(future.as_mut(), &mut context) {
+            std::task::Poll::Ready(output) => output,
+            std::task::Poll::Pending => panic!("The future waited on a call"),
+        }
+    }
+
+    fn rental(id: u64, car_id: u64, customer_id: u64, start_date: u64) -> RentalRequest {
+        let mut rental_request =
+            RentalRequest::from_bytes(Cow::Owned(baseline_rental_request(id, customer_id)));
+        rental_request.car_id = car_id;
+        rental_request.start_date = start_date;
+        rental_request.end_date = start_date + NANOS_PER_DAY;
+        rental_request
+    }
+
+    // Store a rental request and keep its indexes in step, as store_rental_request does
+    fn insert_rental(rental_request: &RentalRequest) {
+        let previous = RENTAL_REQUEST_STORAGE.with(|storage| {
+            storage
+                .borrow_mut()
+                .insert(rental_request.id, rental_request.clone())
+        });
+        if let Some(previous) = previous {
+            unindex_rental_request(&previous);
+        }
+        index_rental_request(rental_request);
+    }
+
+    fn rental_ids(rentals: Vec<RentalRequest>) -> Vec<u64> {
+        rentals
+            .iter()
+            .map(|rental_request| rental_request.id)
+            .collect()
+    }
+
+    fn car(id: u64, make: &str, model: &str) -> Car {
+        Car::from_bytes(Cow::Owned(
+            Encode!(&BaselineCar {
+                id,
+                make: make.to_string(),
+                model: model.to_string(),
+                year: 2020,
+                available: true,
+            })
+            .unwrap(),
+        ))
+    }
+
+    #[test]
+    fn baseline_car_decodes_with_defaults() {
+        let bytes = Encode!(&BaselineCar {
+            id: 3,
+            make: "Toyota".to_string(),
+            model: "Corolla".to_string(),
+            year: 2020,
+            available: true,
+        })
+        .unwrap();
+        let car = Car::from_bytes(Cow::Owned(bytes));
+        assert_eq!(car.id, 3);
+        assert_eq!(car.make, "Toyota");
+        assert_eq!(car.model, "Corolla");
+        assert_eq!(car.year, 2020);
+        assert!(car.available);
+        assert_eq!(car.daily_rate_e8s, 0);
+        assert_eq!(car.category, CarCategory::Economy);
+        assert_eq!(car.version, 0);
+
+        // Once decoded, the car is written in the current layout and reads back unchanged
+        let car = Car::from_bytes(Cow::Owned(car.to_bytes().into_owned()));
+        assert_eq!(car.id, 3);
+        assert_eq!(car.make, "Toyota");
+        assert_eq!(car.category, CarCategory::Economy);
+    }
+
+    #[test]
+    fn owner_listed_car_is_listed_only_once_approved() {
+        let bytes = Encode!(&BaselineCar {
+            id: 4,
+            make: "Mazda".to_string(),
+            model: "CX-5".to_string(),
+            year: 2022,
+            available: false,
+        })
+        .unwrap();
+        let mut car = Car::from_bytes(Cow::Owned(bytes));
+        assert!(car.is_listed());
+
+        car.listed_by = Some(Principal::from_slice(&[1]));
+        car.listing_status = Some(ListingStatus::Pending);
+        assert!(!car.is_listed());
+        car.listing_status = Some(ListingStatus::Rejected {
+            reason: "Photos missing".to_string(),
+        });
+        assert!(!car.is_listed());
+        car.listing_status = Some(ListingStatus::Approved);
+        assert!(car.is_listed());
+
+        car.status = Some(CarStatus::Sold);
+        assert!(!car.is_listed());
+    }
+
+    #[test]
+    fn car_stored_before_the_marketplace_is_not_owner_listed() {
+        let bytes = Encode!(&BaselineCar {
+            id: 5,
+            make: "Kia".to_string(),
+            model: "Niro".to_string(),
+            year: 2021,
+            available: true,
+        })
+        .unwrap();
+        let car = Car::from_bytes(Cow::Owned(bytes));
+        // Version 2 had no listing fields, the last two of a car
+        let mut fields: std::collections::BTreeMap<serde_cbor::Value, serde_cbor::Value> =
+            serde_cbor::from_slice(&encode_record(&car)).unwrap();
+        let last = fields.len() as i128;
+        assert!(fields
+            .remove(&serde_cbor::Value::Integer(last - 1))
+            .is_some());
+        assert!(fields
+            .remove(&serde_cbor::Value::Integer(last - 2))
+            .is_some());
+        let stored = tag_schema_version(2, serde_cbor::to_vec(&fields).unwrap());
+
+        let car = Car::from_bytes(Cow::Owned(stored));
+        assert_eq!(car.id, 5);
+        assert_eq!(car.model, "Niro");
+        assert!(car.listed_by.is_none());
+        assert!(car.listing_status.is_none());
+        assert_eq!(car.to_bytes()[0], CAR_SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn name_alone_is_validated_without_the_encrypted_details() {
+        let payload = CustomerPayload {
+            name: "Ada".to_string(),
+            email: String::new(),
+            phone: String::new(),
+            license_number: String::new(),
+        };
+        assert!(validate_customer_name(&payload.name).is_ok());
+        assert!(validate_customer_payload(&payload).is_err());
+        assert!(validate_customer_name("  ").is_err());
+    }
+
+    #[test]
+    fn records_are_stored_behind_their_schema_version() {
+        let rental_request = RentalRequest::from_bytes(Cow::Owned(baseline_rental_request(4, 404)));
+        let bytes = rental_request.to_bytes().into_owned();
+        assert_eq!(bytes[0], RENTAL_SCHEMA_VERSION);
+        assert_eq!(RentalRequest::from_bytes(Cow::Owned(bytes)).id, 4);
+
+        // Records in the latest layout stored before versions existed still decode
+        let untagged = Encode!(&rental_request).unwrap();
+        assert_eq!(split_schema_version(&untagged).0, 0);
+        let rental_request = RentalRequest::from_bytes(Cow::Owned(untagged));
+        assert_eq!(rental_request.id, 4);
+        assert_eq!(rental_request.end_date, 20);
+    }
+
+    #[derive(candid::CandidType, Serialize)]
+    struct StoredBefore {
+        id: u64,
+        owner: Principal,
+    }
+
+    #[derive(candid::CandidType, Serialize, Deserialize)]
+    struct StoredAfter {
+        id: u64,
+        owner: Principal,
+        added: Option<String>,
+    }
+
+    #[test]
+    fn compact_records_round_trip_smaller_than_candid() {
+        let rental_request = RentalRequest::from_bytes(Cow::Owned(baseline_rental_request(6, 404)));
+        let bytes = rental_request.to_bytes().into_owned();
+        assert!(bytes.len() * 2 < Encode!(&rental_request).unwrap().len());
+        let decoded = RentalRequest::from_bytes(Cow::Owned(bytes));
+        assert_eq!(decoded.id, 6);
+        assert_eq!(decoded.status, RentalStatus::Active);
+        assert_eq!(decoded.owner, Principal::management_canister());
+
+        // Version 1 records hold Candid behind the version byte
+        let candid = tag_schema_version(1, Encode!(&rental_request).unwrap());
+        assert_eq!(RentalRequest::from_bytes(Cow::Owned(candid)).id, 6);
+
+        let deposit = deposit(1_000);
+        let decoded: Deposit = decode_record(&encode_record(&deposit));
+        assert_eq!(decoded.subaccount, deposit.subaccount);
+        assert_eq!(decoded.state, DepositState::Held);
+        let decoded: Deposit = decode_record(&Encode!(&deposit).unwrap());
+        assert_eq!(decoded.amount_e8s, 1_000);
+    }
+
+    #[test]
+    fn compact_record_decodes_with_fields_added_since() {
+        let owner = Principal::from_slice(&[7; 29]);
+        let bytes = encode_record(&StoredBefore { id: 2, owner });
+        let decoded: StoredAfter = decode_record(&bytes);
+        assert_eq!(decoded.id, 2);
+        assert_eq!(decoded.owner, owner);
+        assert_eq!(decoded.added, None);
+    }
+
+    #[test]
+    fn car_depreciates_only_within_its_useful_life() {
+        let acquisition = CarAcquisition {
+            car_id: 1,
+            purchase_price_e8s: 1_000,
+            acquired_at: 10 * NANOS_PER_DAY,
+            residual_value_e8s: 200,
+            useful_life_days: 100,
+        };
+        let period = |from_day: u64, to_day: u64| Period {
+            from: from_day * NANOS_PER_DAY,
+            to: to_day * NANOS_PER_DAY,
+        };
+        assert_eq!(acquisition.depreciation_in(&period(0, 10)), 0);
+        assert_eq!(acquisition.depreciation_in(&period(10, 35)), 200);
+        assert_eq!(acquisition.depreciation_in(&period(0, 60)), 400);
+        assert_eq!(acquisition.depreciation_in(&period(0, 500)), 800);
+        assert_eq!(acquisition.depreciation_in(&period(110, 200)), 0);
+    }
+
+    #[test]
+    fn rental_days_are_counted_in_the_bucket_they_reach() {
+        let mut counts = Vec::new();
+        for days in [0, 1, 2, 7, 8, 400] {
+            count_in_bucket(&mut counts, &RENTAL_DAYS_BUCKETS, days);
+        }
+        assert_eq!(counts, vec![2, 1, 0, 1, 1, 0, 1]);
+        let buckets = days_buckets(&RENTAL_DAYS_BUCKETS, &counts);
+        assert_eq!((buckets[3].min_days, buckets[3].max_days), (4, Some(7)));
+        assert_eq!((buckets[6].min_days, buckets[6].max_days), (31, None));
+    }
+
+    #[test]
+    fn exchange_rate_converts_amounts_at_its_decimals() {
+        let rate = ExchangeRate {
+            currency: "USD".to_string(),
+            rate: 12_345_000_000,
+            decimals: 9,
+            timestamp: 0,
+            fetched_at: 0,
+        };
+        assert_eq!(rate.convert(200_000_000), 2_469_000_000);
+        assert_eq!(rate.convert(-100_000_000), -1_234_500_000);
+        assert_eq!(currency_code(" usd ").ok().as_deref(), Some("USD"));
+        assert!(currency_code("US1").is_err());
+    }
+
+    #[test]
+    fn default_subaccount_is_the_same_account_as_none() {
+        let owner = Principal::management_canister();
+        let account = |subaccount| Account { owner, subaccount };
+        assert!(same_account(&account(None), &account(Some([0; 32]))));
+        assert!(!same_account(&account(None), &account(Some([1; 32]))));
+        assert!(!same_account(
+            &account(None),
+            &Account {
+                owner: Principal::anonymous(),
+                subaccount: None,
+            }
+        ));
+    }
+
+    #[test]
+    fn owner_shares_must_not_exceed_the_whole_car() {
+        let owner = |id: u8, share_bps: u64| OwnerShare {
+            owner: Principal::from_slice(&[id]),
+            share_bps,
+        };
+        assert!(validate_owner_shares(&[owner(1, 6_000), owner(2, 4_000)]).is_ok());
+        assert!(validate_owner_shares(&[owner(1, 2_500)]).is_ok());
+        assert!(validate_owner_shares(&[owner(1, 6_000), owner(2, 4_001)]).is_err());
+        assert!(validate_owner_shares(&[owner(1, 100), owner(1, 100)]).is_err());
+        assert!(validate_owner_shares(&[owner(1, 0)]).is_err());
+        assert!(validate_owner_shares(&[owner(1, u64::MAX), owner(2, 1)]).is_err());
+    }
+
+    #[test]
+    fn oversized_record_is_refused_before_insert() {
+        let mut car = Car::from_bytes(Cow::Owned(
+            Encode!(&BaselineCar {
+                id: 1,
+                make: "Toyota".to_string(),
+                model: "Corolla".to_string(),
+                year: 2020,
+                available: true,
+            })
+            .unwrap(),
+        ));
+        assert!(check_record_size(&car).is_ok());
+        car.features = vec!["f".repeat(32); 40];
+        assert!(matches!(
+            check_record_size(&car),
+            Err(Error::RecordTooLarge { size, max_size: 1024 }) if size > 1024
+        ));
+    }
+
+    #[test]
+    fn config_stored_before_later_settings_decodes_with_defaults() {
+        // The config as stored by the ledger payment releases, before deposits existed
+        #[derive(candid::CandidType, Serialize)]
+        struct PaymentsConfig {
+            min_rental_days: u64,
+            max_rental_days: u64,
+            ledger_canister_id: Option<Principal>,
+            token_ledger_canister_id: Option<Principal>,
+        }
+        let stored = PaymentsConfig {
+            min_rental_days: 2,
+            max_rental_days: 30,
+            ledger_canister_id: None,
+            token_ledger_canister_id: Some(Principal::from_slice(&[3; 29])),
+        };
+        let defaults = Config::default();
+        for bytes in [Encode!(&stored).unwrap(), encode_record(&stored)] {
+            let config = Config::from_bytes(Cow::Owned(bytes));
+            assert_eq!(config.min_rental_days, 2);
+            assert_eq!(config.max_rental_days, 30);
+            assert_eq!(
+                config.token_ledger_canister_id,
+                stored.token_ledger_canister_id
+            );
+            assert_eq!(config.ledger_fee_e8s, defaults.ledger_fee_e8s);
+            assert_eq!(config.deposits.len(), defaults.deposits.len());
+            assert_eq!(config.hold_minutes, defaults.hold_minutes);
+            assert!(!config.opaque_ids);
+        }
+    }
+
+    #[test]
+    fn baseline_rental_request_takes_owner_from_customer() {
+        let owner = Principal::from_slice(&[7; 29]);
+        let mut customer = Customer::from(LegacyCustomer {
+            id: 5,
+            name: "Ada".to_string(),
+            email: "ada@example.com".to_string(),
+            phone: "555".to_string(),
+            license_number: "L-1".to_string(),
+            created_at: 0,
+        });
+        customer.owner = owner;
+        CUSTOMER_STORAGE.with(|storage| storage.borrow_mut().insert(5, customer));
+        let rental_request = RentalRequest::from_bytes(Cow::Owned(baseline_rental_request(8, 5)));
+        assert_eq!(rental_request.id, 8);
+        assert_eq!(rental_request.car_id, 1);
+        assert_eq!(rental_request.customer_id, 5);
+        assert_eq!(rental_request.start_date, 10);
+        assert_eq!(rental_request.end_date, 20);
+        assert_eq!(rental_request.status, RentalStatus::Active);
+        assert_eq!(rental_request.owner, owner);
+        assert_eq!(rental_request.quoted_price_e8s, 0);
+        assert_eq!(rental_request.payment_id, None);
+        assert_eq!(rental_request.version, 0);
+    }
+
+    #[test]
+    fn agreement_template_placeholders_are_filled_in() {
+        let customer = Customer::from(LegacyCustomer {
+            id: 6,
+            name: "Grace".to_string(),
+            email: String::new(),
+            phone: String::new(),
+            license_number: String::new(),
+            created_at: 0,
+        });
+        let mut rental_request =
+            RentalRequest::from_bytes(Cow::Owned(baseline_rental_request(9, 6)));
+        rental_request.quoted_price_e8s = 150_000_000;
+        rental_request.included_km = Some(300);
+        assert_eq!(
+            render_agreement(
+                "{customer} #{customer_id}, rental {rental_id} of the {car}: {price}, \
+                 {included_km}, from {pickup_branch}, terms {terms_version}, {unknown}",
+                &rental_request,
+                &customer
+            ),
+            "Grace #6, rental 9 of the car #1: 1.50000000 ICP, 300 km, \
+             from the car's location, terms none, {unknown}"
+        );
+    }
+
+    #[test]
+    fn agreement_values_are_not_filled_in_again() {
+        let customer = Customer::from(LegacyCustomer {
+            id: 7,
+            name: "{price}".to_string(),
+            email: String::new(),
+            phone: String::new(),
+            license_number: String::new(),
+            created_at: 0,
+        });
+        let rental_request = RentalRequest::from_bytes(Cow::Owned(baseline_rental_request(9, 7)));
+        assert_eq!(
+            render_agreement("{{customer} owes {price}", &rental_request, &customer),
+            "{{price} owes 0.00000000 ICP"
+        );
+        assert_eq!(
+            render_template("{customer}: {rental_id", &rental_request, &customer),
+            "{price}: {rental_id"
+        );
+    }
+
+    #[test]
+    fn erased_customer_is_redacted_from_summaries() {
+        let mut customer = Customer::from(LegacyCustomer {
+            id: 5,
+            name: "Al".to_string(),
+            email: "al@example.com".to_string(),
+            phone: String::new(),
+            license_number: "L-1".to_string(),
+            created_at: 0,
+        });
+        customer.owner = Principal::from_slice(&[7; 29]);
+        let erased = erased_values(&customer);
+        let summary = format!(
+            "{{\"name\":\"Al\",\"email\":\"al@example.com\",\"phone\":\"\",\"note\":\"Always\",\"owner\":\"{}\"}}",
+            customer.owner
+        );
+        assert_eq!(
+            redact(&summary, &erased),
+            "{\"name\":\"[erased]\",\"email\":\"[erased]\",\"phone\":\"\",\"note\":\"Always\",\"owner\":\"[erased]\"}"
+        );
+
+        scrub_customer(&mut customer, 9);
+        assert!(customer.name.is_empty() && customer.email.is_empty());
+        assert_eq!(customer.owner, Principal::management_canister());
+        assert_eq!(customer.erased_at, Some(9));
+    }
+
+    #[test]
+    fn booking_needs_the_latest_mandatory_terms_accepted() {
+        let publish = |version: u64, mandatory: bool| {
+            let terms = TermsOfService {
+                version,
+                text: format!("Terms {}", version),
+                text_hash: vec![],
+                mandatory,
+                published_by: Principal::anonymous(),
+                published_at: 0,
+            };
+            TERMS_OF_SERVICE.with(|storage| storage.borrow_mut().insert(version, terms));
+        };
+        let accept = |version: u64| {
+            let acceptance = TermsAcceptance {
+                customer_id: 3,
+                version,
+                principal: Principal::anonymous(),
+                accepted_at: 0,
+            };
+            TERMS_ACCEPTANCES.with(|storage| storage.borrow_mut().insert((3, version), acceptance));
+        };
+        assert!(ensure_terms_accepted(3).is_ok());
+        publish(1, true);
+        assert!(ensure_terms_accepted(3).is_err());
+        accept(1);
+        assert!(ensure_terms_accepted(3).is_ok());
+        // An optional version does not block bookings
+        publish(2, false);
+        assert!(ensure_terms_accepted(3).is_ok());
+        publish(3, true);
+        assert!(ensure_terms_accepted(3).is_err());
+        accept(3);
+        assert!(ensure_terms_accepted(3).is_ok());
+    }
+
+    #[test]
+    fn baseline_rental_request_without_customer_belongs_to_nobody() {
+        let rental_request = RentalRequest::from_bytes(Cow::Owned(baseline_rental_request(9, 404)));
+        assert_eq!(rental_request.owner, Principal::management_canister());
+    }
+
+    #[test]
+    fn payment_stored_before_wallets_decodes_with_nothing_refunded() {
+        // The payment as stored by the ledger payment releases
+        #[derive(candid::CandidType)]
+        struct LedgerPayment {
+            id: u64,
+            rental_id: u64,
+            customer_id: u64,
+            payer: Principal,
+            amount_e8s: u64,
+            block_index: u64,
+            paid_at: u64,
+            method: PaymentMethod,
+        }
+        let bytes = Encode!(&LedgerPayment {
+            id: 4,
+            rental_id: 8,
+            customer_id: 5,
+            payer: Principal::from_slice(&[7; 29]),
+            amount_e8s: 1_000,
+            block_index: 12,
+            paid_at: 0,
+            method: PaymentMethod::IcpTransfer,
+        })
+        .unwrap();
+        let payment = Payment::from_bytes(Cow::Owned(bytes));
+        assert_eq!(payment.id, 4);
+        assert_eq!(payment.amount_e8s, 1_000);
+        assert_eq!(payment.method, PaymentMethod::IcpTransfer);
+        assert_eq!(payment.refunded_e8s, 0);
+        assert_eq!(payment.wallet_e8s, 0);
+        assert_eq!(payment.refund_split(400), (0, 400));
+    }
+
+    #[test]
+    fn refund_is_paid_from_wallet_share_first() {
+        // A payment without a wallet share is refunded entirely on the ledger
+        assert_eq!(payment(1_000, 0, 0).refund_split(400), (0, 400));
+        // A refund within the wallet share goes back to the wallet
+        assert_eq!(payment(1_000, 300, 0).refund_split(200), (200, 0));
+        // A refund beyond the wallet share is split
+        assert_eq!(payment(1_000, 300, 0).refund_split(500), (300, 200));
+        // Earlier refunds use up the wallet share first
+        assert_eq!(payment(1_000, 300, 100).refund_split(500), (200, 300));
+        assert_eq!(payment(1_000, 300, 300).refund_split(400), (0, 400));
+        assert_eq!(payment(1_000, 300, 600).refund_split(400), (0, 400));
+    }
+
+    #[test]
+    fn deposit_released_in_parts_ends_released() {
+        let mut deposit = deposit(1_000);
+        deposit.record_movement(movement(400, false));
+        assert_eq!(deposit.state, DepositState::PartiallyReleased);
+        assert_eq!(deposit.remaining_e8s(), 600);
+        deposit.record_movement(movement(600, true));
+        assert_eq!(deposit.state, DepositState::Released);
+        assert_eq!(deposit.released_e8s, 400);
+        assert_eq!(deposit.forfeited_e8s, 600);
+        assert_eq!(deposit.remaining_e8s(), 0);
+        assert_eq!(deposit.movements.len(), 2);
+    }
+
+    #[test]
+    fn deposit_fully_forfeited_ends_forfeited() {
+        let mut deposit = deposit(1_000);
+        deposit.record_movement(movement(250, true));
+        assert_eq!(deposit.state, DepositState::PartiallyReleased);
+        deposit.record_movement(movement(750, true));
+        assert_eq!(deposit.state, DepositState::Forfeited);
+        assert_eq!(deposit.released_e8s, 0);
+    }
+
+    #[test]
+    fn deposit_released_at_once_ends_released() {
+        let mut deposit = deposit(1_000);
+        deposit.record_movement(movement(1_000, false));
+        assert_eq!(deposit.state, DepositState::Released);
+        assert_eq!(deposit.movements.len(), 1);
+    }
+
+    #[test]
+    fn pages_follow_their_cursor_to_the_end() {
+        let entries = |from: u64| {
+            (1u64..=5)
+                .map(|n| (n * 10, n))
+                .filter(move |(key, _)| *key >= from)
+        };
+        let page = page_of(entries(0), 5, 2);
+        assert_eq!(page.items, vec![1, 2]);
+        assert_eq!(page.total, 5);
+        assert_eq!(page.next_cursor, Some(30));
+        let items = collect_pages(|cursor| page_of(entries(cursor.unwrap_or(0)), 5, 2));
+        assert_eq!(items, vec![1, 2, 3, 4, 5]);
+        // A limit of zero still returns one item
+        assert_eq!(page_of(entries(0), 5, 0).items, vec![1]);
+    }
+
+    #[test]
+    fn sorted_pages_use_offsets_as_cursors() {
+        let page = page_of_sorted(vec!["a", "b", "c"], Some(1), 1);
+        assert_eq!(page.items, vec!["b"]);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.next_cursor, Some(2));
+        assert!(page_of_sorted(vec!["a"], Some(1), 10).items.is_empty());
+        assert_eq!(
+            directed(1.cmp(&2), SortDirection::Descending),
+            std::cmp::Ordering::Greater
+        );
+        assert_eq!(
+            directed(1.cmp(&2), SortDirection::Ascending),
+            std::cmp::Ordering::Less
+        );
+    }
+
+    #[test]
+    fn text_search_ranks_word_starts_first() {
+        assert_eq!(
+            text_tokens("Land-Rover  DEFENDER 110"),
+            vec!["land", "rover", "defender", "110"]
+        );
+        assert!(text_tokens(" -- ").is_empty());
+
+        for car in [
+            car(1, "Land Rover", "Defender"),
+            car(2, "Toyota", "Land Cruiser"),
+            car(3, "Ford", "Ranger"),
+        ] {
+            index_car_text(&car);
+            CAR_STORAGE.with(|storage| storage.borrow_mut().insert(car.id, car));
+        }
+        let found = |query: &str| -> Vec<u64> {
+            search_cars_by_text(query.to_string())
+                .iter()
+                .map(|car| car.id)
+                .collect()
+        };
+        assert_eq!(found("LAND"), vec![1, 2]);
+        assert_eq!(found("rover def"), vec![1]);
+        assert_eq!(found("ange"), vec![3]);
+        // Rover and Ranger start with the letter, Cruiser only contains it
+        assert_eq!(found("r"), vec![1, 3, 2]);
+        assert!(found("land ford").is_empty());
+    }
+
+    #[test]
+    fn rental_moved_to_another_car_is_indexed_under_it_only() {
+        insert_rental(&rental(1, 10, 100, 0));
+        let mut moved = rental(2, 10, 100, 0);
+        insert_rental(&moved);
+        assert_eq!(rental_ids(rentals_for_car(10)), vec![1, 2]);
+
+        moved.car_id = 11;
+        insert_rental(&moved);
+        assert_eq!(rental_ids(rentals_for_car(10)), vec![1]);
+        assert_eq!(rental_ids(rentals_for_car(11)), vec![2]);
+        assert!(rentals_for_car(12).is_empty());
+
+        // An index lost on upgrade is rebuilt from the stored rentals
+        RENTALS_BY_CAR.with(|index| {
+            let keys: Vec<(u64, u64)> = index.borrow().iter().map(|(key, _)| key).collect();
+            for key in keys {
+                index.borrow_mut().remove(&key);
+            }
+        });
+        rebuild_rental_indexes();
+        assert_eq!(rental_ids(rentals_for_car(11)), vec![2]);
+    }
+
+    #[test]
+    fn customer_index_pages_through_their_rentals_only() {
+        for (id, customer_id) in [(1, 100), (2, 101), (3, 100), (4, 100)] {
+            insert_rental(&rental(id, 10, customer_id, 0));
+        }
+        assert_eq!(rental_ids(rentals_for_customer(100)), vec![1, 3, 4]);
+        assert_eq!(rental_ids(rentals_for_customer(101)), vec![2]);
+
+        let page = RENTALS_BY_CUSTOMER
+            .with(|index| page_of_indexed_rentals(&index.borrow(), 100, None, 2));
+        assert_eq!(rental_ids(page.items), vec![1, 3]);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.next_cursor, Some(4));
+        let page = RENTALS_BY_CUSTOMER
+            .with(|index| page_of_indexed_rentals(&index.borrow(), 100, Some(4), 2));
+        assert_eq!(rental_ids(page.items), vec![4]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn period_holds_the_rentals_starting_within_it_by_start_date() {
+        let day = NANOS_PER_DAY;
+        for (id, start_date) in [(1, 3 * day), (2, day), (3, 2 * day), (4, 5 * day)] {
+            insert_rental(&rental(id, 10, 100, start_date));
+        }
+        assert_eq!(rental_ids(rentals_starting_in(day, 5 * day)), vec![2, 3, 1]);
+        assert!(rentals_starting_in(0, day).is_empty());
+
+        // A rental moved to a new start date is found only there
+        insert_rental(&rental(2, 10, 100, 6 * day));
+        assert_eq!(rental_ids(rentals_starting_in(day, 5 * day)), vec![3, 1]);
+        assert_eq!(
+            rental_ids(rentals_starting_in(5 * day, 7 * day)),
+            vec![4, 2]
+        );
+    }
+
+    #[test]
+    fn vin_check_digit_is_verified() {
+        assert!(validate_vin("1M8GDM9AXKP042788").is_ok());
+        assert!(validate_vin(" 1m8gdm9axkp042788 ").is_ok());
+        assert!(validate_vin("11111111111111111").is_ok());
+        // Wrong check digit, a letter never used in a VIN, and the wrong length
+        assert!(validate_vin("1M8GDM9A1KP042788").is_err());
+        assert!(validate_vin("1M8GDM9AXKP04278O").is_err());
+        assert!(validate_vin("1M8GDM9AXKP04278").is_err());
+    }
+
+    #[test]
+    fn kilometers_beyond_the_included_mileage_are_billed() {
+        let mut updated_config = config();
+        updated_config.included_km_per_day = vec![CategoryMileage {
+            category: CarCategory::Economy,
+            km_per_day: 100,
+        }];
+        set_config(updated_config);
+        let three_days = 3 * NANOS_PER_DAY;
+        assert_eq!(included_km(CarCategory::Economy, 0, three_days), Some(300));
+        assert_eq!(
+            included_km(CarCategory::Economy, 0, three_days + 1),
+            Some(400)
+        );
+        assert_eq!(included_km(CarCategory::Luxury, 0, three_days), None);
+
+        let mut rental_request = rental(1, 10, 100, 0);
+        rental_request.included_km = Some(300);
+        rental_request.checkout_odometer_km = Some(1_000);
+        rental_request.checkin_odometer_km = Some(1_300);
+        bill_mileage_overage(&rental_request, 0).unwrap();
+        assert_eq!(CHARGE_STORAGE.with(|storage| storage.borrow().len()), 0);
+
+        rental_request.checkin_odometer_km = Some(1_320);
+        bill_mileage_overage(&rental_request, 0).unwrap();
+        let charges: Vec<Charge> = CHARGE_STORAGE
+            .with(|storage| storage.borrow().iter().map(|(_, charge)| charge).collect());
+        assert_eq!(charges.len(), 1);
+        assert_eq!(charges[0].kind, ChargeKind::MileageOverage);
+        assert_eq!(
+            charges[0].amount_e8s,
+            20 * config().mileage_overage_per_km_e8s
+        );
+    }
+
+    #[test]
+    fn missing_fuel_is_billed_unless_prepaid() {
+        assert!(validate_fuel_percent(100).is_ok());
+        assert!(validate_fuel_percent(101).is_err());
+
+        let mut rental_request = rental(1, 10, 100, 0);
+        rental_request.checkout_fuel_percent = Some(100);
+        rental_request.checkin_fuel_percent = Some(75);
+        rental_request.fuel_policy = Some(FuelPolicy::Prepaid);
+        bill_refueling(&rental_request, 0).unwrap();
+        assert_eq!(CHARGE_STORAGE.with(|storage| storage.borrow().len()), 0);
+
+        rental_request.fuel_policy = None;
+        bill_refueling(&rental_request, 0).unwrap();
+        let charge = CHARGE_STORAGE.with(|storage| storage.borrow().iter().next().unwrap().1);
+        assert_eq!(charge.kind, ChargeKind::Refueling);
+        assert_eq!(charge.amount_e8s, 25 * config().refuel_fee_per_percent_e8s);
+    }
+
+    #[test]
+    fn cancellation_fee_follows_the_hours_left_before_the_start() {
+        let start_date = 10 * NANOS_PER_DAY;
+        let mut rental_request = rental(1, 10, 100, start_date);
+        rental_request.quoted_price_e8s = 1_000;
+        rental_request.coupon_discount_e8s = 200;
+        rental_request.status = RentalStatus::Pending;
+        assert_eq!(cancellation_fee(&rental_request, 0), 0);
+
+        // By default free from 48 hours ahead, half the price due after that, all of it once started
+        rental_request.status = RentalStatus::Approved;
+        assert_eq!(
+            cancellation_fee(&rental_request, start_date - 48 * NANOS_PER_HOUR),
+            0
+        );
+        assert_eq!(
+            cancellation_fee(&rental_request, start_date - 47 * NANOS_PER_HOUR),
+            400
+        );
+        assert_eq!(cancellation_fee(&rental_request, start_date), 800);
+    }
+
+    #[test]
+    fn csv_fields_may_be_quoted() {
+        let records =
+            parse_csv("make,model\r\n\"Land, Rover\",\"Defender \"\"110\"\"\"\nKia,\n").unwrap();
+        assert_eq!(
+            records,
+            vec![
+                vec!["make".to_string(), "model".to_string()],
+                vec!["Land, Rover".to_string(), "Defender \"110\"".to_string()],
+                vec!["Kia".to_string(), String::new()],
+            ]
+        );
+        assert!(parse_csv("make\n\"Kia").is_err());
+    }
+
+    #[test]
+    fn exported_fields_are_quoted_only_when_needed() {
+        assert_eq!(csv_field("Corolla"), "Corolla");
+        assert_eq!(csv_field("Land, Rover"), "\"Land, Rover\"");
+        assert_eq!(csv_field("12\" wheels"), "\"12\"\" wheels\"");
+        assert_eq!(csv_optional(Some(FuelPolicy::Prepaid)), "Prepaid");
+        assert_eq!(csv_optional::<u64>(None), "");
+        // What is exported reads back the same
+        let line = format!("{},{}", csv_field("a,b"), csv_field("c\"d"));
+        assert_eq!(parse_csv(&line).unwrap(), vec![vec!["a,b", "c\"d"]]);
+    }
+
+    #[test]
+    fn only_closed_rentals_that_ended_before_the_cutoff_are_archived() {
+        let day = NANOS_PER_DAY;
+        let cutoff = 100 * day;
+        let mut completed = rental(1, 10, 100, day);
+        completed.status = RentalStatus::Completed;
+        completed.completed_at = Some(2 * day);
+        let active = rental(2, 10, 100, day);
+        // Returned after the cutoff, though booked to end before it
+        let mut returned_late = rental(3, 10, 100, day);
+        returned_late.status = RentalStatus::Completed;
+        returned_late.completed_at = Some(cutoff + 1);
+        let mut canceled = rental(4, 10, 100, 50 * day);
+        canceled.status = RentalStatus::Canceled;
+        let mut recent = rental(5, 10, 100, cutoff);
+        recent.status = RentalStatus::Canceled;
+        for rental_request in [&completed, &active, &returned_late, &canceled, &recent] {
+            insert_rental(rental_request);
+        }
+        assert_eq!(rental_ids(rentals_due_for_archive(cutoff)), vec![1, 4]);
+    }
+
+    #[test]
+    fn statements_fall_in_the_utc_month_of_their_date() {
+        assert_eq!(year_month(0), (1970, 1));
+        // 2024-12-31 23:59:59 and a second later
+        assert_eq!(year_month(1_735_689_599 * 1_000_000_000), (2024, 12));
+        assert_eq!(year_month(1_735_689_600 * 1_000_000_000), (2025, 1));
+    }
+
+    #[test]
+    fn webhook_signature_is_hmac_sha256_of_timestamp_and_body() {
+        let signature = hmac_sha256(&[0x0b; 32], b"1700000000.{\"id\":1}");
+        assert_eq!(
+            hex_string(&signature),
+            "c6da4ccd3bebb7983dd80cc33fbc0e2d94bc83c37b0aa0e7e73d6e40bdb98313"
+        );
+
+        let interval = EVENT_DELIVERY_INTERVAL.as_nanos() as u64;
+        assert_eq!(delivery_backoff(0), interval);
+        assert_eq!(delivery_backoff(3), 8 * interval);
+        assert_eq!(delivery_backoff(30), EVENT_RETRY_MAX_BACKOFF);
+    }
+
+    #[test]
+    fn dates_and_amounts_are_written_for_people() {
+        // 2024-02-29 13:45 UTC, a leap day
+        let leap_day = 1_709_164_800 * 1_000_000_000;
+        assert_eq!(civil_date(leap_day), (2024, 2, 29));
+        assert_eq!(civil_date(leap_day + NANOS_PER_DAY), (2024, 3, 1));
+        assert_eq!(
+            date_string(leap_day + 13 * NANOS_PER_HOUR + 45 * NANOS_PER_MINUTE),
+            "2024-02-29 13:45 UTC"
+        );
+        assert_eq!(icp_string(150_000_000), "1.50000000 ICP");
+        assert_eq!(icp_string(1), "0.00000001 ICP");
+    }
+
+    #[test]
+    fn gateway_url_is_split_into_path_and_query() {
+        assert_eq!(
+            split_url("/cars?limit=5&cursor=2"),
+            ("/cars", "limit=5&cursor=2")
+        );
+        assert_eq!(split_url("/cars"), ("/cars", ""));
+        assert_eq!(split_url("/cars?a=1?b"), ("/cars", "a=1?b"));
+    }
+
+    #[test]
+    fn opaque_ids_are_hashed_again_until_free() {
+        assert_eq!(opaque_id("car", 7, |_| false).unwrap(), 7);
+
+        let mut updated_config = config();
+        updated_config.opaque_ids = true;
+        set_config(updated_config);
+        assert!(opaque_id("car", 7, |_| false).is_err());
+
+        ID_SEED.with(|seed| *seed.borrow_mut() = Some([3; 32]));
+        let id = opaque_id("car", 7, |_| false).unwrap();
+        assert_ne!(id, 7);
+        assert_eq!(opaque_id("car", 7, |_| false).unwrap(), id);
+        assert_ne!(opaque_id("rental request", 7, |_| false).unwrap(), id);
+        let next = opaque_id("car", 7, |taken| taken == id).unwrap();
+        assert_ne!(next, id);
+        assert_ne!(next, 0);
+    }
+
+    #[test]
+    fn commission_is_taken_in_basis_points_rounding_down() {
+        assert_eq!(bps_of(1_000, DEFAULT_MARKETPLACE_COMMISSION_BPS), 100);
+        assert_eq!(bps_of(999, 1), 0);
+        assert_eq!(bps_of(u64::MAX, BPS_DENOMINATOR), u64::MAX);
+    }
+
+    #[test]
+    fn wallet_is_credited_and_debited_but_never_overdrawn() {
+        let gift_card = WalletTransactionKind::GiftCard { gift_card_id: 9 };
+        let credit = post_wallet_transaction(1, gift_card, 500, 10).unwrap();
+        assert_eq!(credit.balance_after_e8s, 500);
+        let payment = WalletTransactionKind::RentalPayment { rental_id: 2 };
+        let debit = post_wallet_transaction(1, payment, -300, 20).unwrap();
+        assert_eq!(debit.balance_after_e8s, 200);
+        let payment = WalletTransactionKind::RentalPayment { rental_id: 3 };
+        assert!(post_wallet_transaction(1, payment, -201, 30).is_err());
+        assert_eq!(wallet_balance(1), 200);
+        assert_eq!(wallet_balance(2), 0);
+
+        let history: Vec<(i64, u64)> = WALLET_TRANSACTION_STORAGE.with(|storage| {
+            storage
+                .borrow()
+                .iter()
+                .map(|(_, transaction)| (transaction.amount_e8s, transaction.at))
+                .collect()
+        });
+        assert_eq!(history, vec![(500, 10), (-300, 20)]);
+    }
+
+    #[test]
+    fn agreement_terms_are_encoded_with_fields_in_order() {
+        let mut car = car(10, "Kia", "Niro");
+        car.license_plate = "KCA 123A".to_string();
+        CAR_STORAGE.with(|storage| storage.borrow_mut().insert(car.id, car));
+        let mut rental_request = rental(9, 10, 6, NANOS_PER_DAY);
+        rental_request.quoted_price_e8s = 150_000_000;
+        rental_request.coupon_discount_e8s = 10_000_000;
+        rental_request.included_km = Some(300);
+        rental_request.fuel_policy = Some(FuelPolicy::Prepaid);
+        let canister_id = Principal::from_slice(&[1]);
+        let terms = agreement_terms(canister_id, &rental_request).unwrap();
+        assert_eq!(
+            terms,
+            format!(
+                "{{\"canister_id\":\"{}\",\"rental_id\":9,\"customer_id\":6,\"renter\":\"{}\",\
+                 \"car_id\":10,\"car\":\"2020 Kia Niro\",\"license_plate\":\"KCA 123A\",\
+                 \"start_date\":86400000000000,\"end_date\":172800000000000,\
+                 \"pickup_branch_id\":null,\"dropoff_branch_id\":null,\"price_e8s\":150000000,\
+                 \"discount_e8s\":10000000,\"deposit_e8s\":500000000,\"included_km\":300,\
+                 \"fuel_policy\":\"Prepaid\",\"protection\":null}}",
+                canister_id, rental_request.owner
+            )
+        );
+
+        // A rental that was never approved is refused before the key is asked to sign
+        rental_request.status = RentalStatus::Pending;
+        insert_rental(&rental_request);
+        assert!(matches!(
+            run_ready(sign_agreement(9)),
+            Err(Error::InvalidInput { .. })
+        ));
+
+        assert_eq!(agreement_key_id().name, MAINNET_ECDSA_KEY_NAME);
+        let mut updated_config = config();
+        updated_config.ecdsa_key_name = Some("test_key_1".to_string());
+        set_config(updated_config);
+        assert_eq!(agreement_key_id().name, "test_key_1");
+    }
+
+    #[test]
+    fn pii_key_is_only_derived_for_a_valid_transport_key() {
+        let owner = Principal::from_slice(&[7]);
+        for transport_public_key in [Vec::new(), vec![0; 129]] {
+            assert!(matches!(
+                run_ready(derive_pii_key(owner, transport_public_key)),
+                Err(Error::InvalidInput { .. })
+            ));
+        }
+
+        let key_id = pii_key_id();
+        assert_eq!(key_id.name, MAINNET_VETKD_KEY_NAME);
+        assert!(matches!(key_id.curve, VetKdCurve::Bls12381G2));
+        let mut updated_config = config();
+        updated_config.vetkd_key_name = Some("test_key_1".to_string());
+        set_config(updated_config);
+        assert_eq!(pii_key_id().name, "test_key_1");
+    }
+
+    #[test]
+    fn invoice_tax_and_paid_amount_do_not_overflow() {
+        let mut updated_config = config();
+        updated_config.tax_rate_bps = BPS_DENOMINATOR;
+        set_config(updated_config);
+        PAYMENT_STORAGE.with(|storage| storage.borrow_mut().insert(4, payment(100, 0, 300)));
+        let mut rental_request = rental(1, 10, 8, 0);
+        rental_request.quoted_price_e8s = i64::MAX as u64;
+        rental_request.drop_fee_e8s = 0;
+        rental_request.protection_e8s = 0;
+        rental_request.add_ons_e8s = 0;
+        rental_request.payment_id = Some(4);
+
+        let invoice = issue_invoice(&rental_request, 0);
+        assert_eq!(invoice.lines.last().unwrap().amount_e8s, i64::MAX);
+        assert_eq!(invoice.paid_e8s, 0);
+    }
+
+    #[test]
+    fn late_fee_saturates_on_large_settings() {
+        let mut updated_config = config();
+        updated_config.late_grace_period_minutes = 0;
+        updated_config.late_fee_per_day_e8s = 100;
+        updated_config.late_fee_per_hour_e8s = 30;
+        set_config(updated_config.clone());
+        assert_eq!(late_fee(0, NANOS_PER_DAY + 2 * NANOS_PER_HOUR), 160);
+        assert_eq!(late_fee(0, 5 * NANOS_PER_HOUR), 100);
+
+        updated_config.late_fee_per_day_e8s = u64::MAX;
+        updated_config.late_fee_per_hour_e8s = u64::MAX;
+        set_config(updated_config.clone());
+        assert_eq!(late_fee(0, 3 * NANOS_PER_DAY + NANOS_PER_HOUR), u64::MAX);
+
+        updated_config.late_grace_period_minutes = u64::MAX;
+        set_config(updated_config);
+        assert_eq!(late_fee(0, 3 * NANOS_PER_DAY), 0);
+    }
+
+    #[test]
+    fn loyalty_points_saturate_instead_of_overflowing() {
+        let mut updated_config = config();
+        updated_config.loyalty_points_per_icp = u64::MAX;
+        assert!(validate_config(&updated_config).is_err());
+        set_config(updated_config);
+        assert_eq!(loyalty_points_for(u64::MAX), u64::MAX);
+
+        LOYALTY_POINTS.with(|storage| storage.borrow_mut().insert(8, u64::MAX - 1));
+        credit_points(8, 10);
+        assert_eq!(points_balance(8), u64::MAX);
+    }
+
+    #[test]
+    fn rental_holding_a_deposit_cannot_be_deleted() {
+        let mut pending = rental(1, 10, 8, 0);
+        pending.status = RentalStatus::Pending;
+        pending.payment_id = None;
+        assert!(ensure_deletable(&pending).is_ok());
+
+        let mut approved = pending.clone();
+        approved.id = 2;
+        approved.status = RentalStatus::Approved;
+        assert!(matches!(
+            ensure_deletable(&approved),
+            Err(Error::Conflict { .. })
+        ));
+
+        DEPOSIT_STORAGE.with(|storage| storage.borrow_mut().insert(1, deposit(1_000)));
+        assert!(matches!(
+            ensure_deletable(&pending),
+            Err(Error::Conflict { .. })
+        ));
+
+        let mut paid = pending.clone();
+        paid.id = 3;
+        paid.payment_id = Some(4);
+        assert!(matches!(
+            ensure_deletable(&paid),
+            Err(Error::Conflict { .. })
+        ));
+    }
+
+    #[test]
+    fn erasure_waits_for_money_owed_to_the_customer() {
+        let customer = Customer::from(LegacyCustomer {
+            id: 8,
+            name: "Ada".to_string(),
+            email: "ada@example.com".to_string(),
+            phone: String::new(),
+            license_number: "D123".to_string(),
+            created_at: 0,
+        });
+        let mut completed = rental(1, 10, 8, 0);
+        completed.status = RentalStatus::Completed;
+        insert_rental(&completed);
+        DEPOSIT_STORAGE.with(|storage| storage.borrow_mut().insert(1, deposit(1_000)));
+        assert!(matches!(
+            erase_customer_data(customer.clone(), "anonymize_customer"),
+            Err(Error::InvalidInput { .. })
+        ));
+
+        let mut released = deposit(1_000);
+        released.record_movement(movement(1_000, false));
+        DEPOSIT_STORAGE.with(|storage| storage.borrow_mut().insert(1, released));
+        let gift_card = WalletTransactionKind::GiftCard { gift_card_id: 9 };
+        post_wallet_transaction(8, gift_card, 500, 0).unwrap();
+        assert!(matches!(
+            erase_customer_data(customer, "anonymize_customer"),
+            Err(Error::InvalidInput { .. })
+        ));
+    }
+}